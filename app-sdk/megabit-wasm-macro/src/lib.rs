@@ -35,6 +35,16 @@ fn wasm_app_inner(input: TokenStream2) -> TokenStream2 {
                     APP_SINGLETON.as_mut().unwrap().run()
                 }
             }
+
+            #[plugin_fn]
+            pub fn on_button_event(raw_event: Vec<u8>) -> FnResult<()> {
+                let event = megabit_app_sdk::input::ButtonEvent::from_bytes(&raw_event)?;
+                // Events which arrive before the app is set up have nothing to go to
+                match unsafe { APP_SINGLETON.as_mut() } {
+                    Some(app) => app.on_button_event(event),
+                    None => Ok(()),
+                }
+            }
         }
     }
 }
@@ -21,7 +21,10 @@ extern "ExtismHost" {
     pub fn get_display_info() -> Vec<u8>;
 
     pub fn kv_store_read(key: String) -> Vec<u8>;
-    pub fn kv_store_write(key: String, value: Vec<u8>) -> ();
+    pub fn kv_store_write(key: String, value: Vec<u8>) -> u32;
+    pub fn kv_store_delete(key: String) -> u32;
+    pub fn kv_store_list_keys() -> Vec<u8>;
+    pub fn kv_store_clear() -> ();
 
     pub fn log(level: u32, line: String) -> ();
 }
@@ -0,0 +1,40 @@
+/// What happened to a button, the same kinds of events the device reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ButtonEventKind {
+    Press,
+    Release,
+    /// The button has been held down for a while, it's followed by `Repeat` events for as long as
+    /// it stays down
+    LongPress,
+    /// The button was pressed again shortly after a short press
+    DoublePress,
+    Repeat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ButtonEvent {
+    /// Which of the device's buttons the event is for, starting from zero
+    pub button_id: u8,
+    pub kind: ButtonEventKind,
+}
+
+impl ButtonEvent {
+    /// Parses an event as the runner passes it to the app, the button ID followed by the kind.
+    pub fn from_bytes(raw_event: &[u8]) -> Result<Self, extism_pdk::Error> {
+        let [button_id, kind] = raw_event else {
+            return Err(extism_pdk::Error::msg("Button event must be two bytes"));
+        };
+        let kind = match kind {
+            0 => ButtonEventKind::Press,
+            1 => ButtonEventKind::Release,
+            2 => ButtonEventKind::LongPress,
+            3 => ButtonEventKind::DoublePress,
+            4 => ButtonEventKind::Repeat,
+            _ => return Err(extism_pdk::Error::msg("Unknown button event kind")),
+        };
+        Ok(ButtonEvent {
+            button_id: *button_id,
+            kind,
+        })
+    }
+}
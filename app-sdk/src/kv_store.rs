@@ -1,4 +1,23 @@
 use crate::host;
+use std::fmt;
+
+/// What the runner answers a write with when it would have taken the app's store past its limits.
+const WRITE_QUOTA_EXCEEDED: u32 = 1;
+/// What the runner answers a delete with when there was a value to remove.
+const DELETE_REMOVED: u32 = 1;
+
+/// The write would have taken the app's store past the number of keys or bytes the runner allows
+/// it, so the store was left as it was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaExceeded;
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KV store quota exceeded")
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
 
 pub fn read<T: serde::de::DeserializeOwned>(
     key: impl Into<String>,
@@ -11,11 +30,41 @@ pub fn read<T: serde::de::DeserializeOwned>(
     }
 }
 
+/// Fails with [`QuotaExceeded`] if the store doesn't have room for the value, which can be told
+/// apart from other errors with `downcast_ref`.
 pub fn write(
     key: impl Into<String>,
     value: impl serde::Serialize,
 ) -> Result<(), extism_pdk::Error> {
     let value_bytes = rmp_serde::to_vec(&value)?;
-    unsafe { host::kv_store_write(key.into(), value_bytes)? };
-    Ok(())
+    match unsafe { host::kv_store_write(key.into(), value_bytes)? } {
+        WRITE_QUOTA_EXCEEDED => Err(QuotaExceeded.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Removes the value stored under `key`, returning whether there was one.
+pub fn delete(key: impl Into<String>) -> Result<bool, extism_pdk::Error> {
+    Ok(unsafe { host::kv_store_delete(key.into())? } == DELETE_REMOVED)
+}
+
+/// Every key with a value stored under it, in order.
+pub fn list_keys() -> Result<Vec<String>, extism_pdk::Error> {
+    let mut bytes = &unsafe { host::kv_store_list_keys()? }[..];
+    let mut keys = Vec::new();
+    while let Some((len, rest)) = bytes.split_first_chunk::<4>() {
+        let len = u32::from_be_bytes(*len) as usize;
+        if rest.len() < len {
+            break;
+        }
+        let (key, rest) = rest.split_at(len);
+        keys.push(String::from_utf8(key.to_vec())?);
+        bytes = rest;
+    }
+    Ok(keys)
+}
+
+/// Removes everything the app has stored.
+pub fn clear() -> Result<(), extism_pdk::Error> {
+    unsafe { host::kv_store_clear() }
 }
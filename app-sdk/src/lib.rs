@@ -1,9 +1,11 @@
 pub mod display;
 pub mod host;
+pub mod input;
 pub mod kv_store;
 pub mod log;
 use display::DisplayConfiguration;
 use extism_pdk::FnResult;
+use input::ButtonEvent;
 pub use megabit_wasm_macro::megabit_wasm_app;
 
 pub trait MegabitApp {
@@ -12,4 +14,9 @@ pub trait MegabitApp {
         Self: Sized;
 
     fn run(&mut self) -> FnResult<()>;
+
+    /// Called between runs with the button events the runner is configured to pass on to the app.
+    fn on_button_event(&mut self, _event: ButtonEvent) -> FnResult<()> {
+        Ok(())
+    }
 }
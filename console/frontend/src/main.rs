@@ -23,7 +23,7 @@ fn app() -> Html {
 fn switch(route: navbar::Route) -> Html {
     let page_contents = match route {
         navbar::Route::Control => html! { <tabs::ControlPage /> },
-        navbar::Route::Installed => html! { <p>{ "Installed Apps" }</p> },
+        navbar::Route::Installed => html! { <tabs::InstalledPage /> },
     };
 
     html! {
@@ -0,0 +1,48 @@
+use crate::providers::use_subscription_manager;
+use megabit_runner_msgs::{AppFault, AppFaultKind, ConsoleMessage};
+use yew::{function_component, html, use_state, Callback, Html, Properties};
+
+#[function_component(AppFaultStatus)]
+pub fn app_fault_status(_props: &AppFaultStatusProperties) -> Html {
+    // Only the most recent fault is shown, the runner has already dealt with the app by then
+    let last_fault = use_state(|| None);
+
+    let sub_manager = use_subscription_manager();
+    let _subscription = {
+        let last_fault = last_fault.clone();
+        use_state(move || {
+            sub_manager.subscribe(
+                "app_fault_status",
+                "AppFault",
+                Callback::from(move |msg| {
+                    if let ConsoleMessage::AppFault(fault) = msg {
+                        last_fault.set(Some(fault));
+                    }
+                }),
+            );
+        })
+    };
+
+    match &*last_fault {
+        Some(AppFault {
+            app_name,
+            function,
+            kind,
+            ..
+        }) => {
+            let reason = match kind {
+                AppFaultKind::TimeLimitExceeded { limit_ms } => {
+                    format!("{function} ran past its limit of {limit_ms} ms")
+                }
+                AppFaultKind::Error { message } => format!("{function} failed: {message}"),
+            };
+            html! {
+                <span class="badge text-bg-warning">{format!("{app_name}: {reason}")}</span>
+            }
+        }
+        None => html! {},
+    }
+}
+
+#[derive(Properties, PartialEq)]
+pub struct AppFaultStatusProperties {}
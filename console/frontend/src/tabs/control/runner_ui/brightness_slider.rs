@@ -0,0 +1,32 @@
+use crate::providers::use_websocket;
+use megabit_runner_msgs::{ConsoleMessage, SetBrightness};
+use web_sys::HtmlInputElement;
+use yew::{function_component, html, Callback, Event, Html, Properties, TargetCast};
+
+#[function_component(BrightnessSlider)]
+pub fn brightness_slider(_props: &BrightnessSliderProperties) -> Html {
+    let ws = use_websocket();
+
+    let on_change = {
+        let ws = ws.clone();
+        Callback::from(move |event: Event| {
+            let input = event.target_unchecked_into::<HtmlInputElement>();
+            let Ok(brightness) = input.value().parse() else {
+                return;
+            };
+            let msg = ConsoleMessage::SetBrightness(SetBrightness { brightness });
+            let msg = serde_json::to_vec(&msg).unwrap();
+            ws.send_message(msg);
+        })
+    };
+
+    html! {
+        <label class="form-label">
+            {"Brightness"}
+            <input type="range" class="form-range" min="0" max="255" value="255" onchange={on_change} />
+        </label>
+    }
+}
+
+#[derive(Properties, PartialEq)]
+pub struct BrightnessSliderProperties {}
@@ -0,0 +1,38 @@
+use crate::providers::use_subscription_manager;
+use megabit_runner_msgs::{ConsoleMessage, DeviceConnection};
+use yew::{function_component, html, use_state, Callback, Html, Properties};
+
+#[function_component(DeviceStatus)]
+pub fn device_status(_props: &DeviceStatusProperties) -> Html {
+    // Nothing is known about the device until the runner reports a change to its connection
+    let connected = use_state(|| None);
+
+    let sub_manager = use_subscription_manager();
+    let _subscription = {
+        let connected = connected.clone();
+        use_state(move || {
+            sub_manager.subscribe(
+                "device_status",
+                "DeviceConnection",
+                Callback::from(move |msg| {
+                    if let ConsoleMessage::DeviceConnection(DeviceConnection {
+                        connected: is_connected,
+                    }) = msg
+                    {
+                        connected.set(Some(is_connected));
+                    }
+                }),
+            );
+        })
+    };
+
+    match *connected {
+        Some(false) => html! {
+            <span class="badge text-bg-danger">{"Device disconnected"}</span>
+        },
+        _ => html! {},
+    }
+}
+
+#[derive(Properties, PartialEq)]
+pub struct DeviceStatusProperties {}
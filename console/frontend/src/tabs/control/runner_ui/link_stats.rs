@@ -0,0 +1,66 @@
+use crate::providers::{use_subscription_manager, use_websocket};
+use gloo::timers::callback::Interval;
+use megabit_runner_msgs::{ConsoleMessage, LinkStats, LinkStatsResponse, RequestLinkStats};
+use yew::{function_component, html, use_state, Callback, Html, Properties};
+
+const POLL_PERIOD_MS: u32 = 1000;
+
+#[function_component(LinkStatsPanel)]
+pub fn link_stats_panel(_props: &LinkStatsPanelProperties) -> Html {
+    let ws = use_websocket();
+    let stats = use_state(|| None::<LinkStats>);
+
+    let sub_manager = use_subscription_manager();
+    let _subscription = {
+        let stats = stats.clone();
+        use_state(move || {
+            sub_manager.subscribe(
+                "link_stats",
+                "LinkStatsResponse",
+                Callback::from(move |msg| {
+                    if let ConsoleMessage::LinkStatsResponse(LinkStatsResponse {
+                        stats: new_stats,
+                        ..
+                    }) = msg
+                    {
+                        stats.set(Some(new_stats));
+                    }
+                }),
+            );
+        })
+    };
+
+    let _poller = use_state(move || {
+        Interval::new(POLL_PERIOD_MS, move || {
+            let msg = ConsoleMessage::RequestLinkStats(RequestLinkStats {
+                request_id: String::from("link_stats"),
+            });
+            ws.send_message(serde_json::to_vec(&msg).unwrap());
+        })
+    });
+
+    let Some(stats) = &*stats else {
+        return html! {};
+    };
+    let rtt = |rtt: Option<f64>| rtt.map_or(String::from("-"), |rtt| format!("{rtt:.1} ms"));
+    html! {
+        <small class="text-body-secondary">
+            {format!(
+                "RTT p50 {} / p90 {} / p99 {}, {} of {} pings missed, {} reconnects, tx {:.0} B/s ({:.1} frames/s), rx {:.0} B/s ({:.1} frames/s)",
+                rtt(stats.rtt_p50_ms),
+                rtt(stats.rtt_p90_ms),
+                rtt(stats.rtt_p99_ms),
+                stats.ping_timeouts,
+                stats.pings_sent,
+                stats.reconnects,
+                stats.tx_bytes_per_sec,
+                stats.tx_frames_per_sec,
+                stats.rx_bytes_per_sec,
+                stats.rx_frames_per_sec,
+            )}
+        </small>
+    }
+}
+
+#[derive(Properties, PartialEq)]
+pub struct LinkStatsPanelProperties {}
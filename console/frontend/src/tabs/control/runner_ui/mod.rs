@@ -1,11 +1,21 @@
+use app_fault_status::AppFaultStatus;
+use brightness_slider::BrightnessSlider;
+use device_status::DeviceStatus;
+use link_stats::LinkStatsPanel;
 use next_app_button::NextAppButton;
 use playback_button::PlaybackButton;
 use prev_app_button::PrevAppButton;
+use reload_apps_button::ReloadAppsButton;
 use yew::{function_component, html, Html};
 
+mod app_fault_status;
+mod brightness_slider;
+mod device_status;
+mod link_stats;
 mod next_app_button;
 mod playback_button;
 mod prev_app_button;
+mod reload_apps_button;
 
 #[function_component(RunnerUi)]
 pub fn runner_ui() -> Html {
@@ -20,6 +30,21 @@ pub fn runner_ui() -> Html {
             <div class="col justify-content-center" style="display:grid">
                 <NextAppButton/>
             </div>
+            <div class="col justify-content-center" style="display:grid">
+                <ReloadAppsButton/>
+            </div>
+            <div class="col justify-content-center" style="display:grid">
+                <BrightnessSlider/>
+            </div>
+            <div class="col justify-content-center" style="display:grid">
+                <DeviceStatus/>
+            </div>
+            <div class="col justify-content-center" style="display:grid">
+                <AppFaultStatus/>
+            </div>
+            <div class="col justify-content-center" style="display:grid">
+                <LinkStatsPanel/>
+            </div>
         </>
     }
 }
@@ -0,0 +1,25 @@
+use crate::providers::use_websocket;
+use crate::utils::Button;
+use megabit_runner_msgs::ConsoleMessage;
+use yew::{function_component, html, Callback, Html, Properties};
+
+#[function_component(ReloadAppsButton)]
+pub fn reload_apps_button(_props: &ReloadAppsButtonProperties) -> Html {
+    let ws = use_websocket();
+
+    let on_click = {
+        let ws = ws.clone();
+        Callback::from(move |_| {
+            let msg = ConsoleMessage::ReloadApps;
+            let msg = serde_json::to_vec(&msg).unwrap();
+            ws.send_message(msg);
+        })
+    };
+
+    html! {
+        <Button text={"Reload Apps"} on_click_cb={on_click} />
+    }
+}
+
+#[derive(Properties, PartialEq)]
+pub struct ReloadAppsButtonProperties {}
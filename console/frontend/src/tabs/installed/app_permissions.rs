@@ -0,0 +1,69 @@
+use megabit_runner_msgs::{AppPermissions, InstalledApp};
+use web_sys::HtmlInputElement;
+use yew::{function_component, html, Callback, Event, Html, Properties, TargetCast};
+
+/// One app's permissions, each change is passed on to `on_change` with the rest of them as they
+/// were.
+#[function_component(AppPermissionsRow)]
+pub fn app_permissions_row(props: &AppPermissionsRowProperties) -> Html {
+    let flag = |field: fn(&mut AppPermissions) -> &mut bool| {
+        let checked = *field(&mut props.app.permissions.clone());
+        let onchange = on_edit(props, move |permissions, input| {
+            *field(permissions) = input.checked();
+        });
+        html! {
+            <td><input type="checkbox" class="form-check-input" {checked} {onchange} /></td>
+        }
+    };
+    // Lists are edited as a comma separated line
+    let list = |field: fn(&mut AppPermissions) -> &mut Vec<String>, placeholder: &'static str| {
+        let value = field(&mut props.app.permissions.clone()).join(", ");
+        let onchange = on_edit(props, move |permissions, input| {
+            *field(permissions) = input
+                .value()
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect();
+        });
+        html! {
+            <td>
+                <input type="text" class="form-control form-control-sm" {placeholder} {value} {onchange} />
+            </td>
+        }
+    };
+
+    html! {
+        <tr>
+            <th scope="row">{&props.app.app_name}</th>
+            { flag(|permissions| &mut permissions.kv) }
+            { list(|permissions| &mut permissions.network_hosts, "api.example.com") }
+            { list(|permissions| &mut permissions.filesystem_dirs, "/home/megabit/photos") }
+            { flag(|permissions| &mut permissions.time) }
+            { flag(|permissions| &mut permissions.input) }
+            { flag(|permissions| &mut permissions.notifications) }
+            { flag(|permissions| &mut permissions.messaging) }
+        </tr>
+    }
+}
+
+fn on_edit(
+    props: &AppPermissionsRowProperties,
+    apply: impl Fn(&mut AppPermissions, &HtmlInputElement) + 'static,
+) -> Callback<Event> {
+    let permissions = props.app.permissions.clone();
+    let on_change = props.on_change.clone();
+    Callback::from(move |event: Event| {
+        let input = event.target_unchecked_into::<HtmlInputElement>();
+        let mut permissions = permissions.clone();
+        apply(&mut permissions, &input);
+        on_change.emit(permissions);
+    })
+}
+
+#[derive(Properties, PartialEq)]
+pub struct AppPermissionsRowProperties {
+    pub app: InstalledApp,
+    pub on_change: Callback<AppPermissions>,
+}
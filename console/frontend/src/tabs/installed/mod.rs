@@ -0,0 +1,101 @@
+use crate::providers::{use_subscription_manager, use_websocket};
+use app_permissions::AppPermissionsRow;
+use gloo::timers::callback::Interval;
+use megabit_runner_msgs::{
+    AppPermissions, AppPermissionsResponse, ConsoleMessage, InstalledApp, RequestAppPermissions,
+    SetAppPermissions,
+};
+use yew::{function_component, html, use_state, Callback, Html};
+
+mod app_permissions;
+
+/// Apps can be installed while the console is open, so the listing is asked for again this often.
+const POLL_PERIOD_MS: u32 = 5000;
+
+#[function_component(InstalledPage)]
+pub fn installed_page() -> Html {
+    let ws = use_websocket();
+    let apps = use_state(Vec::<InstalledApp>::new);
+
+    let sub_manager = use_subscription_manager();
+    let _subscription = {
+        let apps = apps.clone();
+        use_state(move || {
+            sub_manager.subscribe(
+                "installed_apps",
+                "AppPermissionsResponse",
+                Callback::from(move |msg| {
+                    if let ConsoleMessage::AppPermissionsResponse(AppPermissionsResponse {
+                        apps: installed_apps,
+                        ..
+                    }) = msg
+                    {
+                        apps.set(installed_apps);
+                    }
+                }),
+            );
+        })
+    };
+
+    let _poller = {
+        let ws = ws.clone();
+        use_state(move || {
+            let request_apps = move || {
+                let msg = ConsoleMessage::RequestAppPermissions(RequestAppPermissions {
+                    request_id: String::from("installed_apps"),
+                });
+                ws.send_message(serde_json::to_vec(&msg).unwrap());
+            };
+            request_apps();
+            Interval::new(POLL_PERIOD_MS, request_apps)
+        })
+    };
+
+    let rows = apps.iter().map(|app| {
+        let on_change = {
+            let ws = ws.clone();
+            let apps = apps.clone();
+            let app_id = app.app_id.clone();
+            Callback::from(move |permissions: AppPermissions| {
+                // Shown as changed right away so that further changes build on this one, the
+                // next listing from the runner has it anyway
+                let mut updated_apps = (*apps).clone();
+                if let Some(app) = updated_apps.iter_mut().find(|app| app.app_id == app_id) {
+                    app.permissions = permissions.clone();
+                }
+                apps.set(updated_apps);
+
+                let msg = ConsoleMessage::SetAppPermissions(SetAppPermissions {
+                    app_id: app_id.clone(),
+                    permissions,
+                });
+                ws.send_message(serde_json::to_vec(&msg).unwrap());
+            })
+        };
+        html! {
+            <AppPermissionsRow key={app.app_id.clone()} app={app.clone()} {on_change} />
+        }
+    });
+
+    html! {
+        <div class="container bg-dark" style="padding-top: 75px; padding-bottom: 20px; height: 100%">
+            <table class="table table-dark table-striped align-middle">
+                <thead>
+                    <tr>
+                        <th scope="col">{"App"}</th>
+                        <th scope="col">{"KV Store"}</th>
+                        <th scope="col">{"Network Hosts"}</th>
+                        <th scope="col">{"Filesystem Dirs"}</th>
+                        <th scope="col">{"Time"}</th>
+                        <th scope="col">{"Input"}</th>
+                        <th scope="col">{"Notifications"}</th>
+                        <th scope="col">{"Messaging"}</th>
+                    </tr>
+                </thead>
+                <tbody>
+                    { for rows }
+                </tbody>
+            </table>
+        </div>
+    }
+}
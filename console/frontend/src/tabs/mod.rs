@@ -1,2 +1,4 @@
 pub mod control;
+pub mod installed;
 pub use control::ControlPage;
+pub use installed::InstalledPage;
@@ -1,16 +1,18 @@
 #![no_std]
 #![no_main]
 
+use core::cell::RefCell;
 use defmt::unwrap;
 use defmt_rtt as _;
 use embassy_executor::Executor;
 use embassy_rp::{
     Peripheral, bind_interrupts,
+    flash::{Blocking, Flash},
     gpio::{Input, Level, Output, Pin, Pull},
     multicore::{Stack, spawn_core1},
     peripherals::{
-        self, PIN_0, PIN_1, PIN_2, PIN_3, PIN_4, PIN_5, PIN_6, PIN_7, PIN_8, PIN_9, PIN_10, PIN_11,
-        PIN_12, PIN_17, PIN_19, PIN_21, PIN_25, PWM_CH0, PWM_CH1, PWM_CH2, USB,
+        self, FLASH, PIN_0, PIN_1, PIN_2, PIN_3, PIN_4, PIN_5, PIN_6, PIN_7, PIN_8, PIN_9, PIN_10,
+        PIN_11, PIN_12, PIN_17, PIN_19, PIN_21, PIN_25, PWM_CH0, PWM_CH1, PWM_CH2, USB,
     },
     pwm::{self, Pwm},
     usb::{self, InterruptHandler},
@@ -22,19 +24,30 @@ use embassy_sync::{
 };
 use megabit_coproc_common::{
     cobs_buffer::CobsBuffer,
+    device_info::DeviceInfo,
     display::{
-        COLUMNS, DISPLAY_CMD_QUEUE_SIZE, PixelBuffer, ROWS, WaveshareDriver,
+        COLUMNS, DISPLAY_CMD_QUEUE_SIZE, PixelBuffer, ROWS, SharedPixelBuffer, WaveshareDriver,
         rgb_matrix::DisplayCommandHandler,
     },
+    firmware_update::UpdateStorage,
     msg_router::{
-        MessageRouter,
+        MessageRouter, Request,
         display_cmd_router::{DisplayCmdRouter, DisplayCommand},
         system_cmd_router::{SystemCmdRouter, SystemCommand},
     },
+    settings::{SettingsPersister, SettingsStorage},
     system_state::{Button, RgbLed, SYSTEM_CMD_QUEUE_SIZE, SystemStateManager},
     usb::{Responder, init_usb_device, split},
 };
+use megabit_serial_protocol::wire::{DeviceFeatures, ErrorCode, ResetReason, Status};
+use memory::{flash_app::AppPartition, flash_boot};
 use panic_probe as _;
+use pico_flash::PicoFlash;
+use rw_flash::{
+    FlashStorage, image,
+    nvs::{self, BootState},
+    update::{ImageUpdate, UpdateError},
+};
 use static_cell::StaticCell;
 
 type DisplayDriver = WaveshareDriver<
@@ -59,6 +72,8 @@ type UsbDriver = usb::Driver<'static, peripherals::USB>;
 
 const DEFAULT_MONO_COLOR: (u8, u8, u8) = (0xff, 00, 00);
 
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
 const COBS_DECODE_BUFFER_SIZE: usize = 1024;
 const COBS_ENCODE_BUFFER_SIZE: usize = 256;
 
@@ -70,14 +85,14 @@ static COBS_DECODE_BUFFER: StaticCell<[u8; COBS_DECODE_BUFFER_SIZE]> = StaticCel
 static COBS_ENCODE_BUFFER: StaticCell<[u8; COBS_ENCODE_BUFFER_SIZE]> = StaticCell::new();
 static MESSAGE_BUFFER: StaticCell<[u8; COBS_DECODE_BUFFER_SIZE]> = StaticCell::new();
 static USB_RESPONDER: StaticCell<Responder<UsbDriver, COBS_ENCODE_BUFFER_SIZE>> = StaticCell::new();
-static ROW_DATA_BUFFER: StaticCell<Mutex<NoopRawMutex, [u16; COLUMNS]>> = StaticCell::new();
 static DISPLAY_CMD_CHANNEL: StaticCell<
-    Channel<NoopRawMutex, DisplayCommand, DISPLAY_CMD_QUEUE_SIZE>,
+    Channel<NoopRawMutex, Request<DisplayCommand>, DISPLAY_CMD_QUEUE_SIZE>,
+> = StaticCell::new();
+static SYSTEM_CMD_CHANNEL: StaticCell<
+    Channel<NoopRawMutex, Request<SystemCommand>, SYSTEM_CMD_QUEUE_SIZE>,
 > = StaticCell::new();
-static SYSTEM_CMD_CHANNEL: StaticCell<Channel<NoopRawMutex, SystemCommand, SYSTEM_CMD_QUEUE_SIZE>> =
-    StaticCell::new();
-static PIXEL_BUFFER_HANDLE: StaticCell<PixelBuffer<CriticalSectionRawMutex, { ROWS * COLUMNS }>> =
-    StaticCell::new();
+static PIXEL_BUFFER_HANDLE: StaticCell<SharedPixelBuffer> = StaticCell::new();
+static APP_FLASH: StaticCell<AppFlash> = StaticCell::new();
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
@@ -88,19 +103,26 @@ fn main() -> ! {
     let peripherals = embassy_rp::init(Default::default());
 
     // Initialize the USB driver and handles
+    let mut flash = Flash::new_blocking(peripherals.FLASH);
     let usb_driver = usb::Driver::new(peripherals.USB, Irqs);
-    let (usb, cdc_acm) = init_usb_device(usb_driver);
+    let (usb, cdc_acm) = init_usb_device(usb_driver, read_serial_number(&mut flash));
+    let flash: &'static AppFlash = APP_FLASH.init(AppFlash(RefCell::new(flash)));
     let (responder, receiver) = split(
         cdc_acm,
         COBS_ENCODE_BUFFER.init_with(|| [0; COBS_ENCODE_BUFFER_SIZE]),
     );
     let responder = USB_RESPONDER.init(responder);
 
-    let row_data_buffer = ROW_DATA_BUFFER.init_with(|| Mutex::new([0u16; COLUMNS]));
+    let pixel_buffer = PIXEL_BUFFER_HANDLE.init_with(|| {
+        PixelBuffer::new(
+            Mutex::new([0u16; ROWS * COLUMNS]),
+            Mutex::new([0u16; ROWS * COLUMNS]),
+        )
+    });
 
     let display_cmd_channel = DISPLAY_CMD_CHANNEL.init(Channel::new());
     let display_cmd_router =
-        DisplayCmdRouter::new(display_cmd_channel.sender(), row_data_buffer, true);
+        DisplayCmdRouter::new(display_cmd_channel.sender(), pixel_buffer, true);
     let system_cmd_channel = SYSTEM_CMD_CHANNEL.init(Channel::new());
     let system_cmd_router = SystemCmdRouter::new(system_cmd_channel.sender());
 
@@ -111,15 +133,10 @@ fn main() -> ! {
         responder,
         display_cmd_router,
         system_cmd_router,
+        AppUpdater::new(flash),
+        read_device_info(),
     );
 
-    let pixel_buffer = PIXEL_BUFFER_HANDLE.init_with(|| {
-        PixelBuffer::new(
-            Mutex::new([0u16; ROWS * COLUMNS]),
-            Mutex::new([0u16; ROWS * COLUMNS]),
-        )
-    });
-
     let r1 = Output::new(peripherals.PIN_0, Level::Low);
     let g1 = Output::new(peripherals.PIN_1, Level::Low);
     let r2 = Output::new(peripherals.PIN_2, Level::Low);
@@ -147,6 +164,8 @@ fn main() -> ! {
     let led_pin = Output::new(peripherals.PIN_25, Level::Low);
     let button_pin = UserButton::new(peripherals.PIN_17);
 
+    let settings_persister = SettingsPersister::new(NvsSettings::new(flash), pixel_buffer);
+
     let debug_pin = Output::new(peripherals.PIN_19, Level::Low);
     let debug_2 = Output::new(peripherals.PIN_21, Level::Low);
 
@@ -155,7 +174,6 @@ fn main() -> ! {
         display_cmd_channel.receiver(),
         DEFAULT_MONO_COLOR,
         driver_handle,
-        row_data_buffer,
         debug_pin,
     );
     let system_state_mgr = SystemStateManager::new(
@@ -163,7 +181,7 @@ fn main() -> ! {
         responder,
         rgb_led,
         led_pin,
-        button_pin,
+        [button_pin],
     );
 
     spawn_core1(
@@ -180,6 +198,7 @@ fn main() -> ! {
         unwrap!(spawner.spawn(core0_task(
             display_cmd_handler,
             system_state_mgr,
+            settings_persister,
             usb,
             router
         )))
@@ -198,17 +217,21 @@ async fn core0_task(
         NanoRgbLed<PWM_CH0, PWM_CH1, PWM_CH2>,
         Output<'static, PIN_25>,
         UserButton<PIN_17>,
+        1,
     >,
+    settings_persister: SettingsPersister<NvsSettings>,
     mut usb: embassy_usb::UsbDevice<'static, UsbDriver>,
     router: MessageRouter<
         UsbDriver,
         Responder<UsbDriver, COBS_ENCODE_BUFFER_SIZE>,
+        AppUpdater,
         COBS_DECODE_BUFFER_SIZE,
     >,
 ) {
-    embassy_futures::join::join4(
+    embassy_futures::join::join5(
         display_cmd_handler.run(),
         system_state_mgr.run(),
+        settings_persister.run(),
         usb.run(),
         router.run(),
     )
@@ -224,6 +247,230 @@ async fn core1_task(mut waveshare: DisplayDriver, mut debug_pin: Output<'static,
     }
 }
 
+/// Uses the flash chip's unique ID as the USB serial number so that each board reports its own.
+fn read_serial_number(flash: &mut Flash<'static, FLASH, Blocking, FLASH_SIZE>) -> &'static str {
+    static SERIAL_NUMBER: StaticCell<[u8; 16]> = StaticCell::new();
+    const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut unique_id = [0u8; 8];
+    if flash.blocking_unique_id(&mut unique_id).is_err() {
+        defmt::warn!("Unable to read the flash unique ID for the serial number");
+    }
+    let serial_number = SERIAL_NUMBER.init([0; 16]);
+    for (digits, byte) in serial_number.chunks_exact_mut(2).zip(unique_id) {
+        digits[0] = HEX_DIGITS[usize::from(byte >> 4)];
+        digits[1] = HEX_DIGITS[usize::from(byte & 0x0f)];
+    }
+    unwrap!(core::str::from_utf8(serial_number))
+}
+
+fn read_device_info() -> DeviceInfo {
+    let flash = PicoFlash::new(nvs::SECTOR_SIZE);
+    let nvs = nvs::NvsHandle { flash: &flash };
+
+    let active_partition = running_partition();
+    let image_header = image::read_image_header(active_partition, &flash);
+
+    DeviceInfo {
+        image_version: if image_header.is_valid() {
+            image_header.image_version()
+        } else {
+            0
+        },
+        active_partition: active_partition as u8,
+        bootloader_version: nvs
+            .read_boot_state()
+            .map_or(0, |state| state.bootloader_version),
+        features: DeviceFeatures::UPDATE_ROW_RGB_RLE
+            | DeviceFeatures::UPDATE_ROW_RGB_DELTA
+            | DeviceFeatures::UPDATE_REGION_RGB
+            | DeviceFeatures::FRAME_CRC32
+            | DeviceFeatures::SET_BRIGHTNESS
+            | DeviceFeatures::FIRMWARE_UPDATE,
+        reset_reason: read_reset_reason(),
+    }
+}
+
+/// The bootloader points the vector table at the image it jumped into.
+fn running_partition() -> AppPartition {
+    let vtor = unsafe { (*cortex_m::peripheral::SCB::PTR).vtor.read() };
+    if vtor >= AppPartition::B.boot_target_addr() {
+        AppPartition::B
+    } else {
+        AppPartition::A
+    }
+}
+
+/// A watchdog reset leaves the chip's own reset reason alone, so it's checked for first. A reset
+/// requested through the core's AIRCR doesn't show up in either and is reported as unknown.
+fn read_reset_reason() -> ResetReason {
+    let watchdog_reason = embassy_rp::pac::WATCHDOG.reason().read();
+    if watchdog_reason.force() {
+        return ResetReason::Software;
+    }
+    if watchdog_reason.timer() {
+        return ResetReason::Watchdog;
+    }
+
+    let chip_reset = embassy_rp::pac::VREG_AND_CHIP_RESET.chip_reset().read();
+    if chip_reset.had_psm_restart() {
+        ResetReason::Debugger
+    } else if chip_reset.had_run() {
+        ResetReason::ResetPin
+    } else if chip_reset.had_por() {
+        ResetReason::PowerOn
+    } else {
+        ResetReason::Unknown
+    }
+}
+
+/// NVS keys of the app's settings.
+const BRIGHTNESS_KEY: u16 = 0x0100;
+
+/// Keeps each setting under its own key in NVS.
+struct NvsSettings {
+    flash: &'static AppFlash,
+}
+
+impl NvsSettings {
+    fn new(flash: &'static AppFlash) -> Self {
+        Self { flash }
+    }
+}
+
+impl SettingsStorage for NvsSettings {
+    fn load_brightness(&self) -> Option<u8> {
+        let nvs = nvs::NvsHandle { flash: self.flash };
+        let mut brightness = [0; 1];
+        match nvs.read(BRIGHTNESS_KEY, &mut brightness) {
+            Some(1) => Some(brightness[0]),
+            // Earlier images kept the brightness in the boot state
+            _ => nvs.read_boot_state()?.brightness(),
+        }
+    }
+
+    fn store_brightness(&mut self, brightness: u8) {
+        let nvs = nvs::NvsHandle { flash: self.flash };
+        if nvs.write(BRIGHTNESS_KEY, &[brightness]).is_err() {
+            defmt::warn!("No room left in NVS to store the brightness");
+        }
+    }
+}
+
+/// Writes firmware updates into the partition the running image wasn't loaded from, leaving the
+/// bootloader to try it out on the next boot.
+struct AppUpdater {
+    flash: &'static AppFlash,
+    update: Option<ImageUpdate>,
+}
+
+impl AppUpdater {
+    fn new(flash: &'static AppFlash) -> Self {
+        Self {
+            flash,
+            update: None,
+        }
+    }
+}
+
+impl UpdateStorage for AppUpdater {
+    fn begin_update(
+        &mut self,
+        image_size: u32,
+        image_version: u32,
+        crc32: u32,
+    ) -> Result<(), ErrorCode> {
+        let partition = match running_partition() {
+            AppPartition::A => AppPartition::B,
+            AppPartition::B => AppPartition::A,
+        };
+        // Starting over abandons whatever was written of an earlier update
+        self.update = None;
+        let update = ImageUpdate::begin(self.flash, partition, image_version, image_size, crc32)
+            .map_err(update_error_code)?;
+        defmt::info!(
+            "Updating partition {} to image version {:x}",
+            partition as u8,
+            image_version
+        );
+        self.update = Some(update);
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, offset: u32, data: &[u8]) -> Result<(), ErrorCode> {
+        let update = self.update.as_mut().ok_or(ErrorCode::InvalidState)?;
+        update
+            .write_chunk(self.flash, offset, data)
+            .map_err(update_error_code)
+    }
+
+    fn finish_update(&mut self) -> Result<Status, ErrorCode> {
+        let update = self.update.take().ok_or(ErrorCode::InvalidState)?;
+        let nvs = nvs::NvsHandle { flash: self.flash };
+        match update.finish(self.flash, &nvs) {
+            Ok(()) => Ok(Status::Success),
+            Err(UpdateError::CrcMismatch) => {
+                defmt::warn!("Updated image doesn't match its CRC");
+                Ok(Status::Failure)
+            }
+            Err(err) => Err(update_error_code(err)),
+        }
+    }
+
+    fn confirm_boot(&mut self) {
+        let nvs = nvs::NvsHandle { flash: self.flash };
+        if let Some(mut state) = nvs.read_boot_state().filter(BootState::needs_confirming) {
+            state.confirm_boot();
+            nvs.write_boot_state(&state);
+        }
+    }
+
+    fn reboot(&mut self) -> ! {
+        // Forcing a watchdog reset is reported as a software reset on the way back up
+        embassy_rp::pac::WATCHDOG
+            .ctrl()
+            .write(|w| w.set_trigger(true));
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+}
+
+fn update_error_code(err: UpdateError) -> ErrorCode {
+    match err {
+        UpdateError::TooLarge | UpdateError::OutOfOrder => ErrorCode::OutOfRange,
+        UpdateError::Incomplete | UpdateError::CrcMismatch | UpdateError::NoBootState => {
+            ErrorCode::InvalidState
+        }
+    }
+}
+
+/// Writes to flash through embassy's driver, which parks core 1 somewhere it can run while flash
+/// can't be read from.
+struct AppFlash(RefCell<Flash<'static, FLASH, Blocking, FLASH_SIZE>>);
+
+impl FlashStorage for AppFlash {
+    unsafe fn read_as<T>(&self, offset: u32) -> T {
+        unsafe { core::ptr::read_unaligned((offset + flash_boot::ORIGIN) as *const T) }
+    }
+
+    unsafe fn as_slice(&self, offset: u32, len: usize) -> &[u8] {
+        unsafe { core::slice::from_raw_parts((offset + flash_boot::ORIGIN) as *const u8, len) }
+    }
+
+    unsafe fn erase_sector(&self, flash_offset: u32) {
+        unwrap!(
+            self.0
+                .borrow_mut()
+                .blocking_erase(flash_offset, flash_offset + nvs::SECTOR_SIZE as u32)
+        );
+    }
+
+    unsafe fn write_page(&self, flash_offset: u32, data: &[u8]) {
+        unwrap!(self.0.borrow_mut().blocking_write(flash_offset, data));
+    }
+}
+
 const PWM_MAX_COUNT: u16 = 0x7fff;
 
 struct NanoRgbLed<C1: pwm::Channel, C2: pwm::Channel, C3: pwm::Channel> {
@@ -279,4 +526,8 @@ impl<T: Pin> Button for UserButton<T> {
     fn wait_for_release(&mut self) -> impl core::future::Future<Output = ()> {
         self.button_input.wait_for_falling_edge()
     }
+
+    fn is_pressed(&mut self) -> bool {
+        self.button_input.is_high()
+    }
 }
@@ -18,7 +18,9 @@ const fn bootloader_version() -> u32 {
         | (BOOTLOADER_VERSION[3] as u32)
 }
 
-const MAX_BOOT_ATTEMPTS: u8 = 3;
+/// A pending update boots once on trial. It's confirmed by the runner once the handshake succeeds,
+/// and rolled back if the device resets before then.
+const MAX_BOOT_ATTEMPTS: u8 = 1;
 
 #[rp2040_hal::entry]
 fn main() -> ! {
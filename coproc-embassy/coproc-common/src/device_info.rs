@@ -0,0 +1,35 @@
+use megabit_serial_protocol::wire::{
+    DeviceBooted, DeviceFeatures, GetDeviceInfoResponse, PROTOCOL_VERSION, ResetReason,
+};
+
+/// Identifies the running firmware image to the host.
+#[derive(Clone, Copy)]
+pub struct DeviceInfo {
+    pub image_version: u32,
+    pub active_partition: u8,
+    pub bootloader_version: u32,
+    pub features: DeviceFeatures,
+    /// What caused the device to come out of reset before booting this image
+    pub reset_reason: ResetReason,
+}
+
+impl From<DeviceInfo> for GetDeviceInfoResponse {
+    fn from(info: DeviceInfo) -> Self {
+        GetDeviceInfoResponse {
+            protocol_version: PROTOCOL_VERSION,
+            image_version: info.image_version,
+            active_partition: info.active_partition,
+            bootloader_version: info.bootloader_version,
+            features: info.features,
+        }
+    }
+}
+
+impl From<DeviceInfo> for DeviceBooted {
+    fn from(info: DeviceInfo) -> Self {
+        DeviceBooted {
+            reset_reason: info.reset_reason,
+            image_version: info.image_version,
+        }
+    }
+}
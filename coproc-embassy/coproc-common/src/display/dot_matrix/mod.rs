@@ -1,12 +1,15 @@
 use super::DisplayCmdReceiver;
 use crate::{
     msg_router::{
-        cmds::*,
-        display_cmd_router::{DisplayCommand, RowUpdate, RowUpdateRgb, UpdateSingleCell},
+        Request,
+        display_cmd_router::{
+            DisplayCommand, RowEncoding, RowUpdate, RowUpdateRgb, UpdateSingleCell,
+        },
     },
     usb::UsbResponder,
 };
 use core::future::Future;
+use megabit_serial_protocol::wire::{self, Frame, Message, PixelRepresentation, Status};
 
 mod driver;
 pub use driver::{COLUMN_DATA_SIZE, COLUMNS, DotMatrix, ROWS};
@@ -52,67 +55,57 @@ impl<D: DotMatrixDriver<COLUMN_DATA_SIZE>, R: UsbResponder + 'static> DisplayCom
         }
     }
 
-    async fn handle_cmd(&mut self, cmd: &DisplayCommand) {
-        match cmd {
+    async fn handle_cmd(&mut self, Request { seq, cmd }: &Request<DisplayCommand>) {
+        let response: Message = match cmd {
             DisplayCommand::UpdateSingleCell(UpdateSingleCell { row, col, value }) => {
                 self.driver
                     .set_pixel(*row as usize, *col as usize, *value)
                     .await
                     .unwrap();
-                let response_buf = [
-                    set_single_cell_response::MAJOR,
-                    set_single_cell_response::MINOR,
-                    0x00,
-                ];
-                self.responder.send(&response_buf).await.unwrap();
+                wire::SetSingleCellResponse {
+                    status: Status::Success,
+                }
+                .into()
             }
             DisplayCommand::RowUpdate(RowUpdate { row, row_data }) => {
                 self.driver
                     .update_row(*row as usize, *row_data)
                     .await
                     .unwrap();
-                let response_buf = [update_row_response::MAJOR, update_row_response::MINOR, 0x00];
-                self.responder.send(&response_buf).await.unwrap();
+                wire::UpdateRowResponse {
+                    status: Status::Success,
+                }
+                .into()
             }
-            DisplayCommand::RowUpdateRgb(RowUpdateRgb { row: _ }) => {
-                let response_buf = [
-                    update_row_rgb_response::MAJOR,
-                    update_row_rgb_response::MINOR,
-                    0x01,
-                ];
-                self.responder.send(&response_buf).await.unwrap();
+            DisplayCommand::RowUpdateRgb(RowUpdateRgb { encoding, .. }) => {
+                let status = Status::Failure;
+                match encoding {
+                    RowEncoding::Raw => wire::UpdateRowRgbResponse { status }.into(),
+                    RowEncoding::RunLength => wire::UpdateRowRgbRleResponse { status }.into(),
+                    RowEncoding::Delta => wire::UpdateRowRgbDeltaResponse { status }.into(),
+                }
             }
-            DisplayCommand::GetDisplayInfo => {
-                let mut response_buf = [0u8; 11];
-                response_buf[0] = get_display_info_response::MAJOR;
-                response_buf[1] = get_display_info_response::MINOR;
-                response_buf[2..6]
-                    .iter_mut()
-                    .zip(((COLUMNS) as u32).to_be_bytes().into_iter())
-                    .for_each(|(dst, src)| *dst = src);
-                response_buf[6..10]
-                    .iter_mut()
-                    .zip(((ROWS) as u32).to_be_bytes().into_iter())
-                    .for_each(|(dst, src)| *dst = src);
-                response_buf[10] = 0x00; // false
-                self.responder.send(&response_buf).await.unwrap();
+            DisplayCommand::GetDisplayInfo => wire::GetDisplayInfoResponse {
+                width: COLUMNS as u32,
+                height: ROWS as u32,
+                pixel_representation: PixelRepresentation::Monocolor,
+                cmd_queue_depth: Some(DISPLAY_CMD_QUEUE_SIZE as u8),
             }
-            DisplayCommand::CommitRender => {
-                let response_buf = [
-                    commit_render_response::MAJOR,
-                    commit_render_response::MINOR,
-                    0x00,
-                ];
-                self.responder.send(&response_buf).await.unwrap();
+            .into(),
+            DisplayCommand::CommitRender => wire::CommitRenderResponse {
+                status: Status::Success,
             }
-            DisplayCommand::SetMonocolorPalette(_) => {
-                let response_buf = [
-                    set_monocolor_palette_response::MAJOR,
-                    set_monocolor_palette_response::MINOR,
-                    0x01,
-                ];
-                self.responder.send(&response_buf).await.unwrap();
+            .into(),
+            DisplayCommand::SetMonocolorPalette(_) => wire::SetMonocolorPaletteResponse {
+                status: Status::Failure,
             }
-        }
+            .into(),
+            DisplayCommand::SetBrightness(_) => wire::SetBrightnessResponse {
+                status: Status::Failure,
+            }
+            .into(),
+        };
+        // There's nobody to tell about a response which can't be sent, the host has gone away
+        let _ = self.responder.send_frame(Frame::new(*seq, response)).await;
     }
 }
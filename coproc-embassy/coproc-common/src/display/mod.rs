@@ -1,8 +1,8 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 
-use crate::msg_router::display_cmd_router::DisplayCommand;
+use crate::msg_router::{Request, display_cmd_router::DisplayCommand};
 use embassy_sync::{
-    blocking_mutex::raw::{NoopRawMutex, RawMutex},
+    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex, RawMutex},
     channel::{Receiver, Sender},
     mutex::Mutex,
 };
@@ -18,14 +18,20 @@ pub use dot_matrix::{COLUMNS, DISPLAY_CMD_QUEUE_SIZE, DotMatrix, ROWS};
 #[cfg(feature = "rgb_matrix")]
 pub use rgb_matrix::{COLUMNS, DISPLAY_CMD_QUEUE_SIZE, DriverPins, ROWS, WaveshareDriver};
 
-pub type DisplayCmdSender = Sender<'static, NoopRawMutex, DisplayCommand, DISPLAY_CMD_QUEUE_SIZE>;
+pub type DisplayCmdSender =
+    Sender<'static, NoopRawMutex, Request<DisplayCommand>, DISPLAY_CMD_QUEUE_SIZE>;
 pub type DisplayCmdReceiver =
-    Receiver<'static, NoopRawMutex, DisplayCommand, DISPLAY_CMD_QUEUE_SIZE>;
+    Receiver<'static, NoopRawMutex, Request<DisplayCommand>, DISPLAY_CMD_QUEUE_SIZE>;
+
+/// The pixel buffer shared between the core handling commands and the core driving the display.
+pub type SharedPixelBuffer = PixelBuffer<CriticalSectionRawMutex, { ROWS * COLUMNS }>;
 
 pub struct PixelBuffer<M: RawMutex + 'static, const N: usize> {
     buffer_a: Mutex<M, [u16; N]>,
     buffer_b: Mutex<M, [u16; N]>,
     write_a: AtomicBool,
+    /// How long pixels are lit for when they're drawn, from off at 0 to fully on at 255
+    brightness: AtomicU8,
 }
 
 impl<M: RawMutex + 'static, const N: usize> PixelBuffer<M, N> {
@@ -34,6 +40,7 @@ impl<M: RawMutex + 'static, const N: usize> PixelBuffer<M, N> {
             buffer_a,
             buffer_b,
             write_a: AtomicBool::new(true),
+            brightness: AtomicU8::new(u8::MAX),
         }
     }
 
@@ -57,4 +64,12 @@ impl<M: RawMutex + 'static, const N: usize> PixelBuffer<M, N> {
         self.write_a
             .store(!self.write_a.load(Ordering::SeqCst), Ordering::SeqCst);
     }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness.load(Ordering::Relaxed)
+    }
+
+    pub fn set_brightness(&self, brightness: u8) {
+        self.brightness.store(brightness, Ordering::Relaxed);
+    }
 }
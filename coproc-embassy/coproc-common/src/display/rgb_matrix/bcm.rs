@@ -0,0 +1,123 @@
+// Binary code modulation for the HUB75 driver.
+//
+// Each channel of a pixel is gamma corrected to a level of `bit_depth` bits and the display is
+// scanned out once for each bit of the level, a bit plane. Every plane is lit for twice as long
+// as the plane below it, so over a whole frame a channel is lit for a time proportional to its
+// level.
+
+/// The deepest levels the gamma table can produce
+pub const MAX_BIT_DEPTH: u8 = 16;
+
+/// Enough depth to keep the gamma corrected low end distinct while still refreshing well above
+/// what's visible as flicker
+pub const DEFAULT_BIT_DEPTH: u8 = 8;
+
+/// How many cycles the least significant bit plane is lit for at full brightness
+pub const BASE_PLANE_CYCLES: u32 = 40;
+
+/// 5-bit channel values raised to a gamma of 2.2 and scaled to 16 bits, generated with
+/// `round((value / 31) ^ 2.2 * 65535)`
+const GAMMA_16: [u16; 32] = [
+    0, 34, 158, 385, 724, 1184, 1768, 2481, 3329, 4313, 5438, 6707, 8122, 9686, 11401, 13270,
+    15295, 17477, 19819, 22322, 24989, 27820, 30818, 33984, 37320, 40827, 44506, 48359, 52387,
+    56592, 60974, 65535,
+];
+
+/// Builds the table of levels for each 5-bit channel value at `bit_depth` bits. Channels which
+/// aren't off are kept at the lowest level rather than rounding down to off, so dim colors don't
+/// disappear at lower bit depths.
+pub const fn gamma_table(bit_depth: u8) -> [u16; 32] {
+    assert!(bit_depth > 0 && bit_depth <= MAX_BIT_DEPTH);
+    let mut table = [0u16; 32];
+    let mut value = 1;
+    while value < table.len() {
+        let level = GAMMA_16[value] >> (MAX_BIT_DEPTH - bit_depth);
+        table[value] = if level == 0 { 1 } else { level };
+        value += 1;
+    }
+    table
+}
+
+/// Splits an RGB555 pixel into its 5-bit red, green and blue channels.
+#[inline]
+pub fn channels(pixel: u16) -> [u8; 3] {
+    let r = (pixel & 0b11111_00000_00000) >> 10;
+    let g = (pixel & 0b00000_11111_00000) >> 5;
+    let b = pixel & 0b00000_00000_11111;
+    [r as u8, g as u8, b as u8]
+}
+
+/// Which of a pixel's red, green and blue channels are lit in bit plane `plane`.
+#[inline]
+pub fn plane_bits(gamma: &[u16; 32], pixel: u16, plane: u8) -> [bool; 3] {
+    channels(pixel).map(|channel| (gamma[channel as usize] >> plane) & 1 != 0)
+}
+
+/// How many cycles a row stays lit for while showing bit plane `plane`, scaled down from the
+/// full on-time by `brightness`.
+#[inline]
+pub fn plane_on_cycles(plane: u8, brightness: u8) -> u32 {
+    (BASE_PLANE_CYCLES << plane) * brightness as u32 / u8::MAX as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gamma_table_spans_the_bit_depth() {
+        for bit_depth in 1..=MAX_BIT_DEPTH {
+            let table = gamma_table(bit_depth);
+            assert_eq!(table[0], 0);
+            assert_eq!(table[31], ((1u32 << bit_depth) - 1) as u16);
+            assert!(table.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+    }
+
+    #[test]
+    fn gamma_table_keeps_dim_channels_lit() {
+        let table = gamma_table(4);
+        assert!(table[1..].iter().all(|level| *level > 0));
+        // Gamma correction pushes the low end down compared to a linear ramp
+        assert!(table[8] < 8 * 15 / 31);
+    }
+
+    #[test]
+    fn channels_match_rgb555_layout() {
+        assert_eq!(channels(0b11111_00000_00000), [31, 0, 0]);
+        assert_eq!(channels(0b00000_11111_00000), [0, 31, 0]);
+        assert_eq!(channels(0b00000_00000_11111), [0, 0, 31]);
+        assert_eq!(channels(0b10000_01000_00001), [16, 8, 1]);
+    }
+
+    #[test]
+    fn plane_bits_add_up_to_the_level() {
+        let bit_depth = 8;
+        let gamma = gamma_table(bit_depth);
+        for value in 0..32u16 {
+            let pixel = (value << 10) | (value << 5) | value;
+            let level = (0..bit_depth)
+                .filter(|plane| plane_bits(&gamma, pixel, *plane)[0])
+                .map(|plane| 1u16 << plane)
+                .sum::<u16>();
+            assert_eq!(level, gamma[value as usize]);
+            assert_eq!(
+                plane_bits(&gamma, pixel, 3),
+                [plane_bits(&gamma, pixel, 3)[0]; 3]
+            );
+        }
+    }
+
+    #[test]
+    fn plane_on_time_doubles_and_scales_with_brightness() {
+        assert_eq!(plane_on_cycles(0, u8::MAX), BASE_PLANE_CYCLES);
+        for plane in 1..8 {
+            assert_eq!(
+                plane_on_cycles(plane, u8::MAX),
+                2 * plane_on_cycles(plane - 1, u8::MAX)
+            );
+        }
+        assert_eq!(plane_on_cycles(7, 0), 0);
+        assert!(plane_on_cycles(7, 128) < plane_on_cycles(7, u8::MAX));
+    }
+}
@@ -5,7 +5,10 @@
 
 use crate::display::PixelBuffer;
 
-use super::{COLUMNS, ROWS};
+use super::{
+    COLUMNS, ROWS,
+    bcm::{DEFAULT_BIT_DEPTH, gamma_table, plane_bits, plane_on_cycles},
+};
 use core::convert::Infallible;
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_time::Timer;
@@ -129,14 +132,26 @@ impl<
     }
 }
 
-pub struct WaveshareDriver<PINS: DriverPins, M: RawMutex + 'static> {
+pub struct WaveshareDriver<
+    PINS: DriverPins,
+    M: RawMutex + 'static,
+    const BIT_DEPTH: u8 = DEFAULT_BIT_DEPTH,
+> {
     pins: PINS,
     pixel_data: &'static PixelBuffer<M, { ROWS * COLUMNS }>,
+    /// Level of each 5-bit channel value at `BIT_DEPTH` bits
+    gamma: [u16; 32],
 }
 
-impl<PINS: DriverPins, M: RawMutex + 'static> WaveshareDriver<PINS, M> {
+impl<PINS: DriverPins, M: RawMutex + 'static, const BIT_DEPTH: u8>
+    WaveshareDriver<PINS, M, BIT_DEPTH>
+{
     pub fn new(pins: PINS, pixel_data: &'static PixelBuffer<M, { ROWS * COLUMNS }>) -> Self {
-        Self { pins, pixel_data }
+        Self {
+            pins,
+            pixel_data,
+            gamma: gamma_table(BIT_DEPTH),
+        }
     }
 
     pub fn handle(&self) -> DriverHandle<M> {
@@ -152,42 +167,42 @@ impl<PINS: DriverPins, M: RawMutex + 'static> WaveshareDriver<PINS, M> {
 
     pub async fn render(&mut self, debug_pin: &mut impl StatefulOutputPin) {
         let pixel_data = self.pixel_data.read_buffer().lock().await;
+        let brightness = self.pixel_data.brightness();
         debug_pin.toggle().unwrap();
-        for pwm_step in 0..(1u8 << 4) {
-            let pwm_step = pwm_step << 1;
+        for plane in 0..BIT_DEPTH {
+            let on_cycles = plane_on_cycles(plane, brightness);
             for row in 0..(ROWS / 2) {
-                for idx in ((row * COLUMNS)..((row + 1) * COLUMNS)).step_by(16) {
-                    for idx_add in 0..16 {
-                        let idx = idx + idx_add;
-                        let idx2 = idx + pixel_data.len() / 2;
-                        let (r1, g1, b1) = channels(pixel_data[idx]);
-                        let (r2, g2, b2) = channels(pixel_data[idx2]);
-                        self.pins.r1().set_state((r1 > pwm_step).into()).unwrap();
-                        self.pins.g1().set_state((g1 > pwm_step).into()).unwrap();
-                        self.pins.b1().set_state((b1 > pwm_step).into()).unwrap();
-                        self.pins.r2().set_state((r2 > pwm_step).into()).unwrap();
-                        self.pins.g2().set_state((g2 > pwm_step).into()).unwrap();
-                        self.pins.b2().set_state((b2 > pwm_step).into()).unwrap();
-
-                        self.pins.clk().set_high().unwrap();
-                        cortex_m::asm::delay(1);
-                        self.pins.clk().set_low().unwrap();
-                    }
+                for idx in (row * COLUMNS)..((row + 1) * COLUMNS) {
+                    let idx2 = idx + pixel_data.len() / 2;
+                    let [r1, g1, b1] = plane_bits(&self.gamma, pixel_data[idx], plane);
+                    let [r2, g2, b2] = plane_bits(&self.gamma, pixel_data[idx2], plane);
+                    self.pins.r1().set_state(r1.into()).unwrap();
+                    self.pins.g1().set_state(g1.into()).unwrap();
+                    self.pins.b1().set_state(b1.into()).unwrap();
+                    self.pins.r2().set_state(r2.into()).unwrap();
+                    self.pins.g2().set_state(g2.into()).unwrap();
+                    self.pins.b2().set_state(b2.into()).unwrap();
+
+                    self.pins.clk().set_high().unwrap();
+                    cortex_m::asm::delay(1);
+                    self.pins.clk().set_low().unwrap();
                 }
 
-                self.pins.oe().set_high().unwrap();
-                cortex_m::asm::delay(50);
                 self.pins.lat().set_low().unwrap();
                 cortex_m::asm::delay(50);
                 self.pins.lat().set_high().unwrap();
 
                 self.set_addr(row as u8);
                 cortex_m::asm::delay(50);
-                self.pins.oe().set_low().unwrap();
+                // The row is only lit for its plane's on-time and stays dark while the next row
+                // is shifted in, so every plane gets exactly its share of the frame
+                if on_cycles > 0 {
+                    self.pins.oe().set_low().unwrap();
+                    cortex_m::asm::delay(on_cycles);
+                    self.pins.oe().set_high().unwrap();
+                }
             }
         }
-
-        self.pins.oe().set_high().unwrap();
     }
 
     fn set_addr(&mut self, addr: u8) {
@@ -210,14 +225,6 @@ impl<PINS: DriverPins, M: RawMutex + 'static> WaveshareDriver<PINS, M> {
     }
 }
 
-#[inline]
-fn channels(pixel_color: u16) -> (u8, u8, u8) {
-    let r = (pixel_color & 0b11111_00000_00000) >> 10;
-    let g = (pixel_color & 0b00000_11111_00000) >> 5;
-    let b = pixel_color & 0b00000_00000_11111;
-    (r as u8, g as u8, b as u8)
-}
-
 pub struct DriverHandle<M: RawMutex + 'static> {
     pixel_data: &'static PixelBuffer<M, { ROWS * COLUMNS }>,
 }
@@ -258,7 +265,25 @@ impl<M: RawMutex + 'static> DriverHandle<M> {
             .for_each(|(dst, src)| *dst = *src)
     }
 
-    pub fn flip(&self) {
+    pub async fn apply_row_delta_rgb(&mut self, row: u8, deltas: &[u16]) {
+        let start_idx = COLUMNS * row as usize;
+        let mut pixel_data = self.pixel_data.write_buffer().lock().await;
+        pixel_data[start_idx..]
+            .iter_mut()
+            .zip(deltas[..core::cmp::min(COLUMNS, deltas.len())].iter())
+            .for_each(|(dst, delta)| *dst ^= *delta)
+    }
+
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.pixel_data.set_brightness(brightness);
+    }
+
+    pub async fn flip(&mut self) {
         self.pixel_data.flip();
+        // Carry the frame that was just committed over into the new write buffer so rows the
+        // host doesn't resend keep their contents and deltas apply against what's on screen
+        let front = self.pixel_data.read_buffer().lock().await;
+        let mut back = self.pixel_data.write_buffer().lock().await;
+        back.copy_from_slice(&front[..]);
     }
 }
@@ -1,19 +1,19 @@
 use super::DisplayCmdReceiver;
 use crate::{
     msg_router::{
-        cmds::*,
+        Request,
         display_cmd_router::{
-            DisplayCommand, RowUpdate, RowUpdateRgb, SetMonocolorPalette, UpdateSingleCell,
+            DisplayCommand, RowEncoding, RowUpdate, RowUpdateRgb, SetBrightness,
+            SetMonocolorPalette, UpdateSingleCell,
         },
     },
     usb::UsbResponder,
 };
-use embassy_sync::{
-    blocking_mutex::raw::{NoopRawMutex, RawMutex},
-    mutex::Mutex,
-};
+use embassy_sync::blocking_mutex::raw::RawMutex;
 use embedded_hal::digital::StatefulOutputPin;
+use megabit_serial_protocol::wire::{self, Frame, Message, PixelRepresentation, Status};
 
+pub mod bcm;
 mod driver;
 pub use driver::{DriverHandle, DriverPins, WaveshareDriver};
 
@@ -30,7 +30,6 @@ pub struct DisplayCommandHandler<
     cmd_rx: DisplayCmdReceiver,
     monocolor: u16,
     driver: DriverHandle<M>,
-    row_data_buffer: &'static Mutex<NoopRawMutex, [u16; COLUMNS]>,
     debug_pin: DBG,
 }
 
@@ -42,7 +41,6 @@ impl<R: UsbResponder + 'static, DBG: StatefulOutputPin, M: RawMutex + 'static>
         cmd_rx: DisplayCmdReceiver,
         (r, g, b): (u8, u8, u8),
         driver: DriverHandle<M>,
-        row_data_buffer: &'static Mutex<NoopRawMutex, [u16; COLUMNS]>,
         debug_pin: DBG,
     ) -> Self {
         let monocolor =
@@ -52,7 +50,6 @@ impl<R: UsbResponder + 'static, DBG: StatefulOutputPin, M: RawMutex + 'static>
             cmd_rx,
             monocolor,
             driver,
-            row_data_buffer,
             debug_pin,
         }
     }
@@ -64,86 +61,85 @@ impl<R: UsbResponder + 'static, DBG: StatefulOutputPin, M: RawMutex + 'static>
         }
     }
 
-    async fn handle_cmd(&mut self, cmd: &DisplayCommand) {
-        match cmd {
+    async fn handle_cmd(&mut self, Request { seq, cmd }: &Request<DisplayCommand>) {
+        let response: Message = match cmd {
             DisplayCommand::UpdateSingleCell(UpdateSingleCell { row, col, value }) => {
                 let status = if (*row as usize) < ROWS && (*col as usize) < COLUMNS {
                     self.driver
                         .set_cell(*row, *col, if *value { self.monocolor } else { 0x00 })
                         .await;
-                    0x00
+                    Status::Success
                 } else {
-                    0x01
+                    Status::Failure
                 };
-                let response_buf = [
-                    set_single_cell_response::MAJOR,
-                    set_single_cell_response::MINOR,
-                    status,
-                ];
-                self.responder.send(&response_buf).await.unwrap();
+                wire::SetSingleCellResponse { status }.into()
             }
             DisplayCommand::RowUpdate(RowUpdate { row, row_data }) => {
                 let status = if (*row as usize) < ROWS {
                     self.driver
                         .update_row(*row, self.monocolor, &row_data[..])
                         .await;
-                    0x00
+                    Status::Success
                 } else {
-                    0x01
+                    Status::Failure
                 };
-                let response_buf = [
-                    update_row_response::MAJOR,
-                    update_row_response::MINOR,
-                    status,
-                ];
-                self.responder.send(&response_buf).await.unwrap();
+                wire::UpdateRowResponse { status }.into()
             }
-            DisplayCommand::RowUpdateRgb(RowUpdateRgb { row }) => {
-                {
-                    let row_buf = self.row_data_buffer.lock().await;
-                    self.driver.update_row_rgb(*row, &row_buf[..]).await;
+            DisplayCommand::RowUpdateRgb(RowUpdateRgb {
+                row,
+                row_data,
+                encoding,
+            }) => {
+                let status = if (*row as usize) < ROWS {
+                    match encoding {
+                        RowEncoding::Raw | RowEncoding::RunLength => {
+                            self.driver.update_row_rgb(*row, &row_data[..]).await
+                        }
+                        RowEncoding::Delta => {
+                            self.driver.apply_row_delta_rgb(*row, &row_data[..]).await
+                        }
+                    }
+                    Status::Success
+                } else {
+                    Status::Failure
+                };
+                match encoding {
+                    RowEncoding::Raw => wire::UpdateRowRgbResponse { status }.into(),
+                    RowEncoding::RunLength => wire::UpdateRowRgbRleResponse { status }.into(),
+                    RowEncoding::Delta => wire::UpdateRowRgbDeltaResponse { status }.into(),
                 }
-                let response_buf = [
-                    update_row_rgb_response::MAJOR,
-                    update_row_rgb_response::MINOR,
-                    0x00,
-                ];
-                self.responder.send(&response_buf).await.unwrap();
             }
-            DisplayCommand::GetDisplayInfo => {
-                let mut response_buf = [0u8; 11];
-                response_buf[0] = get_display_info_response::MAJOR;
-                response_buf[1] = get_display_info_response::MINOR;
-                response_buf[2..6]
-                    .iter_mut()
-                    .zip(((COLUMNS) as u32).to_be_bytes())
-                    .for_each(|(dst, src)| *dst = src);
-                response_buf[6..10]
-                    .iter_mut()
-                    .zip(((ROWS) as u32).to_be_bytes())
-                    .for_each(|(dst, src)| *dst = src);
-                response_buf[10] = 0x01; // true
-                self.responder.send(&response_buf).await.unwrap();
+            DisplayCommand::GetDisplayInfo => wire::GetDisplayInfoResponse {
+                width: COLUMNS as u32,
+                height: ROWS as u32,
+                pixel_representation: PixelRepresentation::RGB555,
+                cmd_queue_depth: Some(DISPLAY_CMD_QUEUE_SIZE as u8),
             }
+            .into(),
             DisplayCommand::CommitRender => {
                 self.debug_pin.toggle().unwrap();
-                self.driver.flip();
-                let response_buf = [
-                    commit_render_response::MAJOR,
-                    commit_render_response::MINOR,
-                    0x00,
-                ];
-                self.responder.send(&response_buf).await.unwrap();
+                self.driver.flip().await;
+                wire::CommitRenderResponse {
+                    status: Status::Success,
+                }
+                .into()
             }
             DisplayCommand::SetMonocolorPalette(SetMonocolorPalette { color }) => {
                 self.monocolor = *color;
-                let response_buf = [
-                    set_monocolor_palette_response::MAJOR,
-                    set_monocolor_palette_response::MINOR,
-                    0x00,
-                ];
-                self.responder.send(&response_buf).await.unwrap();
+                wire::SetMonocolorPaletteResponse {
+                    status: Status::Success,
+                }
+                .into()
             }
-        }
+            DisplayCommand::SetBrightness(SetBrightness { brightness }) => {
+                self.driver.set_brightness(*brightness);
+                wire::SetBrightnessResponse {
+                    status: Status::Success,
+                }
+                .into()
+            }
+        };
+        // There's nobody to tell about a response which can't be sent, the host has gone away
+        let _ = self.responder.send_frame(Frame::new(*seq, response)).await;
     }
 }
@@ -0,0 +1,25 @@
+use megabit_serial_protocol::wire::{ErrorCode, Status};
+
+/// Somewhere to write a new firmware image while the current one keeps running, the image is
+/// written to whichever boot partition the running image wasn't loaded from.
+pub trait UpdateStorage {
+    /// Erases the other partition to start writing an image to it.
+    fn begin_update(
+        &mut self,
+        image_size: u32,
+        image_version: u32,
+        crc32: u32,
+    ) -> Result<(), ErrorCode>;
+
+    fn write_chunk(&mut self, offset: u32, data: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Checks the written image against its CRC and marks it as the image to boot next, failing
+    /// if the image doesn't check out.
+    fn finish_update(&mut self) -> Result<Status, ErrorCode>;
+
+    /// Marks the running image as good so that the bootloader keeps booting it.
+    fn confirm_boot(&mut self);
+
+    /// Resets the device to boot into whatever the bootloader picks.
+    fn reboot(&mut self) -> !;
+}
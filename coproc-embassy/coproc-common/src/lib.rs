@@ -1,7 +1,10 @@
 #![no_std]
 
 pub mod cobs_buffer;
+pub mod device_info;
 pub mod display;
+pub mod firmware_update;
 pub mod msg_router;
+pub mod settings;
 pub mod system_state;
 pub mod usb;
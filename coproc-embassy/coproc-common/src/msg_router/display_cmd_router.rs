@@ -1,7 +1,6 @@
-use core::future::Future;
-
-use crate::display::{COLUMNS, DisplayCmdSender};
-use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use super::Request;
+use crate::display::{COLUMNS, DisplayCmdSender, ROWS, SharedPixelBuffer};
+use megabit_serial_protocol::wire::{self, ErrorCode, RleRun, RunSlice};
 
 pub enum DisplayCommand {
     UpdateSingleCell(UpdateSingleCell),
@@ -10,6 +9,7 @@ pub enum DisplayCommand {
     GetDisplayInfo,
     CommitRender,
     SetMonocolorPalette(SetMonocolorPalette),
+    SetBrightness(SetBrightness),
 }
 
 pub struct UpdateSingleCell {
@@ -23,90 +23,220 @@ pub struct RowUpdate {
     pub row_data: [u8; COLUMNS / 8],
 }
 
+/// How the host sent a row, which decides how it's applied and which response it gets.
+pub enum RowEncoding {
+    Raw,
+    RunLength,
+    /// The row data is XORed into the row's current contents
+    Delta,
+}
+
 pub struct RowUpdateRgb {
     pub row: u8,
+    pub row_data: [u16; COLUMNS],
+    pub encoding: RowEncoding,
 }
 
 pub struct SetMonocolorPalette {
     pub color: u16,
 }
 
+pub struct SetBrightness {
+    pub brightness: u8,
+}
+
 pub struct DisplayCmdRouter {
     request_sender: DisplayCmdSender,
+    pixel_buffer: &'static SharedPixelBuffer,
     rgb_enabled: bool,
-    row_data_buffer: &'static Mutex<NoopRawMutex, [u16; COLUMNS]>,
 }
 
 impl DisplayCmdRouter {
     pub fn new(
         request_sender: DisplayCmdSender,
-        row_data_buffer: &'static Mutex<NoopRawMutex, [u16; COLUMNS]>,
+        pixel_buffer: &'static SharedPixelBuffer,
         rgb_enabled: bool,
     ) -> Self {
         Self {
             request_sender,
-            row_data_buffer,
+            pixel_buffer,
             rgb_enabled,
         }
     }
 
-    pub fn handle_update_single_cell(&self, payload: &[u8]) -> impl Future<Output = ()> + '_ {
-        let row = payload[0];
-        let col = payload[1];
-        let value = payload[2] != 0;
-        self.request_sender
-            .send(DisplayCommand::UpdateSingleCell(UpdateSingleCell {
-                row,
-                col,
-                value,
-            }))
+    pub fn handle_update_single_cell(
+        &self,
+        seq: u16,
+        wire::SetSingleCell { row, col, value }: wire::SetSingleCell,
+    ) -> Result<(), ErrorCode> {
+        if row as usize >= ROWS || col as usize >= COLUMNS {
+            return Err(ErrorCode::OutOfRange);
+        }
+        self.queue(
+            seq,
+            DisplayCommand::UpdateSingleCell(UpdateSingleCell { row, col, value }),
+        )
     }
 
-    pub fn handle_row_update(&self, payload: &[u8]) -> impl Future<Output = ()> + '_ {
-        let row = payload[0];
-        let _row_data_len = payload[1];
-        let mut row_data = [0u8; COLUMNS / 8];
+    pub fn handle_row_update(&self, seq: u16, msg: wire::UpdateRow<'_>) -> Result<(), ErrorCode> {
+        if msg.row_number as usize >= ROWS {
+            return Err(ErrorCode::OutOfRange);
+        }
+        let row_data = msg.row_data.try_into().map_err(|_| ErrorCode::BadLength)?;
+        self.queue(
+            seq,
+            DisplayCommand::RowUpdate(RowUpdate {
+                row: msg.row_number,
+                row_data,
+            }),
+        )
+    }
+
+    pub fn handle_get_display_info(&self, seq: u16) -> Result<(), ErrorCode> {
+        self.queue(seq, DisplayCommand::GetDisplayInfo)
+    }
+
+    pub fn handle_row_update_rgb(
+        &self,
+        seq: u16,
+        msg: wire::UpdateRowRgb<'_>,
+    ) -> Result<(), ErrorCode> {
+        self.check_rgb_row(msg.row_number)?;
+        if msg.row_data.len() != COLUMNS {
+            return Err(ErrorCode::BadLength);
+        }
+        // The row travels with the command rather than through a shared buffer since the host
+        // may have several row updates queued up at once
+        let mut row_data = [0u16; COLUMNS];
         row_data
             .iter_mut()
-            .zip(&payload[2..])
-            .for_each(|(dst, src)| {
-                *dst = *src;
-            });
-        self.request_sender
-            .send(DisplayCommand::RowUpdate(RowUpdate { row, row_data }))
+            .zip(msg.row_data.iter())
+            .for_each(|(dst, src)| *dst = src);
+        self.queue(
+            seq,
+            DisplayCommand::RowUpdateRgb(RowUpdateRgb {
+                row: msg.row_number,
+                row_data,
+                encoding: RowEncoding::Raw,
+            }),
+        )
     }
 
-    pub fn handle_get_display_info(&self) -> impl Future<Output = ()> + '_ {
-        self.request_sender.send(DisplayCommand::GetDisplayInfo)
+    pub fn handle_row_update_rgb_rle(
+        &self,
+        seq: u16,
+        msg: wire::UpdateRowRgbRle<'_>,
+    ) -> Result<(), ErrorCode> {
+        self.handle_row_update_rgb_runs(seq, msg.row_number, msg.runs, RowEncoding::RunLength)
     }
 
-    pub async fn handle_row_update_rgb(&self, payload: &[u8]) {
-        let row = payload[0];
-        {
-            let mut row_buf = self.row_data_buffer.lock().await;
-            row_buf
-                .iter_mut()
-                .zip((2..payload.len()).step_by(2))
-                .for_each(|(dst, src_idx)| {
-                    *dst = u16::from_be_bytes([payload[src_idx], payload[src_idx + 1]])
-                });
+    pub fn handle_row_update_rgb_delta(
+        &self,
+        seq: u16,
+        msg: wire::UpdateRowRgbDelta<'_>,
+    ) -> Result<(), ErrorCode> {
+        self.handle_row_update_rgb_runs(seq, msg.row_number, msg.runs, RowEncoding::Delta)
+    }
+
+    fn handle_row_update_rgb_runs(
+        &self,
+        seq: u16,
+        row: u8,
+        runs: RunSlice<'_>,
+        encoding: RowEncoding,
+    ) -> Result<(), ErrorCode> {
+        self.check_rgb_row(row)?;
+        let row_data = decode_runs(runs).ok_or(ErrorCode::BadLength)?;
+        self.queue(
+            seq,
+            DisplayCommand::RowUpdateRgb(RowUpdateRgb {
+                row,
+                row_data,
+                encoding,
+            }),
+        )
+    }
+
+    /// Writes a region straight into the pixel buffer's write buffer rather than queueing it up
+    /// as a command since regions can be far larger than a row.
+    pub async fn handle_region_update_rgb(
+        &self,
+        msg: wire::UpdateRegionRgb<'_>,
+    ) -> Result<(), ErrorCode> {
+        let x = msg.x as usize;
+        let y = msg.y as usize;
+        let width = msg.width as usize;
+        let height = msg.height as usize;
+        if !self.rgb_enabled {
+            return Err(ErrorCode::Unsupported);
+        } else if x + width > COLUMNS || y + height > ROWS {
+            return Err(ErrorCode::OutOfRange);
+        } else if msg.data.len() != width * height {
+            return Err(ErrorCode::BadLength);
+        } else if msg.data.is_empty() {
+            return Ok(());
         }
-        if self.rgb_enabled {
-            self.request_sender
-                .send(DisplayCommand::RowUpdateRgb(RowUpdateRgb { row }))
-                .await;
+
+        let mut pixel_data = self.pixel_buffer.write_buffer().lock().await;
+        for (idx, value) in msg.data.iter().enumerate() {
+            pixel_data[(y + idx / width) * COLUMNS + x + idx % width] = value;
         }
+        Ok(())
+    }
+
+    pub fn handle_request_commit_render(&self, seq: u16) -> Result<(), ErrorCode> {
+        self.queue(seq, DisplayCommand::CommitRender)
     }
 
-    pub fn handle_request_commit_render(&self) -> impl Future<Output = ()> + '_ {
-        self.request_sender.send(DisplayCommand::CommitRender)
+    pub fn handle_set_monocolor_palette(
+        &self,
+        seq: u16,
+        wire::SetMonocolorPalette { color }: wire::SetMonocolorPalette,
+    ) -> Result<(), ErrorCode> {
+        self.queue(
+            seq,
+            DisplayCommand::SetMonocolorPalette(SetMonocolorPalette { color }),
+        )
     }
 
-    pub fn handle_set_monocolor_palette(&self, payload: &[u8]) -> impl Future<Output = ()> + '_ {
-        let color = u16::from_be_bytes([payload[0], payload[1]]);
+    pub fn handle_set_brightness(
+        &self,
+        seq: u16,
+        wire::SetBrightness { brightness }: wire::SetBrightness,
+    ) -> Result<(), ErrorCode> {
+        self.queue(
+            seq,
+            DisplayCommand::SetBrightness(SetBrightness { brightness }),
+        )
+    }
+
+    fn check_rgb_row(&self, row: u8) -> Result<(), ErrorCode> {
+        if !self.rgb_enabled {
+            Err(ErrorCode::Unsupported)
+        } else if row as usize >= ROWS {
+            Err(ErrorCode::OutOfRange)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Hands a command to the display task, the host is told to back off rather than stalling
+    /// the router when the task has fallen behind.
+    fn queue(&self, seq: u16, cmd: DisplayCommand) -> Result<(), ErrorCode> {
         self.request_sender
-            .send(DisplayCommand::SetMonocolorPalette(SetMonocolorPalette {
-                color,
-            }))
+            .try_send(Request { seq, cmd })
+            .map_err(|_| ErrorCode::QueueFull)
+    }
+}
+
+/// Expands runs into a row, rejecting runs which don't cover the row exactly.
+fn decode_runs(runs: RunSlice<'_>) -> Option<[u16; COLUMNS]> {
+    let mut row_data = [0u16; COLUMNS];
+    let mut idx = 0;
+    for RleRun { length, value } in runs.iter() {
+        let length = length as usize;
+        row_data.get_mut(idx..idx + length)?.fill(value);
+        idx += length;
     }
+    (idx == COLUMNS).then_some(row_data)
 }
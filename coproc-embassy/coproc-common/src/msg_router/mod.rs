@@ -1,19 +1,33 @@
 use core::future::Future;
 
 use crate::cobs_buffer::CobsBuffer;
+use crate::device_info::DeviceInfo;
+use crate::firmware_update::UpdateStorage;
 use crate::usb::{Disconnected, UsbResponder};
+use embassy_time::{Duration, Timer};
 use embassy_usb::class::cdc_acm::Receiver as UsbReceiver;
+use megabit_serial_protocol::wire::{self, CRC_FLAG, ErrorCode, Frame, Message, Status};
 
-pub mod cmds;
-use cmds::*;
 pub mod display_cmd_router;
 use display_cmd_router::DisplayCmdRouter;
 pub mod system_cmd_router;
 use system_cmd_router::SystemCmdRouter;
 
+/// How long to wait after answering a finished firmware update before resetting into it, so the
+/// response has a chance to reach the host.
+const UPDATE_REBOOT_DELAY: Duration = Duration::from_millis(100);
+
+/// A command decoded from the host along with the sequence number of the frame which carried it,
+/// the sequence number is echoed back in the response so the host can match the two up.
+pub struct Request<T> {
+    pub seq: u16,
+    pub cmd: T,
+}
+
 pub struct MessageRouter<
     D: embassy_usb_driver::Driver<'static> + 'static,
     R: UsbResponder + 'static,
+    U: UpdateStorage,
     const DECODE_BUFFER_SIZE: usize,
 > {
     class: UsbReceiver<'static, D>,
@@ -22,13 +36,18 @@ pub struct MessageRouter<
     responder: &'static R,
     display_router: DisplayCmdRouter,
     system_router: SystemCmdRouter,
+    update_storage: U,
+    device_info: DeviceInfo,
+    /// Set once a firmware update has been finished, the device resets into it after answering
+    reboot_pending: bool,
 }
 
 impl<
     D: embassy_usb_driver::Driver<'static> + 'static,
     R: UsbResponder + 'static,
+    U: UpdateStorage,
     const DECODE_BUFFER_SIZE: usize,
-> MessageRouter<D, R, DECODE_BUFFER_SIZE>
+> MessageRouter<D, R, U, DECODE_BUFFER_SIZE>
 {
     pub fn new(
         class: UsbReceiver<'static, D>,
@@ -37,6 +56,8 @@ impl<
         responder: &'static R,
         display_router: DisplayCmdRouter,
         system_router: SystemCmdRouter,
+        update_storage: U,
+        device_info: DeviceInfo,
     ) -> Self {
         Self {
             class,
@@ -45,97 +66,183 @@ impl<
             responder,
             display_router,
             system_router,
+            update_storage,
+            device_info,
+            reboot_pending: false,
         }
     }
 
     pub async fn run(mut self) {
+        let mut announced_boot = false;
         loop {
             self.wait_for_connection().await;
+            if !announced_boot {
+                announced_boot = self.announce_boot().await.is_ok();
+            }
             let _ = self.handle_incoming().await;
         }
     }
 
+    /// Lets the host know the device has just come out of reset, so that it can set the device
+    /// back up rather than assuming it still has whatever the host last sent it.
+    async fn announce_boot(&self) -> Result<(), Disconnected> {
+        let booted = wire::DeviceBooted::from(self.device_info);
+        self.responder
+            .send_frame(Frame::unsolicited(booted))
+            .await?;
+        Ok(())
+    }
+
     fn wait_for_connection(&mut self) -> impl Future<Output = ()> + '_ {
         self.class.wait_connection()
     }
 
     async fn handle_incoming(&mut self) -> Result<(), Disconnected> {
         let mut incoming_buf = [0; 64];
-        let mut unencoded_buf = [0; 64];
         loop {
             let bytes_read = self.class.read_packet(&mut incoming_buf).await?;
             self.cobs_decoder.write_bytes(&incoming_buf[..bytes_read]);
 
-            while let Ok(decoded_bytes @ 2..) =
+            while let Ok(decoded_bytes @ 4..) =
                 self.cobs_decoder.read_packet(&mut self.msg_buffer[..])
             {
-                if let Some(unencoded_bytes) =
-                    self.handle_decoded(decoded_bytes, &mut unencoded_buf).await
-                {
-                    self.responder
-                        .send(&unencoded_buf[..(unencoded_bytes as usize)])
-                        .await?;
+                if let Some(response) = self.handle_decoded(decoded_bytes).await {
+                    self.responder.send_frame(response).await?;
+                }
+                if self.reboot_pending {
+                    Timer::after(UPDATE_REBOOT_DELAY).await;
+                    self.update_storage.reboot();
                 }
             }
         }
     }
 
-    async fn handle_decoded(
-        &mut self,
-        decoded_bytes: usize,
-        unencoded_buf: &mut [u8],
-    ) -> Option<u8> {
-        match (self.msg_buffer[0], self.msg_buffer[1], decoded_bytes) {
-            (ping::MAJOR, ping::MINOR, _) => {
-                unencoded_buf[0] = ping_response::MAJOR;
-                unencoded_buf[1] = ping_response::MINOR;
-                Some(2)
+    /// Routes a decoded frame to the handler for its message, returning the response for
+    /// messages which are answered right away rather than by the task handling them. Requests
+    /// which can't be acted on are answered with an `ErrorResponse`.
+    async fn handle_decoded(&mut self, decoded_bytes: usize) -> Option<Frame<'static>> {
+        let frame_bytes = &self.msg_buffer[..decoded_bytes];
+        let (seq, msg) = match Frame::decode(frame_bytes) {
+            Ok(Frame { seq, msg, crc }) => {
+                // Answer in kind, a host which sends CRC trailers wants them back
+                self.responder.set_crc_enabled(crc);
+                (seq, msg)
             }
-            (update_row::MAJOR, update_row::MINOR, 4..) => {
-                self.display_router
-                    .handle_row_update(&self.msg_buffer[2..])
-                    .await;
-                None
+            Err(wire::Error::CrcMismatch { seq }) => {
+                self.responder.set_crc_enabled(true);
+                return Some(Frame::new(seq, wire::Nack {}));
             }
-            (update_row_rgb::MAJOR, update_row_rgb::MINOR, _) => {
-                self.display_router
-                    .handle_row_update_rgb(&self.msg_buffer[2..])
-                    .await;
-                None
+            Err(err) => {
+                let code = ErrorCode::for_decode_error(err)?;
+                let seq = u16::from_be_bytes([frame_bytes[0], frame_bytes[1]]) & !CRC_FLAG;
+                let request_type = u16::from_be_bytes([frame_bytes[2], frame_bytes[3]]);
+                return Some(Frame::new(seq, wire::ErrorResponse { request_type, code }));
             }
-            (get_display_info::MAJOR, get_display_info::MINOR, _) => {
-                self.display_router.handle_get_display_info().await;
-                None
-            }
-            (request_commit_render::MAJOR, request_commit_render::MINOR, _) => {
-                self.display_router.handle_request_commit_render().await;
-                None
-            }
-            (set_monocolor_palette::MAJOR, set_monocolor_palette::MINOR, _) => {
-                self.display_router
-                    .handle_set_monocolor_palette(&self.msg_buffer[2..])
-                    .await;
-                None
-            }
-            (set_single_cell::MAJOR, set_single_cell::MINOR, 5..) => {
-                self.display_router
-                    .handle_update_single_cell(&self.msg_buffer[2..])
-                    .await;
-                None
-            }
-            (set_led_state::MAJOR, set_led_state::MINOR, 3..) => {
-                self.system_router
-                    .handle_set_led_state(&self.msg_buffer[2..])
-                    .await;
-                None
-            }
-            (set_rgb_state::MAJOR, set_rgb_state::MINOR, 5..) => {
-                self.system_router
-                    .handle_set_rgb_state(&self.msg_buffer[2..])
-                    .await;
-                None
+        };
+
+        let request_type = msg.request_type();
+        let response: Result<Option<Message<'static>>, ErrorCode> = match msg {
+            Message::Ping(_) => Ok(Some(wire::PingResponse {}.into())),
+            Message::GetDeviceInfo(_) => Ok(Some(
+                wire::GetDeviceInfoResponse::from(self.device_info).into(),
+            )),
+            Message::UpdateRow(update) => self
+                .display_router
+                .handle_row_update(seq, update)
+                .map(|()| None),
+            Message::UpdateRowRgb(update) => self
+                .display_router
+                .handle_row_update_rgb(seq, update)
+                .map(|()| None),
+            Message::UpdateRowRgbRle(update) => self
+                .display_router
+                .handle_row_update_rgb_rle(seq, update)
+                .map(|()| None),
+            Message::UpdateRowRgbDelta(update) => self
+                .display_router
+                .handle_row_update_rgb_delta(seq, update)
+                .map(|()| None),
+            Message::UpdateRegionRgb(update) => self
+                .display_router
+                .handle_region_update_rgb(update)
+                .await
+                .map(|()| {
+                    Some(
+                        wire::UpdateRegionRgbResponse {
+                            status: Status::Success,
+                        }
+                        .into(),
+                    )
+                }),
+            Message::GetDisplayInfo(_) => self
+                .display_router
+                .handle_get_display_info(seq)
+                .map(|()| None),
+            Message::RequestCommitRender(_) => self
+                .display_router
+                .handle_request_commit_render(seq)
+                .map(|()| None),
+            Message::SetMonocolorPalette(palette) => self
+                .display_router
+                .handle_set_monocolor_palette(seq, palette)
+                .map(|()| None),
+            Message::SetBrightness(brightness) => self
+                .display_router
+                .handle_set_brightness(seq, brightness)
+                .map(|()| None),
+            Message::SetSingleCell(cell) => self
+                .display_router
+                .handle_update_single_cell(seq, cell)
+                .map(|()| None),
+            Message::SetLedState(led) => self
+                .system_router
+                .handle_set_led_state(seq, led)
+                .map(|()| None),
+            Message::SetRgbState(rgb) => self
+                .system_router
+                .handle_set_rgb_state(seq, rgb)
+                .map(|()| None),
+            Message::BeginUpdate(begin) => self
+                .update_storage
+                .begin_update(begin.image_size, begin.image_version, begin.crc32)
+                .map(|()| {
+                    Some(
+                        wire::BeginUpdateResponse {
+                            status: Status::Success,
+                        }
+                        .into(),
+                    )
+                }),
+            Message::WriteChunk(chunk) => self
+                .update_storage
+                .write_chunk(chunk.offset, chunk.data)
+                .map(|()| {
+                    Some(
+                        wire::WriteChunkResponse {
+                            status: Status::Success,
+                        }
+                        .into(),
+                    )
+                }),
+            Message::FinishUpdate(_) => self.update_storage.finish_update().map(|status| {
+                self.reboot_pending = status == Status::Success;
+                Some(wire::FinishUpdateResponse { status }.into())
+            }),
+            Message::ConfirmBoot(_) => {
+                self.update_storage.confirm_boot();
+                Ok(Some(
+                    wire::ConfirmBootResponse {
+                        status: Status::Success,
+                    }
+                    .into(),
+                ))
             }
-            _ => None,
+            _ => Err(ErrorCode::UnknownMessage),
+        };
+
+        match response {
+            Ok(response) => response.map(|response| Frame::new(seq, response)),
+            Err(code) => Some(Frame::new(seq, wire::ErrorResponse { request_type, code })),
         }
     }
 }
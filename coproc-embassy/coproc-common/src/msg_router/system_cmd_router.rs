@@ -1,6 +1,6 @@
-use core::future::Future;
-
+use super::Request;
 use crate::system_state::SystemCmdSender;
+use megabit_serial_protocol::wire::{self, ErrorCode};
 
 pub enum SystemCommand {
     SetRgbState(SetRgbState),
@@ -26,17 +26,26 @@ impl SystemCmdRouter {
         Self { request_sender }
     }
 
-    pub fn handle_set_led_state(&self, payload: &[u8]) -> impl Future<Output = ()> + '_ {
-        let state = payload[0] != 0;
-        self.request_sender
-            .send(SystemCommand::SetDebugLedState(SetDebugLedState { state }))
+    pub fn handle_set_led_state(&self, seq: u16, msg: wire::SetLedState) -> Result<(), ErrorCode> {
+        self.queue(
+            seq,
+            SystemCommand::SetDebugLedState(SetDebugLedState {
+                state: msg.new_state,
+            }),
+        )
+    }
+
+    pub fn handle_set_rgb_state(
+        &self,
+        seq: u16,
+        wire::SetRgbState { r, g, b }: wire::SetRgbState,
+    ) -> Result<(), ErrorCode> {
+        self.queue(seq, SystemCommand::SetRgbState(SetRgbState { r, g, b }))
     }
 
-    pub fn handle_set_rgb_state(&self, payload: &[u8]) -> impl Future<Output = ()> + '_ {
-        let r = payload[0];
-        let g = payload[1];
-        let b = payload[2];
+    fn queue(&self, seq: u16, cmd: SystemCommand) -> Result<(), ErrorCode> {
         self.request_sender
-            .send(SystemCommand::SetRgbState(SetRgbState { r, g, b }))
+            .try_send(Request { seq, cmd })
+            .map_err(|_| ErrorCode::QueueFull)
     }
 }
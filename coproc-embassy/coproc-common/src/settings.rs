@@ -0,0 +1,44 @@
+use crate::display::SharedPixelBuffer;
+use embassy_time::{Duration, Timer};
+
+/// How often the current settings are checked against what's been stored. Settings are only
+/// written once they've held for this long so a host sweeping through brightness levels doesn't
+/// wear out the flash.
+const STORE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Somewhere to keep settings across reboots of the coprocessor.
+pub trait SettingsStorage {
+    fn load_brightness(&self) -> Option<u8>;
+
+    fn store_brightness(&mut self, brightness: u8);
+}
+
+/// Restores the stored settings on startup and then stores any changes the host makes to them.
+pub struct SettingsPersister<S: SettingsStorage> {
+    storage: S,
+    pixel_buffer: &'static SharedPixelBuffer,
+}
+
+impl<S: SettingsStorage> SettingsPersister<S> {
+    pub fn new(storage: S, pixel_buffer: &'static SharedPixelBuffer) -> Self {
+        if let Some(brightness) = storage.load_brightness() {
+            pixel_buffer.set_brightness(brightness);
+        }
+        Self {
+            storage,
+            pixel_buffer,
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut stored_brightness = self.pixel_buffer.brightness();
+        loop {
+            Timer::after(STORE_PERIOD).await;
+            let brightness = self.pixel_buffer.brightness();
+            if brightness != stored_brightness {
+                self.storage.store_brightness(brightness);
+                stored_brightness = brightness;
+            }
+        }
+    }
+}
@@ -1,7 +1,20 @@
 use core::future::Future;
 
+use embassy_time::Duration;
+
+/// How long a button has to be held before it's reported as a long press
+pub(super) const LONG_PRESS: Duration = Duration::from_millis(800);
+/// How often a long press is repeated while the button stays held
+pub(super) const REPEAT_INTERVAL: Duration = Duration::from_millis(200);
+/// How soon after a short press the next one has to start to be reported as a double press
+pub(super) const DOUBLE_PRESS_WINDOW: Duration = Duration::from_millis(300);
+/// How long the contacts are left to settle after the button changes state
+pub(super) const DEBOUNCE: Duration = Duration::from_millis(20);
+
 pub trait Button {
     fn wait_for_press(&mut self) -> impl Future<Output = ()>;
 
     fn wait_for_release(&mut self) -> impl Future<Output = ()>;
+
+    fn is_pressed(&mut self) -> bool;
 }
@@ -2,56 +2,68 @@ use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::{
     msg_router::{
-        cmds,
+        Request,
         system_cmd_router::{SetDebugLedState, SetRgbState, SystemCommand},
     },
     usb::UsbResponder,
 };
+use embassy_futures::select::{Either, select};
 use embassy_sync::{
     blocking_mutex::raw::NoopRawMutex,
     channel::{Receiver, Sender},
 };
-use embassy_time::Timer;
+use embassy_time::{Instant, Timer};
 use embedded_hal::digital::StatefulOutputPin;
+use megabit_serial_protocol::wire::{self, ButtonEventKind, Frame, Message, Status};
 
 mod button;
 pub use button::Button;
+use button::{DEBOUNCE, DOUBLE_PRESS_WINDOW, LONG_PRESS, REPEAT_INTERVAL};
 mod rgb_led;
 pub use rgb_led::RgbLed;
 
 pub const SYSTEM_CMD_QUEUE_SIZE: usize = 2;
-pub type SystemCmdSender = Sender<'static, NoopRawMutex, SystemCommand, SYSTEM_CMD_QUEUE_SIZE>;
-pub type SystemCmdReceiver = Receiver<'static, NoopRawMutex, SystemCommand, SYSTEM_CMD_QUEUE_SIZE>;
+pub type SystemCmdSender =
+    Sender<'static, NoopRawMutex, Request<SystemCommand>, SYSTEM_CMD_QUEUE_SIZE>;
+pub type SystemCmdReceiver =
+    Receiver<'static, NoopRawMutex, Request<SystemCommand>, SYSTEM_CMD_QUEUE_SIZE>;
 
 pub struct SystemStateManager<
     R: UsbResponder + 'static,
     RGB: RgbLed,
     DEBUG: StatefulOutputPin,
     BTN: Button,
+    const BUTTONS: usize,
 > {
     cmd_rx: SystemCmdReceiver,
     responder: &'static R,
     rgb_led: RGB,
     debug_led: DEBUG,
-    button: BTN,
+    /// The device's buttons, each reported with its index as the button ID
+    buttons: [BTN; BUTTONS],
 }
 
-impl<R: UsbResponder + 'static, RGB: RgbLed, DEBUG: StatefulOutputPin, BTN: Button>
-    SystemStateManager<R, RGB, DEBUG, BTN>
+impl<
+    R: UsbResponder + 'static,
+    RGB: RgbLed,
+    DEBUG: StatefulOutputPin,
+    BTN: Button,
+    const BUTTONS: usize,
+> SystemStateManager<R, RGB, DEBUG, BTN, BUTTONS>
 {
     pub fn new(
         cmd_rx: SystemCmdReceiver,
         responder: &'static R,
         rgb_led: RGB,
         debug_led: DEBUG,
-        button: BTN,
+        buttons: [BTN; BUTTONS],
     ) -> Self {
         Self {
             cmd_rx,
             responder,
             rgb_led,
             debug_led,
-            button,
+            buttons,
         }
     }
 
@@ -61,7 +73,7 @@ impl<R: UsbResponder + 'static, RGB: RgbLed, DEBUG: StatefulOutputPin, BTN: Butt
         let error_state = AtomicBool::new(false);
 
         embassy_futures::join::join3(
-            Self::report_button_presses(self.button, self.responder, &error_state),
+            Self::report_all_button_events(self.buttons, self.responder, &error_state),
             Self::blink_debug_led(
                 self.debug_led,
                 &debug_led_overridden,
@@ -79,26 +91,87 @@ impl<R: UsbResponder + 'static, RGB: RgbLed, DEBUG: StatefulOutputPin, BTN: Butt
         .await;
     }
 
-    async fn report_button_presses(
+    async fn report_all_button_events(
+        buttons: [BTN; BUTTONS],
+        responder: &'static R,
+        error_state: &AtomicBool,
+    ) {
+        let mut button_id = 0;
+        embassy_futures::join::join_array(buttons.map(|button| {
+            let id = button_id;
+            button_id += 1;
+            Self::report_button_events(id, button, responder, error_state)
+        }))
+        .await;
+    }
+
+    /// Reports a press and a release for every push of the button, along with a long press and
+    /// then repeats at a fixed interval while it's held down, and a double press when it's pushed
+    /// again shortly after a short press.
+    async fn report_button_events(
+        button_id: u8,
         mut button: BTN,
         responder: &'static R,
         error_state: &AtomicBool,
     ) {
+        let report = |kind| Self::report_button_event(button_id, kind, responder, error_state);
+        let mut last_short_release: Option<Instant> = None;
+
         loop {
-            button.wait_for_release().await;
-            Timer::after_millis(50).await;
-            if responder
-                .send(&[
-                    cmds::report_button_press::MAJOR,
-                    cmds::report_button_press::MINOR,
-                ])
-                .await
-                .is_err()
-            {
-                error_state.store(true, Ordering::Relaxed);
-            } else {
-                error_state.store(false, Ordering::Relaxed);
+            button.wait_for_press().await;
+            let pressed_at = Instant::now();
+            report(ButtonEventKind::Press).await;
+
+            // A double press finishes the gesture, so the press after it starts a new one
+            let double_press = last_short_release
+                .take()
+                .is_some_and(|released_at| pressed_at - released_at <= DOUBLE_PRESS_WINDOW);
+            if double_press {
+                report(ButtonEventKind::DoublePress).await;
             }
+            Timer::after(DEBOUNCE).await;
+
+            let mut long_press = false;
+            let mut deadline = pressed_at + LONG_PRESS;
+            // Check the level before every wait so a release during a report isn't missed
+            while button.is_pressed() {
+                if let Either::Second(()) =
+                    select(button.wait_for_release(), Timer::at(deadline)).await
+                {
+                    report(if long_press {
+                        ButtonEventKind::Repeat
+                    } else {
+                        ButtonEventKind::LongPress
+                    })
+                    .await;
+                    long_press = true;
+                    deadline += REPEAT_INTERVAL;
+                }
+            }
+
+            report(ButtonEventKind::Release).await;
+            if !long_press && !double_press {
+                last_short_release = Some(Instant::now());
+            }
+            Timer::after(DEBOUNCE).await;
+        }
+    }
+
+    async fn report_button_event(
+        button_id: u8,
+        kind: ButtonEventKind,
+        responder: &'static R,
+        error_state: &AtomicBool,
+    ) {
+        let event = wire::ReportButtonEvent { button_id, kind };
+        if responder
+            .send_frame(Frame::unsolicited(event))
+            .await
+            .is_err()
+        {
+            error_state.store(true, Ordering::Relaxed);
+        } else {
+            error_state.store(false, Ordering::Relaxed);
         }
     }
 
@@ -128,31 +201,32 @@ impl<R: UsbResponder + 'static, RGB: RgbLed, DEBUG: StatefulOutputPin, BTN: Butt
         debug_led_override_state: &AtomicBool,
     ) {
         loop {
-            let response = match cmd_rx.receive().await {
+            let Request { seq, cmd } = cmd_rx.receive().await;
+            let response: Message = match cmd {
                 SystemCommand::SetRgbState(SetRgbState { r, g, b }) => {
                     rgb_led.set_state(r, g, b);
-                    &[
-                        cmds::set_rgb_state_response::MAJOR,
-                        cmds::set_rgb_state_response::MINOR,
-                        0x00,
-                    ]
+                    wire::SetRgbStateResponse {
+                        status: Status::Success,
+                    }
+                    .into()
                 }
                 SystemCommand::SetDebugLedState(SetDebugLedState { state }) => {
                     debug_led_override_state.store(state, Ordering::Relaxed);
                     debug_led_overridden.store(true, Ordering::Relaxed);
-                    &[
-                        cmds::set_led_state_response::MAJOR,
-                        cmds::set_led_state_response::MINOR,
-                        0x00,
-                    ]
+                    wire::SetLedStateResponse {
+                        status: Status::Success,
+                    }
+                    .into()
                 }
             };
 
-            responder
-                .send(response)
+            if responder
+                .send_frame(Frame::new(seq, response))
                 .await
-                .map_err(|_| error_state.store(true, Ordering::Relaxed))
-                .unwrap();
+                .is_err()
+            {
+                error_state.store(true, Ordering::Relaxed);
+            }
         }
     }
 }
@@ -7,8 +7,11 @@ use static_cell::StaticCell;
 mod responder;
 pub use responder::{Responder, UsbResponder};
 
+/// Sets up the USB device with a CDC ACM class for the serial link. Hosts look for the device by
+/// its IDs or product string, and tell boards apart by their serial number.
 pub fn init_usb_device<T>(
     usb_driver: T,
+    serial_number: &'static str,
 ) -> (embassy_usb::UsbDevice<'static, T>, CdcAcmClass<'static, T>)
 where
     T: embassy_usb_driver::Driver<'static>,
@@ -16,7 +19,7 @@ where
     let mut config = embassy_usb::Config::new(0x16c0, 0x27de);
     config.manufacturer = Some("Snostorm Labs");
     config.product = Some("Megabit coproc");
-    config.serial_number = Some("0123456789ABCDEF");
+    config.serial_number = Some(serial_number);
     config.max_power = 250;
     config.max_packet_size_0 = 64;
     config.device_class = 0xEF;
@@ -1,19 +1,43 @@
-use core::future::Future;
+use core::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
 use embassy_usb::{class::cdc_acm::Sender, driver::EndpointError};
+use megabit_serial_protocol::wire::Frame;
 
 pub trait UsbResponder {
     fn send(&self, unencoded_buf: &[u8]) -> impl Future<Output = Result<(), EndpointError>>;
+
+    /// Whether frames sent to the host carry a CRC trailer.
+    fn crc_enabled(&self) -> bool;
+
+    fn set_crc_enabled(&self, enabled: bool);
+
+    /// Encodes a frame and sends it to the host, frames which don't fit in a single packet are
+    /// rejected as a buffer overflow.
+    fn send_frame(&self, frame: Frame<'_>) -> impl Future<Output = Result<(), EndpointError>> {
+        let frame = frame.with_crc(self.crc_enabled());
+        async move {
+            let mut unencoded_buf = [0u8; 64];
+            let len = frame
+                .encode(&mut unencoded_buf)
+                .map_err(|_| EndpointError::BufferOverflow)?;
+            self.send(&unencoded_buf[..len]).await
+        }
+    }
 }
 
 pub struct Responder<T: embassy_usb_driver::Driver<'static>, const N: usize> {
     inner: Mutex<NoopRawMutex, ResponderInner<T, N>>,
+    crc_enabled: AtomicBool,
 }
 
 impl<T: embassy_usb_driver::Driver<'static>, const N: usize> Responder<T, N> {
     pub fn new(tx: Sender<'static, T>, encoded_buffer: &'static mut [u8; N]) -> Self {
         Self {
             inner: Mutex::new(ResponderInner::new(tx, encoded_buffer)),
+            crc_enabled: AtomicBool::new(false),
         }
     }
 }
@@ -22,6 +46,14 @@ impl<T: embassy_usb_driver::Driver<'static>, const N: usize> UsbResponder for Re
     fn send(&self, unencoded_buf: &[u8]) -> impl Future<Output = Result<(), EndpointError>> {
         async { self.inner.lock().await.send(unencoded_buf).await }
     }
+
+    fn crc_enabled(&self) -> bool {
+        self.crc_enabled.load(Ordering::Relaxed)
+    }
+
+    fn set_crc_enabled(&self, enabled: bool) {
+        self.crc_enabled.store(enabled, Ordering::Relaxed);
+    }
 }
 
 struct ResponderInner<T: embassy_usb_driver::Driver<'static>, const N: usize> {
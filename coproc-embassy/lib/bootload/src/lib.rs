@@ -7,22 +7,29 @@ use rw_flash::{
     nvs::{self, BootState},
 };
 
+/// Picks the partition to boot, counting the boot against the image in it. A freshly written
+/// image is booted on trial, if it hasn't confirmed itself within `max_boot_attempts` boots, or
+/// doesn't check out at all, the bootloader rolls back to the image it replaced. An image which
+/// has confirmed itself once is kept regardless of whether later boots confirm it, as there's no
+/// telling whether the image in the other partition is any better. Only boots on trial are
+/// counted, booting a confirmed image doesn't write to flash.
 pub fn detect_partition_to_run<'a, F: FlashStorage>(
     nvs: &nvs::NvsHandle<'a, F>,
     flash: &'a F,
     bootloader_version: u32,
     max_boot_attempts: u8,
 ) -> Option<AppPartition> {
-    let mut state = nvs
-        .read_boot_state()
-        .unwrap_or_else(|| BootState::default(bootloader_version));
+    let stored_state = nvs.read_boot_state();
+    let mut state = stored_state.unwrap_or_else(|| BootState::default(bootloader_version));
 
-    // Increment the counter prior to boot
-    state.increment_boot_attempts();
-    nvs.write_boot_state(&state);
-
-    if state.boot_attempts > max_boot_attempts {
-        state.revert();
+    if state.update_pending || stored_state.is_none() {
+        // Increment the counter prior to boot
+        state.increment_boot_attempts();
+        if state.update_pending
+            && (state.boot_attempts > max_boot_attempts || !is_pending_image_valid(&state, flash))
+        {
+            state.revert();
+        }
         nvs.write_boot_state(&state);
     }
 
@@ -30,11 +37,19 @@ pub fn detect_partition_to_run<'a, F: FlashStorage>(
 
     // Verify the image by reading the appropriate image header, then checking crc32
     let image_header = image::read_image_header(partition, flash);
-    if image_header.is_valid() {
-        if verify_image(partition, &image_header, flash) {
-            return Some(partition);
-        }
+    if image_header.is_valid() && verify_image(partition, &image_header, flash) {
+        return Some(partition);
     }
 
     None
 }
+
+/// The update has to be the image that was marked pending, not something else that happens to be
+/// in the partition.
+fn is_pending_image_valid<F: FlashStorage>(state: &BootState, flash: &F) -> bool {
+    let partition = AppPartition::from_u8(state.active_partition);
+    let image_header = image::read_image_header(partition, flash);
+    image_header.is_valid()
+        && image_header.image_version() == state.update_version
+        && verify_image(partition, &image_header, flash)
+}
@@ -37,8 +37,133 @@ fn full_app_cycle() -> std::io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn confirmed_update_is_kept() {
+    let flash = TestFlash::with_running_app();
+    let nvs = nvs::NvsHandle { flash: &flash };
+
+    flash
+        .write_update(&nvs, AppPartition::B, 0x00020000)
+        .unwrap();
+    // The update gets booted on trial
+    let partition = bootload::detect_partition_to_run(&nvs, &flash, 0x00010000, 1);
+    assert!(matches!(partition, Some(AppPartition::B)));
+    let state = nvs.read_boot_state().unwrap();
+    assert!(state.update_pending);
+    assert_eq!(state.boot_attempts, 1);
+
+    // Then confirms itself after talking to the host
+    flash.confirm_boot(&nvs);
+    let state = nvs.read_boot_state().unwrap();
+    assert!(!state.update_pending);
+    assert_eq!(state.boot_attempts, 0);
+
+    // Once confirmed it keeps being booted even when it hasn't confirmed itself since
+    for _ in 0..5 {
+        let partition = bootload::detect_partition_to_run(&nvs, &flash, 0x00010000, 1);
+        assert!(matches!(partition, Some(AppPartition::B)));
+    }
+}
+
+#[test]
+fn confirmed_image_boots_without_writing_nvs() {
+    let flash = TestFlash::with_running_app();
+    let nvs = nvs::NvsHandle { flash: &flash };
+
+    let pages_written = flash.pages_written.get();
+    for _ in 0..5 {
+        let partition = bootload::detect_partition_to_run(&nvs, &flash, 0x00010000, 1);
+        assert!(matches!(partition, Some(AppPartition::A)));
+        // Nor does the app have anything to confirm
+        assert!(!nvs.read_boot_state().unwrap().needs_confirming());
+    }
+    assert_eq!(flash.pages_written.get(), pages_written);
+}
+
+#[test]
+fn unconfirmed_update_is_rolled_back() {
+    let flash = TestFlash::with_running_app();
+    let nvs = nvs::NvsHandle { flash: &flash };
+
+    flash
+        .write_update(&nvs, AppPartition::B, 0x00020000)
+        .unwrap();
+    let partition = bootload::detect_partition_to_run(&nvs, &flash, 0x00010000, 1);
+    assert!(matches!(partition, Some(AppPartition::B)));
+
+    // The update never confirmed itself, so the next boot goes back to the previous image
+    let partition = bootload::detect_partition_to_run(&nvs, &flash, 0x00010000, 1);
+    assert!(matches!(partition, Some(AppPartition::A)));
+    let state = nvs.read_boot_state().unwrap();
+    assert_eq!(state.active_partition, AppPartition::A as u8);
+    assert!(!state.update_pending);
+
+    // And stays there
+    let partition = bootload::detect_partition_to_run(&nvs, &flash, 0x00010000, 1);
+    assert!(matches!(partition, Some(AppPartition::A)));
+}
+
+#[test]
+fn corrupted_update_is_rolled_back_without_booting_it() {
+    let flash = TestFlash::with_running_app();
+    let nvs = nvs::NvsHandle { flash: &flash };
+
+    flash
+        .write_update(&nvs, AppPartition::B, 0x00020000)
+        .unwrap();
+    flash.corrupt_app_image(AppPartition::B);
+
+    let partition = bootload::detect_partition_to_run(&nvs, &flash, 0x00010000, 1);
+    assert!(matches!(partition, Some(AppPartition::A)));
+    assert!(!nvs.read_boot_state().unwrap().update_pending);
+}
+
+#[test]
+fn power_loss_while_writing_an_update_keeps_the_running_image() {
+    let image_len = TestFlash::generate_app_image(0x00020000).len();
+    let page_writes = image_len.div_ceil(256);
+    // Power is lost partway through each page of the image, then while writing the image header
+    // and finally while marking the update as pending
+    for writes in 0..=(page_writes + 1) {
+        let flash = TestFlash::with_running_app();
+        let nvs = nvs::NvsHandle { flash: &flash };
+
+        flash.cut_power_after(writes);
+        let _ = flash.write_update(&nvs, AppPartition::B, 0x00020000);
+        flash.restore_power();
+
+        let partition = bootload::detect_partition_to_run(&nvs, &flash, 0x00010000, 1);
+        assert!(
+            matches!(partition, Some(AppPartition::A)),
+            "Booted {partition:?} after losing power {writes} writes into an update"
+        );
+        let state = nvs.read_boot_state().unwrap();
+        assert!(!state.update_pending);
+    }
+}
+
+#[test]
+fn power_loss_while_starting_a_trial_boots_the_update_on_trial_again() {
+    let flash = TestFlash::with_running_app();
+    let nvs = nvs::NvsHandle { flash: &flash };
+    flash
+        .write_update(&nvs, AppPartition::B, 0x00020000)
+        .unwrap();
+
+    // Power is lost as the bootloader counts the boot, leaving a torn boot state record behind
+    flash.cut_power_after(0);
+    let _ = bootload::detect_partition_to_run(&nvs, &flash, 0x00010000, 1);
+    flash.restore_power();
+
+    let partition = bootload::detect_partition_to_run(&nvs, &flash, 0x00010000, 1);
+    assert!(matches!(partition, Some(AppPartition::B)));
+    let state = nvs.read_boot_state().unwrap();
+    assert!(state.update_pending);
+    assert_eq!(state.boot_attempts, 1);
+}
+
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     cmp::min,
     mem::{self, MaybeUninit},
 };
@@ -48,12 +173,17 @@ use rw_flash::{
     flash::FlashStorage,
     image::ImageHeader,
     nvs::{self, SECTOR_SIZE, SectorHeader},
+    update::{ImageUpdate, UpdateError},
 };
 
 pub struct TestFlash {
     mem: RefCell<Box<[u8; 2 * 1024 * 1024]>>,
-    origin_offset: usize,
     sector_size: usize,
+    /// How many more pages can be written before the power goes out, if it's going to
+    writes_until_power_loss: Cell<Option<usize>>,
+    power_lost: Cell<bool>,
+    /// Every page written so far, whether or not the write made it to flash
+    pages_written: Cell<usize>,
 }
 
 impl TestFlash {
@@ -63,9 +193,11 @@ impl TestFlash {
 
     fn initialize_with_blank_nvs() -> Self {
         let flash = Self {
-            origin_offset: memory::flash_boot::ORIGIN as usize,
             mem: RefCell::new(Box::new([0xFFu8; 2 * 1024 * 1024])),
             sector_size: SECTOR_SIZE,
+            writes_until_power_loss: Cell::new(None),
+            power_lost: Cell::new(false),
+            pages_written: Cell::new(0),
         };
         // The first page of NVS region should be a sector header
         // We'll just initialize one
@@ -74,6 +206,28 @@ impl TestFlash {
         flash
     }
 
+    /// Flash with a confirmed app in partition A which has booted once already.
+    fn with_running_app() -> Self {
+        let flash = Self::new();
+        let nvs = nvs::NvsHandle { flash: &flash };
+        flash.write_app_image(AppPartition::A, 0x00010000);
+        let partition = bootload::detect_partition_to_run(&nvs, &flash, 0x00010000, 1);
+        assert!(matches!(partition, Some(AppPartition::A)));
+        flash.confirm_boot(&nvs);
+        flash
+    }
+
+    /// The next `writes` pages are written, then the one after is only partly written and
+    /// nothing is written or erased after that.
+    fn cut_power_after(&self, writes: usize) {
+        self.writes_until_power_loss.set(Some(writes));
+    }
+
+    fn restore_power(&self) {
+        self.writes_until_power_loss.set(None);
+        self.power_lost.set(false);
+    }
+
     fn write_sector_header(&self, header: SectorHeader, sector: u8) {
         let bytes = unsafe {
             core::slice::from_raw_parts(
@@ -89,10 +243,39 @@ impl TestFlash {
     fn write_boot_state(&self, nvs: &nvs::NvsHandle<'_, Self>, partition: AppPartition) {
         let mut boot_state = nvs::BootState::default(0x00010000);
         boot_state.active_partition = partition as u8;
+        // Confirming the image also brings the CRC up to date with the partition change
+        boot_state.confirm_boot();
         nvs.write_boot_state(&boot_state);
     }
 
-    fn generate_app_image(&self, version: u32) -> Vec<u8> {
+    /// Writes an image the way the app does when it's updated over the serial link.
+    fn write_update(
+        &self,
+        nvs: &nvs::NvsHandle<'_, Self>,
+        partition: AppPartition,
+        version: u32,
+    ) -> Result<(), UpdateError> {
+        let image = Self::generate_app_image(version);
+        let crc = crc32::crc32(&image);
+        let mut update = ImageUpdate::begin(self, partition, version, image.len() as u32, crc)?;
+        for (idx, chunk) in image.chunks(100).enumerate() {
+            update.write_chunk(self, (idx * 100) as u32, chunk)?;
+        }
+        update.finish(self, nvs)
+    }
+
+    fn confirm_boot(&self, nvs: &nvs::NvsHandle<'_, Self>) {
+        let mut state = nvs.read_boot_state().unwrap();
+        state.confirm_boot();
+        nvs.write_boot_state(&state);
+    }
+
+    fn corrupt_app_image(&self, partition: AppPartition) {
+        let offset = (partition.boot_target_addr() - flash_boot::ORIGIN) as usize;
+        self.mem.borrow_mut()[offset] ^= 0xFF;
+    }
+
+    fn generate_app_image(version: u32) -> Vec<u8> {
         const LEN: usize = 1028;
         let version = version.to_le_bytes();
         let mut image_bytes = vec![0u8; LEN];
@@ -106,7 +289,7 @@ impl TestFlash {
     }
 
     fn write_app_image(&self, partition: AppPartition, version: u32) {
-        let image = self.generate_app_image(version);
+        let image = Self::generate_app_image(version);
         let crc = crc32::crc32(&image);
         let image_header = ImageHeader::new(version, image.len() as u32, crc);
         let header_bytes = unsafe {
@@ -154,7 +337,10 @@ impl FlashStorage for TestFlash {
     unsafe fn erase_sector(&self, flash_offset: u32) {
         const FLASH_RESET_VALUE: u8 = 0xFF;
         assert!(flash_offset.is_multiple_of(256));
-        let flash_offset = flash_offset as usize - self.origin_offset;
+        if self.power_lost.get() {
+            return;
+        }
+        let flash_offset = flash_offset as usize;
 
         for byte in self
             .mem
@@ -178,6 +364,22 @@ impl FlashStorage for TestFlash {
         );
         assert!(data.len() <= FLASH_PAGE_SIZE);
         assert!(flash_offset.rem_euclid(FLASH_PAGE_SIZE) + data.len() <= FLASH_PAGE_SIZE);
+        self.pages_written.set(self.pages_written.get() + 1);
+        if self.power_lost.get() {
+            return;
+        }
+        let data = match self.writes_until_power_loss.get() {
+            Some(0) => {
+                // The power goes out partway through this write, leaving only the start of it
+                self.power_lost.set(true);
+                &data[..data.len() / 4]
+            }
+            Some(writes) => {
+                self.writes_until_power_loss.set(Some(writes - 1));
+                data
+            }
+            None => data,
+        };
         for (idx, byte) in self
             .mem
             .borrow_mut()
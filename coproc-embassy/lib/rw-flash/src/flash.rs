@@ -1,3 +1,9 @@
+pub const PAGE_SIZE: usize = 256;
+
+/// Flash can only be written a page at a time from a word-aligned buffer.
+#[repr(C, align(4))]
+pub(crate) struct PageBuffer(pub [u8; PAGE_SIZE]);
+
 pub trait FlashStorage {
     /// Read an element of data from flash storage. This requires that the type
     /// has a representation that ensures that it is consistent across compiler
@@ -34,6 +34,10 @@ impl ImageHeader {
         }
     }
 
+    pub fn image_version(&self) -> u32 {
+        self.image_version
+    }
+
     pub fn is_valid(&self) -> bool {
         self.magic == IMAGE_MAGIC
             && self.app_size < (flash_app::APP_LENGTH - flash_app::VECTOR_TABLE_OFFSET)
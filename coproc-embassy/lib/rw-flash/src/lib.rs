@@ -4,5 +4,6 @@
 pub mod flash;
 pub mod image;
 pub mod nvs;
+pub mod update;
 
 pub use flash::FlashStorage;
@@ -1,15 +1,33 @@
 use core::mem;
 
-use crate::flash;
-use memory::{flash_boot, flash_nvs};
+use crate::flash::{self, PAGE_SIZE, PageBuffer};
+use crc32::Crc32;
+use memory::{flash_app::AppPartition, flash_boot, flash_nvs};
 
 const SECTOR_COUNT: usize = 2;
 pub const SECTOR_SIZE: usize = flash_nvs::LENGTH as usize / SECTOR_COUNT;
 const RECORD_SIZE: usize = 64;
 const SECTOR_MAGIC: u32 = 0x4e565300; // "NVS\0"
 const RECORD_MAGIC: u32 = 0x52454300; // "REC\0"
-
-const RECORDS_PER_SECTOR: usize = (SECTOR_SIZE - mem::size_of::<SectorHeader>()) / RECORD_SIZE;
+const KV_RECORD_MAGIC: u32 = 0x4b455900; // "KEY\0"
+const ERASED_WORD: u32 = 0xffffffff;
+
+/// The key the boot state is stored under. Keys below 0x100 are kept for the bootloader, the app
+/// is free to use the rest.
+pub const BOOT_STATE_KEY: u16 = 0x0000;
+/// The longest value which can be stored under a key.
+pub const MAX_VALUE_LEN: usize = 1024;
+/// How many different keys can be stored at once.
+pub const MAX_KEYS: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NvsError {
+    /// The value is longer than `MAX_VALUE_LEN`
+    ValueTooLarge,
+    /// There's no room for the value even once everything but the latest value of each key has
+    /// been cleared out, or it would be one key too many
+    Full,
+}
 
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
@@ -50,6 +68,26 @@ impl SectorHeader {
     }
 }
 
+/// Precedes each value in the log, the CRC covers the rest of the header along with the value.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct RecordHeader {
+    magic: u32,
+    key: u16,
+    len: u16,
+    crc32: u32,
+}
+
+const RECORD_HEADER_SIZE: usize = mem::size_of::<RecordHeader>();
+
+/// A record in a sector which checked out.
+#[derive(Clone, Copy)]
+struct Record {
+    key: u16,
+    value_offset: u32,
+    len: usize,
+}
+
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
 pub struct BootState {
@@ -61,7 +99,12 @@ pub struct BootState {
     _pad: u8,
     pub update_version: u32,
     pub crc32: u32,
-    _reserved: [u8; 44],
+    // Settings which the app used to keep here come after the CRC so that records written before
+    // they existed still check out, each is stored along with its complement to tell whether it's
+    // ever been set
+    brightness: u8,
+    brightness_complement: u8,
+    _reserved: [u8; 42],
 }
 
 const _: () = assert!(mem::size_of::<BootState>() == RECORD_SIZE);
@@ -77,14 +120,16 @@ impl BootState {
             _pad: 0,
             update_version: 0,
             crc32: 0,
-            _reserved: [0; 44],
+            brightness: 0,
+            brightness_complement: 0,
+            _reserved: [0; 42],
         };
         state.crc32 = state.compute_crc();
         state
     }
 
     pub fn increment_boot_attempts(&mut self) {
-        self.boot_attempts += 1;
+        self.boot_attempts = self.boot_attempts.saturating_add(1);
         self.crc32 = self.compute_crc();
     }
 
@@ -100,12 +145,42 @@ impl BootState {
         self.crc32 = self.compute_crc();
     }
 
+    /// Makes a newly written image the one to boot next, on trial until it confirms itself. The
+    /// partition it replaces is left alone so that there's something to roll back to.
+    pub fn set_update_pending(&mut self, partition: AppPartition, image_version: u32) {
+        self.active_partition = partition as u8;
+        self.update_pending = true;
+        self.update_version = image_version;
+        self.boot_attempts = 0;
+        self.crc32 = self.compute_crc();
+    }
+
+    /// Marks the active image as good, it booted and is running well enough to say so.
+    pub fn confirm_boot(&mut self) {
+        self.update_pending = false;
+        self.boot_attempts = 0;
+        self.crc32 = self.compute_crc();
+    }
+
+    /// Whether there's anything for `confirm_boot` to change, so that a confirmed image doesn't
+    /// write out a record on every boot. Boots of a confirmed image aren't counted, so only an
+    /// image on trial needs confirming.
+    pub fn needs_confirming(&self) -> bool {
+        self.update_pending
+    }
+
+    /// The display brightness the app stored here before settings had keys of their own, if it
+    /// ever did.
+    pub fn brightness(&self) -> Option<u8> {
+        (self.brightness == !self.brightness_complement).then_some(self.brightness)
+    }
+
     fn is_valid(&self) -> bool {
         self.magic == RECORD_MAGIC && self.crc32 == self.compute_crc()
     }
 
     fn compute_crc(&self) -> u32 {
-        // Calculate the CRC of all members except the CRC field, which is last
+        // Calculate the CRC of all members up to the CRC field
         let bytes = unsafe {
             core::slice::from_raw_parts(
                 self as *const _ as *const u8,
@@ -116,25 +191,63 @@ impl BootState {
     }
 }
 
+/// A log of key/value records spread over two sectors. Records are appended to the active sector
+/// until it fills up, at which point the latest value of every key is carried over into the other
+/// sector and the full one is erased.
 pub struct NvsHandle<'a, F: flash::FlashStorage> {
     pub flash: &'a F,
 }
 
 impl<'a, F: flash::FlashStorage> NvsHandle<'a, F> {
     pub fn read_boot_state(&self) -> Option<BootState> {
-        let sector = self.find_active_sector()?;
-        self.read_latest_record(sector)
+        let mut buf = [0u8; RECORD_SIZE];
+        let len = self.read(BOOT_STATE_KEY, &mut buf)?;
+        let state = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const BootState) };
+        (len == RECORD_SIZE && state.is_valid()).then_some(state)
     }
 
     pub fn write_boot_state(&self, boot_state: &BootState) {
-        let sector = self.find_active_sector().unwrap_or(0);
-        let next_entry = self.find_next_free_entry(sector);
+        let bytes = unsafe {
+            core::slice::from_raw_parts(boot_state as *const _ as *const u8, RECORD_SIZE)
+        };
+        // The boot state is the first key to be stored and is always carried forward, so there's
+        // always room for it
+        let _ = self.write(BOOT_STATE_KEY, bytes);
+    }
 
-        if next_entry >= RECORDS_PER_SECTOR {
-            let other = 1 - sector;
-            self.compact(sector, other, boot_state);
-        } else {
-            self.append_record(sector, next_entry, boot_state);
+    /// Reads the latest value stored under `key` into `buf`, returning its length. Nothing is
+    /// read if the value is longer than `buf`.
+    pub fn read(&self, key: u16, buf: &mut [u8]) -> Option<usize> {
+        let sector = self.find_active_sector()?;
+        let mut latest = None;
+        self.scan(sector, |record| {
+            if record.key == key {
+                latest = Some(record);
+            }
+        });
+
+        let record = latest?;
+        let value = buf.get_mut(..record.len)?;
+        value.copy_from_slice(unsafe { self.flash.as_slice(record.value_offset, record.len) });
+        Some(record.len)
+    }
+
+    /// Stores a value under `key`, replacing whatever was stored under it before.
+    pub fn write(&self, key: u16, value: &[u8]) -> Result<(), NvsError> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(NvsError::ValueTooLarge);
+        }
+
+        let Some(sector) = self.find_active_sector() else {
+            // Nothing readable has been stored yet, so start afresh
+            return self.compact(None, 0, key, value);
+        };
+        match self.scan(sector, |_| {}) {
+            Some(free) if free + record_len(value.len()) <= SECTOR_SIZE => {
+                self.append_record(sector, free, key, value);
+                Ok(())
+            }
+            _ => self.compact(Some(sector), 1 - sector, key, value),
         }
     }
 
@@ -157,58 +270,144 @@ impl<'a, F: flash::FlashStorage> NvsHandle<'a, F> {
                 }
             }
             (Some(_), None) => Some(0),
-            (None, Some(_)) => None,
+            (None, Some(_)) => Some(1),
             (None, None) => None,
         }
     }
 
-    fn find_next_free_entry(&self, sector: usize) -> usize {
-        for entry in 0..RECORDS_PER_SECTOR {
-            let offset = record_offset(sector, entry);
-            // The magic number is the first field
-            const _: () = assert!(mem::offset_of!(BootState, magic) == 0);
-            let magic = unsafe { self.flash.read_as::<u32>(offset) };
-            if magic == 0xffffffff {
-                return entry;
+    /// Calls `f` with each record in a sector which checks out, in the order they were written,
+    /// then returns where in the sector the next record can go. There's nowhere for one to go
+    /// once the sector is full, or if a record was cut short in a way that leaves no telling where
+    /// it ends.
+    fn scan(&self, sector: usize, mut f: impl FnMut(Record)) -> Option<usize> {
+        let base = sector_base_offset(sector);
+        let mut pos = mem::size_of::<SectorHeader>();
+        while pos + mem::size_of::<u32>() <= SECTOR_SIZE {
+            let offset = base + pos as u32;
+            match unsafe { self.flash.read_as::<u32>(offset) } {
+                ERASED_WORD => return Some(pos),
+                // Boot states were written bare before there were keys
+                RECORD_MAGIC if pos + RECORD_SIZE <= SECTOR_SIZE => {
+                    let state = unsafe { self.flash.read_as::<BootState>(offset) };
+                    if state.is_valid() {
+                        f(Record {
+                            key: BOOT_STATE_KEY,
+                            value_offset: offset,
+                            len: RECORD_SIZE,
+                        });
+                    }
+                    pos += RECORD_SIZE;
+                }
+                KV_RECORD_MAGIC if pos + RECORD_HEADER_SIZE <= SECTOR_SIZE => {
+                    let header = unsafe { self.flash.read_as::<RecordHeader>(offset) };
+                    let len = header.len as usize;
+                    if len > MAX_VALUE_LEN || pos + record_len(len) > SECTOR_SIZE {
+                        return None;
+                    }
+                    let value_offset = offset + RECORD_HEADER_SIZE as u32;
+                    let value = unsafe { self.flash.as_slice(value_offset, len) };
+                    if { header.crc32 } == record_crc(header.key, value) {
+                        f(Record {
+                            key: header.key,
+                            value_offset,
+                            len,
+                        });
+                    }
+                    pos += record_len(len);
+                }
+                _ => return None,
             }
         }
-        // Indicates the sector is full
-        RECORDS_PER_SECTOR
+        None
     }
 
-    fn read_record(&self, sector: usize, entry: usize) -> Option<BootState> {
-        let offset = record_offset(sector, entry);
-        let record = unsafe { self.flash.read_as::<BootState>(offset) };
-        record.is_valid().then_some(record)
-    }
+    fn append_record(&self, sector: usize, pos: usize, key: u16, value: &[u8]) {
+        let header = RecordHeader {
+            magic: KV_RECORD_MAGIC,
+            key,
+            len: value.len() as u16,
+            crc32: record_crc(key, value),
+        };
+        let header_bytes = unsafe {
+            core::slice::from_raw_parts(&header as *const _ as *const u8, RECORD_HEADER_SIZE)
+        };
 
-    fn read_latest_record(&self, sector: usize) -> Option<BootState> {
-        let mut latest = None;
-        for entry in 0..RECORDS_PER_SECTOR {
-            if let Some(record) = self.read_record(sector, entry) {
-                latest = Some(record);
+        // The record goes out through a buffer a page at a time, as the value may itself be in
+        // flash when it's being carried over from the other sector
+        let mut offset = sector_base_offset(sector) + pos as u32;
+        let mut page = PageBuffer([0xff; PAGE_SIZE]);
+        let mut filled = 0;
+        for &byte in header_bytes.iter().chain(value) {
+            page.0[filled] = byte;
+            filled += 1;
+            if filled == PAGE_SIZE - offset as usize % PAGE_SIZE {
+                unsafe { self.flash.write_page(offset, &page.0[..filled]) };
+                offset += filled as u32;
+                filled = 0;
+                page.0.fill(0xff);
             }
         }
-
-        latest
-    }
-
-    fn append_record(&self, sector: usize, entry: usize, state: &BootState) {
-        let offset = record_offset(sector, entry);
-        let bytes =
-            unsafe { core::slice::from_raw_parts(state as *const _ as *const u8, RECORD_SIZE) };
-        unsafe { self.flash.write_page(offset, bytes) };
+        if filled > 0 {
+            // Records are padded out to a whole number of words with erased bytes
+            let len = filled.next_multiple_of(mem::size_of::<u32>());
+            unsafe { self.flash.write_page(offset, &page.0[..len]) };
+        }
     }
 
-    fn compact(&self, from_sector: usize, to_sector: usize, new_state: &BootState) {
-        let old_generation = self
-            .read_sector_header(from_sector)
-            .map(|header| header.generation)
-            .unwrap_or(0);
+    /// Carries the latest value of every key over into the other sector along with the new
+    /// value. The new sector's header is only written once everything else is, so losing power
+    /// partway through leaves the old sector in use.
+    fn compact(
+        &self,
+        from_sector: Option<usize>,
+        to_sector: usize,
+        key: u16,
+        value: &[u8],
+    ) -> Result<(), NvsError> {
+        let mut latest: [Option<Record>; MAX_KEYS] = [None; MAX_KEYS];
+        let mut too_many_keys = false;
+        if let Some(from_sector) = from_sector {
+            self.scan(from_sector, |record| {
+                if record.key == key {
+                    return;
+                }
+                // Keys fill the table from the front, so a key's own slot comes before any empty one
+                match latest
+                    .iter_mut()
+                    .find(|slot| slot.is_none_or(|slot| slot.key == record.key))
+                {
+                    Some(slot) => *slot = Some(record),
+                    None => too_many_keys = true,
+                }
+            });
+        }
+        let needed = latest
+            .iter()
+            .flatten()
+            .map(|record| record_len(record.len))
+            .sum::<usize>()
+            + record_len(value.len());
+        // The table filling up with other keys leaves no room for this one
+        let too_many_keys = too_many_keys || latest.iter().all(Option::is_some);
+        if too_many_keys || mem::size_of::<SectorHeader>() + needed > SECTOR_SIZE {
+            return Err(NvsError::Full);
+        }
 
         let to_base = sector_base_offset(to_sector);
         unsafe { self.flash.erase_sector(to_base) };
 
+        let mut pos = mem::size_of::<SectorHeader>();
+        for record in latest.iter().flatten() {
+            let value = unsafe { self.flash.as_slice(record.value_offset, record.len) };
+            self.append_record(to_sector, pos, record.key, value);
+            pos += record_len(record.len);
+        }
+        self.append_record(to_sector, pos, key, value);
+
+        let old_generation = from_sector
+            .and_then(|sector| self.read_sector_header(sector))
+            .map(|header| header.generation)
+            .unwrap_or(0);
         let header = SectorHeader::new(old_generation.wrapping_add(1));
         let header_bytes = unsafe {
             core::slice::from_raw_parts(
@@ -218,10 +417,10 @@ impl<'a, F: flash::FlashStorage> NvsHandle<'a, F> {
         };
         unsafe { self.flash.write_page(to_base, header_bytes) };
 
-        self.append_record(to_sector, 0, new_state);
-
-        let from_base = sector_base_offset(from_sector);
-        unsafe { self.flash.erase_sector(from_base) };
+        if let Some(from_sector) = from_sector {
+            unsafe { self.flash.erase_sector(sector_base_offset(from_sector)) };
+        }
+        Ok(())
     }
 }
 
@@ -229,8 +428,16 @@ fn sector_base_offset(sector: usize) -> u32 {
     (flash_nvs::ORIGIN - flash_boot::ORIGIN) + (sector * SECTOR_SIZE) as u32
 }
 
-fn record_offset(sector: usize, entry: usize) -> u32 {
-    sector_base_offset(sector)
-        + mem::size_of::<SectorHeader>() as u32
-        + (entry * RECORD_SIZE) as u32
+/// How much of a sector a record with a value of `len` bytes takes up.
+fn record_len(len: usize) -> usize {
+    (RECORD_HEADER_SIZE + len).next_multiple_of(mem::size_of::<u32>())
+}
+
+fn record_crc(key: u16, value: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&KV_RECORD_MAGIC.to_le_bytes());
+    crc.update(&key.to_le_bytes());
+    crc.update(&(value.len() as u16).to_le_bytes());
+    crc.update(value);
+    crc.finish()
 }
@@ -0,0 +1,147 @@
+use core::mem;
+
+use memory::{
+    flash_app::{self, AppPartition},
+    flash_boot,
+};
+
+use crate::{
+    flash::{FlashStorage, PAGE_SIZE, PageBuffer},
+    image::{ImageHeader, verify_image},
+    nvs::{self, NvsHandle},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateError {
+    /// The image doesn't fit in a partition
+    TooLarge,
+    /// A chunk didn't start where the last one left off, or ran past the end of the image
+    OutOfOrder,
+    /// The update was finished before the whole image was written
+    Incomplete,
+    /// The written image doesn't match the CRC it was begun with
+    CrcMismatch,
+    /// There's no boot state to mark the update as pending in
+    NoBootState,
+}
+
+/// A firmware image being written into a partition, a piece at a time as it arrives from the
+/// host. Flash is erased a sector at a time as the image reaches it, and the image header is
+/// only written once the whole image checks out so that a partially written image is never
+/// mistaken for a valid one.
+pub struct ImageUpdate {
+    partition: AppPartition,
+    image_version: u32,
+    image_size: u32,
+    crc32: u32,
+    /// How much of the image has been received
+    received: u32,
+    page: PageBuffer,
+}
+
+impl ImageUpdate {
+    /// Starts an update of the given partition, which mustn't be the one running.
+    pub fn begin(
+        flash: &impl FlashStorage,
+        partition: AppPartition,
+        image_version: u32,
+        image_size: u32,
+        crc32: u32,
+    ) -> Result<Self, UpdateError> {
+        if image_size >= flash_app::APP_LENGTH - flash_app::VECTOR_TABLE_OFFSET {
+            return Err(UpdateError::TooLarge);
+        }
+
+        // The header goes in the first sector, which is written last
+        unsafe { flash.erase_sector(partition_offset(partition)) };
+        Ok(Self {
+            partition,
+            image_version,
+            image_size,
+            crc32,
+            received: 0,
+            page: PageBuffer([0xff; PAGE_SIZE]),
+        })
+    }
+
+    pub fn partition(&self) -> AppPartition {
+        self.partition
+    }
+
+    pub fn image_version(&self) -> u32 {
+        self.image_version
+    }
+
+    pub fn write_chunk(
+        &mut self,
+        flash: &impl FlashStorage,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), UpdateError> {
+        if offset != self.received || data.len() as u32 > self.image_size - self.received {
+            return Err(UpdateError::OutOfOrder);
+        }
+
+        let mut data = data;
+        while !data.is_empty() {
+            let page_pos = self.received as usize % PAGE_SIZE;
+            let len = data.len().min(PAGE_SIZE - page_pos);
+            self.page.0[page_pos..(page_pos + len)].copy_from_slice(&data[..len]);
+            self.received += len as u32;
+            data = &data[len..];
+            if page_pos + len == PAGE_SIZE {
+                self.write_page(flash);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the written image and marks it as the update to boot into next.
+    pub fn finish<F: FlashStorage>(
+        mut self,
+        flash: &F,
+        nvs: &NvsHandle<'_, F>,
+    ) -> Result<(), UpdateError> {
+        if self.received != self.image_size {
+            return Err(UpdateError::Incomplete);
+        }
+        if !(self.received as usize).is_multiple_of(PAGE_SIZE) {
+            self.write_page(flash);
+        }
+
+        let header = ImageHeader::new(self.image_version, self.image_size, self.crc32);
+        if !verify_image(self.partition, &header, flash) {
+            return Err(UpdateError::CrcMismatch);
+        }
+        let mut state = nvs.read_boot_state().ok_or(UpdateError::NoBootState)?;
+
+        let header_bytes = unsafe {
+            core::slice::from_raw_parts(
+                &header as *const _ as *const u8,
+                mem::size_of::<ImageHeader>(),
+            )
+        };
+        unsafe { flash.write_page(partition_offset(self.partition), header_bytes) };
+
+        state.set_update_pending(self.partition, self.image_version);
+        nvs.write_boot_state(&state);
+        Ok(())
+    }
+
+    /// Writes out the page the last byte received falls in, the rest of a partial page is left
+    /// erased.
+    fn write_page(&mut self, flash: &impl FlashStorage) {
+        let page_start = (self.received - 1) / PAGE_SIZE as u32 * PAGE_SIZE as u32;
+        let flash_offset =
+            partition_offset(self.partition) + flash_app::VECTOR_TABLE_OFFSET + page_start;
+        if flash_offset.is_multiple_of(nvs::SECTOR_SIZE as u32) {
+            unsafe { flash.erase_sector(flash_offset) };
+        }
+        unsafe { flash.write_page(flash_offset, &self.page.0) };
+        self.page.0.fill(0xff);
+    }
+}
+
+fn partition_offset(partition: AppPartition) -> u32 {
+    partition.origin() - flash_boot::ORIGIN
+}
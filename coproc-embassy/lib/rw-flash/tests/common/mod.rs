@@ -0,0 +1,73 @@
+// Not every test uses every helper
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+
+use memory::{
+    flash_app::{self, AppPartition},
+    flash_boot, flash_nvs,
+};
+use rw_flash::{
+    flash::{FlashStorage, PAGE_SIZE},
+    nvs::{self, BootState, SECTOR_SIZE, SectorHeader},
+};
+
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Flash kept in RAM which, like NOR flash, can only have bits cleared by a write.
+pub struct RamFlash {
+    mem: RefCell<Vec<u8>>,
+}
+
+impl RamFlash {
+    pub fn erased() -> Self {
+        Self {
+            mem: RefCell::new(vec![0xff; FLASH_SIZE]),
+        }
+    }
+
+    /// Blank flash with an NVS sector holding a fresh boot state, as the bootloader leaves it.
+    pub fn new() -> Self {
+        let flash = Self::erased();
+        let header = SectorHeader::new(0);
+        let header_bytes = unsafe {
+            core::slice::from_raw_parts(&header as *const _ as *const u8, size_of::<SectorHeader>())
+        };
+        unsafe { flash.write_page(flash_nvs::ORIGIN - flash_boot::ORIGIN, header_bytes) };
+        nvs::NvsHandle { flash: &flash }.write_boot_state(&BootState::default(0x00010000));
+        flash
+    }
+
+    /// Fills a partition with something other than an erased value, like a previous image.
+    pub fn scribble_over(&self, partition: AppPartition) {
+        let start = (partition.origin() - flash_boot::ORIGIN) as usize;
+        self.mem.borrow_mut()[start..(start + flash_app::APP_LENGTH as usize)].fill(0x5a);
+    }
+}
+
+impl FlashStorage for RamFlash {
+    unsafe fn read_as<T>(&self, offset: u32) -> T {
+        let mem = self.mem.borrow();
+        let bytes = &mem[offset as usize..(offset as usize + size_of::<T>())];
+        unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const T) }
+    }
+
+    unsafe fn as_slice(&self, offset: u32, len: usize) -> &[u8] {
+        assert!(offset as usize + len <= FLASH_SIZE);
+        unsafe { core::slice::from_raw_parts(self.mem.borrow().as_ptr().add(offset as usize), len) }
+    }
+
+    unsafe fn erase_sector(&self, flash_offset: u32) {
+        let flash_offset = flash_offset as usize;
+        assert!(flash_offset.is_multiple_of(SECTOR_SIZE));
+        self.mem.borrow_mut()[flash_offset..(flash_offset + SECTOR_SIZE)].fill(0xff);
+    }
+
+    unsafe fn write_page(&self, flash_offset: u32, data: &[u8]) {
+        let flash_offset = flash_offset as usize;
+        assert!(flash_offset % PAGE_SIZE + data.len() <= PAGE_SIZE);
+        for (byte, new) in self.mem.borrow_mut()[flash_offset..].iter_mut().zip(data) {
+            *byte &= new;
+        }
+    }
+}
@@ -0,0 +1,164 @@
+mod common;
+
+use common::RamFlash;
+use memory::{flash_boot, flash_nvs};
+use rw_flash::{
+    flash::FlashStorage,
+    nvs::{self, BOOT_STATE_KEY, BootState, MAX_KEYS, MAX_VALUE_LEN, NvsError, SECTOR_SIZE},
+};
+
+fn read_vec(nvs: &nvs::NvsHandle<'_, RamFlash>, key: u16) -> Option<Vec<u8>> {
+    let mut buf = [0; MAX_VALUE_LEN];
+    let len = nvs.read(key, &mut buf)?;
+    Some(buf[..len].to_vec())
+}
+
+fn test_value(key: u16, round: usize, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|idx| (idx + round * 3 + key as usize) as u8)
+        .collect()
+}
+
+#[test]
+fn reads_back_the_latest_value_of_each_key() {
+    let flash = RamFlash::new();
+    let nvs = nvs::NvsHandle { flash: &flash };
+
+    assert_eq!(read_vec(&nvs, 0x100), None);
+    nvs.write(0x100, &[1]).unwrap();
+    // Long enough to run over into the next page
+    nvs.write(0x101, &test_value(0x101, 0, 300)).unwrap();
+    nvs.write(0x100, &[2, 3]).unwrap();
+    nvs.write(0x102, &[]).unwrap();
+
+    assert_eq!(read_vec(&nvs, 0x100), Some(vec![2, 3]));
+    assert_eq!(read_vec(&nvs, 0x101), Some(test_value(0x101, 0, 300)));
+    assert_eq!(read_vec(&nvs, 0x102), Some(vec![]));
+    // The boot state is just another key
+    assert!(nvs.read_boot_state().is_some());
+
+    // A value which doesn't fit the buffer isn't read
+    let mut buf = [0; 1];
+    assert_eq!(nvs.read(0x100, &mut buf), None);
+}
+
+#[test]
+fn rejects_values_which_are_too_long() {
+    let flash = RamFlash::new();
+    let nvs = nvs::NvsHandle { flash: &flash };
+
+    assert_eq!(
+        nvs.write(0x100, &[0; MAX_VALUE_LEN + 1]),
+        Err(NvsError::ValueTooLarge)
+    );
+    assert_eq!(read_vec(&nvs, 0x100), None);
+}
+
+#[test]
+fn compaction_carries_forward_the_latest_value_of_every_key() {
+    let flash = RamFlash::new();
+    let nvs = nvs::NvsHandle { flash: &flash };
+
+    let mut state = nvs.read_boot_state().unwrap();
+    // Enough writes to fill each sector at least once
+    for round in 0..(3 * SECTOR_SIZE / 100) {
+        let key = 0x100 + (round % 4) as u16;
+        nvs.write(key, &test_value(key, round, 20 + round % 50))
+            .unwrap();
+        if round % 10 == 0 {
+            state.increment_boot_attempts();
+            nvs.write_boot_state(&state);
+        }
+
+        for (key_round, key) in
+            ((round.saturating_sub(3))..=round).map(|r| (r, 0x100 + (r % 4) as u16))
+        {
+            assert_eq!(
+                read_vec(&nvs, key),
+                Some(test_value(key, key_round, 20 + key_round % 50)),
+                "Lost key {key:x} after {round} writes"
+            );
+        }
+        assert_eq!(
+            nvs.read_boot_state().unwrap().boot_attempts,
+            state.boot_attempts
+        );
+    }
+}
+
+#[test]
+fn stops_taking_new_keys_once_full() {
+    let flash = RamFlash::new();
+    let nvs = nvs::NvsHandle { flash: &flash };
+
+    // The boot state takes up one of the keys
+    for key in 1..MAX_KEYS as u16 {
+        nvs.write(0x100 + key, &[key as u8]).unwrap();
+    }
+    let extra_key = 0x100 + MAX_KEYS as u16;
+    let mut result = Ok(());
+    for _ in 0..SECTOR_SIZE {
+        result = nvs.write(extra_key, &[0; 64]);
+        if result.is_err() {
+            break;
+        }
+    }
+    assert_eq!(result, Err(NvsError::Full));
+
+    // Nothing stored before is lost, and existing keys can still be written
+    for key in 1..MAX_KEYS as u16 {
+        assert_eq!(read_vec(&nvs, 0x100 + key), Some(vec![key as u8]));
+    }
+    assert!(nvs.read_boot_state().is_some());
+    nvs.write(0x101, &[0xaa]).unwrap();
+    assert_eq!(read_vec(&nvs, 0x101), Some(vec![0xaa]));
+}
+
+#[test]
+fn starts_afresh_on_erased_flash() {
+    let flash = RamFlash::erased();
+    let nvs = nvs::NvsHandle { flash: &flash };
+
+    assert!(nvs.read_boot_state().is_none());
+    nvs.write_boot_state(&BootState::default(0x00010000));
+    nvs.write(0x100, &[1, 2, 3]).unwrap();
+
+    assert_eq!(
+        { nvs.read_boot_state().unwrap().bootloader_version },
+        0x00010000
+    );
+    assert_eq!(read_vec(&nvs, 0x100), Some(vec![1, 2, 3]));
+}
+
+#[test]
+fn reads_boot_states_written_before_there_were_keys() {
+    let flash = RamFlash::erased();
+    let nvs = nvs::NvsHandle { flash: &flash };
+    let sector_base = flash_nvs::ORIGIN - flash_boot::ORIGIN;
+    let header = nvs::SectorHeader::new(0);
+    unsafe { flash.write_page(sector_base, as_bytes(&header)) };
+    // Boot states used to be written bare, one after another
+    let mut state = BootState::default(0x00010000);
+    for entry in 0..3 {
+        state.increment_boot_attempts();
+        unsafe { flash.write_page(sector_base + 256 + entry * 64, as_bytes(&state)) };
+    }
+
+    assert_eq!(nvs.read_boot_state().unwrap().boot_attempts, 3);
+
+    // New records go after the old ones, and the old boot state survives compaction
+    nvs.write(0x100, &[1]).unwrap();
+    assert_eq!(nvs.read_boot_state().unwrap().boot_attempts, 3);
+    for round in 0..(2 * SECTOR_SIZE / 16) {
+        nvs.write(0x100, &[round as u8]).unwrap();
+    }
+    assert_eq!(nvs.read_boot_state().unwrap().boot_attempts, 3);
+    assert_eq!(
+        read_vec(&nvs, BOOT_STATE_KEY).map(|value| value.len()),
+        Some(64)
+    );
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
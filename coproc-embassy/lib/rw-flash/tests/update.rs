@@ -0,0 +1,87 @@
+mod common;
+
+use common::RamFlash;
+use memory::flash_app::{self, AppPartition};
+use rw_flash::{
+    image,
+    nvs::{self, SECTOR_SIZE},
+    update::{ImageUpdate, UpdateError},
+};
+
+fn test_image(len: usize) -> Vec<u8> {
+    (0..len).map(|idx| (idx * 7 + idx / 251) as u8).collect()
+}
+
+fn write_image(
+    flash: &RamFlash,
+    partition: AppPartition,
+    image: &[u8],
+    crc32: u32,
+) -> Result<(), UpdateError> {
+    let mut update = ImageUpdate::begin(flash, partition, 0x00020000, image.len() as u32, crc32)?;
+    // Chunks which don't line up with pages or sectors
+    for (idx, chunk) in image.chunks(300).enumerate() {
+        update.write_chunk(flash, (idx * 300) as u32, chunk)?;
+    }
+    update.finish(flash, &nvs::NvsHandle { flash })
+}
+
+#[test]
+fn writes_an_image_and_marks_it_pending() {
+    let flash = RamFlash::new();
+    flash.scribble_over(AppPartition::B);
+    // Long enough to reach into a third sector
+    let image = test_image(2 * SECTOR_SIZE + 1000);
+
+    write_image(&flash, AppPartition::B, &image, crc32::crc32(&image)).unwrap();
+
+    let header = image::read_image_header(AppPartition::B, &flash);
+    assert!(header.is_valid());
+    assert_eq!(header.image_version(), 0x00020000);
+    assert!(image::verify_image(AppPartition::B, &header, &flash));
+    let state = nvs::NvsHandle { flash: &flash }.read_boot_state().unwrap();
+    assert!(state.update_pending);
+    assert_eq!({ state.update_version }, 0x00020000);
+    assert_eq!(state.active_partition, AppPartition::B as u8);
+}
+
+#[test]
+fn rejects_an_image_which_fails_its_crc() {
+    let flash = RamFlash::new();
+    let image = test_image(1000);
+
+    let res = write_image(&flash, AppPartition::B, &image, crc32::crc32(&image) ^ 1);
+    assert_eq!(res, Err(UpdateError::CrcMismatch));
+
+    assert!(!image::read_image_header(AppPartition::B, &flash).is_valid());
+    let state = nvs::NvsHandle { flash: &flash }.read_boot_state().unwrap();
+    assert!(!state.update_pending);
+}
+
+#[test]
+fn rejects_chunks_out_of_order() {
+    let flash = RamFlash::new();
+    let image = test_image(1000);
+    let mut update = ImageUpdate::begin(&flash, AppPartition::B, 1, 1000, 0).unwrap();
+
+    assert_eq!(
+        update.write_chunk(&flash, 100, &image[100..200]),
+        Err(UpdateError::OutOfOrder)
+    );
+    update.write_chunk(&flash, 0, &image[..600]).unwrap();
+    assert_eq!(
+        update.write_chunk(&flash, 600, &test_image(500)),
+        Err(UpdateError::OutOfOrder)
+    );
+    assert_eq!(
+        update.finish(&flash, &nvs::NvsHandle { flash: &flash }),
+        Err(UpdateError::Incomplete)
+    );
+}
+
+#[test]
+fn rejects_an_image_larger_than_a_partition() {
+    let flash = RamFlash::new();
+    let res = ImageUpdate::begin(&flash, AppPartition::B, 1, flash_app::APP_LENGTH, 0);
+    assert!(matches!(res, Err(UpdateError::TooLarge)));
+}
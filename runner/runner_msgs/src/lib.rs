@@ -10,9 +10,21 @@ pub enum ConsoleMessage {
     ResumeRendering,
     NextApp,
     PreviousApp,
+    /// Rescans the runner's app library, the runner also does this itself when the library changes
+    ReloadApps,
     SetMatrixRowRgb(SetMatrixRowRgb),
+    SetBrightness(SetBrightness),
+    DeviceConnection(DeviceConnection),
     RequestAppListing(RequestAppListing),
     AppListingResponse(AppListingResponse),
+    RequestLinkStats(RequestLinkStats),
+    LinkStatsResponse(LinkStatsResponse),
+    AppFault(AppFault),
+    RequestAppUsage(RequestAppUsage),
+    AppUsageResponse(AppUsageResponse),
+    RequestAppPermissions(RequestAppPermissions),
+    AppPermissionsResponse(AppPermissionsResponse),
+    SetAppPermissions(SetAppPermissions),
     #[cfg(test)]
     TestMessage(TestMessage),
 }
@@ -23,6 +35,18 @@ pub struct SetMatrixRowRgb {
     pub data: Vec<u16>,
 }
 
+/// Dims the display, from off at 0 up to full brightness at 255.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetBrightness {
+    pub brightness: u8,
+}
+
+/// Sent by the runner when it loses or regains its link to the display device.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceConnection {
+    pub connected: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RequestAppListing {
     pub request_id: String,
@@ -40,6 +64,123 @@ pub struct App {
     pub app_name: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestLinkStats {
+    pub request_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkStatsResponse {
+    pub request_id: String,
+    pub stats: LinkStats,
+}
+
+/// How healthy the runner's link to the display device is, round trip times are measured by
+/// pinging the device and are absent until a ping has been answered.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkStats {
+    pub rtt_p50_ms: Option<f64>,
+    pub rtt_p90_ms: Option<f64>,
+    pub rtt_p99_ms: Option<f64>,
+    pub pings_sent: u64,
+    pub ping_timeouts: u64,
+    pub reconnects: u64,
+    pub tx_bytes_per_sec: f64,
+    pub rx_bytes_per_sec: f64,
+    pub tx_frames_per_sec: f64,
+    pub rx_frames_per_sec: f64,
+}
+
+/// Sent by the runner when a call into an app fails, after which the runner's crash policy is
+/// applied to the app.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppFault {
+    pub md5sum: String,
+    pub app_name: String,
+    /// The app's function which was called, like `run`
+    pub function: String,
+    pub kind: AppFaultKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum AppFaultKind {
+    /// The call was interrupted for running longer than the app is allowed to
+    TimeLimitExceeded {
+        limit_ms: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestAppUsage {
+    pub request_id: String,
+}
+
+/// Answers with the running app's usage, or without any when the runner has no app running.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppUsageResponse {
+    pub request_id: String,
+    pub usage: Option<AppUsage>,
+}
+
+/// How much of what it's allowed the running app is using, memory isn't tracked past the number
+/// of pages the app is limited to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppUsage {
+    pub md5sum: String,
+    pub app_name: String,
+    pub memory_pages_max: u32,
+    pub kv_bytes: u64,
+    pub kv_bytes_max: u64,
+    pub kv_keys: u64,
+    pub kv_keys_max: u64,
+    pub log_lines_this_minute: u32,
+    pub log_lines_per_minute_max: u32,
+    /// Log lines dropped for going over the limit since the app was loaded
+    pub log_lines_dropped: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestAppPermissions {
+    pub request_id: String,
+}
+
+/// Answers with the permissions of every app in the runner's library.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppPermissionsResponse {
+    pub request_id: String,
+    pub apps: Vec<InstalledApp>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InstalledApp {
+    /// The name of the app's bundle directory, which stays the same when the app is rebuilt
+    pub app_id: String,
+    pub app_name: String,
+    pub permissions: AppPermissions,
+}
+
+/// Replaces the permissions in an app's manifest, the app is reloaded with them if it's running.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetAppPermissions {
+    pub app_id: String,
+    pub permissions: AppPermissions,
+}
+
+/// What an app is granted access to, as declared in the `permissions` section of its manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AppPermissions {
+    pub kv: bool,
+    pub network_hosts: Vec<String>,
+    pub filesystem_dirs: Vec<String>,
+    pub time: bool,
+    pub input: bool,
+    pub notifications: bool,
+    pub messaging: bool,
+}
+
 #[cfg(test)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct TestMessage {
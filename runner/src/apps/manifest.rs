@@ -1,7 +1,8 @@
 use md5::Digest;
-use serde::Deserialize;
+use megabit_runner_msgs::AppPermissions;
+use serde::{Deserialize, Serialize};
 use std::{
-    io::{self, Read},
+    io::{self, Read, Write},
     path::{Path, PathBuf},
     time::Duration,
 };
@@ -13,6 +14,96 @@ pub struct AppManifest {
     pub app_name: String,
     pub app_bin_path: PathBuf,
     pub refresh_period: Option<Duration>,
+    pub limits: ManifestLimits,
+    pub permissions: Permissions,
+}
+
+/// Limits the app asks for, which are used in place of the runner's own where they're lower.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestLimits {
+    pub call_timeout: Option<Duration>,
+    pub memory_pages: Option<u32>,
+    pub kv_bytes: Option<usize>,
+    pub kv_keys: Option<usize>,
+    pub log_lines_per_minute: Option<u32>,
+}
+
+/// What an app is granted access to, only the host functions and WASI capabilities it's granted
+/// are linked into it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Permissions {
+    /// Keeping state in its KV store
+    pub kv: bool,
+    /// Hosts the app can make HTTP requests to
+    pub network_hosts: Vec<String>,
+    /// Directories the app can get at through WASI, at the same paths as they are on the host
+    pub filesystem_dirs: Vec<PathBuf>,
+    /// Reading the clock through WASI. WASI is linked as a whole so an app which is granted any
+    /// filesystem dirs can read the clock too.
+    pub time: bool,
+    /// Having button events delivered to it
+    pub input: bool,
+    /// Not linked to anything yet, the grant is kept for when there's an API for it
+    pub notifications: bool,
+    /// Not linked to anything yet, the grant is kept for when there's an API for it
+    pub messaging: bool,
+}
+
+impl Permissions {
+    /// What apps whose manifests don't have a `permissions` section are granted, which is
+    /// everything every app was given before there were permissions: the KV store, the clock
+    /// and button events, but no network hosts or directories. Every manifest written before
+    /// there were permissions is granted these until its permissions are edited.
+    pub fn implicit() -> Self {
+        Self {
+            kv: true,
+            time: true,
+            input: true,
+            ..Default::default()
+        }
+    }
+
+    /// Whether WASI needs to be linked into the app.
+    pub fn needs_wasi(&self) -> bool {
+        self.time || !self.filesystem_dirs.is_empty()
+    }
+}
+
+impl From<AppPermissions> for Permissions {
+    fn from(permissions: AppPermissions) -> Self {
+        Self {
+            kv: permissions.kv,
+            network_hosts: permissions.network_hosts,
+            filesystem_dirs: permissions
+                .filesystem_dirs
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            time: permissions.time,
+            input: permissions.input,
+            notifications: permissions.notifications,
+            messaging: permissions.messaging,
+        }
+    }
+}
+
+impl From<Permissions> for AppPermissions {
+    fn from(permissions: Permissions) -> Self {
+        Self {
+            kv: permissions.kv,
+            network_hosts: permissions.network_hosts,
+            filesystem_dirs: permissions
+                .filesystem_dirs
+                .iter()
+                .map(|dir| dir.display().to_string())
+                .collect(),
+            time: permissions.time,
+            input: permissions.input,
+            notifications: permissions.notifications,
+            messaging: permissions.messaging,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -20,6 +111,12 @@ struct ManifestSchema {
     name: String,
     bin: String,
     refresh_period_ms: Option<u32>,
+    call_timeout_ms: Option<u32>,
+    max_memory_pages: Option<u32>,
+    max_kv_bytes: Option<usize>,
+    max_kv_keys: Option<usize>,
+    max_log_lines_per_minute: Option<u32>,
+    permissions: Option<Permissions>,
 }
 
 impl AppManifest {
@@ -58,6 +155,16 @@ impl AppManifest {
                 refresh_period: manifest
                     .refresh_period_ms
                     .map(|duration| Duration::from_millis(duration.into())),
+                limits: ManifestLimits {
+                    call_timeout: manifest
+                        .call_timeout_ms
+                        .map(|duration| Duration::from_millis(duration.into())),
+                    memory_pages: manifest.max_memory_pages,
+                    kv_bytes: manifest.max_kv_bytes,
+                    kv_keys: manifest.max_kv_keys,
+                    log_lines_per_minute: manifest.max_log_lines_per_minute,
+                },
+                permissions: manifest.permissions.unwrap_or_else(Permissions::implicit),
             })
         } else {
             tracing::error!(
@@ -67,4 +174,29 @@ impl AppManifest {
             Err(io::ErrorKind::InvalidData.into())
         }
     }
+
+    /// The name of the app's bundle directory, which stays the same when the app's rebuilt unlike
+    /// its checksum does.
+    pub fn app_id(&self) -> Option<&str> {
+        self.path.parent()?.file_name()?.to_str()
+    }
+}
+
+/// Replaces the `permissions` section of the manifest at `manifest_path`, leaving the rest of it
+/// as it was. The manifest is written out to a hidden file in the bundle and moved over the old one
+/// so it's never seen half written.
+pub fn write_permissions(manifest_path: &Path, permissions: &Permissions) -> io::Result<()> {
+    let mut manifest: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(&std::fs::read(manifest_path)?)?;
+    manifest.insert(
+        String::from("permissions"),
+        serde_json::to_value(permissions)?,
+    );
+
+    let temp_path = manifest_path.with_file_name(".manifest.json.tmp");
+    let mut file = std::fs::File::create(&temp_path)?;
+    serde_json::to_writer_pretty(&mut file, &manifest)?;
+    file.write_all(b"\n")?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, manifest_path)
 }
@@ -25,16 +25,40 @@ impl Library {
         Ok(library)
     }
 
-    fn get(&self, checksum: &str) -> Option<(usize, AppManifest)> {
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Scans the data dir again, picking up bundles which have been added, removed or changed
+    /// since it was last scanned.
+    pub fn reload(&self) -> io::Result<()> {
+        let apps = load_from_path(&self.data_dir)?;
+        *self.apps.lock().unwrap() = apps;
+        Ok(())
+    }
+
+    /// Finds the app whose manifest is at `path`, which stays the same when the app is rebuilt
+    /// unlike its checksum.
+    pub fn get_by_path(&self, path: &Path) -> Option<AppManifest> {
         let apps = self.apps.lock().unwrap();
-        apps.iter()
-            .enumerate()
-            .find(|(_idx, app)| app.md5sum.as_str() == checksum)
-            .map(|(idx, app)| (idx, app.clone()))
+        apps.iter().find(|app| app.path == path).cloned()
+    }
+
+    pub fn apps(&self) -> Vec<AppManifest> {
+        self.apps.lock().unwrap().clone()
     }
 
     pub fn get_app(&self, checksum: &str) -> Option<AppManifest> {
-        self.get(checksum).map(|(_idx, app)| app)
+        let apps = self.apps.lock().unwrap();
+        position(&apps, checksum).map(|idx| apps[idx].clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.apps.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     pub fn get_first(&self) -> Option<AppManifest> {
@@ -44,7 +68,7 @@ impl Library {
 
     pub fn get_next(&self, current_checksum: &str) -> Option<AppManifest> {
         let apps = self.apps.lock().unwrap();
-        if let Some((idx, _)) = self.get(current_checksum) {
+        if let Some(idx) = position(&apps, current_checksum) {
             let next_idx = if idx == apps.len() - 1 { 0 } else { idx + 1 };
             Some(apps[next_idx].clone())
         } else {
@@ -55,7 +79,7 @@ impl Library {
 
     pub fn get_prev(&self, current_checksum: &str) -> Option<AppManifest> {
         let apps = self.apps.lock().unwrap();
-        if let Some((idx, _)) = self.get(current_checksum) {
+        if let Some(idx) = position(&apps, current_checksum) {
             let next_idx = if idx == 0 { apps.len() - 1 } else { idx - 1 };
             Some(apps[next_idx].clone())
         } else {
@@ -65,12 +89,23 @@ impl Library {
     }
 }
 
+/// Hidden directories in the data dir aren't app bundles, the runner keeps apps' KV stores in one.
+pub fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Looked up with the library already locked, as its lock can't be taken twice.
+fn position(apps: &[AppManifest], checksum: &str) -> Option<usize> {
+    apps.iter().position(|app| app.md5sum == checksum)
+}
+
 fn load_from_path(path: impl AsRef<Path>) -> io::Result<Vec<AppManifest>> {
-    let apps = std::fs::read_dir(&path)?
+    let mut apps = std::fs::read_dir(&path)?
         .map(|entry| {
             let entry = entry?;
             let path = entry.path();
-            if path.is_dir() {
+            if path.is_dir() && !is_hidden(&path) {
                 if let Ok(app) = AppManifest::open(&path) {
                     tracing::info!("Found app {} at path: {}", &app.app_name, path.display());
                     Ok(Some(app))
@@ -87,6 +122,9 @@ fn load_from_path(path: impl AsRef<Path>) -> io::Result<Vec<AppManifest>> {
             Err(_) => None,
         })
         .collect::<Vec<_>>();
+    // The directory isn't listed in any particular order, and the apps should keep theirs when
+    // the library is reloaded
+    apps.sort_by(|a, b| a.path.cmp(&b.path));
 
     tracing::info!("Found {} apps", apps.len());
 
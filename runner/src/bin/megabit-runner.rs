@@ -2,24 +2,60 @@ use clap::Parser;
 use megabit_runner::{
     apps::Library,
     display::{DisplayConfiguration, PixelRepresentation, ScreenBuffer},
-    events::EventListener,
+    events::{ButtonBinding, EventListener},
     streams::{
         api_server,
         coproc_client::{self, DeviceTransport},
     },
-    Runner,
+    wasm_env::{
+        AppLimits, DEFAULT_CALL_TIMEOUT, DEFAULT_KV_BYTES, DEFAULT_KV_KEYS,
+        DEFAULT_LOG_LINES_PER_MINUTE, DEFAULT_MEMORY_PAGES,
+    },
+    CrashPolicy, Runner,
 };
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Clone, Debug, Parser)]
 pub struct Args {
-    /// Path to the tty serial device for the display coprocessor
-    #[arg(long)]
+    /// URI of the display coprocessor, like `serial:///dev/ttyACM0` or `tcp://localhost:9009`,
+    /// found among the attached serial ports by default
+    #[arg(long, default_value = "discover://")]
     device: DeviceTransport,
+    /// Maximum number of row updates to have in flight at once, defaults to the depth of the
+    /// device's command queue
+    #[arg(long)]
+    pipeline_window: Option<usize>,
     /// Directory containing megabit app bundles in subdirectories
     #[arg(long)]
     data_dir: Option<PathBuf>,
+    /// Binds a button event to an action as `[<button id>:]<kind>=<action>`, where kind is one of
+    /// press, release, long-press, double-press or repeat and action is one of next, prev, pause,
+    /// app or none. Added on top of the defaults of `release=next` and `long-press=pause`, with
+    /// later bindings taking precedence.
+    #[arg(long = "button-binding")]
+    button_bindings: Vec<ButtonBinding>,
+    /// Milliseconds each call into an app can run for before it's interrupted, apps can ask for
+    /// less in their manifest
+    #[arg(long, default_value_t = DEFAULT_CALL_TIMEOUT.as_millis() as u64)]
+    call_timeout_ms: u64,
+    /// Most 64 KiB pages of linear memory an app can grow to, apps can ask for fewer in their
+    /// manifest
+    #[arg(long, default_value_t = DEFAULT_MEMORY_PAGES)]
+    max_memory_pages: u32,
+    /// Most bytes of keys and values an app can keep in its KV store
+    #[arg(long, default_value_t = DEFAULT_KV_BYTES)]
+    max_kv_bytes: usize,
+    /// Most keys an app can keep in its KV store
+    #[arg(long, default_value_t = DEFAULT_KV_KEYS)]
+    max_kv_keys: usize,
+    /// Most lines an app can log each minute, the rest of the minute's lines are dropped
+    #[arg(long, default_value_t = DEFAULT_LOG_LINES_PER_MINUTE)]
+    max_log_lines_per_minute: u32,
+    /// What to do with an app which crashes or runs past its time limit, one of restart, next or
+    /// stop
+    #[arg(long, default_value = "restart")]
+    crash_policy: CrashPolicy,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -44,7 +80,14 @@ fn main() -> anyhow::Result<()> {
 
     let _serial_task_handle = rt.spawn(Box::into_pin(serial_task));
 
+    tracing::info!("Waiting for the device to connect");
+    sync_serial_conn.connect()?;
+
     let display_info = sync_serial_conn.get_display_info()?;
+    if let Some(max_window) = args.pipeline_window {
+        sync_serial_conn.set_max_pipeline_window(max_window);
+    }
+    sync_serial_conn.set_pipeline_window(display_info.queue_depth() as usize);
     let display_info = DisplayConfiguration {
         width: display_info.width as usize,
         height: display_info.height as usize,
@@ -65,17 +108,35 @@ fn main() -> anyhow::Result<()> {
 
     let library = Library::new(data_dir)?;
     let api_server_handle = api_server::start(8003, rt.handle().clone());
-    let event_listener =
-        EventListener::new(serial_conn, api_server_handle.clone(), rt.handle().clone());
-    let screen_buffer = ScreenBuffer::create(display_info.width, display_info.height);
+    let button_bindings = ButtonBinding::defaults()
+        .into_iter()
+        .chain(args.button_bindings)
+        .collect();
+    let event_listener = EventListener::new(
+        serial_conn,
+        api_server_handle.clone(),
+        library.data_dir().to_path_buf(),
+        button_bindings,
+        rt.handle().clone(),
+    );
+    let screen_buffer = ScreenBuffer::create_for_display(&display_info);
 
+    let limits = AppLimits {
+        call_timeout: Duration::from_millis(args.call_timeout_ms),
+        memory_pages: args.max_memory_pages,
+        kv_bytes: args.max_kv_bytes,
+        kv_keys: args.max_kv_keys,
+        log_lines_per_minute: args.max_log_lines_per_minute,
+    };
     let mut runner = Runner::new(
         library,
+        limits,
+        args.crash_policy,
         sync_serial_conn,
         screen_buffer,
         event_listener,
         api_server_handle,
-    )?;
+    );
     runner.run();
 
     tracing::info!("Exiting runner");
@@ -1,6 +1,7 @@
 use clap::Parser;
 use inotify::{EventMask, Inotify, WatchMask};
 use megabit_runner::{
+    apps::{manifest::Permissions, AppManifest},
     display::{DisplayConfiguration, PixelRepresentation, ScreenBuffer},
     streams::{
         api_server,
@@ -13,9 +14,14 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Clone, Debug, Parser)]
 pub struct Args {
-    /// Path to the tty serial device for the display coprocessor
-    #[arg(long)]
+    /// URI of the display coprocessor, like `serial:///dev/ttyACM0` or `tcp://localhost:9009`,
+    /// found among the attached serial ports by default
+    #[arg(long, default_value = "discover://")]
     device: DeviceTransport,
+    /// Maximum number of row updates to have in flight at once, defaults to the depth of the
+    /// device's command queue
+    #[arg(long)]
+    pipeline_window: Option<usize>,
     /// Path to a wasm binary application
     #[arg(long)]
     app: PathBuf,
@@ -45,7 +51,14 @@ fn main() -> anyhow::Result<()> {
 
     let _serial_task_handle = rt.spawn(Box::into_pin(serial_task));
 
+    tracing::info!("Waiting for the device to connect");
+    serial_conn.connect()?;
+
     let display_info = serial_conn.get_display_info()?;
+    if let Some(max_window) = args.pipeline_window {
+        serial_conn.set_max_pipeline_window(max_window);
+    }
+    serial_conn.set_pipeline_window(display_info.queue_depth() as usize);
     let display_info = DisplayConfiguration {
         width: display_info.width as usize,
         height: display_info.height as usize,
@@ -55,7 +68,7 @@ fn main() -> anyhow::Result<()> {
         ),
     };
     tracing::info!("Retrieved info about the display: {display_info:?}");
-    let screen_buffer = ScreenBuffer::create(display_info.width, display_info.height);
+    let screen_buffer = ScreenBuffer::create_for_display(&display_info);
 
     let mut inotify = Inotify::init().unwrap();
     let watch_mask = WatchMask::MODIFY
@@ -69,11 +82,19 @@ fn main() -> anyhow::Result<()> {
         // If the file was updated, the watch is removed since the inode is replaced
         inotify.watches().add(&args.app, watch_mask).unwrap();
 
+        let app_manifest = AppManifest {
+            path: args.app.clone(),
+            md5sum: String::new(),
+            app_name: String::from("Demo"),
+            app_bin_path: args.app.clone(),
+            refresh_period: args.refresh.map(Duration::from_millis),
+            limits: Default::default(),
+            permissions: Permissions::implicit(),
+        };
         let mut wasm_app = wasm_env::WasmAppRunner::new(
-            &args.app,
-            args.refresh.map(Duration::from_millis),
-            "Demo",
-            "".into(),
+            &app_manifest,
+            wasm_env::AppLimits::default(),
+            None,
             serial_conn.clone(),
             screen_buffer.clone(),
             api_server_handle.clone(),
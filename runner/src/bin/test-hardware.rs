@@ -6,7 +6,8 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Clone, Debug, Parser)]
 pub struct Args {
-    #[arg(short, long)]
+    /// URI of the display coprocessor, found among the attached serial ports by default
+    #[arg(short, long, default_value = "discover://")]
     device: DeviceTransport,
 }
 
@@ -0,0 +1,121 @@
+use anyhow::{anyhow, bail};
+use clap::Parser;
+use megabit_runner::streams::coproc_client::{self, DeviceTransport, LinkState};
+use megabit_serial_protocol::{Status, WriteChunk};
+use std::{path::PathBuf, time::Duration};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+/// How long the device gets to reset into the new image and come back.
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, Parser)]
+pub struct Args {
+    /// URI of the display coprocessor, found among the attached serial ports by default
+    #[arg(short, long, default_value = "discover://")]
+    device: DeviceTransport,
+    /// App image to write, as built by `image-builder` without `--combined`
+    #[arg(short, long, default_value = "./coproc-app.bin")]
+    image: PathBuf,
+    /// Version to give the image, like `0x00010001`
+    #[arg(long, value_parser = parse_version)]
+    image_version: u32,
+}
+
+fn parse_version(version: &str) -> Result<u32, String> {
+    match version.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => version.parse(),
+    }
+    .map_err(|err| format!("invalid image version {version}: {err}"))
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "megabit_runner=info,update_coproc=info".into()),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let image = std::fs::read(&args.image)?;
+    let image_crc = crc32::crc32(&image);
+
+    let (serial_conn, serial_task) = coproc_client::start_transport_task(args.device);
+    let _serial_task_handle = tokio::spawn(Box::into_pin(serial_task));
+
+    serial_conn.wait_until_connected().await?;
+    let device_info = serial_conn
+        .handshake()
+        .await?
+        .ok_or_else(|| anyhow!("device doesn't report its info, so can't be updated"))?;
+    tracing::info!(
+        "Device is running image 0x{:08x} from partition {}, writing image 0x{:08x} of {} bytes",
+        device_info.image_version,
+        device_info.active_partition,
+        args.image_version,
+        image.len()
+    );
+
+    let response = serial_conn
+        .begin_update(image.len() as u32, args.image_version, image_crc)
+        .await?;
+    if response.status != Status::Success {
+        bail!("device refused to begin the update");
+    }
+    for (i, chunk) in image.chunks(WriteChunk::MAX_LEN).enumerate() {
+        let offset = (i * WriteChunk::MAX_LEN) as u32;
+        let response = serial_conn.write_chunk(offset, chunk.to_vec()).await?;
+        if response.status != Status::Success {
+            bail!("device failed to write the chunk at offset {offset}");
+        }
+        tracing::debug!(
+            "Wrote {} of {} bytes",
+            offset as usize + chunk.len(),
+            image.len()
+        );
+    }
+
+    let mut link_state = serial_conn.watch_link_state();
+    link_state.mark_unchanged();
+    let response = serial_conn.finish_update().await?;
+    if response.status != Status::Success {
+        bail!("written image failed its CRC check");
+    }
+    tracing::info!("Image written, waiting for the device to reboot into it");
+
+    tokio::time::timeout(REBOOT_TIMEOUT, async {
+        link_state
+            .wait_for(|state| *state != LinkState::Connected)
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::NotConnected))?;
+        serial_conn.wait_until_connected().await
+    })
+    .await
+    .map_err(|_| anyhow!("device didn't come back after rebooting"))??;
+
+    let device_info = serial_conn
+        .handshake()
+        .await?
+        .ok_or_else(|| anyhow!("device stopped reporting its info after the update"))?;
+    if device_info.image_version != args.image_version {
+        bail!(
+            "device booted image 0x{:08x} instead, the bootloader rejected the update",
+            device_info.image_version
+        );
+    }
+    let response = serial_conn.confirm_boot().await?;
+    if response.status != Status::Success {
+        bail!("device failed to confirm the new image");
+    }
+    tracing::info!(
+        "Device is running image 0x{:08x} from partition {}",
+        device_info.image_version,
+        device_info.active_partition
+    );
+
+    Ok(())
+}
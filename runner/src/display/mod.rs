@@ -1,6 +1,7 @@
 pub use megabit_serial_protocol::PixelRepresentation;
 use megabit_utils::rgb555::Rgb555;
 use std::{
+    collections::BTreeSet,
     io,
     sync::{Arc, Mutex},
 };
@@ -17,6 +18,52 @@ pub const DEFAULT_MONO_PALETTE: MonocolorPalette = MonocolorPalette::new(
     Rgb555::from_rgb(0x00, 0x00, 0x00),
 );
 
+/// The most dirty rectangles tracked at once before they're collapsed into their bounding box.
+const MAX_DIRTY_RECTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Whether the rectangles overlap or share an edge, i.e. whether their union covers no
+    /// pixels outside of the two.
+    fn touches(&self, other: &Rect) -> bool {
+        self.x <= other.x + other.width
+            && other.x <= self.x + self.width
+            && self.y <= other.y + other.height
+            && other.y <= self.y + self.height
+    }
+
+    fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+
+    pub fn contains_row(&self, row: usize) -> bool {
+        (self.y..(self.y + self.height)).contains(&row)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ScreenBufferHandle {
     inner: Arc<Mutex<ScreenBuffer>>,
@@ -85,11 +132,26 @@ impl ScreenBufferHandle {
         buffer.get_row_rgb(row)
     }
 
+    pub fn get_region_rgb(&self, region: Rect) -> io::Result<Vec<Rgb555>> {
+        let buffer = self.inner.lock().expect("Mutex not poisoned");
+        buffer.get_region_rgb(region)
+    }
+
+    pub fn dirty_rects(&self) -> Vec<Rect> {
+        let buffer = self.inner.lock().expect("Mutex not poisoned");
+        buffer.dirty_rects().to_vec()
+    }
+
     pub fn clear_dirty_status(&self) {
         let mut buffer = self.inner.lock().expect("Mutex not poisoned");
         buffer.clear_dirty_status()
     }
 
+    pub fn clear_dirty_rows(&self, rows: &BTreeSet<usize>) {
+        let mut buffer = self.inner.lock().expect("Mutex not poisoned");
+        buffer.clear_dirty_rows(rows)
+    }
+
     pub fn all_dirty(&self) {
         let mut buffer = self.inner.lock().expect("Mutex not poisoned");
         buffer.all_dirty()
@@ -102,7 +164,8 @@ pub struct ScreenBuffer {
     height: usize,
     data: Vec<Rgb555>,
     palette: MonocolorPalette,
-    dirty_row_buffer: Vec<bool>,
+    dirty_rects: Vec<Rect>,
+    is_rgb: bool,
 }
 
 impl ScreenBuffer {
@@ -112,7 +175,8 @@ impl ScreenBuffer {
             height,
             data: vec![Rgb555::from_rgb(0x00, 0x00, 0x00); width * height],
             palette: MonocolorPalette::from_on_color(Rgb555::from_rgb(0xff, 0x00, 0x00)),
-            dirty_row_buffer: vec![false; height],
+            dirty_rects: Vec::new(),
+            is_rgb: true,
         }
     }
 
@@ -123,8 +187,20 @@ impl ScreenBuffer {
         }
     }
 
+    /// A buffer for the display described by `config`. On a monocolor display only pixels in the
+    /// palette's on color are lit.
+    pub fn create_for_display(config: &DisplayConfiguration) -> ScreenBufferHandle {
+        let buffer = Self {
+            is_rgb: config.is_rgb,
+            ..Self::new(config.width, config.height)
+        };
+        ScreenBufferHandle {
+            inner: Arc::new(Mutex::new(buffer)),
+        }
+    }
+
     pub fn is_rgb(&self) -> bool {
-        true
+        self.is_rgb
     }
 
     pub fn display_config(&self) -> DisplayConfiguration {
@@ -142,12 +218,12 @@ impl ScreenBuffer {
     }
 
     pub fn set_cell(&mut self, row: usize, col: usize, value: bool) -> io::Result<()> {
-        if row < self.height || col < self.width {
+        if row < self.height && col < self.width {
             let cell_idx = row * self.width + col;
             let old_color = self.data[cell_idx];
             let new_color = self.palette.get_color(value);
             if old_color != new_color {
-                self.dirty_row_buffer[row] = true;
+                self.mark_dirty(Rect::new(col, row, 1, 1));
                 self.data[cell_idx] = new_color;
             }
             Ok(())
@@ -157,10 +233,10 @@ impl ScreenBuffer {
     }
 
     pub fn set_cell_rgb(&mut self, row: usize, col: usize, value: Rgb555) -> io::Result<()> {
-        if row < self.height || col < self.width {
+        if row < self.height && col < self.width {
             let cell_idx = row * self.width + col;
             if self.data[cell_idx] != value {
-                self.dirty_row_buffer[row] = true;
+                self.mark_dirty(Rect::new(col, row, 1, 1));
                 self.data[cell_idx] = value;
             }
             Ok(())
@@ -176,7 +252,7 @@ impl ScreenBuffer {
                 .into_iter()
                 .map(|color| *color == self.palette.on)
                 .collect();
-            Ok((row_data, self.dirty_row_buffer[row]))
+            Ok((row_data, self.is_row_dirty(row)))
         } else {
             Err(io::ErrorKind::InvalidInput.into())
         }
@@ -189,20 +265,136 @@ impl ScreenBuffer {
                 .into_iter()
                 .copied()
                 .collect();
-            //Ok((row_data, self.dirty_row_buffer[row]))
-            Ok((row_data, true))
+            Ok((row_data, self.is_row_dirty(row)))
+        } else {
+            Err(io::ErrorKind::InvalidInput.into())
+        }
+    }
+
+    pub fn get_region_rgb(&self, region: Rect) -> io::Result<Vec<Rgb555>> {
+        if region.x + region.width <= self.width && region.y + region.height <= self.height {
+            Ok((region.y..(region.y + region.height))
+                .flat_map(|row| {
+                    let start_idx = row * self.width + region.x;
+                    self.data[start_idx..(start_idx + region.width)]
+                        .iter()
+                        .copied()
+                })
+                .collect())
         } else {
             Err(io::ErrorKind::InvalidInput.into())
         }
     }
 
+    pub fn dirty_rects(&self) -> &[Rect] {
+        &self.dirty_rects
+    }
+
+    fn is_row_dirty(&self, row: usize) -> bool {
+        self.dirty_rects.iter().any(|rect| rect.contains_row(row))
+    }
+
+    /// Adds a rectangle to the dirty set, merging it with any rectangles it touches.
+    fn mark_dirty(&mut self, mut rect: Rect) {
+        while let Some(idx) = self
+            .dirty_rects
+            .iter()
+            .position(|dirty_rect| dirty_rect.touches(&rect))
+        {
+            rect = rect.union(&self.dirty_rects.swap_remove(idx));
+        }
+        self.dirty_rects.push(rect);
+
+        if self.dirty_rects.len() > MAX_DIRTY_RECTS {
+            let bounding_box = self
+                .dirty_rects
+                .drain(..)
+                .reduce(|acc, rect| acc.union(&rect))
+                .unwrap();
+            self.dirty_rects.push(bounding_box);
+        }
+    }
+
     pub fn clear_dirty_status(&mut self) {
-        self.dirty_row_buffer
-            .iter_mut()
-            .for_each(|row| *row = false);
+        self.dirty_rects.clear();
+    }
+
+    /// Marks `rows` as clean, leaving whatever's dirty in the rest of the rows for a later render.
+    /// Dirty rects which span both are cut down to the rows which are still dirty.
+    pub fn clear_dirty_rows(&mut self, rows: &BTreeSet<usize>) {
+        self.dirty_rects = self
+            .dirty_rects
+            .iter()
+            .flat_map(|rect| {
+                let dirty_rows = (rect.y..(rect.y + rect.height))
+                    .filter(|row| !rows.contains(row))
+                    .collect::<Vec<_>>();
+                dirty_rows
+                    .chunk_by(|prev, next| *prev + 1 == *next)
+                    .map(|run| Rect::new(rect.x, run[0], rect.width, run.len()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
     }
 
     pub fn all_dirty(&mut self) {
-        self.dirty_row_buffer.iter_mut().for_each(|row| *row = true);
+        self.dirty_rects = vec![Rect::new(0, 0, self.width, self.height)];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rects_touch_when_they_overlap_or_share_an_edge() {
+        let rect = Rect::new(2, 2, 4, 3);
+        assert!(rect.touches(&Rect::new(4, 3, 4, 4)));
+        assert!(rect.touches(&Rect::new(6, 2, 1, 3)));
+        assert!(rect.touches(&Rect::new(2, 0, 4, 2)));
+        assert!(!rect.touches(&Rect::new(7, 2, 1, 3)));
+        assert!(!rect.touches(&Rect::new(2, 6, 4, 1)));
+
+        assert_eq!(rect.union(&Rect::new(6, 2, 1, 3)), Rect::new(2, 2, 5, 3));
+        assert_eq!(rect.union(&Rect::new(0, 4, 1, 4)), Rect::new(0, 2, 6, 6));
+    }
+
+    #[test]
+    fn touching_dirty_rects_are_merged() {
+        let mut buffer = ScreenBuffer::new(16, 8);
+        buffer.mark_dirty(Rect::new(0, 0, 2, 1));
+        buffer.mark_dirty(Rect::new(8, 4, 2, 2));
+        assert_eq!(buffer.dirty_rects().len(), 2);
+
+        // Touches both, so all three become one
+        buffer.mark_dirty(Rect::new(2, 1, 6, 3));
+        assert_eq!(buffer.dirty_rects(), [Rect::new(0, 0, 10, 6)]);
+    }
+
+    #[test]
+    fn too_many_dirty_rects_collapse_into_their_bounding_box() {
+        let mut buffer = ScreenBuffer::new(64, 32);
+        for idx in 0..MAX_DIRTY_RECTS {
+            buffer.mark_dirty(Rect::new(idx * 4, idx * 2, 1, 1));
+        }
+        assert_eq!(buffer.dirty_rects().len(), MAX_DIRTY_RECTS);
+
+        buffer.mark_dirty(Rect::new(60, 30, 1, 1));
+        assert_eq!(buffer.dirty_rects(), [Rect::new(0, 0, 61, 31)]);
+    }
+
+    #[test]
+    fn clearing_rows_keeps_the_rest_dirty() {
+        let mut buffer = ScreenBuffer::new(16, 8);
+        buffer.mark_dirty(Rect::new(2, 0, 4, 6));
+        buffer.mark_dirty(Rect::new(10, 7, 2, 1));
+
+        buffer.clear_dirty_rows(&BTreeSet::from([0, 2, 3, 7]));
+        assert_eq!(
+            buffer.dirty_rects(),
+            [Rect::new(2, 1, 4, 1), Rect::new(2, 4, 4, 2)]
+        );
+        assert!(!buffer.is_row_dirty(3));
+        assert!(buffer.is_row_dirty(4));
     }
 }
@@ -1,8 +1,17 @@
-use crate::streams::{api_server::ApiServerHandle, coproc_client::Connection};
+use crate::apps::manifest::Permissions;
+use crate::streams::{
+    api_server::ApiServerHandle,
+    coproc_client::{self, Connection, LinkState},
+    inotify::LibraryWatcher,
+};
 use async_channel::{Receiver, Sender, TryRecvError};
-use megabit_runner_msgs::ConsoleMessage;
-use megabit_serial_protocol::SerialMessage;
-use std::{sync::Arc, time::Duration};
+use megabit_runner_msgs::{
+    ConsoleMessage, LinkStats, LinkStatsResponse, RequestAppPermissions, RequestAppUsage,
+    RequestLinkStats, SetAppPermissions, SetBrightness,
+};
+use megabit_serial_protocol::{ButtonEventKind, ReportButtonEvent, SerialMessage};
+use std::{collections::HashSet, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+use tokio::signal::unix::{signal, SignalKind};
 
 #[derive(Clone, Debug)]
 pub enum Event {
@@ -10,9 +19,131 @@ pub enum Event {
     PreviousAppRequest,
     ResumePauseRequest,
     ReloadAppsRequest,
+    SetBrightnessRequest(u8),
+    /// The console asked for the running app's usage, answered with `request_id`
+    AppUsageRequest {
+        request_id: String,
+    },
+    /// The console asked for the permissions of the apps in the library, answered with `request_id`
+    AppPermissionsRequest {
+        request_id: String,
+    },
+    /// The console changed the permissions granted to the app in the bundle named `app_id`
+    SetAppPermissionsRequest {
+        app_id: String,
+        permissions: Permissions,
+    },
+    /// A button event which is bound to being handled by the running app
+    ButtonEvent {
+        button_id: u8,
+        kind: ButtonEventKind,
+    },
+    /// The link to the device was lost or the device rebooted, it's brought back in the background
+    DeviceDisconnected,
+    /// The device is back and in sync, but whatever it was displaying may have been lost
+    DeviceReconnected,
     Shutdown,
 }
 
+/// What the runner does in response to a button event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ButtonAction {
+    NextApp,
+    PreviousApp,
+    ResumePause,
+    DeliverToApp,
+    Ignore,
+}
+
+impl FromStr for ButtonAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "next" => Ok(ButtonAction::NextApp),
+            "prev" => Ok(ButtonAction::PreviousApp),
+            "pause" => Ok(ButtonAction::ResumePause),
+            "app" => Ok(ButtonAction::DeliverToApp),
+            "none" => Ok(ButtonAction::Ignore),
+            _ => Err(format!(
+                "unknown button action '{s}', expected one of next, prev, pause, app or none"
+            )),
+        }
+    }
+}
+
+/// Binds a kind of button event to an action, either for a single button or for all of them.
+///
+/// Parsed from `[<button id>:]<kind>=<action>`, e.g. `long-press=pause` or `1:release=prev`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ButtonBinding {
+    pub button_id: Option<u8>,
+    pub kind: ButtonEventKind,
+    pub action: ButtonAction,
+}
+
+impl ButtonBinding {
+    /// Releasing a button goes to the next app and holding it down pauses rendering, the same as
+    /// the single button on older devices did.
+    pub fn defaults() -> Vec<ButtonBinding> {
+        vec![
+            ButtonBinding {
+                button_id: None,
+                kind: ButtonEventKind::Release,
+                action: ButtonAction::NextApp,
+            },
+            ButtonBinding {
+                button_id: None,
+                kind: ButtonEventKind::LongPress,
+                action: ButtonAction::ResumePause,
+            },
+        ]
+    }
+
+    fn matches(&self, button_id: u8, kind: ButtonEventKind) -> bool {
+        self.kind == kind && self.button_id.is_none_or(|id| id == button_id)
+    }
+}
+
+impl FromStr for ButtonBinding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (event, action) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected '[<button id>:]<kind>=<action>', got '{s}'"))?;
+        let (button_id, kind) = match event.split_once(':') {
+            Some((button_id, kind)) => (
+                Some(
+                    button_id
+                        .parse()
+                        .map_err(|_| format!("invalid button id '{button_id}'"))?,
+                ),
+                kind,
+            ),
+            None => (None, event),
+        };
+        let kind = match kind {
+            "press" => ButtonEventKind::Press,
+            "release" => ButtonEventKind::Release,
+            "long-press" => ButtonEventKind::LongPress,
+            "double-press" => ButtonEventKind::DoublePress,
+            "repeat" => ButtonEventKind::Repeat,
+            _ => {
+                return Err(format!(
+                    "unknown button event '{kind}', expected one of press, release, long-press, double-press or repeat"
+                ))
+            }
+        };
+
+        Ok(ButtonBinding {
+            button_id,
+            kind,
+            action: action.parse()?,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct EventListener {
     pending_event: Option<Event>,
@@ -21,13 +152,24 @@ pub struct EventListener {
 }
 
 impl EventListener {
+    /// Button events are handled by the last of `button_bindings` which matches them, and are
+    /// ignored if none do. Changes to the app library in `library_dir` are reported as requests to
+    /// reload it.
     pub fn new(
         conn: Connection,
         api_server_handle: ApiServerHandle,
+        library_dir: PathBuf,
+        button_bindings: Vec<ButtonBinding>,
         rt_handle: tokio::runtime::Handle,
     ) -> Self {
         let (tx, rx) = async_channel::bounded(10);
-        let handle = rt_handle.spawn(event_listener_task(tx, conn, api_server_handle));
+        let handle = rt_handle.spawn(event_listener_task(
+            tx,
+            conn,
+            api_server_handle,
+            library_dir,
+            button_bindings,
+        ));
         Self {
             pending_event: None,
             event_rx: rx,
@@ -53,6 +195,16 @@ impl EventListener {
         self.pending_event.is_some()
     }
 
+    /// Blocks until there's an event, for when the runner has nothing to do until there is.
+    pub fn wait(&mut self) {
+        if self.pending_event.is_none() {
+            self.pending_event = Some(self.event_rx.recv_blocking().unwrap_or_else(|_| {
+                tracing::error!("Event listener sender dropped");
+                Event::Shutdown
+            }));
+        }
+    }
+
     pub fn next(&mut self) -> Option<Event> {
         self.try_get_next_event();
         self.pending_event.take().map(|event| {
@@ -66,44 +218,198 @@ async fn event_listener_task(
     tx: Sender<Event>,
     conn: Connection,
     api_server_handle: ApiServerHandle,
+    library_dir: PathBuf,
+    button_bindings: Vec<ButtonBinding>,
 ) {
     tokio::join!(
-        api_listener_task(tx.clone(), api_server_handle),
-        button_press_listener_task(tx.clone(), conn),
+        api_listener_task(tx.clone(), api_server_handle, conn.clone()),
+        button_event_listener_task(tx.clone(), conn.clone(), button_bindings),
+        link_state_listener_task(tx.clone(), conn),
+        library_listener_task(tx.clone(), library_dir),
+        shutdown_signal_task(tx.clone()),
     );
 }
 
-async fn button_press_listener_task(tx: Sender<Event>, conn: Connection) {
-    let button_press_matcher =
-        Box::new(|msg: &SerialMessage| matches!(msg, &SerialMessage::ReportButtonPress));
+async fn button_event_listener_task(
+    tx: Sender<Event>,
+    conn: Connection,
+    button_bindings: Vec<ButtonBinding>,
+) {
+    let button_event_matcher = Box::new(|msg: &SerialMessage| {
+        matches!(
+            msg,
+            SerialMessage::ReportButtonPress(_) | SerialMessage::ReportButtonEvent(_)
+        )
+    });
+    // Buttons whose current press has already been reported as a long or double press, the
+    // release that ends those gestures isn't handled as a release of its own
+    let mut gesture_in_progress = HashSet::new();
 
     loop {
-        if conn
-            .wait_for_message(button_press_matcher.clone(), Some(Duration::from_secs(1)))
+        let Some(msg) = conn
+            .wait_for_message(button_event_matcher.clone(), Some(Duration::from_secs(1)))
             .await
-            .is_some()
-        {
-            tracing::info!("Received button press");
-            if let Err(err) = tx.send(Event::NextAppRequest).await {
-                tracing::error!("Failed to send event from event listener task: {err:?}");
-                return;
-            }
-        } else {
+        else {
             if tx.receiver_count() == 0 {
                 tracing::warn!("All event listener receivers have hung up, exiting");
                 return;
             }
+            continue;
+        };
+
+        let (button_id, kind) = match msg {
+            SerialMessage::ReportButtonEvent(ReportButtonEvent { button_id, kind }) => {
+                (button_id, kind)
+            }
+            // Older firmware only reports its one button being released
+            _ => (0, ButtonEventKind::Release),
+        };
+        tracing::info!("Received button event {kind:?} for button {button_id}");
+        match kind {
+            ButtonEventKind::LongPress | ButtonEventKind::DoublePress => {
+                gesture_in_progress.insert(button_id);
+            }
+            ButtonEventKind::Release if gesture_in_progress.remove(&button_id) => continue,
+            _ => {}
+        }
+
+        let action = button_bindings
+            .iter()
+            .rev()
+            .find(|binding| binding.matches(button_id, kind))
+            .map_or(ButtonAction::Ignore, |binding| binding.action);
+        let event = match action {
+            ButtonAction::NextApp => Event::NextAppRequest,
+            ButtonAction::PreviousApp => Event::PreviousAppRequest,
+            ButtonAction::ResumePause => Event::ResumePauseRequest,
+            ButtonAction::DeliverToApp => Event::ButtonEvent { button_id, kind },
+            ButtonAction::Ignore => continue,
+        };
+        if let Err(err) = tx.send(event).await {
+            tracing::error!("Failed to send event from event listener task: {err:?}");
+            return;
+        }
+    }
+}
+
+async fn link_state_listener_task(tx: Sender<Event>, conn: Connection) {
+    let mut link_state = conn.watch_link_state();
+    let mut was_connected = *link_state.borrow_and_update() == LinkState::Connected;
+
+    while link_state.changed().await.is_ok() {
+        // A device which is still being resynced isn't ready to be drawn to yet
+        let is_connected = *link_state.borrow_and_update() == LinkState::Connected;
+        let event = match (was_connected, is_connected) {
+            (true, false) => Event::DeviceDisconnected,
+            (false, true) => Event::DeviceReconnected,
+            _ => continue,
+        };
+        was_connected = is_connected;
+        if let Err(err) = tx.send(event).await {
+            tracing::error!("Failed to send event from link state listener task: {err:?}");
+            return;
+        }
+    }
+}
+
+async fn library_listener_task(tx: Sender<Event>, library_dir: PathBuf) {
+    let mut watcher = match LibraryWatcher::new(&library_dir) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            tracing::error!(
+                "Unable to watch the app library at {}, it won't be reloaded on changes: {err}",
+                library_dir.display()
+            );
+            return;
+        }
+    };
+
+    loop {
+        if let Err(err) = watcher.changed().await {
+            tracing::error!("Stopped watching the app library: {err}");
+            return;
+        }
+        tracing::info!("App library changed, reloading it");
+        if let Err(err) = tx.send(Event::ReloadAppsRequest).await {
+            tracing::error!("Failed to send event from library listener task: {err:?}");
+            return;
         }
     }
 }
 
-async fn api_listener_task(tx: Sender<Event>, api_server_handle: ApiServerHandle) {
+/// Shutting down on a signal gives the runner the chance to save the running app's KV store, a
+/// second signal exits straight away in case the runner is stuck.
+async fn shutdown_signal_task(tx: Sender<Event>) {
+    let signals = signal(SignalKind::interrupt())
+        .and_then(|interrupt| Ok((interrupt, signal(SignalKind::terminate())?)));
+    let (mut interrupt, mut terminate) = match signals {
+        Ok(signals) => signals,
+        Err(err) => {
+            tracing::error!("Unable to listen for shutdown signals: {err}");
+            return;
+        }
+    };
+
+    let mut shutting_down = false;
+    loop {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        if shutting_down {
+            tracing::warn!(
+                "Received another shutdown signal, exiting without waiting on the runner"
+            );
+            std::process::exit(1);
+        }
+        tracing::info!("Received shutdown signal");
+        shutting_down = true;
+        if let Err(err) = tx.send(Event::Shutdown).await {
+            tracing::error!("Failed to send event from shutdown signal task: {err:?}");
+        }
+    }
+}
+
+async fn api_listener_task(
+    tx: Sender<Event>,
+    api_server_handle: ApiServerHandle,
+    conn: Connection,
+) {
     while let Ok(msg) = api_server_handle.next().await {
         let event = match msg {
+            ConsoleMessage::RequestLinkStats(RequestLinkStats { request_id }) => {
+                // Answered right away, the runner doesn't need to be involved
+                let response = ConsoleMessage::LinkStatsResponse(LinkStatsResponse {
+                    request_id,
+                    stats: conn.link_stats().into(),
+                });
+                if let Err(err) = api_server_handle.send(response).await {
+                    tracing::error!("Failed to send link stats to the console: {err:?}");
+                }
+                continue;
+            }
             ConsoleMessage::NextApp => Event::NextAppRequest,
             ConsoleMessage::PauseRendering => Event::ResumePauseRequest,
             ConsoleMessage::PreviousApp => Event::PreviousAppRequest,
+            ConsoleMessage::ReloadApps => Event::ReloadAppsRequest,
             ConsoleMessage::ResumeRendering => Event::ResumePauseRequest,
+            ConsoleMessage::SetBrightness(SetBrightness { brightness }) => {
+                Event::SetBrightnessRequest(brightness)
+            }
+            // Answered by the runner, which is the only one with the app
+            ConsoleMessage::RequestAppUsage(RequestAppUsage { request_id }) => {
+                Event::AppUsageRequest { request_id }
+            }
+            ConsoleMessage::RequestAppPermissions(RequestAppPermissions { request_id }) => {
+                Event::AppPermissionsRequest { request_id }
+            }
+            ConsoleMessage::SetAppPermissions(SetAppPermissions {
+                app_id,
+                permissions,
+            }) => Event::SetAppPermissionsRequest {
+                app_id,
+                permissions: permissions.into(),
+            },
             _ => {
                 continue;
             }
@@ -115,3 +421,21 @@ async fn api_listener_task(tx: Sender<Event>, api_server_handle: ApiServerHandle
         }
     }
 }
+
+impl From<coproc_client::LinkStats> for LinkStats {
+    fn from(stats: coproc_client::LinkStats) -> Self {
+        let millis = |rtt: Option<Duration>| rtt.map(|rtt| rtt.as_secs_f64() * 1000.0);
+        LinkStats {
+            rtt_p50_ms: millis(stats.rtt_p50),
+            rtt_p90_ms: millis(stats.rtt_p90),
+            rtt_p99_ms: millis(stats.rtt_p99),
+            pings_sent: stats.pings_sent,
+            ping_timeouts: stats.ping_timeouts,
+            reconnects: stats.reconnects,
+            tx_bytes_per_sec: stats.tx_bytes_per_sec,
+            rx_bytes_per_sec: stats.rx_bytes_per_sec,
+            tx_frames_per_sec: stats.tx_frames_per_sec,
+            rx_frames_per_sec: stats.rx_frames_per_sec,
+        }
+    }
+}
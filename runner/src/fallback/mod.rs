@@ -0,0 +1,175 @@
+use crate::display::ScreenBufferHandle;
+use font::{GLYPH_ADVANCE, GLYPH_HEIGHT, GLYPH_WIDTH};
+use megabit_utils::rgb555::Rgb555;
+use std::{
+    io,
+    net::{IpAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+mod font;
+
+/// How often the fallback screen is redrawn, text too wide for the display scrolls by a pixel
+/// each time.
+pub const FRAME_PERIOD: Duration = Duration::from_millis(100);
+
+/// The network may come up after the runner does, so its address is looked up again this often.
+const ADDRESS_REFRESH_PERIOD: Duration = Duration::from_secs(10);
+/// Blank columns between the end of scrolling text and its start coming back around.
+const SCROLL_GAP: usize = 8;
+const LINE_SPACING: usize = 2;
+
+const BACKGROUND_COLOR: Rgb555 = Rgb555::from_rgb(0x00, 0x00, 0x00);
+const ADDRESS_COLOR: Rgb555 = Rgb555::from_rgb(0xff, 0xff, 0xff);
+const WARNING_COLOR: Rgb555 = Rgb555::from_rgb(0xff, 0xc0, 0x00);
+const ERROR_COLOR: Rgb555 = Rgb555::from_rgb(0xff, 0x00, 0x00);
+const DETAIL_COLOR: Rgb555 = Rgb555::from_rgb(0x80, 0x80, 0x80);
+
+/// Why there's no app to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FallbackStatus {
+    NoAppsInstalled,
+    /// Every app in the library failed, `app_name` is the last one tried.
+    AppsFailed {
+        app_name: String,
+        error: String,
+    },
+}
+
+/// A status screen built into the runner, shown in place of an app when none can be run so that
+/// the display shows where to reach the runner to install one rather than going dark.
+pub struct FallbackScreen {
+    status: FallbackStatus,
+    address: Option<IpAddr>,
+    address_checked_at: Option<Instant>,
+    frame_count: usize,
+}
+
+impl FallbackScreen {
+    pub fn new(status: FallbackStatus) -> Self {
+        Self {
+            status,
+            address: None,
+            address_checked_at: None,
+            frame_count: 0,
+        }
+    }
+
+    pub fn status(&self) -> &FallbackStatus {
+        &self.status
+    }
+
+    pub fn set_status(&mut self, status: FallbackStatus) {
+        if status != self.status {
+            self.status = status;
+            self.frame_count = 0;
+        }
+    }
+
+    /// Draws the next frame to `screen_buffer`, it's up to the caller to render it to the display.
+    pub fn draw(&mut self, screen_buffer: &ScreenBufferHandle) -> io::Result<()> {
+        if self
+            .address_checked_at
+            .is_none_or(|checked_at| checked_at.elapsed() >= ADDRESS_REFRESH_PERIOD)
+        {
+            self.address = local_address();
+            self.address_checked_at = Some(Instant::now());
+        }
+
+        let config = screen_buffer.display_config();
+        let mut frame = vec![BACKGROUND_COLOR; config.width * config.height];
+        // Lines are dropped from the bottom when the display's too short for all of them
+        let line_pitch = GLYPH_HEIGHT + LINE_SPACING;
+        let lines = self.lines();
+        let visible_lines = ((config.height + LINE_SPACING) / line_pitch).min(lines.len());
+        if visible_lines > 0 {
+            let top = (config.height - (visible_lines * line_pitch - LINE_SPACING)) / 2;
+            for (idx, (text, color)) in lines.iter().take(visible_lines).enumerate() {
+                draw_line(
+                    &mut frame,
+                    config.width,
+                    top + idx * line_pitch,
+                    text,
+                    *color,
+                    self.frame_count,
+                );
+            }
+        }
+
+        for (idx, color) in frame.into_iter().enumerate() {
+            let (row, col) = (idx / config.width, idx % config.width);
+            if config.is_rgb {
+                screen_buffer.set_cell_rgb(row, col, color)?;
+            } else {
+                // A monocolor display only lights pixels in its palette's on color
+                screen_buffer.set_cell(row, col, color != BACKGROUND_COLOR)?;
+            }
+        }
+        self.frame_count = self.frame_count.wrapping_add(1);
+
+        Ok(())
+    }
+
+    fn lines(&self) -> Vec<(String, Rgb555)> {
+        let address = self
+            .address
+            .map_or_else(|| String::from("NO NETWORK"), |address| address.to_string());
+        let mut lines = vec![(address, ADDRESS_COLOR)];
+        match &self.status {
+            FallbackStatus::NoAppsInstalled => {
+                lines.push((String::from("NO APPS"), WARNING_COLOR));
+                lines.push((String::from("INSTALLED"), WARNING_COLOR));
+            }
+            FallbackStatus::AppsFailed { app_name, error } => {
+                lines.push((format!("{app_name} FAILED"), ERROR_COLOR));
+                lines.push((error.clone(), DETAIL_COLOR));
+            }
+        }
+        lines
+    }
+}
+
+/// Text which fits is centered, text which doesn't scrolls with a second copy following it around.
+fn draw_line(
+    frame: &mut [Rgb555],
+    width: usize,
+    top: usize,
+    text: &str,
+    color: Rgb555,
+    frame_count: usize,
+) {
+    let text_width = font::text_width(text);
+    let starts = if text_width <= width {
+        vec![((width - text_width) / 2) as isize]
+    } else {
+        let period = text_width + SCROLL_GAP;
+        let offset = (frame_count % period) as isize;
+        vec![-offset, period as isize - offset]
+    };
+
+    for start in starts {
+        for (idx, c) in text.chars().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                let x = start + (idx * GLYPH_ADVANCE + col) as isize;
+                if x < 0 || x >= width as isize {
+                    continue;
+                }
+                for row in (0..GLYPH_HEIGHT).filter(|row| font::is_lit(c, col, *row)) {
+                    frame[(top + row) * width + x as usize] = color;
+                }
+            }
+        }
+    }
+}
+
+/// The address of the interface the default route goes out of. Connecting a UDP socket only looks
+/// up the route, nothing is sent.
+fn local_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).ok()?;
+    socket.connect(("192.0.2.1", 9)).ok()?;
+    socket
+        .local_addr()
+        .ok()
+        .map(|addr| addr.ip())
+        .filter(|ip| !ip.is_unspecified())
+}
@@ -1,23 +1,69 @@
-use apps::AppManifest;
+use apps::{manifest::Permissions, AppManifest};
 use display::ScreenBufferHandle;
 use events::{Event, EventListener};
-use std::{io, time::Duration};
+use fallback::{FallbackScreen, FallbackStatus};
+use megabit_runner_msgs::{
+    AppFault, AppFaultKind, AppPermissionsResponse, AppUsage, AppUsageResponse, ConsoleMessage,
+    DeviceConnection, InstalledApp,
+};
+use std::{path::PathBuf, str::FromStr, time::Duration};
 use streams::{api_server::ApiServerHandle, coproc_client::SyncConnection};
-use wasm_env::WasmAppRunner;
+use wasm_env::{AppLimits, CallError, CallErrorKind, WasmAppRunner};
 
 pub mod apps;
 pub mod cmd_queue;
 pub mod display;
 pub mod events;
+pub mod fallback;
 pub mod streams;
 pub mod wasm_env;
 
 const DEFAULT_RUN_PERIOD: Duration = Duration::from_secs(1);
 
+/// What the runner does with an app once a call into it fails or runs past its time limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CrashPolicy {
+    /// Loads the app again from its bundle and sets it up from scratch
+    #[default]
+    Restart,
+    /// Moves on to the next app in the library
+    NextApp,
+    /// Unloads the app and shows the fallback screen until another app is picked
+    Stop,
+}
+
+impl FromStr for CrashPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restart" => Ok(CrashPolicy::Restart),
+            "next" => Ok(CrashPolicy::NextApp),
+            "stop" => Ok(CrashPolicy::Stop),
+            _ => Err(format!(
+                "unknown crash policy '{s}', expected one of restart, next or stop"
+            )),
+        }
+    }
+}
+
 pub struct Runner {
     app_library: apps::Library,
     is_running: bool,
-    runner: WasmAppRunner,
+    /// Whether the current app has been set up, it's only set up again when a new app is loaded
+    app_started: bool,
+    /// Apps are held while the device is disconnected rather than failing to render to it
+    device_connected: bool,
+    /// The running app, which is unloaded if it's removed from the library
+    runner: Option<WasmAppRunner>,
+    /// Where the running app's bundle is, which unlike its checksum stays the same when it's rebuilt
+    app_path: Option<PathBuf>,
+    /// Apps which have failed to set up in a row, once they all have there's no app to run
+    setup_failures: usize,
+    /// Shown while there's no app to run
+    fallback: FallbackScreen,
+    limits: AppLimits,
+    crash_policy: CrashPolicy,
     serial_conn: SyncConnection,
     screen_buffer: ScreenBufferHandle,
     event_listener: EventListener,
@@ -25,68 +71,167 @@ pub struct Runner {
 }
 
 impl Runner {
+    /// Each app is run within `limits`, unless its manifest says otherwise, and `crash_policy` is
+    /// applied to apps which fault.
     pub fn new(
         app_library: apps::Library,
+        limits: AppLimits,
+        crash_policy: CrashPolicy,
         serial_conn: SyncConnection,
         screen_buffer: ScreenBufferHandle,
         event_listener: EventListener,
         api_server: ApiServerHandle,
-    ) -> io::Result<Self> {
-        if let Some(app) = app_library.get_first() {
-            let initial_app = Self::load_app(
-                &app,
-                serial_conn.clone(),
-                screen_buffer.clone(),
-                api_server.clone(),
-            )?;
-            Ok(Self {
-                app_library,
-                is_running: true,
-                runner: initial_app,
-                serial_conn,
-                screen_buffer,
-                event_listener,
-                api_server,
-            })
-        } else {
-            todo!("Needs at least one app, write a default app for the future");
+    ) -> Self {
+        let mut runner = Self {
+            app_library,
+            is_running: true,
+            app_started: false,
+            device_connected: true,
+            runner: None,
+            app_path: None,
+            setup_failures: 0,
+            fallback: FallbackScreen::new(FallbackStatus::NoAppsInstalled),
+            limits,
+            crash_policy,
+            serial_conn,
+            screen_buffer,
+            event_listener,
+            api_server,
+        };
+        runner.step_to_app(apps::Library::get_next);
+        runner
+    }
+
+    fn current_app_id(&self) -> Option<&str> {
+        self.runner.as_ref().map(WasmAppRunner::id)
+    }
+
+    /// Whether the running app is this build of the app, with the same permissions.
+    fn is_running(&self, app: &AppManifest) -> bool {
+        self.runner.as_ref().is_some_and(|runner| {
+            runner.id() == app.md5sum && *runner.permissions() == app.permissions
+        })
+    }
+
+    /// Steps through the library from the running app until an app loads, starting from the first
+    /// app if none is running. The fallback screen is shown if none of them load.
+    fn step_to_app(&mut self, step: fn(&apps::Library, &str) -> Option<AppManifest>) {
+        let mut next_app = self
+            .current_app_id()
+            .and_then(|id| step(&self.app_library, id))
+            .or_else(|| self.app_library.get_first());
+        let first_tried = next_app.as_ref().map(|app| app.path.clone());
+        let mut status = FallbackStatus::NoAppsInstalled;
+
+        while let Some(app) = next_app {
+            match self.switch_to_app(&app) {
+                Ok(()) => {
+                    self.is_running = true;
+                    return;
+                }
+                Err(err) => {
+                    tracing::error!("Unable to load app: {err:?}, going to the next");
+                    next_app = step(&self.app_library, &app.md5sum)
+                        .filter(|next_app| Some(&next_app.path) != first_tried.as_ref());
+                    status = FallbackStatus::AppsFailed {
+                        app_name: app.app_name,
+                        error: err.to_string(),
+                    };
+                }
+            }
         }
+
+        self.show_fallback(status);
     }
 
-    fn load_next_app(&mut self) -> io::Result<()> {
-        if let Some(app) = self.app_library.get_next(self.runner.id()) {
-            self.runner = Self::load_app(
-                &app,
-                self.serial_conn.clone(),
-                self.screen_buffer.clone(),
-                self.api_server.clone(),
-            )?;
-            self.is_running = true;
+    fn show_fallback(&mut self, status: FallbackStatus) {
+        match &status {
+            FallbackStatus::NoAppsInstalled => {
+                tracing::warn!("No apps are installed, showing the fallback screen");
+            }
+            FallbackStatus::AppsFailed { .. } => {
+                tracing::error!("Cannot run any apps, showing the fallback screen");
+            }
         }
+        self.unload_app();
+        self.setup_failures = 0;
+        self.fallback.set_status(status);
+    }
+
+    fn switch_to_app(&mut self, manifest: &AppManifest) -> anyhow::Result<()> {
+        // A new build of the same app picks up where the old one left its KV store
+        self.flush_kv_store();
+        self.runner = Some(Self::load_app(
+            manifest,
+            self.limits,
+            self.serial_conn.clone(),
+            self.screen_buffer.clone(),
+            self.api_server.clone(),
+        )?);
+        self.app_path = Some(manifest.path.clone());
+        self.app_started = false;
         Ok(())
     }
 
-    fn load_previous_app(&mut self) -> io::Result<()> {
-        if let Some(app) = self.app_library.get_prev(self.runner.id()) {
-            self.runner = Self::load_app(
-                &app,
-                self.serial_conn.clone(),
-                self.screen_buffer.clone(),
-                self.api_server.clone(),
-            )?;
-            self.is_running = true;
+    /// Rescans the library, the running app is swapped for its new build if it's been changed
+    /// and unloaded if it's been removed.
+    fn reload_apps(&mut self) {
+        if let Err(err) = self.app_library.reload() {
+            tracing::error!(
+                "Unable to reload the app library at {}: {err}",
+                self.app_library.data_dir().display()
+            );
+            return;
+        }
+
+        if let Some(app_path) = self.app_path.clone() {
+            match self.app_library.get_by_path(&app_path) {
+                Some(app) if self.is_running(&app) => {}
+                Some(app) => {
+                    tracing::info!("App {} changed, reloading it", app.app_name);
+                    if let Err(err) = self.switch_to_app(&app) {
+                        tracing::error!("Unable to reload app: {err:?}, unloading it");
+                        self.unload_app();
+                    }
+                }
+                None => {
+                    tracing::info!("App at {} was removed, unloading it", app_path.display());
+                    self.unload_app();
+                }
+            }
+        }
+
+        if self.runner.is_none() {
+            self.step_to_app(apps::Library::get_next);
+        }
+    }
+
+    fn unload_app(&mut self) {
+        self.flush_kv_store();
+        self.runner = None;
+        self.app_path = None;
+        self.app_started = false;
+    }
+
+    fn flush_kv_store(&self) {
+        let Some(runner) = self.runner.as_ref() else {
+            return;
+        };
+        if let Err(err) = runner.flush_kv_store() {
+            tracing::error!("Failed to save KV store of app {}: {err:?}", runner.name());
         }
-        Ok(())
     }
 
     fn load_app(
         manifest: &AppManifest,
+        limits: AppLimits,
         serial_conn: SyncConnection,
         screen_buffer: ScreenBufferHandle,
         api_server: ApiServerHandle,
-    ) -> io::Result<WasmAppRunner> {
+    ) -> anyhow::Result<WasmAppRunner> {
         WasmAppRunner::from_manifest(
             &manifest.app_bin_path.parent().unwrap(),
+            limits,
             serial_conn,
             screen_buffer,
             api_server,
@@ -97,7 +242,7 @@ impl Runner {
                 manifest.app_name,
                 err
             );
-            io::ErrorKind::InvalidData.into()
+            err
         })
         .map(|runner| {
             tracing::info!("Loaded WebAssembly binary for app {}", &manifest.app_name);
@@ -107,37 +252,24 @@ impl Runner {
 
     pub fn run(&mut self) {
         loop {
-            if self.is_running {
-                self.run_app(false);
+            if self.device_connected && self.runner.is_none() {
+                self.run_fallback();
+            } else if self.is_running && self.device_connected {
+                self.run_app(self.app_started);
+            } else {
+                // Nothing changes until there's an event to handle
+                self.event_listener.wait();
             }
             while let Some(event) = self.event_listener.next() {
                 match event {
                     Event::NextAppRequest => {
-                        let current_app = self.runner.id().to_string();
-
-                        while let Err(err) = self.load_next_app() {
-                            if &current_app == self.runner.id() {
-                                // We've fully cycled around...
-                                tracing::error!("Cannot load any apps, exiting");
-                                std::process::exit(1);
-                            }
-                            tracing::error!("Unable to load app: {err:?}, going to the next");
-                        }
+                        self.step_to_app(apps::Library::get_next);
                     }
                     Event::PreviousAppRequest => {
-                        let current_app = self.runner.id().to_string();
-
-                        while let Err(err) = self.load_previous_app() {
-                            if &current_app == self.runner.id() {
-                                // We've fully cycled around...
-                                tracing::error!("Cannot load any apps, exiting");
-                                std::process::exit(1);
-                            }
-                            tracing::error!("Unable to load app: {err:?}, going to the next");
-                        }
+                        self.step_to_app(apps::Library::get_prev);
                     }
                     Event::ReloadAppsRequest => {
-                        tracing::error!("Unsupported");
+                        self.reload_apps();
                     }
                     Event::ResumePauseRequest => {
                         match self.is_running {
@@ -150,8 +282,46 @@ impl Runner {
                         }
                         self.is_running = !self.is_running;
                     }
+                    Event::SetBrightnessRequest(brightness) => {
+                        tracing::info!("Setting display brightness to {brightness}");
+                        if let Err(err) = self.serial_conn.set_brightness(brightness) {
+                            tracing::error!("Failed to set display brightness: {err}");
+                        }
+                    }
+                    Event::AppUsageRequest { request_id } => {
+                        self.send_app_usage(request_id);
+                    }
+                    Event::AppPermissionsRequest { request_id } => {
+                        self.send_app_permissions(request_id);
+                    }
+                    Event::SetAppPermissionsRequest {
+                        app_id,
+                        permissions,
+                    } => {
+                        self.set_app_permissions(&app_id, &permissions);
+                    }
+                    Event::ButtonEvent { button_id, kind } => {
+                        let Some(runner) = self.runner.as_mut() else {
+                            continue;
+                        };
+                        if let Err(err) = runner.handle_button_event(button_id, kind) {
+                            tracing::error!("App failed to handle button event: {err}");
+                            self.handle_app_fault(err);
+                        }
+                    }
+                    Event::DeviceDisconnected => {
+                        tracing::warn!("Device disconnected, holding the app until it's back");
+                        self.set_device_connected(false);
+                    }
+                    Event::DeviceReconnected => {
+                        tracing::info!("Device reconnected, repainting the whole display");
+                        // The device may have rebooted and lost whatever it was displaying
+                        self.screen_buffer.all_dirty();
+                        self.set_device_connected(true);
+                    }
                     Event::Shutdown => {
                         tracing::info!("Received shutdown, stopping runner");
+                        self.flush_kv_store();
                         return;
                     }
                 }
@@ -159,18 +329,52 @@ impl Runner {
         }
     }
 
+    fn set_device_connected(&mut self, connected: bool) {
+        self.device_connected = connected;
+        if let Err(err) = self
+            .api_server
+            .send_blocking(ConsoleMessage::DeviceConnection(DeviceConnection {
+                connected,
+            }))
+        {
+            tracing::error!("Failed to notify the console of the device connection: {err}");
+        }
+    }
+
     fn run_app(&mut self, resume: bool) {
+        let Some(runner) = self.runner.as_mut() else {
+            return;
+        };
         if !resume {
-            tracing::info!("Starting app {} [{}]", self.runner.name(), self.runner.id());
-            self.runner.setup_app().unwrap();
+            tracing::info!("Starting app {} [{}]", runner.name(), runner.id());
+            if let Err(err) = runner.setup_app() {
+                tracing::error!("Failed to set up app {}: {err}", runner.name());
+                let status = FallbackStatus::AppsFailed {
+                    app_name: runner.name().to_string(),
+                    error: err.to_string(),
+                };
+                // Restarting an app which can't be set up would only fail again, so the crash
+                // policy isn't applied and the runner moves on instead
+                self.report_app_fault(&err);
+                self.setup_failures += 1;
+                if self.setup_failures >= self.app_library.len() {
+                    self.show_fallback(status);
+                } else {
+                    self.step_to_app(apps::Library::get_next);
+                }
+                return;
+            }
+            self.setup_failures = 0;
+            self.app_started = true;
         }
 
-        let refresh_period = self.runner.refresh_period().unwrap_or(DEFAULT_RUN_PERIOD);
+        let refresh_period = runner.refresh_period().unwrap_or(DEFAULT_RUN_PERIOD);
         loop {
             let start_time = std::time::Instant::now();
-            tracing::debug!("Running app {} [{}]", self.runner.name(), self.runner.id());
-            if let Err(err) = self.runner.run_app_once() {
-                tracing::error!("Running Wasm app failed: {err}, exiting");
+            tracing::debug!("Running app {} [{}]", runner.name(), runner.id());
+            if let Err(err) = runner.run_app_once() {
+                tracing::error!("Running Wasm app failed: {err}");
+                self.handle_app_fault(err);
                 break;
             }
             tracing::debug!("Finished running app");
@@ -184,4 +388,168 @@ impl Runner {
             }
         }
     }
+
+    fn handle_app_fault(&mut self, err: CallError) {
+        self.report_app_fault(&err);
+        let Some(app_name) = self.runner.as_ref().map(|runner| runner.name().to_string()) else {
+            return;
+        };
+
+        tracing::warn!(
+            "Applying crash policy {:?} to app {app_name}",
+            self.crash_policy
+        );
+        match self.crash_policy {
+            CrashPolicy::Restart => {
+                let app = self
+                    .app_path
+                    .as_ref()
+                    .and_then(|app_path| self.app_library.get_by_path(app_path));
+                match app {
+                    Some(app) => {
+                        if let Err(err) = self.switch_to_app(&app) {
+                            tracing::error!("Unable to restart app: {err:?}, going to the next");
+                            self.step_to_app(apps::Library::get_next);
+                        }
+                    }
+                    None => self.step_to_app(apps::Library::get_next),
+                }
+            }
+            CrashPolicy::NextApp => self.step_to_app(apps::Library::get_next),
+            CrashPolicy::Stop => self.show_fallback(FallbackStatus::AppsFailed {
+                app_name,
+                error: err.to_string(),
+            }),
+        }
+    }
+
+    fn send_app_usage(&self, request_id: String) {
+        let usage = self.runner.as_ref().and_then(|runner| {
+            let usage = runner
+                .usage()
+                .inspect_err(|err| tracing::error!("Unable to get the app's usage: {err}"))
+                .ok()?;
+            let limits = runner.limits();
+            Some(AppUsage {
+                md5sum: runner.id().to_string(),
+                app_name: runner.name().to_string(),
+                memory_pages_max: limits.memory_pages,
+                kv_bytes: usage.kv_bytes as u64,
+                kv_bytes_max: limits.kv_bytes as u64,
+                kv_keys: usage.kv_keys as u64,
+                kv_keys_max: limits.kv_keys as u64,
+                log_lines_this_minute: usage.log_lines_this_minute,
+                log_lines_per_minute_max: limits.log_lines_per_minute,
+                log_lines_dropped: usage.log_lines_dropped,
+            })
+        });
+        if let Err(err) = self
+            .api_server
+            .send_blocking(ConsoleMessage::AppUsageResponse(AppUsageResponse {
+                request_id,
+                usage,
+            }))
+        {
+            tracing::error!("Failed to send the app's usage to the console: {err}");
+        }
+    }
+
+    fn send_app_permissions(&self, request_id: String) {
+        let apps = self
+            .app_library
+            .apps()
+            .into_iter()
+            .filter_map(|app| {
+                Some(InstalledApp {
+                    app_id: app.app_id()?.to_string(),
+                    app_name: app.app_name,
+                    permissions: app.permissions.into(),
+                })
+            })
+            .collect();
+        if let Err(err) = self
+            .api_server
+            .send_blocking(ConsoleMessage::AppPermissionsResponse(
+                AppPermissionsResponse { request_id, apps },
+            ))
+        {
+            tracing::error!("Failed to send app permissions to the console: {err}");
+        }
+    }
+
+    /// Writes the permissions to the app's manifest, and reloads the library so that the app is
+    /// linked again with them if it's running.
+    fn set_app_permissions(&mut self, app_id: &str, permissions: &Permissions) {
+        let Some(app) = self
+            .app_library
+            .apps()
+            .into_iter()
+            .find(|app| app.app_id() == Some(app_id))
+        else {
+            tracing::warn!("Can't set permissions of app {app_id}, it isn't in the library");
+            return;
+        };
+        tracing::info!("Granting app {} permissions: {permissions:?}", app.app_name);
+        if let Err(err) = apps::manifest::write_permissions(&app.path, permissions) {
+            tracing::error!(
+                "Failed to write permissions to manifest at {}: {err}",
+                app.path.display()
+            );
+            return;
+        }
+        self.reload_apps();
+    }
+
+    fn report_app_fault(&self, err: &CallError) {
+        let Some(runner) = self.runner.as_ref() else {
+            return;
+        };
+        let kind = match &err.kind {
+            CallErrorKind::TimeLimitExceeded(limit) => AppFaultKind::TimeLimitExceeded {
+                limit_ms: limit.as_millis() as u64,
+            },
+            CallErrorKind::Failed(err) => AppFaultKind::Error {
+                message: err.to_string(),
+            },
+        };
+        if let Err(err) = self
+            .api_server
+            .send_blocking(ConsoleMessage::AppFault(AppFault {
+                md5sum: runner.id().to_string(),
+                app_name: runner.name().to_string(),
+                function: err.function.to_string(),
+                kind,
+            }))
+        {
+            tracing::error!("Failed to report the app fault to the console: {err}");
+        }
+    }
+
+    /// Shows the fallback screen until there's an event to handle, which may bring an app back.
+    fn run_fallback(&mut self) {
+        let height = self.screen_buffer.display_config().height;
+        let rows = (0..height).map(|row| row as u8).collect::<Vec<_>>();
+        loop {
+            let start_time = std::time::Instant::now();
+            if let Err(err) = self.draw_fallback(rows.clone()) {
+                tracing::error!("Failed to draw the fallback screen: {err}");
+            }
+            if start_time.elapsed() < fallback::FRAME_PERIOD {
+                std::thread::sleep(fallback::FRAME_PERIOD - start_time.elapsed())
+            }
+            if self.event_listener.has_pending_events() {
+                break;
+            }
+        }
+    }
+
+    fn draw_fallback(&mut self, rows: Vec<u8>) -> anyhow::Result<()> {
+        self.fallback.draw(&self.screen_buffer)?;
+        wasm_env::render(
+            &self.screen_buffer,
+            &self.api_server,
+            self.serial_conn.clone(),
+            rows,
+        )
+    }
 }
@@ -1,31 +1,252 @@
-use super::{msg_inbox::InboxHandle, tasks::SerialTaskRequest};
+use super::{
+    link_stats::{LinkStats, LinkStatsRecorder},
+    msg_inbox::InboxHandle,
+    row_encoder::RowEncoder,
+    tasks::SerialTaskRequest,
+    LinkState,
+};
 use async_channel::Sender;
+use futures::{StreamExt, TryStreamExt};
 use megabit_serial_protocol::*;
 use megabit_utils::rgb555::Rgb555;
 use std::{
     io,
+    sync::{
+        atomic::{AtomicU16, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, watch};
+
+/// How long a request waits for the device to respond before giving up on it.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many times a request the device rejected as corrupted is sent again before giving up on
+/// it.
+const MAX_RETRANSMITS: usize = 3;
 
 #[derive(Clone)]
 pub struct Connection {
     pub actor_tx: Sender<SerialTaskRequest>,
     pub inbox_handle: InboxHandle,
+    next_seq: Arc<AtomicU16>,
+    pipeline_window: Arc<AtomicUsize>,
+    /// The most row updates the host was configured to keep in flight, whatever the device reports
+    max_pipeline_window: Arc<AtomicUsize>,
+    row_encoder: Arc<Mutex<RowEncoder>>,
+    device_features: Arc<AtomicU32>,
+    /// The last color the device was told to draw monocolor pixels in, sent again after the device
+    /// reconnects
+    monocolor_palette: Arc<Mutex<Option<Rgb555>>>,
+    /// The last brightness the display was set to, sent again after the device reconnects
+    brightness: Arc<Mutex<Option<u8>>>,
+    link_state: watch::Receiver<LinkState>,
+    link_stats: Arc<Mutex<LinkStatsRecorder>>,
+}
+
+/// Forgets about a pending request when dropped so that a request abandoned partway through,
+/// whether by timing out or by having its future dropped, doesn't leave its entry behind.
+struct PendingRequest<'a> {
+    inbox_handle: &'a InboxHandle,
+    seq: u16,
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        self.inbox_handle.cancel_request(self.seq);
+    }
+}
+
+/// Forgets the contents of the rows an update covers when dropped before the update was settled.
+/// An update whose future is dropped partway through, i.e. because another update in the same
+/// batch failed, may still have been applied by the device, so its rows can't be sent as deltas
+/// against what the encoder remembers of them.
+struct UnsettledRows<'a> {
+    row_encoder: &'a Mutex<RowEncoder>,
+    first_row: u8,
+    row_count: u8,
+}
+
+impl UnsettledRows<'_> {
+    /// Records how the update went with the row encoder, after which the rows are left alone on
+    /// drop.
+    fn settle(mut self, record: impl FnOnce(&mut RowEncoder)) {
+        record(&mut self.row_encoder.lock().unwrap());
+        self.row_count = 0;
+    }
+}
+
+impl Drop for UnsettledRows<'_> {
+    fn drop(&mut self) {
+        let mut row_encoder = self.row_encoder.lock().unwrap();
+        for row_offset in 0..self.row_count {
+            row_encoder.forget(self.first_row.wrapping_add(row_offset));
+        }
+    }
 }
 
 impl Connection {
-    async fn send_message(&self, msg: SerialMessage) -> io::Result<()> {
-        Self::send_message_inner(&self.actor_tx, msg).await
+    pub fn new(
+        actor_tx: Sender<SerialTaskRequest>,
+        inbox_handle: InboxHandle,
+        link_state: watch::Receiver<LinkState>,
+        link_stats: Arc<Mutex<LinkStatsRecorder>>,
+    ) -> Self {
+        Self {
+            actor_tx,
+            inbox_handle,
+            next_seq: Arc::new(AtomicU16::new(UNSOLICITED_SEQ + 1)),
+            pipeline_window: Arc::new(AtomicUsize::new(1)),
+            max_pipeline_window: Arc::new(AtomicUsize::new(usize::MAX)),
+            row_encoder: Arc::new(Mutex::new(RowEncoder::default())),
+            device_features: Arc::new(AtomicU32::new(DeviceFeatures::NONE.0)),
+            monocolor_palette: Arc::new(Mutex::new(None)),
+            brightness: Arc::new(Mutex::new(None)),
+            link_state,
+            link_stats,
+        }
+    }
+
+    /// Round trip times and traffic over the link to the device, as measured by the pings which
+    /// are sent to it in the background.
+    pub fn link_stats(&self) -> LinkStats {
+        self.link_stats.lock().unwrap().stats()
+    }
+
+    /// Drops the link to the device so that it's opened again, for when the device has stopped
+    /// answering.
+    pub async fn reconnect(&self) -> io::Result<()> {
+        self.actor_tx
+            .send(SerialTaskRequest::Reconnect)
+            .await
+            .map_err(|err| {
+                tracing::error!("Failed to send reconnect request to serial task: {err}");
+                io::ErrorKind::NotConnected.into()
+            })
+    }
+
+    pub fn link_state(&self) -> LinkState {
+        *self.link_state.borrow()
+    }
+
+    /// A receiver which is notified each time the link to the device changes state.
+    pub fn watch_link_state(&self) -> watch::Receiver<LinkState> {
+        self.link_state.clone()
+    }
+
+    /// Waits until the link to the device is up and in sync with the host.
+    pub async fn wait_until_connected(&self) -> io::Result<()> {
+        let mut link_state = self.link_state.clone();
+        link_state
+            .wait_for(|state| *state == LinkState::Connected)
+            .await
+            .map(|_| ())
+            .map_err(|_| io::ErrorKind::NotConnected.into())
+    }
+
+    /// Brings a device which has just reconnected back to the state the host left it in. The
+    /// device may have rebooted into different firmware while it was gone, so the handshake is
+    /// done again and the image confirmed, the pipeline window follows its queue depth, the monocolor palette and
+    /// brightness are sent again and the contents of every row are forgotten so that none are
+    /// sent as a delta against data the device no longer has.
+    pub async fn resync(&self) -> io::Result<GetDisplayInfoResponse> {
+        self.row_encoder.lock().unwrap().forget_all();
+        self.handshake_and_confirm().await?;
+        let display_info = self.get_display_info().await?;
+        self.set_pipeline_window(display_info.queue_depth() as usize);
+        let palette = *self.monocolor_palette.lock().unwrap();
+        if let Some(color) = palette {
+            self.set_monocolor_palette(color).await?;
+        }
+        let brightness = *self.brightness.lock().unwrap();
+        if let Some(brightness) = brightness {
+            self.set_brightness(brightness).await?;
+        }
+        Ok(display_info)
+    }
+
+    /// The optional messages the device advertised during the handshake, none are assumed until
+    /// the handshake has happened.
+    pub fn device_features(&self) -> DeviceFeatures {
+        DeviceFeatures(self.device_features.load(Ordering::Relaxed))
+    }
+
+    /// Checks that the device speaks a compatible version of the protocol and records which
+    /// optional messages it supports. Firmware which predates the handshake doesn't answer it, in
+    /// which case the connection carries on using only the baseline message set.
+    pub async fn handshake(&self) -> io::Result<Option<GetDeviceInfoResponse>> {
+        let device_info = match self.get_device_info().await {
+            Ok(device_info) => device_info,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                tracing::warn!(
+                    "Device didn't report its info, falling back to the baseline message set"
+                );
+                self.device_features
+                    .store(DeviceFeatures::NONE.0, Ordering::Relaxed);
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+
+        if !PROTOCOL_VERSION.is_compatible_with(&device_info.protocol_version) {
+            tracing::error!(
+                "Device speaks protocol version {}, which is incompatible with {PROTOCOL_VERSION}",
+                device_info.protocol_version
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "incompatible device protocol version {}",
+                    device_info.protocol_version
+                ),
+            ));
+        }
+
+        self.device_features
+            .store(device_info.features.0, Ordering::Relaxed);
+        Ok(Some(device_info))
+    }
+
+    /// Sets how many row updates may be outstanding at once during a batched transfer, up to the
+    /// maximum the host was configured with. This shouldn't exceed the command queue depth
+    /// reported by the device in its display info.
+    pub fn set_pipeline_window(&self, window: usize) {
+        let window = window.min(self.max_pipeline_window.load(Ordering::Relaxed));
+        self.pipeline_window.store(window.max(1), Ordering::Relaxed);
+    }
+
+    /// Caps the pipeline window, both as it is now and as it's set from the queue depth a device
+    /// reports when it reconnects.
+    pub fn set_max_pipeline_window(&self, max_window: usize) {
+        self.max_pipeline_window
+            .store(max_window.max(1), Ordering::Relaxed);
+        self.set_pipeline_window(self.pipeline_window());
+    }
+
+    pub fn pipeline_window(&self) -> usize {
+        self.pipeline_window.load(Ordering::Relaxed)
+    }
+
+    fn allocate_seq(&self) -> u16 {
+        loop {
+            // The top bit of the sequence number flags a CRC trailer on the wire
+            let seq = self.next_seq.fetch_add(1, Ordering::Relaxed) & !CRC_FLAG;
+            if seq != UNSOLICITED_SEQ {
+                break seq;
+            }
+        }
     }
 
-    pub async fn send_message_inner(
-        actor_tx: &Sender<SerialTaskRequest>,
-        msg: SerialMessage,
-    ) -> io::Result<()> {
+    async fn send_message(&self, seq: u16, msg: SerialMessage) -> io::Result<()> {
         let (tx, rx) = oneshot::channel();
-        actor_tx
-            .send(SerialTaskRequest::SendMessage { msg, response: tx })
+        self.actor_tx
+            .send(SerialTaskRequest::SendMessage {
+                seq,
+                msg,
+                crc: self.device_features().contains(DeviceFeatures::FRAME_CRC32),
+                response: tx,
+            })
             .await
             .map_err(|err| {
                 tracing::error!("Failed to send message to serial task: {err}");
@@ -37,6 +258,44 @@ impl Connection {
         })?
     }
 
+    /// Sends a request to the device and waits for the response carrying its sequence number. A
+    /// request the device rejects as corrupted is retransmitted under the same sequence number,
+    /// one it refuses with an `ErrorResponse` fails with that response as the error.
+    async fn request(&self, msg: SerialMessage) -> io::Result<SerialMessage> {
+        let seq = self.allocate_seq();
+        let request_kind = msg.as_ref().to_owned();
+        let _pending = PendingRequest {
+            inbox_handle: &self.inbox_handle,
+            seq,
+        };
+
+        for _ in 0..=MAX_RETRANSMITS {
+            let response_rx = self.inbox_handle.register_request(seq);
+            self.send_message(seq, msg.clone()).await?;
+
+            match tokio::time::timeout(RESPONSE_TIMEOUT, response_rx).await {
+                Ok(Ok(SerialMessage::Nack(_))) => {
+                    tracing::warn!("Device rejected {request_kind} ({seq}) as corrupted");
+                }
+                Ok(Ok(SerialMessage::ErrorResponse(err))) => {
+                    tracing::warn!("Device refused {request_kind} ({seq}): {:?}", err.code);
+                    return Err(err.into());
+                }
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(_)) => return Err(io::ErrorKind::ConnectionAborted.into()),
+                Err(_) => {
+                    tracing::warn!("Timed out waiting for response to {request_kind} ({seq})");
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("device kept rejecting {request_kind} ({seq}) as corrupted"),
+        ))
+    }
+
     pub async fn wait_for_message(
         &self,
         matcher: Box<dyn Fn(&SerialMessage) -> bool + Send + Sync>,
@@ -55,35 +314,30 @@ impl Connection {
             .check_for_message_since(matcher, start_time)
     }
 
+    pub async fn ping(&self) -> io::Result<()> {
+        match self.request(SerialMessage::Ping(Ping {})).await? {
+            SerialMessage::PingResponse(_) => Ok(()),
+            msg => Err(unexpected_response(msg)),
+        }
+    }
+
     pub async fn set_led_state(&self, new_state: bool) -> io::Result<SetLedStateResponse> {
-        self.send_message(SerialMessage::SetLedState(SetLedState { new_state }))
-            .await?;
-        let msg = self
-            .wait_for_message(
-                Box::new(|msg| matches!(msg, &SerialMessage::SetLedStateResponse(_))),
-                None,
-            )
-            .await;
-        match msg {
-            Some(SerialMessage::SetLedStateResponse(response)) => Ok(response),
-            Some(_) => panic!("Got unexpected message type: {msg:?}"),
-            None => Err(io::ErrorKind::ConnectionAborted.into()),
+        match self
+            .request(SerialMessage::SetLedState(SetLedState { new_state }))
+            .await?
+        {
+            SerialMessage::SetLedStateResponse(response) => Ok(response),
+            msg => Err(unexpected_response(msg)),
         }
     }
 
     pub async fn set_rgb_state(&self, (r, g, b): (u8, u8, u8)) -> io::Result<SetRgbStateResponse> {
-        self.send_message(SerialMessage::SetRgbState(SetRgbState { r, g, b }))
-            .await?;
-        let msg = self
-            .wait_for_message(
-                Box::new(|msg| matches!(msg, &SerialMessage::SetRgbStateResponse(_))),
-                None,
-            )
-            .await;
-        match msg {
-            Some(SerialMessage::SetRgbStateResponse(response)) => Ok(response),
-            Some(_) => panic!("Got unexpected message type: {msg:?}"),
-            None => Err(io::ErrorKind::ConnectionAborted.into()),
+        match self
+            .request(SerialMessage::SetRgbState(SetRgbState { r, g, b }))
+            .await?
+        {
+            SerialMessage::SetRgbStateResponse(response) => Ok(response),
+            msg => Err(unexpected_response(msg)),
         }
     }
 
@@ -93,78 +347,149 @@ impl Connection {
         row_data: Vec<bool>,
     ) -> io::Result<UpdateRowResponse> {
         let data = pack_bools_to_bytes(&row_data[..]);
-        self.send_message(SerialMessage::UpdateRow(UpdateRow {
-            row_number,
-            row_data_len: row_data.len() as u8,
-            row_data: data,
-        }))
-        .await?;
-        let msg = self
-            .wait_for_message(
-                Box::new(|msg| matches!(msg, &SerialMessage::UpdateRowResponse(_))),
-                None,
-            )
-            .await;
-        match msg {
-            Some(SerialMessage::UpdateRowResponse(response)) => Ok(response),
-            Some(_) => panic!("Got unexpected message type: {msg:?}"),
-            None => Err(io::ErrorKind::ConnectionAborted.into()),
+        self.row_encoder.lock().unwrap().forget(row_number);
+        match self
+            .request(SerialMessage::UpdateRow(UpdateRow {
+                row_number,
+                row_data_len: row_data.len() as u8,
+                row_data: data,
+            }))
+            .await?
+        {
+            SerialMessage::UpdateRowResponse(response) => Ok(response),
+            msg => Err(unexpected_response(msg)),
         }
     }
 
+    /// Sends an RGB row in whichever of the raw, run-length or delta encodings is smallest.
     pub async fn update_row_rgb(
         &self,
         row_number: u8,
         row_data: Vec<u16>,
     ) -> io::Result<UpdateRowRgbResponse> {
-        self.send_message(SerialMessage::UpdateRowRgb(UpdateRowRgb {
-            row_number,
-            row_data_len: row_data.len() as u8,
-            row_data,
-        }))
-        .await?;
-        let msg = self
-            .wait_for_message(
-                Box::new(|msg| matches!(msg, &SerialMessage::UpdateRowRgbResponse(_))),
-                None,
-            )
-            .await;
-        match msg {
-            Some(SerialMessage::UpdateRowRgbResponse(response)) => Ok(response),
-            Some(_) => panic!("Got unexpected message type: {msg:?}"),
-            None => Err(io::ErrorKind::ConnectionAborted.into()),
-        }
+        let msg =
+            self.row_encoder
+                .lock()
+                .unwrap()
+                .encode(self.device_features(), row_number, &row_data);
+        let unsettled_rows = UnsettledRows {
+            row_encoder: &self.row_encoder,
+            first_row: row_number,
+            row_count: 1,
+        };
+        let status = match self.request(msg).await {
+            Ok(SerialMessage::UpdateRowRgbResponse(response)) => Ok(response.status),
+            Ok(SerialMessage::UpdateRowRgbRleResponse(response)) => Ok(response.status),
+            Ok(SerialMessage::UpdateRowRgbDeltaResponse(response)) => Ok(response.status),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(err) => Err(err),
+        };
+
+        unsettled_rows.settle(|row_encoder| match status {
+            Ok(Status::Success) => row_encoder.acknowledge(row_number, row_data),
+            _ => row_encoder.forget(row_number),
+        });
+        status.map(|status| UpdateRowRgbResponse { status })
+    }
+
+    /// Sends a batch of row updates while keeping up to `pipeline_window` of them in flight, so
+    /// that the transfer isn't bound by a round trip per row. Responses come back in the same
+    /// order as the rows. The first update to fail fails the batch and abandons the rest of the
+    /// updates in flight.
+    pub async fn update_rows(
+        &self,
+        rows: Vec<(u8, Vec<bool>)>,
+    ) -> io::Result<Vec<UpdateRowResponse>> {
+        futures::stream::iter(rows)
+            .map(|(row_number, row_data)| self.update_row(row_number, row_data))
+            .buffered(self.pipeline_window())
+            .try_collect()
+            .await
+    }
+
+    /// The RGB counterpart to `update_rows`.
+    pub async fn update_rows_rgb(
+        &self,
+        rows: Vec<(u8, Vec<u16>)>,
+    ) -> io::Result<Vec<UpdateRowRgbResponse>> {
+        futures::stream::iter(rows)
+            .map(|(row_number, row_data)| self.update_row_rgb(row_number, row_data))
+            .buffered(self.pipeline_window())
+            .try_collect()
+            .await
+    }
+
+    /// Sends a rectangular block of RGB pixels, given row by row, to the device. Regions which
+    /// don't hold exactly `width` by `height` pixels, or hold more than fit in a single update,
+    /// are refused without being sent.
+    pub async fn update_region_rgb(
+        &self,
+        region: UpdateRegionRgb,
+    ) -> io::Result<UpdateRegionRgbResponse> {
+        region.validate()?;
+        let unsettled_rows = UnsettledRows {
+            row_encoder: &self.row_encoder,
+            first_row: region.y,
+            row_count: region.height,
+        };
+        let response = match self
+            .request(SerialMessage::UpdateRegionRgb(region.clone()))
+            .await
+        {
+            Ok(SerialMessage::UpdateRegionRgbResponse(response)) => Ok(response),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(err) => Err(err),
+        };
+
+        unsettled_rows.settle(|row_encoder| match response {
+            Ok(UpdateRegionRgbResponse {
+                status: Status::Success,
+            }) => row_encoder.apply_region(&region),
+            _ => (0..region.height)
+                .for_each(|row_offset| row_encoder.forget(region.y.wrapping_add(row_offset))),
+        });
+        response
+    }
+
+    /// The region counterpart to `update_rows_rgb`.
+    pub async fn update_regions_rgb(
+        &self,
+        regions: Vec<UpdateRegionRgb>,
+    ) -> io::Result<Vec<UpdateRegionRgbResponse>> {
+        futures::stream::iter(regions)
+            .map(|region| self.update_region_rgb(region))
+            .buffered(self.pipeline_window())
+            .try_collect()
+            .await
     }
 
     pub async fn get_display_info(&self) -> io::Result<GetDisplayInfoResponse> {
-        self.send_message(SerialMessage::GetDisplayInfo(GetDisplayInfo))
-            .await?;
-        let msg = self
-            .wait_for_message(
-                Box::new(|msg| matches!(msg, &SerialMessage::GetDisplayInfoResponse(_))),
-                None,
-            )
-            .await;
-        match msg {
-            Some(SerialMessage::GetDisplayInfoResponse(response)) => Ok(response),
-            Some(_) => panic!("Got unexpected message type: {msg:?}"),
-            None => Err(io::ErrorKind::ConnectionAborted.into()),
+        match self
+            .request(SerialMessage::GetDisplayInfo(GetDisplayInfo {}))
+            .await?
+        {
+            SerialMessage::GetDisplayInfoResponse(response) => Ok(response),
+            msg => Err(unexpected_response(msg)),
+        }
+    }
+
+    pub async fn get_device_info(&self) -> io::Result<GetDeviceInfoResponse> {
+        match self
+            .request(SerialMessage::GetDeviceInfo(GetDeviceInfo {}))
+            .await?
+        {
+            SerialMessage::GetDeviceInfoResponse(response) => Ok(response),
+            msg => Err(unexpected_response(msg)),
         }
     }
 
     pub async fn commit_render(&self) -> io::Result<CommitRenderResponse> {
-        self.send_message(SerialMessage::RequestCommitRender(RequestCommitRender {}))
-            .await?;
-        let msg = self
-            .wait_for_message(
-                Box::new(|msg| matches!(msg, &SerialMessage::CommitRenderResponse(_))),
-                None,
-            )
-            .await;
-        match msg {
-            Some(SerialMessage::CommitRenderResponse(response)) => Ok(response),
-            Some(_) => unreachable!(),
-            None => Err(io::ErrorKind::ConnectionAborted.into()),
+        match self
+            .request(SerialMessage::RequestCommitRender(RequestCommitRender {}))
+            .await?
+        {
+            SerialMessage::CommitRenderResponse(response) => Ok(response),
+            msg => Err(unexpected_response(msg)),
         }
     }
 
@@ -174,22 +499,42 @@ impl Connection {
         col: u8,
         value: bool,
     ) -> io::Result<SetSingleCellResponse> {
-        self.send_message(SerialMessage::SetSingleCell(SetSingleCell {
-            row,
-            col,
-            value,
-        }))
-        .await?;
-        let msg = self
-            .wait_for_message(
-                Box::new(|msg| matches!(msg, &SerialMessage::SetSingleCellResponse(_))),
-                None,
-            )
-            .await;
-        match msg {
-            Some(SerialMessage::SetSingleCellResponse(response)) => Ok(response),
-            Some(_) => unreachable!(),
-            None => Err(io::ErrorKind::ConnectionAborted.into()),
+        self.row_encoder.lock().unwrap().forget(row);
+        match self
+            .request(SerialMessage::SetSingleCell(SetSingleCell {
+                row,
+                col,
+                value,
+            }))
+            .await?
+        {
+            SerialMessage::SetSingleCellResponse(response) => Ok(response),
+            msg => Err(unexpected_response(msg)),
+        }
+    }
+
+    /// Dims the display, the device remembers the brightness across reboots.
+    pub async fn set_brightness(&self, brightness: u8) -> io::Result<SetBrightnessResponse> {
+        if !self
+            .device_features()
+            .contains(DeviceFeatures::SET_BRIGHTNESS)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "device doesn't support setting the brightness",
+            ));
+        }
+        match self
+            .request(SerialMessage::SetBrightness(SetBrightness { brightness }))
+            .await?
+        {
+            SerialMessage::SetBrightnessResponse(response) => {
+                if response.status == Status::Success {
+                    *self.brightness.lock().unwrap() = Some(brightness);
+                }
+                Ok(response)
+            }
+            msg => Err(unexpected_response(msg)),
         }
     }
 
@@ -197,22 +542,127 @@ impl Connection {
         &self,
         color: Rgb555,
     ) -> io::Result<SetMonocolorPaletteResponse> {
-        self.send_message(SerialMessage::SetMonocolorPalette(SetMonocolorPalette {
-            color: color.into(),
-        }))
-        .await?;
-        let msg = self
-            .wait_for_message(
-                Box::new(|msg| matches!(msg, &SerialMessage::SetMonocolorPaletteResponse(_))),
-                None,
-            )
-            .await;
-        match msg {
-            Some(SerialMessage::SetMonocolorPaletteResponse(response)) => Ok(response),
-            Some(_) => unreachable!(),
-            None => Err(io::ErrorKind::ConnectionAborted.into()),
+        match self
+            .request(SerialMessage::SetMonocolorPalette(SetMonocolorPalette {
+                color: color.into(),
+            }))
+            .await?
+        {
+            SerialMessage::SetMonocolorPaletteResponse(response) => {
+                if response.status == Status::Success {
+                    *self.monocolor_palette.lock().unwrap() = Some(color);
+                }
+                Ok(response)
+            }
+            msg => Err(unexpected_response(msg)),
+        }
+    }
+
+    /// Starts writing a new firmware image into the partition the device isn't running from.
+    pub async fn begin_update(
+        &self,
+        image_size: u32,
+        image_version: u32,
+        crc32: u32,
+    ) -> io::Result<BeginUpdateResponse> {
+        if !self
+            .device_features()
+            .contains(DeviceFeatures::FIRMWARE_UPDATE)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "device doesn't support firmware updates",
+            ));
+        }
+        match self
+            .request(SerialMessage::BeginUpdate(BeginUpdate {
+                image_size,
+                image_version,
+                crc32,
+            }))
+            .await?
+        {
+            SerialMessage::BeginUpdateResponse(response) => Ok(response),
+            msg => Err(unexpected_response(msg)),
+        }
+    }
+
+    /// Chunks are written one at a time rather than pipelined, since the device has to erase flash
+    /// as the image reaches each new sector.
+    pub async fn write_chunk(&self, offset: u32, data: Vec<u8>) -> io::Result<WriteChunkResponse> {
+        match self
+            .request(SerialMessage::WriteChunk(WriteChunk { offset, data }))
+            .await?
+        {
+            SerialMessage::WriteChunkResponse(response) => Ok(response),
+            msg => Err(unexpected_response(msg)),
+        }
+    }
+
+    /// The device resets into the new image shortly after answering, so the link drops soon
+    /// after this returns.
+    pub async fn finish_update(&self) -> io::Result<FinishUpdateResponse> {
+        match self
+            .request(SerialMessage::FinishUpdate(FinishUpdate {}))
+            .await?
+        {
+            SerialMessage::FinishUpdateResponse(response) => Ok(response),
+            msg => Err(unexpected_response(msg)),
+        }
+    }
+
+    /// Keeps the bootloader from rolling back the image the device is running.
+    /// Waits for the device to connect and then handshakes with it and confirms its image, see
+    /// `handshake_and_confirm`.
+    pub async fn connect(&self) -> io::Result<Option<GetDeviceInfoResponse>> {
+        self.wait_until_connected().await?;
+        self.handshake_and_confirm().await
+    }
+
+    /// Handshakes with the device and then confirms the image it's running. An image is only
+    /// kept once it's shown that it speaks a protocol the runner understands, otherwise the
+    /// bootloader rolls it back the next time the device resets. Failing to confirm it doesn't
+    /// stop the device from being used.
+    pub async fn handshake_and_confirm(&self) -> io::Result<Option<GetDeviceInfoResponse>> {
+        let Some(device_info) = self.handshake().await? else {
+            return Ok(None);
+        };
+        tracing::info!(
+            "Connected to device running image 0x{:08x} from partition {}, protocol version {}",
+            device_info.image_version,
+            device_info.active_partition,
+            device_info.protocol_version
+        );
+        // Devices which can't be updated have nothing to roll back to
+        if device_info
+            .features
+            .contains(DeviceFeatures::FIRMWARE_UPDATE)
+        {
+            match self.confirm_boot().await {
+                Ok(response) if response.status == Status::Success => {}
+                Ok(response) => {
+                    tracing::warn!("Device failed to confirm its image: {:?}", response.status)
+                }
+                Err(err) => tracing::warn!("Unable to confirm the device's image: {err}"),
+            }
         }
+        Ok(Some(device_info))
     }
+
+    pub async fn confirm_boot(&self) -> io::Result<ConfirmBootResponse> {
+        match self
+            .request(SerialMessage::ConfirmBoot(ConfirmBoot {}))
+            .await?
+        {
+            SerialMessage::ConfirmBootResponse(response) => Ok(response),
+            msg => Err(unexpected_response(msg)),
+        }
+    }
+}
+
+fn unexpected_response(msg: SerialMessage) -> io::Error {
+    tracing::error!("Got unexpected response type: {}", msg.as_ref());
+    io::ErrorKind::InvalidData.into()
 }
 
 #[derive(Clone)]
@@ -226,6 +676,14 @@ impl SyncConnection {
         Self { inner: conn, rt }
     }
 
+    pub fn wait_until_connected(&self) -> io::Result<()> {
+        self.rt.block_on(self.inner.wait_until_connected())
+    }
+
+    pub fn link_stats(&self) -> LinkStats {
+        self.inner.link_stats()
+    }
+
     pub fn wait_for_message(
         &self,
         matcher: Box<dyn Fn(&SerialMessage) -> bool + Send + Sync>,
@@ -265,10 +723,59 @@ impl SyncConnection {
             .block_on(self.inner.update_row_rgb(row_number, row_data))
     }
 
+    pub fn update_rows(&self, rows: Vec<(u8, Vec<bool>)>) -> io::Result<Vec<UpdateRowResponse>> {
+        self.rt.block_on(self.inner.update_rows(rows))
+    }
+
+    pub fn update_rows_rgb(
+        &self,
+        rows: Vec<(u8, Vec<u16>)>,
+    ) -> io::Result<Vec<UpdateRowRgbResponse>> {
+        self.rt.block_on(self.inner.update_rows_rgb(rows))
+    }
+
+    pub fn update_region_rgb(
+        &self,
+        region: UpdateRegionRgb,
+    ) -> io::Result<UpdateRegionRgbResponse> {
+        self.rt.block_on(self.inner.update_region_rgb(region))
+    }
+
+    pub fn update_regions_rgb(
+        &self,
+        regions: Vec<UpdateRegionRgb>,
+    ) -> io::Result<Vec<UpdateRegionRgbResponse>> {
+        self.rt.block_on(self.inner.update_regions_rgb(regions))
+    }
+
+    pub fn set_pipeline_window(&self, window: usize) {
+        self.inner.set_pipeline_window(window)
+    }
+
+    pub fn set_max_pipeline_window(&self, max_window: usize) {
+        self.inner.set_max_pipeline_window(max_window)
+    }
+
     pub fn get_display_info(&self) -> io::Result<GetDisplayInfoResponse> {
         self.rt.block_on(self.inner.get_display_info())
     }
 
+    pub fn handshake(&self) -> io::Result<Option<GetDeviceInfoResponse>> {
+        self.rt.block_on(self.inner.handshake())
+    }
+
+    pub fn confirm_boot(&self) -> io::Result<ConfirmBootResponse> {
+        self.rt.block_on(self.inner.confirm_boot())
+    }
+
+    pub fn connect(&self) -> io::Result<Option<GetDeviceInfoResponse>> {
+        self.rt.block_on(self.inner.connect())
+    }
+
+    pub fn device_features(&self) -> DeviceFeatures {
+        self.inner.device_features()
+    }
+
     pub fn commit_render(&self) -> io::Result<CommitRenderResponse> {
         self.rt.block_on(self.inner.commit_render())
     }
@@ -276,4 +783,8 @@ impl SyncConnection {
     pub fn set_monocolor_palette(&self, color: Rgb555) -> io::Result<SetMonocolorPaletteResponse> {
         self.rt.block_on(self.inner.set_monocolor_palette(color))
     }
+
+    pub fn set_brightness(&self, brightness: u8) -> io::Result<SetBrightnessResponse> {
+        self.rt.block_on(self.inner.set_brightness(brightness))
+    }
 }
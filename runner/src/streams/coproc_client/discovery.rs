@@ -0,0 +1,202 @@
+//! Finds the coprocessor among the serial ports attached to the host, so that it doesn't need to
+//! be named by a path which changes whenever the board enumerates again.
+
+use super::{AsyncIo, DeviceTransport, DEFAULT_BAUD_RATE};
+use megabit_serial_protocol::*;
+use std::{io, path::PathBuf, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_serial::{SerialPortInfo, SerialPortType};
+
+/// USB vendor ID set in the firmware's device descriptor.
+const COPROC_VID: u16 = 0x16c0;
+/// USB product ID set in the firmware's device descriptor.
+const COPROC_PID: u16 = 0x27de;
+/// Product string set in the firmware's device descriptor.
+const COPROC_PRODUCT: &str = "Megabit coproc";
+
+/// How long a candidate has to answer the probe before it's passed over.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a port reports about the USB device behind it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UsbInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+/// A port which may have the coprocessor behind it.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub transport: DeviceTransport,
+    pub usb: UsbInfo,
+}
+
+/// Which devices to consider, ports are picked by their USB IDs or by their product string. A
+/// serial number narrows it down to a single board when several are attached.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveryFilter {
+    pub vid: u16,
+    pub pid: u16,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// Baud rate to open the chosen port with
+    pub baud: u32,
+}
+
+impl Default for DiscoveryFilter {
+    fn default() -> Self {
+        Self {
+            vid: COPROC_VID,
+            pid: COPROC_PID,
+            product: Some(String::from(COPROC_PRODUCT)),
+            serial_number: None,
+            baud: DEFAULT_BAUD_RATE,
+        }
+    }
+}
+
+impl DiscoveryFilter {
+    pub fn matches(&self, usb: &UsbInfo) -> bool {
+        let is_coproc = (usb.vid == self.vid && usb.pid == self.pid)
+            || (self.product.is_some() && usb.product == self.product);
+        let is_selected = self.serial_number.is_none() || usb.serial_number == self.serial_number;
+        is_coproc && is_selected
+    }
+}
+
+/// Lists the USB serial ports attached to the host, in order of their paths.
+pub fn serial_candidates(baud: u32) -> io::Result<Vec<Candidate>> {
+    let mut ports = tokio_serial::available_ports().map_err(io::Error::from)?;
+    ports.sort_by(|a, b| a.port_name.cmp(&b.port_name));
+    Ok(ports
+        .into_iter()
+        .filter_map(
+            |SerialPortInfo {
+                 port_name,
+                 port_type,
+             }| match port_type {
+                SerialPortType::UsbPort(usb) => Some(Candidate {
+                    transport: DeviceTransport::Serial {
+                        path: PathBuf::from(port_name),
+                        baud,
+                    },
+                    usb: UsbInfo {
+                        vid: usb.vid,
+                        pid: usb.pid,
+                        product: usb.product,
+                        serial_number: usb.serial_number,
+                    },
+                }),
+                _ => None,
+            },
+        )
+        .collect())
+}
+
+/// Picks the first candidate which passes the filter and answers a probe for its display info.
+pub async fn discover(
+    filter: &DiscoveryFilter,
+    candidates: Vec<Candidate>,
+) -> io::Result<DeviceTransport> {
+    find_device(filter, candidates)
+        .await
+        .map(|(transport, _)| transport)
+}
+
+/// Discovers the device among the serial ports, handing back the link which was used to probe it.
+pub(super) async fn connect(filter: &DiscoveryFilter) -> io::Result<Box<dyn AsyncIo>> {
+    let candidates = serial_candidates(filter.baud)?;
+    find_device(filter, candidates)
+        .await
+        .map(|(_, stream)| stream)
+}
+
+async fn find_device(
+    filter: &DiscoveryFilter,
+    candidates: Vec<Candidate>,
+) -> io::Result<(DeviceTransport, Box<dyn AsyncIo>)> {
+    for Candidate { transport, usb } in candidates {
+        if !filter.matches(&usb) {
+            tracing::trace!("Skipping {transport:?}, it doesn't match {filter:?}");
+            continue;
+        }
+        match tokio::time::timeout(PROBE_TIMEOUT, probe(&transport)).await {
+            Ok(Ok((stream, display_info))) => {
+                tracing::info!(
+                    "Found the device at {transport:?} (serial number {:?}) with display {display_info:?}",
+                    usb.serial_number
+                );
+                return Ok((transport, stream));
+            }
+            Ok(Err(err)) => tracing::debug!("Probing {transport:?} failed: {err}"),
+            Err(_) => tracing::debug!("Timed out probing {transport:?}"),
+        }
+    }
+
+    tracing::warn!("No device found matching {filter:?}");
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no attached device matches the discovery filter",
+    ))
+}
+
+/// Checks that the device behind a port answers like the coprocessor does.
+async fn probe(
+    transport: &DeviceTransport,
+) -> io::Result<(Box<dyn AsyncIo>, GetDisplayInfoResponse)> {
+    let mut stream = super::open(transport).await?;
+    let mut incoming_buffer = Vec::with_capacity(256);
+
+    match request(&mut stream, &mut incoming_buffer, 1, Ping {}.into()).await? {
+        SerialMessage::PingResponse(_) => {}
+        msg => return Err(unexpected_probe_response(msg)),
+    }
+    match request(
+        &mut stream,
+        &mut incoming_buffer,
+        2,
+        GetDisplayInfo {}.into(),
+    )
+    .await?
+    {
+        SerialMessage::GetDisplayInfoResponse(display_info) => Ok((stream, display_info)),
+        msg => Err(unexpected_probe_response(msg)),
+    }
+}
+
+/// Sends a request and waits for its response, anything else the device sends is dropped.
+async fn request(
+    stream: &mut Box<dyn AsyncIo>,
+    incoming_buffer: &mut Vec<u8>,
+    seq: u16,
+    msg: SerialMessage,
+) -> io::Result<SerialMessage> {
+    let mut payload = cobs::encode_vec(&SerialFrame::new(seq, msg).to_bytes());
+    payload.push(0x00);
+    stream.write_all(&payload).await?;
+    stream.flush().await?;
+
+    loop {
+        while let Some(terminator) = incoming_buffer.iter().position(|byte| *byte == 0x00) {
+            let packet = incoming_buffer.drain(..=terminator).collect::<Vec<_>>();
+            let frame = cobs::decode_vec(&packet[..terminator])
+                .ok()
+                .and_then(|decoded| SerialFrame::try_from_bytes(&decoded).ok());
+            if let Some(frame) = frame.filter(|frame| frame.seq == seq) {
+                return Ok(frame.msg);
+            }
+        }
+        if stream.read_buf(incoming_buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+fn unexpected_probe_response(msg: SerialMessage) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response to probe: {}", msg.as_ref()),
+    )
+}
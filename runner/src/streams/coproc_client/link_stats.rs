@@ -0,0 +1,130 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How many of the most recent round trip times the percentiles are taken over.
+const RTT_SAMPLES: usize = 100;
+
+/// How far back the byte and frame rates are averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// A snapshot of how healthy the link to the device is.
+#[derive(Clone, Debug, Default)]
+pub struct LinkStats {
+    pub rtt_p50: Option<Duration>,
+    pub rtt_p90: Option<Duration>,
+    pub rtt_p99: Option<Duration>,
+    pub pings_sent: u64,
+    pub ping_timeouts: u64,
+    /// How many pings in a row have gone unanswered
+    pub consecutive_timeouts: u32,
+    /// How many times the link was dropped because the device stopped answering pings
+    pub reconnects: u64,
+    pub tx_bytes_per_sec: f64,
+    pub rx_bytes_per_sec: f64,
+    pub tx_frames_per_sec: f64,
+    pub rx_frames_per_sec: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct TrafficCounters {
+    tx_bytes: u64,
+    rx_bytes: u64,
+    tx_frames: u64,
+    rx_frames: u64,
+}
+
+/// Collects the measurements behind `LinkStats` as the transport and ping tasks make them.
+#[derive(Debug, Default)]
+pub struct LinkStatsRecorder {
+    rtts: VecDeque<Duration>,
+    pings_sent: u64,
+    ping_timeouts: u64,
+    consecutive_timeouts: u32,
+    reconnects: u64,
+    traffic: TrafficCounters,
+    /// Snapshots of the traffic counters going back as far as `RATE_WINDOW`, oldest first
+    traffic_history: VecDeque<(Instant, TrafficCounters)>,
+}
+
+impl LinkStatsRecorder {
+    pub fn record_tx_frame(&mut self, bytes: usize) {
+        self.traffic.tx_bytes += bytes as u64;
+        self.traffic.tx_frames += 1;
+    }
+
+    pub fn record_rx_bytes(&mut self, bytes: usize) {
+        self.traffic.rx_bytes += bytes as u64;
+    }
+
+    pub fn record_rx_frame(&mut self) {
+        self.traffic.rx_frames += 1;
+    }
+
+    pub fn record_ping(&mut self, rtt: Duration) {
+        self.pings_sent += 1;
+        self.consecutive_timeouts = 0;
+        if self.rtts.len() == RTT_SAMPLES {
+            self.rtts.pop_front();
+        }
+        self.rtts.push_back(rtt);
+        self.sample_traffic(Instant::now());
+    }
+
+    /// Returns how many pings in a row have now gone unanswered.
+    pub fn record_ping_timeout(&mut self) -> u32 {
+        self.pings_sent += 1;
+        self.ping_timeouts += 1;
+        self.consecutive_timeouts += 1;
+        self.sample_traffic(Instant::now());
+        self.consecutive_timeouts
+    }
+
+    pub fn record_reconnect(&mut self) {
+        self.reconnects += 1;
+        self.consecutive_timeouts = 0;
+    }
+
+    pub fn stats(&mut self) -> LinkStats {
+        let now = Instant::now();
+        self.sample_traffic(now);
+
+        let mut rtts = self.rtts.iter().copied().collect::<Vec<_>>();
+        rtts.sort();
+        let percentile =
+            |percent: usize| (!rtts.is_empty()).then(|| rtts[(rtts.len() - 1) * percent / 100]);
+
+        let (oldest_time, oldest) = self.traffic_history[0];
+        let elapsed = (now - oldest_time).as_secs_f64();
+        let rate = |newest: u64, oldest: u64| match elapsed > 0.0 {
+            true => (newest - oldest) as f64 / elapsed,
+            false => 0.0,
+        };
+
+        LinkStats {
+            rtt_p50: percentile(50),
+            rtt_p90: percentile(90),
+            rtt_p99: percentile(99),
+            pings_sent: self.pings_sent,
+            ping_timeouts: self.ping_timeouts,
+            consecutive_timeouts: self.consecutive_timeouts,
+            reconnects: self.reconnects,
+            tx_bytes_per_sec: rate(self.traffic.tx_bytes, oldest.tx_bytes),
+            rx_bytes_per_sec: rate(self.traffic.rx_bytes, oldest.rx_bytes),
+            tx_frames_per_sec: rate(self.traffic.tx_frames, oldest.tx_frames),
+            rx_frames_per_sec: rate(self.traffic.rx_frames, oldest.rx_frames),
+        }
+    }
+
+    fn sample_traffic(&mut self, now: Instant) {
+        while self
+            .traffic_history
+            .front()
+            .is_some_and(|(sample_time, _)| now - *sample_time > RATE_WINDOW)
+        {
+            self.traffic_history.pop_front();
+        }
+        self.traffic_history.push_back((now, self.traffic));
+    }
+}
@@ -1,65 +1,318 @@
-use std::{net::SocketAddr, path::PathBuf, str::FromStr};
+use megabit_sim_core::{loopback::LoopbackDevice, DisplayConfiguration};
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    str::FromStr,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_serial::{SerialPortBuilderExt, SerialStream};
 
 mod connection;
+mod discovery;
+mod link_stats;
 mod msg_inbox;
+mod row_encoder;
 mod tasks;
 
 pub use connection::{Connection, SyncConnection};
+pub use discovery::{discover, serial_candidates, Candidate, DiscoveryFilter, UsbInfo};
+pub use link_stats::LinkStats;
 pub use tasks::start_transport_task;
 
 trait AsyncIo: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
 
 impl AsyncIo for SerialStream {}
 impl AsyncIo for tokio::net::TcpStream {}
+impl AsyncIo for tokio::net::UnixStream {}
+impl AsyncIo for tokio::io::DuplexStream {}
 
+/// Baud rate used for serial ports which don't specify one.
+const DEFAULT_BAUD_RATE: u32 = 230400;
+
+/// The state of the link to the device, which the transport task keeps reopening whenever it's
+/// lost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    Disconnected,
+    /// The device has come back after being lost and is being brought back in sync with the host
+    Resyncing,
+    Connected,
+}
+
+/// How to reach the device, parsed from a URI:
+///
+/// - `serial:///dev/ttyACM0?baud=230400` for a serial port or the slave end of a PTY pair
+/// - `tcp://localhost:9009` for the simulator
+/// - `unix:///run/megabit.sock` for a Unix domain socket
+/// - `loopback://?monocolor=false` for a simulated device running inside this process
+/// - `discover://?serial=0123456789ABCDEF` to find the coprocessor among the attached serial
+///   ports, optionally narrowed down with `vid`, `pid`, `product` or `serial` and opened at `baud`
+///
+/// A bare socket address is taken as TCP and anything else as the path to a serial port.
 #[derive(Debug, Clone)]
 pub enum DeviceTransport {
-    Serial(PathBuf),
+    Serial { path: PathBuf, baud: u32 },
     Tcp(SocketAddr),
+    Unix(PathBuf),
+    Loopback(LoopbackDevice),
+    Discover(DiscoveryFilter),
 }
 
-impl From<String> for DeviceTransport {
-    fn from(value: String) -> Self {
-        if let Ok(addr) = SocketAddr::from_str(&value) {
-            Self::Tcp(addr)
-        } else {
-            if let Ok(path) = PathBuf::from_str(&value) {
-                Self::Serial(path)
+impl FromStr for DeviceTransport {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some((scheme, rest)) = value.split_once("://") else {
+            return Ok(if let Ok(addr) = SocketAddr::from_str(value) {
+                Self::Tcp(addr)
+            } else if !value.is_empty() {
+                Self::Serial {
+                    path: PathBuf::from(value),
+                    baud: DEFAULT_BAUD_RATE,
+                }
+            } else {
+                return Err(String::from("Device transport can't be empty"));
+            });
+        };
+        let (target, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let params = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| param.split_once('=').unwrap_or((param, "")))
+            .collect::<Vec<_>>();
+        let require_target = || {
+            if target.is_empty() {
+                Err(format!(
+                    "Device transport {value} is missing a {scheme} target"
+                ))
             } else {
-                panic!("Failed to parse DeviceTransport from {value}");
+                Ok(target)
+            }
+        };
+        let unknown_param = |key: &str| {
+            Err(format!(
+                "Unknown parameter {key} for {scheme} device transport"
+            ))
+        };
+
+        match scheme {
+            "serial" => {
+                let mut baud = DEFAULT_BAUD_RATE;
+                for (key, param) in params {
+                    match key {
+                        "baud" => {
+                            baud = param
+                                .parse()
+                                .map_err(|_| format!("Invalid baud rate: {param}"))?
+                        }
+                        _ => return unknown_param(key),
+                    }
+                }
+                Ok(Self::Serial {
+                    path: PathBuf::from(require_target()?),
+                    baud,
+                })
+            }
+            "tcp" => {
+                if let Some((key, _)) = params.first() {
+                    return unknown_param(key);
+                }
+                require_target()?
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addrs| addrs.next())
+                    .map(Self::Tcp)
+                    .ok_or_else(|| format!("Unable to resolve tcp address: {target}"))
             }
+            "unix" => {
+                if let Some((key, _)) = params.first() {
+                    return unknown_param(key);
+                }
+                Ok(Self::Unix(PathBuf::from(require_target()?)))
+            }
+            "loopback" => {
+                if !target.is_empty() {
+                    return Err(format!(
+                        "Loopback device transport takes no target: {target}"
+                    ));
+                }
+                let mut display_cfg = DisplayConfiguration::RGB;
+                for (key, param) in params {
+                    match key {
+                        "monocolor" => {
+                            let is_monocolor = param
+                                .parse()
+                                .map_err(|_| format!("Invalid value for monocolor: {param}"))?;
+                            display_cfg = if is_monocolor {
+                                DisplayConfiguration::MONOCOLOR
+                            } else {
+                                DisplayConfiguration::RGB
+                            };
+                        }
+                        _ => return unknown_param(key),
+                    }
+                }
+                Ok(Self::Loopback(LoopbackDevice::new(display_cfg)))
+            }
+            "discover" => {
+                if !target.is_empty() {
+                    return Err(format!("Discovery takes no target: {target}"));
+                }
+                let mut filter = DiscoveryFilter::default();
+                let parse_id = |param: &str| {
+                    u16::from_str_radix(param, 16).map_err(|_| format!("Invalid USB ID: {param}"))
+                };
+                for (key, param) in params {
+                    match key {
+                        "vid" => filter.vid = parse_id(param)?,
+                        "pid" => filter.pid = parse_id(param)?,
+                        "product" => filter.product = Some(String::from(param)),
+                        "serial" => filter.serial_number = Some(String::from(param)),
+                        "baud" => {
+                            filter.baud = param
+                                .parse()
+                                .map_err(|_| format!("Invalid baud rate: {param}"))?
+                        }
+                        _ => return unknown_param(key),
+                    }
+                }
+                Ok(Self::Discover(filter))
+            }
+            _ => Err(format!("Unknown device transport scheme: {scheme}")),
         }
     }
 }
 
-async fn connect(info: DeviceTransport) -> Box<dyn AsyncIo> {
+async fn connect(info: &DeviceTransport) -> io::Result<Box<dyn AsyncIo>> {
     match info {
-        DeviceTransport::Serial(device_path) => {
-            match tokio_serial::new(device_path.to_str().unwrap(), 230400).open_native_async() {
-                Ok(serial) => {
-                    tracing::info!("Opened serial port: {}", device_path.display());
-                    Box::new(serial)
-                }
-                Err(err) => {
+        DeviceTransport::Discover(filter) => discovery::connect(filter).await,
+        info => open(info).await,
+    }
+}
+
+/// Opens a link to a device which has been named directly rather than discovered.
+async fn open(info: &DeviceTransport) -> io::Result<Box<dyn AsyncIo>> {
+    match info {
+        DeviceTransport::Serial {
+            path: device_path,
+            baud,
+        } => {
+            let serial = tokio_serial::new(device_path.to_string_lossy(), *baud)
+                .open_native_async()
+                .map_err(|err| {
                     tracing::error!(
                         "Failed to open serial port {}: {err}",
                         device_path.display()
                     );
-                    panic!("");
-                }
-            }
+                    io::Error::from(err)
+                })?;
+            tracing::info!("Opened serial port: {}", device_path.display());
+            Ok(Box::new(serial))
         }
-        DeviceTransport::Tcp(addr) => match tokio::net::TcpStream::connect(addr).await {
-            Ok(stream) => {
-                tracing::info!("Opened tcp connection: {addr}");
-                Box::new(stream)
-            }
-            Err(err) => {
+        DeviceTransport::Tcp(addr) => {
+            let stream = tokio::net::TcpStream::connect(addr).await.map_err(|err| {
                 tracing::error!("Failed to open tcp stream to {addr}: {err}");
-                panic!("");
-            }
-        },
+                err
+            })?;
+            tracing::info!("Opened tcp connection: {addr}");
+            Ok(Box::new(stream))
+        }
+        DeviceTransport::Unix(socket_path) => {
+            let stream = tokio::net::UnixStream::connect(socket_path)
+                .await
+                .map_err(|err| {
+                    tracing::error!(
+                        "Failed to open unix socket {}: {err}",
+                        socket_path.display()
+                    );
+                    err
+                })?;
+            tracing::info!("Opened unix socket: {}", socket_path.display());
+            Ok(Box::new(stream))
+        }
+        DeviceTransport::Loopback(device) => {
+            tracing::info!("Opened loopback connection to a simulated device");
+            Ok(Box::new(device.connect()))
+        }
+        DeviceTransport::Discover(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a discovered device can't itself be discovered",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_transport_uris() {
+        assert!(matches!(
+            "serial:///dev/ttyACM0?baud=115200".parse(),
+            Ok(DeviceTransport::Serial { path, baud: 115200 }) if path == PathBuf::from("/dev/ttyACM0")
+        ));
+        assert!(matches!(
+            "serial:///dev/pts/3".parse(),
+            Ok(DeviceTransport::Serial {
+                baud: DEFAULT_BAUD_RATE,
+                ..
+            })
+        ));
+        assert!(matches!(
+            "tcp://127.0.0.1:9009".parse(),
+            Ok(DeviceTransport::Tcp(addr)) if addr.port() == 9009
+        ));
+        assert!(matches!(
+            "unix:///run/megabit.sock".parse(),
+            Ok(DeviceTransport::Unix(path)) if path == PathBuf::from("/run/megabit.sock")
+        ));
+        assert!(matches!(
+            "loopback://?monocolor=true".parse(),
+            Ok(DeviceTransport::Loopback(_))
+        ));
+        assert!(matches!(
+            "discover://?serial=ABCD&vid=1209&baud=115200".parse(),
+            Ok(DeviceTransport::Discover(DiscoveryFilter {
+                vid: 0x1209,
+                baud: 115200,
+                serial_number: Some(serial),
+                ..
+            })) if serial == "ABCD"
+        ));
+    }
+
+    #[test]
+    fn parses_legacy_transports() {
+        assert!(matches!(
+            "127.0.0.1:9009".parse(),
+            Ok(DeviceTransport::Tcp(_))
+        ));
+        assert!(matches!(
+            "/dev/ttyACM0".parse(),
+            Ok(DeviceTransport::Serial {
+                baud: DEFAULT_BAUD_RATE,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_transports() {
+        for uri in [
+            "",
+            "usb:///dev/ttyACM0",
+            "serial://",
+            "serial:///dev/ttyACM0?baud=fast",
+            "serial:///dev/ttyACM0?parity=even",
+            "tcp://not an address",
+            "unix://",
+            "loopback://somewhere",
+            "loopback://?monocolor=maybe",
+            "discover:///dev/ttyACM0",
+            "discover://?vid=usb",
+        ] {
+            assert!(uri.parse::<DeviceTransport>().is_err(), "{uri} parsed");
+        }
     }
 }
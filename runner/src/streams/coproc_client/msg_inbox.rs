@@ -1,12 +1,16 @@
-use megabit_serial_protocol::SerialMessage;
+use megabit_serial_protocol::{SerialFrame, SerialMessage, UNSOLICITED_SEQ};
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     sync::{Arc, Mutex, Weak},
     time::{Duration, Instant},
 };
-use tokio::sync::watch::{channel, Receiver, Sender};
+use tokio::sync::{
+    oneshot,
+    watch::{channel, Receiver, Sender},
+};
 
 type MessageQueue = VecDeque<(Instant, Box<SerialMessage>, bool)>;
+type PendingRequests = HashMap<u16, oneshot::Sender<SerialMessage>>;
 
 #[derive(Clone, Copy)]
 pub enum HandleNotification {
@@ -15,8 +19,9 @@ pub enum HandleNotification {
 }
 
 pub struct MessageInbox {
-    msg_rx: async_channel::Receiver<SerialMessage>,
+    msg_rx: async_channel::Receiver<SerialFrame>,
     msg_queue: Arc<Mutex<MessageQueue>>,
+    pending_requests: Arc<Mutex<PendingRequests>>,
     notification_tx: Sender<HandleNotification>,
     notification_rx: Receiver<HandleNotification>,
     msg_expiration_duration: Option<Duration>,
@@ -25,18 +30,20 @@ pub struct MessageInbox {
 #[derive(Clone)]
 pub struct InboxHandle {
     msg_queue: Weak<Mutex<MessageQueue>>,
+    pending_requests: Weak<Mutex<PendingRequests>>,
     notification_rx: Receiver<HandleNotification>,
 }
 
 impl MessageInbox {
     pub fn new(
-        msg_rx: async_channel::Receiver<SerialMessage>,
+        msg_rx: async_channel::Receiver<SerialFrame>,
         msg_expiration_age: Option<Duration>,
     ) -> Self {
         let (tx, rx) = channel(HandleNotification::NewMessages(Instant::now()));
         Self {
             msg_rx,
             msg_queue: Arc::new(Mutex::new(VecDeque::new())),
+            pending_requests: Arc::new(Mutex::new(HashMap::new())),
             notification_tx: tx,
             notification_rx: rx,
             msg_expiration_duration: msg_expiration_age,
@@ -46,13 +53,29 @@ impl MessageInbox {
     pub fn get_handle(&self) -> InboxHandle {
         InboxHandle {
             msg_queue: Arc::downgrade(&self.msg_queue),
+            pending_requests: Arc::downgrade(&self.pending_requests),
             notification_rx: self.notification_rx.clone(),
         }
     }
 
     pub async fn run(self) {
-        while let Ok(msg) = self.msg_rx.recv().await {
-            if matches!(msg, SerialMessage::PingResponse) {
+        while let Ok(SerialFrame { seq, msg, .. }) = self.msg_rx.recv().await {
+            if seq != UNSOLICITED_SEQ {
+                // Responses go straight to whoever sent the request, a response nobody is
+                // waiting on anymore (i.e. the request timed out) is stale and gets dropped
+                let pending = self.pending_requests.lock().unwrap().remove(&seq);
+                match pending {
+                    Some(response_tx) => {
+                        tracing::debug!("Inbox got {:?} for request {seq}", msg.as_ref());
+                        let _ = response_tx.send(msg);
+                    }
+                    None => {
+                        tracing::debug!(
+                            "Dropping {:?} for request {seq} with no pending caller",
+                            msg.as_ref()
+                        );
+                    }
+                }
                 continue;
             }
             {
@@ -80,6 +103,8 @@ impl MessageInbox {
             }
         }
 
+        // Dropping the response senders wakes any callers still waiting on a response
+        self.pending_requests.lock().unwrap().clear();
         let _ = self
             .notification_tx
             .send(HandleNotification::ClosedConnection);
@@ -88,6 +113,24 @@ impl MessageInbox {
 }
 
 impl InboxHandle {
+    /// Registers interest in the response to the request with sequence number `seq`. This must
+    /// happen before the request is sent so the response can't race past the registration.
+    pub fn register_request(&self, seq: u16) -> oneshot::Receiver<SerialMessage> {
+        let (tx, rx) = oneshot::channel();
+        if let Some(pending_requests) = self.pending_requests.upgrade() {
+            pending_requests.lock().unwrap().insert(seq, tx);
+        }
+        rx
+    }
+
+    /// Forgets about a request so that a late response to it is dropped rather than being
+    /// delivered to an unrelated caller.
+    pub fn cancel_request(&self, seq: u16) {
+        if let Some(pending_requests) = self.pending_requests.upgrade() {
+            pending_requests.lock().unwrap().remove(&seq);
+        }
+    }
+
     pub async fn wait_for_message(
         &mut self,
         matcher: Box<dyn Fn(&SerialMessage) -> bool + Send + Sync>,
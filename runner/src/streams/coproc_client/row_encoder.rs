@@ -0,0 +1,196 @@
+use megabit_serial_protocol::{
+    rle_encode, DeviceFeatures, SerialMessage, UpdateRegionRgb, UpdateRowRgb, UpdateRowRgbDelta,
+    UpdateRowRgbRle,
+};
+use std::collections::HashMap;
+
+/// Picks the smallest encoding for each RGB row update. Delta updates are XORed by the device
+/// into whatever it last had written to the row, so the encoder remembers the contents of every
+/// row the device has acknowledged and forgets a row as soon as its state on the device is
+/// uncertain. Encodings the device doesn't advertise support for are never picked.
+#[derive(Debug, Default)]
+pub struct RowEncoder {
+    acked_rows: HashMap<u8, Vec<u16>>,
+}
+
+impl RowEncoder {
+    pub fn encode(
+        &self,
+        features: DeviceFeatures,
+        row_number: u8,
+        row_data: &[u16],
+    ) -> SerialMessage {
+        let raw_len = row_data.len() * 2;
+        let runs = rle_encode(row_data);
+        let rle_len = if features.contains(DeviceFeatures::UPDATE_ROW_RGB_RLE) {
+            runs.len() * 3
+        } else {
+            usize::MAX
+        };
+        let delta_runs = self
+            .acked_rows
+            .get(&row_number)
+            .filter(|_| features.contains(DeviceFeatures::UPDATE_ROW_RGB_DELTA))
+            .filter(|prev_row| prev_row.len() == row_data.len())
+            .map(|prev_row| {
+                let deltas = row_data
+                    .iter()
+                    .zip(prev_row)
+                    .map(|(new, prev)| new ^ prev)
+                    .collect::<Vec<_>>();
+                rle_encode(&deltas)
+            })
+            .filter(|delta_runs| delta_runs.len() <= u8::MAX as usize);
+        let delta_len = delta_runs
+            .as_ref()
+            .map_or(usize::MAX, |delta_runs| delta_runs.len() * 3);
+
+        if let Some(delta_runs) = delta_runs.filter(|_| delta_len < raw_len.min(rle_len)) {
+            SerialMessage::UpdateRowRgbDelta(UpdateRowRgbDelta {
+                row_number,
+                runs: delta_runs,
+            })
+        } else if rle_len < raw_len && runs.len() <= u8::MAX as usize {
+            SerialMessage::UpdateRowRgbRle(UpdateRowRgbRle { row_number, runs })
+        } else {
+            SerialMessage::UpdateRowRgb(UpdateRowRgb {
+                row_number,
+                row_data_len: row_data.len() as u8,
+                row_data: row_data.to_vec(),
+            })
+        }
+    }
+
+    pub fn acknowledge(&mut self, row_number: u8, row_data: Vec<u16>) {
+        self.acked_rows.insert(row_number, row_data);
+    }
+
+    /// Patches the remembered rows with a region the device has acknowledged. Rows that weren't
+    /// known beforehand stay unknown since the region only covers part of them.
+    pub fn apply_region(&mut self, region: &UpdateRegionRgb) {
+        let (x, width) = (region.x as usize, region.width as usize);
+        for (row_offset, region_row) in region.data.chunks_exact(width.max(1)).enumerate() {
+            let row_number = region.y.wrapping_add(row_offset as u8);
+            if let Some(acked_row) = self.acked_rows.get_mut(&row_number) {
+                if x + width <= acked_row.len() {
+                    acked_row[x..(x + width)].copy_from_slice(region_row);
+                } else {
+                    self.acked_rows.remove(&row_number);
+                }
+            }
+        }
+    }
+
+    pub fn forget(&mut self, row_number: u8) {
+        self.acked_rows.remove(&row_number);
+    }
+
+    pub fn forget_all(&mut self) {
+        self.acked_rows.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_features() -> DeviceFeatures {
+        DeviceFeatures::UPDATE_ROW_RGB_RLE | DeviceFeatures::UPDATE_ROW_RGB_DELTA
+    }
+
+    /// A row with no two neighbouring pixels alike, which run-length encoding only makes bigger.
+    fn noisy_row() -> Vec<u16> {
+        (0..64).collect()
+    }
+
+    #[test]
+    fn smallest_encoding_is_picked() {
+        let row_encoder = RowEncoder::default();
+        let solid_row = vec![0x7fff; 64];
+        assert!(matches!(
+            row_encoder.encode(all_features(), 0, &solid_row),
+            SerialMessage::UpdateRowRgbRle(UpdateRowRgbRle { row_number: 0, ref runs })
+                if runs.len() == 1
+        ));
+        assert!(matches!(
+            row_encoder.encode(all_features(), 0, &noisy_row()),
+            SerialMessage::UpdateRowRgb(_)
+        ));
+        // Encodings the device doesn't support aren't picked, however small
+        assert!(matches!(
+            row_encoder.encode(DeviceFeatures::NONE, 0, &solid_row),
+            SerialMessage::UpdateRowRgb(_)
+        ));
+    }
+
+    #[test]
+    fn acknowledged_rows_are_sent_as_deltas() {
+        let mut row_encoder = RowEncoder::default();
+        row_encoder.acknowledge(3, noisy_row());
+        let mut row = noisy_row();
+        row[10] = 0x7fff;
+
+        let SerialMessage::UpdateRowRgbDelta(delta) = row_encoder.encode(all_features(), 3, &row)
+        else {
+            panic!("Expected the row to be sent as a delta");
+        };
+        assert_eq!(delta.row_number, 3);
+        let deltas = megabit_serial_protocol::rle_decode(&delta.runs);
+        let applied = noisy_row()
+            .iter()
+            .zip(deltas)
+            .map(|(prev, delta)| prev ^ delta)
+            .collect::<Vec<_>>();
+        assert_eq!(applied, row);
+
+        // Only for devices which support them, and rows that were acknowledged
+        assert!(matches!(
+            row_encoder.encode(DeviceFeatures::UPDATE_ROW_RGB_RLE, 3, &row),
+            SerialMessage::UpdateRowRgb(_)
+        ));
+        assert!(matches!(
+            row_encoder.encode(all_features(), 4, &row),
+            SerialMessage::UpdateRowRgb(_)
+        ));
+    }
+
+    #[test]
+    fn forgotten_rows_are_sent_whole() {
+        let mut row_encoder = RowEncoder::default();
+        let mut row = noisy_row();
+        row[10] = 0x7fff;
+
+        row_encoder.acknowledge(3, noisy_row());
+        row_encoder.forget(3);
+        assert!(matches!(
+            row_encoder.encode(all_features(), 3, &row),
+            SerialMessage::UpdateRowRgb(_)
+        ));
+
+        row_encoder.acknowledge(3, noisy_row());
+        row_encoder.forget_all();
+        assert!(matches!(
+            row_encoder.encode(all_features(), 3, &row),
+            SerialMessage::UpdateRowRgb(_)
+        ));
+    }
+
+    #[test]
+    fn regions_patch_acknowledged_rows() {
+        let mut row_encoder = RowEncoder::default();
+        row_encoder.acknowledge(1, noisy_row());
+        row_encoder.apply_region(&UpdateRegionRgb {
+            x: 8,
+            y: 1,
+            width: 2,
+            height: 2,
+            data: vec![0x001f; 4],
+        });
+
+        let mut row = noisy_row();
+        row[8..10].copy_from_slice(&[0x001f, 0x001f]);
+        assert_eq!(row_encoder.acked_rows[&1], row);
+        // The region only covers part of a row which wasn't known before
+        assert!(!row_encoder.acked_rows.contains_key(&2));
+    }
+}
@@ -1,18 +1,44 @@
-use super::{connection::Connection, msg_inbox::MessageInbox, DeviceTransport};
+use super::{
+    connection::Connection, link_stats::LinkStatsRecorder, msg_inbox::MessageInbox,
+    DeviceTransport, LinkState,
+};
 use async_channel::{Receiver, Sender};
 use megabit_serial_protocol::*;
-use std::{future::Future, io, time::Duration};
+use std::{
+    future::Future,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    sync::oneshot,
+    sync::{oneshot, watch},
 };
 
+/// How long to wait before trying to reopen the link to the device, doubled after each failed
+/// attempt up to `MAX_RECONNECT_BACKOFF`.
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(8);
+
+/// How long to wait before trying again to bring a reconnected device back in sync.
+const RESYNC_RETRY_PERIOD: Duration = Duration::from_secs(1);
+
+const PING_PERIOD: Duration = Duration::from_millis(333);
+
+/// How many pings in a row the device can leave unanswered before the link to it is reopened.
+const MAX_MISSED_PINGS: u32 = 3;
+
 #[derive(Debug)]
 pub enum SerialTaskRequest {
     SendMessage {
+        seq: u16,
         msg: SerialMessage,
+        /// Whether to add a CRC trailer to the frame
+        crc: bool,
         response: oneshot::Sender<io::Result<()>>,
     },
+    /// Drops the link to the device so that it's opened again
+    Reconnect,
 }
 
 pub fn start_transport_task(
@@ -20,78 +46,208 @@ pub fn start_transport_task(
 ) -> (Connection, Box<dyn Future<Output = ()> + Send + Sync>) {
     let (msg_tx, msg_rx) = async_channel::unbounded();
     let (tx, rx) = async_channel::unbounded();
+    let (link_state_tx, link_state_rx) = watch::channel(LinkState::Disconnected);
+    let link_state_tx = Arc::new(link_state_tx);
+    let link_stats = Arc::new(Mutex::new(LinkStatsRecorder::default()));
 
-    let serial_future = transport_task(transport_info, rx, msg_tx);
-    let _ping_task = {
-        let tx = tx.clone();
-        async move {
-            loop {
-                tokio::time::sleep(Duration::from_millis(333)).await;
-                if let Err(err) = Connection::send_message_inner(&tx, SerialMessage::Ping).await {
-                    tracing::error!("Failed to send ping to device: {err}");
-                    break;
-                }
-            }
-        }
-    };
+    let serial_future = transport_task(
+        transport_info,
+        rx,
+        msg_tx,
+        link_state_tx.clone(),
+        link_stats.clone(),
+    );
 
     let message_inbox = MessageInbox::new(msg_rx.clone(), Some(Duration::from_secs(5)));
     let inbox_handle = message_inbox.get_handle();
     let message_inbox_task = message_inbox.run();
 
+    let conn = Connection::new(tx, inbox_handle, link_state_rx, link_stats.clone());
+    let resync_task = resync_task(conn.clone(), link_state_tx);
+    let ping_task = ping_task(conn.clone(), link_stats);
+
     let serial_task = async move {
-        tokio::join!(serial_future, message_inbox_task);
+        tokio::join!(serial_future, message_inbox_task, resync_task, ping_task);
     };
 
-    (
-        Connection {
-            actor_tx: tx,
-            inbox_handle,
-        },
-        Box::new(serial_task),
-    )
+    (conn, Box::new(serial_task))
 }
 
+/// Keeps the link to the device open, reopening it with an increasing backoff whenever it can't
+/// be opened or is lost. Requests made while the link is down fail with `NotConnected` rather
+/// than waiting on a device which may never come back.
 async fn transport_task(
     info: DeviceTransport,
     request_rx: Receiver<SerialTaskRequest>,
-    incoming_msg_tx: Sender<SerialMessage>,
+    incoming_msg_tx: Sender<SerialFrame>,
+    link_state_tx: Arc<watch::Sender<LinkState>>,
+    link_stats: Arc<Mutex<LinkStatsRecorder>>,
 ) {
     tracing::info!("Starting serial task");
-    let transport = super::connect(info).await;
+    let mut backoff = MIN_RECONNECT_BACKOFF;
+    let mut was_connected = false;
 
-    let (transport_rx, transport_tx) = tokio::io::split(transport);
+    loop {
+        let transport = match super::connect(&info).await {
+            Ok(transport) => transport,
+            Err(_) => {
+                tracing::warn!("Trying to connect to the device again in {backoff:?}");
+                if !refuse_requests_for(backoff, &request_rx).await {
+                    return;
+                }
+                backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                continue;
+            }
+        };
+        backoff = MIN_RECONNECT_BACKOFF;
+        // The first connection is set up by whoever started the transport, later ones may be to a
+        // device which has lost its state
+        link_state_tx.send_replace(match was_connected {
+            true => LinkState::Resyncing,
+            false => LinkState::Connected,
+        });
+        was_connected = true;
+
+        let (transport_rx, transport_tx) = tokio::io::split(transport);
+
+        tokio::select! {
+            res = handle_requests(transport_tx, &request_rx, &link_stats) => {
+                if let Err(err) = res {
+                    tracing::error!("Serial task request handling exited with error: {err}");
+                } else {
+                    tracing::info!("Serial task request handling exited");
+                }
+            },
+            res = handle_serial_msgs(transport_rx, &incoming_msg_tx, &link_state_tx, &link_stats) => {
+                if let Err(err) = res {
+                    tracing::error!("Serial task serial message handling exited with error: {err}");
+                } else {
+                    tracing::info!("Serial task serial message handling exited");
+                }
+            },
+        };
+
+        link_state_tx.send_replace(LinkState::Disconnected);
+        if request_rx.is_closed() || incoming_msg_tx.is_closed() {
+            return;
+        }
+        tracing::warn!("Lost the connection to the device, reconnecting");
+    }
+}
 
-    tokio::select! {
-        res = handle_requests(transport_tx, request_rx) => {
-            if let Err(err) = res {
-                tracing::error!("Serial task request handling exited with error: {err}");
-            } else {
-                tracing::info!("Serial task request handling exited");
+/// Answers every request made over the next `duration` with `NotConnected`. Returns false if every
+/// `Connection` has been dropped, in which case there's no one left to reconnect for.
+async fn refuse_requests_for(duration: Duration, request_rx: &Receiver<SerialTaskRequest>) -> bool {
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => return true,
+            request = request_rx.recv() => match request {
+                Ok(SerialTaskRequest::SendMessage { response, .. }) => {
+                    let _ = response.send(Err(io::ErrorKind::NotConnected.into()));
+                }
+                Ok(SerialTaskRequest::Reconnect) => {}
+                Err(_) => return false,
+            },
+        }
+    }
+}
+
+/// Brings the device back in sync each time it reconnects, the link is only reported as
+/// connected again once that's done.
+async fn resync_task(conn: Connection, link_state_tx: Arc<watch::Sender<LinkState>>) {
+    let mut link_state = conn.watch_link_state();
+    while link_state.changed().await.is_ok() {
+        while *link_state.borrow_and_update() == LinkState::Resyncing {
+            match conn.resync().await {
+                Ok(display_info) => {
+                    tracing::info!("Resynced with the device, display info: {display_info:?}");
+                    link_state_tx.send_if_modified(|state| {
+                        let resynced = *state == LinkState::Resyncing;
+                        if resynced {
+                            *state = LinkState::Connected;
+                        }
+                        resynced
+                    });
+                }
+                Err(err) => {
+                    tracing::error!("Failed to resync with the device: {err}");
+                    tokio::time::sleep(RESYNC_RETRY_PERIOD).await;
+                }
             }
-        },
-        res = handle_serial_msgs(transport_rx, incoming_msg_tx) => {
-            if let Err(err) = res {
-                tracing::error!("Serial task serial message handling exited with error: {err}");
-            } else {
-                tracing::info!("Serial task serial message handling exited");
+        }
+    }
+}
+
+/// A device which reboots loses whatever the host had set up on it without the link going down,
+/// so it's brought back in sync the same as a device which reconnected.
+fn handle_device_booted(booted: &DeviceBooted, link_state_tx: &watch::Sender<LinkState>) {
+    tracing::warn!(
+        "Device booted into image 0x{:08x} after reset: {:?}",
+        booted.image_version,
+        booted.reset_reason
+    );
+    link_state_tx.send_if_modified(|state| {
+        let was_connected = *state == LinkState::Connected;
+        if was_connected {
+            *state = LinkState::Resyncing;
+        }
+        was_connected
+    });
+}
+
+/// Pings the device to measure the link, and reopens the link if the device stops answering.
+async fn ping_task(conn: Connection, link_stats: Arc<Mutex<LinkStatsRecorder>>) {
+    while !conn.actor_tx.is_closed() {
+        tokio::time::sleep(PING_PERIOD).await;
+        if conn.link_state() == LinkState::Disconnected {
+            continue;
+        }
+
+        let start_time = Instant::now();
+        match conn.ping().await {
+            Ok(()) => link_stats.lock().unwrap().record_ping(start_time.elapsed()),
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                let missed_pings = link_stats.lock().unwrap().record_ping_timeout();
+                if missed_pings >= MAX_MISSED_PINGS {
+                    tracing::error!("Device missed {missed_pings} pings in a row, reconnecting");
+                    link_stats.lock().unwrap().record_reconnect();
+                    let _ = conn.reconnect().await;
+                }
             }
-        },
-    };
+            Err(err) => tracing::debug!("Failed to ping device: {err}"),
+        }
+    }
 }
 
 async fn handle_requests(
     mut serial_tx: impl AsyncWrite + Unpin,
-    request_rx: Receiver<SerialTaskRequest>,
+    request_rx: &Receiver<SerialTaskRequest>,
+    link_stats: &Mutex<LinkStatsRecorder>,
 ) -> anyhow::Result<()> {
     while let Ok(msg) = request_rx.recv().await {
         match msg {
-            SerialTaskRequest::SendMessage { msg, response } => {
-                tracing::trace!("Send message: {}", msg.as_ref());
-                let payload = msg.to_bytes();
+            SerialTaskRequest::SendMessage {
+                seq,
+                msg,
+                crc,
+                response,
+            } => {
+                tracing::trace!("Send message: {} ({seq})", msg.as_ref());
+                let payload = SerialFrame::new(seq, msg).with_crc(crc).to_bytes();
                 let mut payload = cobs::encode_vec(&payload[..]);
                 payload.push(0x00);
-                let _ = response.send(serial_tx.write_all(&payload[..]).await);
+                let res = serial_tx.write_all(&payload[..]).await;
+                if res.is_ok() {
+                    link_stats.lock().unwrap().record_tx_frame(payload.len());
+                }
+                let _ = response.send(res);
+            }
+            SerialTaskRequest::Reconnect => {
+                tracing::warn!("Dropping the link to the device to reconnect");
+                return Ok(());
             }
         }
     }
@@ -101,7 +257,9 @@ async fn handle_requests(
 
 async fn handle_serial_msgs(
     mut serial_rx: impl AsyncRead + Unpin,
-    incoming_msg_tx: Sender<SerialMessage>,
+    incoming_msg_tx: &Sender<SerialFrame>,
+    link_state_tx: &watch::Sender<LinkState>,
+    link_stats: &Mutex<LinkStatsRecorder>,
 ) -> anyhow::Result<()> {
     let mut incoming_serial_buffer = Vec::with_capacity(1024);
     loop {
@@ -112,12 +270,21 @@ async fn handle_serial_msgs(
                     decoded_data.len(),
                     incoming_serial_buffer.len()
                 );
-                if let Ok(msg) = SerialMessage::try_from_bytes(&decoded_data[..]) {
-                    tracing::trace!("Decoded a message: {msg:?}");
-                    if let Err(err) = incoming_msg_tx.send(msg).await {
-                        tracing::error!("Failed to forward deserialized device message: {err}");
-                        return Err(err.into());
+                // Frames which fail their CRC check are dropped here, a request whose response
+                // was lost this way times out rather than acting on a corrupted response
+                match SerialFrame::try_from_bytes(&decoded_data[..]) {
+                    Ok(frame) => {
+                        tracing::trace!("Decoded a message: {frame:?}");
+                        link_stats.lock().unwrap().record_rx_frame();
+                        if let SerialMessage::DeviceBooted(booted) = &frame.msg {
+                            handle_device_booted(booted, link_state_tx);
+                        }
+                        if let Err(err) = incoming_msg_tx.send(frame).await {
+                            tracing::error!("Failed to forward deserialized device message: {err}");
+                            return Err(err.into());
+                        }
                     }
+                    Err(err) => tracing::warn!("Dropping frame from the device: {err}"),
                 }
                 let (encoded_len, _) = incoming_serial_buffer
                     .iter()
@@ -129,8 +296,13 @@ async fn handle_serial_msgs(
             }
         } else {
             match serial_rx.read_buf(&mut incoming_serial_buffer).await {
+                Ok(0) => {
+                    tracing::error!("The device closed the connection");
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
                 Ok(n) => {
                     tracing::trace!("Received {n} bytes from the serial port");
+                    link_stats.lock().unwrap().record_rx_bytes(n);
                 }
                 Err(err) => {
                     tracing::error!("Failed to read data from the serial port: {err}");
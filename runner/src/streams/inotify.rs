@@ -1 +1,91 @@
+use crate::apps;
+use futures::StreamExt;
+use inotify::{Event, EventMask, EventStream, Inotify, WatchMask};
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+/// How long the library has to be left alone before a change to it is reported, so that a bundle
+/// being copied in a file at a time is only picked up once it's all there.
+const SETTLE_PERIOD: Duration = Duration::from_millis(500);
+
+const WATCH_MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::CLOSE_WRITE);
+
+/// Watches the app library's data dir, and each bundle directory in it, for bundles being added,
+/// removed or changed.
+pub struct LibraryWatcher {
+    data_dir: PathBuf,
+    events: EventStream<[u8; 4096]>,
+}
+
+impl LibraryWatcher {
+    pub fn new(data_dir: impl AsRef<Path>) -> io::Result<Self> {
+        let events = Inotify::init()?.into_event_stream([0u8; 4096])?;
+        let watcher = Self {
+            data_dir: data_dir.as_ref().to_path_buf(),
+            events,
+        };
+        watcher
+            .events
+            .watches()
+            .add(&watcher.data_dir, WATCH_MASK)?;
+        watcher.watch_bundles();
+        Ok(watcher)
+    }
+
+    /// Waits for something in the library to change and then to settle down.
+    pub async fn changed(&mut self) -> io::Result<()> {
+        loop {
+            let event = self.next_event().await?;
+            if is_relevant(&event) {
+                tracing::debug!("App library changed: {:?} {:?}", event.mask, event.name);
+                break;
+            }
+        }
+        while let Ok(event) = tokio::time::timeout(SETTLE_PERIOD, self.next_event()).await {
+            event?;
+        }
+
+        // Bundles which have just been added need watches of their own
+        self.watch_bundles();
+        Ok(())
+    }
+
+    async fn next_event(&mut self) -> io::Result<Event<OsString>> {
+        self.events
+            .next()
+            .await
+            .unwrap_or_else(|| Err(io::ErrorKind::UnexpectedEof.into()))
+    }
+
+    /// Watching a directory which is already watched leaves its watch as it was, and the watches
+    /// of removed directories go away along with them.
+    fn watch_bundles(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.data_dir) else {
+            return;
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.is_dir() && !apps::is_hidden(&path) {
+                if let Err(err) = self.events.watches().add(&path, WATCH_MASK) {
+                    tracing::warn!("Unable to watch app bundle at {}: {err}", path.display());
+                }
+            }
+        }
+    }
+}
+
+/// Hidden files are left to whoever's using them, such as an editor's swap files.
+fn is_relevant(event: &Event<OsString>) -> bool {
+    !event.mask.contains(EventMask::IGNORED)
+        && !event
+            .name
+            .as_ref()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
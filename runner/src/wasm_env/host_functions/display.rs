@@ -1,9 +1,11 @@
 use megabit_runner_msgs::{ConsoleMessage, SetMatrixRowRgb};
+use megabit_serial_protocol::{DeviceFeatures, UpdateRegionRgb};
 use megabit_utils::rgb555::Rgb555;
+use std::collections::BTreeSet;
 
 use super::super::ScreenBufferHandle;
 use crate::{
-    display::{DisplayConfiguration, MonocolorPalette},
+    display::{DisplayConfiguration, MonocolorPalette, Rect},
     streams::{api_server::ApiServerHandle, coproc_client::SyncConnection},
 };
 
@@ -52,25 +54,75 @@ pub fn render(
     conn: SyncConnection,
     rows: Vec<u8>,
 ) -> Result<(), extism::Error> {
-    for row_number in rows {
-        if screen_buffer.is_rgb() {
-            let (row_data, dirty) = screen_buffer.get_row_rgb(row_number as usize)?;
-            let row_data: Vec<_> = row_data.into_iter().map(u16::from).collect();
-            if dirty {
-                conn.update_row_rgb(row_number, row_data.clone())?;
-                api_server.send_blocking(ConsoleMessage::SetMatrixRowRgb(SetMatrixRowRgb {
-                    row: row_number as usize,
-                    data: row_data,
-                }))?;
+    let flushed_rows = rows
+        .iter()
+        .map(|row| *row as usize)
+        .collect::<BTreeSet<_>>();
+    if screen_buffer.is_rgb() {
+        let width = screen_buffer.display_config().width;
+        let supports_regions = conn
+            .device_features()
+            .contains(DeviceFeatures::UPDATE_REGION_RGB);
+        let mut dirty_rows = Vec::new();
+        let mut dirty_regions = Vec::new();
+        let mut touched_rows = BTreeSet::new();
+        for rect in screen_buffer.dirty_rects() {
+            // Only the requested rows get flushed, whatever else is dirty waits for a later render
+            let rect_rows = rows
+                .iter()
+                .copied()
+                .filter(|row_number| rect.contains_row(*row_number as usize));
+            if (rect.x == 0 && rect.width == width) || !supports_regions {
+                // Without region updates, rects sharing a row would otherwise send it twice
+                for row_number in rect_rows.filter(|row_number| touched_rows.insert(*row_number)) {
+                    let (row_data, _) = screen_buffer.get_row_rgb(row_number as usize)?;
+                    dirty_rows.push((row_number, row_data.into_iter().map(u16::from).collect()));
+                }
+            } else {
+                let rows_per_region = (UpdateRegionRgb::MAX_PIXELS / rect.width).max(1);
+                let rect_rows = rect_rows.collect::<Vec<_>>();
+                touched_rows.extend(rect_rows.iter().copied());
+                for region_rows in rect_rows
+                    .chunk_by(|prev, next| *prev + 1 == *next)
+                    .flat_map(|contiguous_rows| contiguous_rows.chunks(rows_per_region))
+                {
+                    let region = Rect::new(
+                        rect.x,
+                        region_rows[0] as usize,
+                        rect.width,
+                        region_rows.len(),
+                    );
+                    let data = screen_buffer.get_region_rgb(region)?;
+                    dirty_regions.push(UpdateRegionRgb {
+                        x: region.x as u8,
+                        y: region.y as u8,
+                        width: region.width as u8,
+                        height: region.height as u8,
+                        data: data.into_iter().map(u16::from).collect(),
+                    });
+                }
             }
-        } else {
+        }
+        conn.update_rows_rgb(dirty_rows)?;
+        conn.update_regions_rgb(dirty_regions)?;
+        for row_number in touched_rows {
+            let (row_data, _) = screen_buffer.get_row_rgb(row_number as usize)?;
+            api_server.send_blocking(ConsoleMessage::SetMatrixRowRgb(SetMatrixRowRgb {
+                row: row_number as usize,
+                data: row_data.into_iter().map(u16::from).collect(),
+            }))?;
+        }
+    } else {
+        let mut dirty_rows = Vec::with_capacity(rows.len());
+        for row_number in rows {
             let (row_data, dirty) = screen_buffer.get_row(row_number as usize)?;
             if dirty {
-                conn.update_row(row_number, row_data)?;
+                dirty_rows.push((row_number, row_data));
             }
         }
+        conn.update_rows(dirty_rows)?;
     }
-    screen_buffer.clear_dirty_status();
+    screen_buffer.clear_dirty_rows(&flushed_rows);
     conn.commit_render()?;
     api_server.send_blocking(ConsoleMessage::CommitRender)?;
 
@@ -1,10 +1,121 @@
-use crate::wasm_env::KvStore;
+use crate::wasm_env::{
+    limits::{self, AppLimits},
+    KvStore,
+};
+
+/// Returned to the app when its write went through.
+pub const WRITE_OK: u32 = 0;
+/// Returned to the app when its write would have taken its KV store past its limits, the store is
+/// left as it was.
+pub const WRITE_QUOTA_EXCEEDED: u32 = 1;
+
+/// Returned to the app when there was nothing stored under the key it deleted.
+pub const DELETE_NOT_FOUND: u32 = 0;
+pub const DELETE_REMOVED: u32 = 1;
+
+pub fn write(
+    kv_store: &mut KvStore,
+    limits: &AppLimits,
+    key: String,
+    data: Vec<u8>,
+) -> Result<u32, extism::Error> {
+    let replaced_bytes = kv_store.get(&key).map(|value| key.len() + value.len());
+    let keys = kv_store.len() + usize::from(replaced_bytes.is_none());
+    let bytes =
+        limits::kv_store_bytes(kv_store) - replaced_bytes.unwrap_or(0) + key.len() + data.len();
+    if keys > limits.kv_keys || bytes > limits.kv_bytes {
+        tracing::warn!(
+            "Writing key {key} would take the app's KV store to {keys} keys and {bytes} bytes, \
+             past its limits of {} keys and {} bytes",
+            limits.kv_keys,
+            limits.kv_bytes
+        );
+        return Ok(WRITE_QUOTA_EXCEEDED);
+    }
 
-pub fn write(kv_store: &mut KvStore, key: String, data: Vec<u8>) -> Result<(), extism::Error> {
     kv_store.insert(key, data);
-    Ok(())
+    Ok(WRITE_OK)
 }
 
 pub fn read(kv_store: &KvStore, key: String) -> Result<Vec<u8>, extism::Error> {
     Ok(kv_store.get(&key).unwrap_or(&vec![]).clone())
 }
+
+pub fn delete(kv_store: &mut KvStore, key: String) -> Result<u32, extism::Error> {
+    Ok(match kv_store.remove(&key) {
+        Some(_) => DELETE_REMOVED,
+        None => DELETE_NOT_FOUND,
+    })
+}
+
+/// The keys in order, each as its length in bytes as a big endian u32 followed by the key itself.
+pub fn list_keys(kv_store: &KvStore) -> Result<Vec<u8>, extism::Error> {
+    Ok(kv_store
+        .keys()
+        .flat_map(|key| [&(key.len() as u32).to_be_bytes()[..], key.as_bytes()].concat())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(kv_keys: usize, kv_bytes: usize) -> AppLimits {
+        AppLimits {
+            kv_keys,
+            kv_bytes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn writes_past_the_key_limit_are_refused() {
+        let mut kv_store = KvStore::new();
+        let limits = limits(2, 1024);
+        for key in ["a", "b"] {
+            assert_eq!(
+                write(&mut kv_store, &limits, key.into(), vec![0]).unwrap(),
+                WRITE_OK
+            );
+        }
+        assert_eq!(
+            write(&mut kv_store, &limits, "c".into(), vec![0]).unwrap(),
+            WRITE_QUOTA_EXCEEDED
+        );
+        assert!(!kv_store.contains_key("c"));
+
+        // Writing to a key which is already there doesn't add one
+        assert_eq!(
+            write(&mut kv_store, &limits, "a".into(), vec![1]).unwrap(),
+            WRITE_OK
+        );
+    }
+
+    #[test]
+    fn replaced_values_only_count_once_towards_the_byte_limit() {
+        let mut kv_store = KvStore::new();
+        let limits = limits(8, 9);
+        assert_eq!(
+            write(&mut kv_store, &limits, "key".into(), vec![0; 6]).unwrap(),
+            WRITE_OK
+        );
+        assert_eq!(
+            write(&mut kv_store, &limits, "key".into(), vec![1; 6]).unwrap(),
+            WRITE_OK
+        );
+        assert_eq!(
+            write(&mut kv_store, &limits, "key".into(), vec![2; 7]).unwrap(),
+            WRITE_QUOTA_EXCEEDED
+        );
+        assert_eq!(read(&kv_store, "key".into()).unwrap(), vec![1; 6]);
+    }
+
+    #[test]
+    fn listed_keys_are_length_prefixed() {
+        let kv_store = KvStore::from([(String::from("b"), vec![0]), (String::from("aa"), vec![1])]);
+        assert_eq!(
+            list_keys(&kv_store).unwrap(),
+            [&[0, 0, 0, 2][..], b"aa", &[0, 0, 0, 1], b"b"].concat()
+        );
+    }
+}
@@ -1,20 +1,28 @@
 use super::PersistentData;
+use crate::apps::manifest::Permissions;
 use extism::UserData;
 
-mod display;
+pub(crate) mod display;
 mod kv_store;
 
+/// Every app can draw to the display and log, the rest is only linked into apps granted it.
 pub fn with_host_functions<'a>(
     builder: extism::PluginBuilder<'a>,
     user_data: &UserData<PersistentData>,
+    permissions: &Permissions,
 ) -> extism::PluginBuilder<'a> {
-    with_screen_functions(with_kv_functions(builder, user_data), user_data).with_function(
+    let builder = with_screen_functions(builder, user_data).with_function(
         "log",
         [extism::PTR, extism::PTR],
         [extism::PTR],
-        extism::UserData::new(()),
+        user_data.clone(),
         log,
-    )
+    );
+    if permissions.kv {
+        with_kv_functions(builder, user_data)
+    } else {
+        builder
+    }
 }
 
 pub fn with_screen_functions<'a>(
@@ -90,6 +98,27 @@ pub fn with_kv_functions<'a>(
             user_data.clone(),
             kv_store_write,
         )
+        .with_function(
+            "kv_store_delete",
+            [extism::PTR],
+            [extism::PTR],
+            user_data.clone(),
+            kv_store_delete,
+        )
+        .with_function(
+            "kv_store_list_keys",
+            [],
+            [extism::PTR],
+            user_data.clone(),
+            kv_store_list_keys,
+        )
+        .with_function(
+            "kv_store_clear",
+            [],
+            [extism::PTR],
+            user_data.clone(),
+            kv_store_clear,
+        )
 }
 
 extism::host_fn!(pub write_region(user_data: PersistentData; position_x: u32, position_y: u32, width: u32, height: u32, buffer_data: Vec<u8>) {
@@ -136,15 +165,49 @@ extism::host_fn!(pub kv_store_read(user_data: PersistentData; key: String) -> Ve
     kv_store::read(&kv_store, key)
 });
 
-extism::host_fn!(pub kv_store_write(user_data: PersistentData; key: String, value: Vec<u8>) {
+extism::host_fn!(pub kv_store_write(user_data: PersistentData; key: String, value: Vec<u8>) -> u32 {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+    let status = kv_store::write(&mut data.kv_store.borrow_mut(), &data.limits, key, value)?;
+    data.kv_store_dirty |= status == kv_store::WRITE_OK;
+    Ok(status)
+});
+
+extism::host_fn!(pub kv_store_delete(user_data: PersistentData; key: String) -> u32 {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+    let status = kv_store::delete(&mut data.kv_store.borrow_mut(), key)?;
+    data.kv_store_dirty |= status == kv_store::DELETE_REMOVED;
+    Ok(status)
+});
+
+extism::host_fn!(pub kv_store_list_keys(user_data: PersistentData;) -> Vec<u8> {
     let data = user_data.get()?;
     let data = data.lock().unwrap();
+    let kv_store = data.kv_store.borrow();
+    kv_store::list_keys(&kv_store)
+});
+
+extism::host_fn!(pub kv_store_clear(user_data: PersistentData;) {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
     let mut kv_store = data.kv_store.borrow_mut();
-    kv_store::write(&mut kv_store, key, value)
+    let was_empty = kv_store.is_empty();
+    kv_store.clear();
+    drop(kv_store);
+    data.kv_store_dirty |= !was_empty;
+    Ok(())
 });
 
-extism::host_fn!(pub log(level: u32, line: String) {
-    host::log(level, line)
+extism::host_fn!(pub log(user_data: PersistentData; level: u32, line: String) {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+    let limit = data.limits.log_lines_per_minute;
+    if data.log_rate.count_line(limit) {
+        host::log(level, line)
+    } else {
+        Ok(())
+    }
 });
 
 mod host {
@@ -0,0 +1,135 @@
+use super::KvStore;
+use crate::apps::manifest::ManifestLimits;
+use std::time::{Duration, Instant};
+
+/// How long a call into an app can run for when neither the runner nor the app's manifest say.
+pub const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(5);
+/// 16 MiB of linear memory in 64 KiB wasm pages.
+pub const DEFAULT_MEMORY_PAGES: u32 = 256;
+pub const DEFAULT_KV_BYTES: usize = 64 * 1024;
+pub const DEFAULT_KV_KEYS: usize = 256;
+pub const DEFAULT_LOG_LINES_PER_MINUTE: u32 = 600;
+
+const LOG_WINDOW: Duration = Duration::from_secs(60);
+
+/// Limits on what an app can use, set for every app by the runner.
+///
+/// An app's manifest can lower any of them but can't raise them past the runner's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppLimits {
+    /// How long a single call into the app can run for before it's interrupted, it's enforced by
+    /// wasmtime's epoch interruption so an app stuck in a loop without calling out is stopped too
+    pub call_timeout: Duration,
+    /// Pages of linear memory the app can grow to
+    pub memory_pages: u32,
+    /// Bytes of keys and values the app can keep in its KV store
+    pub kv_bytes: usize,
+    pub kv_keys: usize,
+    /// Lines the app can log each minute, the rest of the minute's lines are dropped
+    pub log_lines_per_minute: u32,
+}
+
+impl Default for AppLimits {
+    fn default() -> Self {
+        Self {
+            call_timeout: DEFAULT_CALL_TIMEOUT,
+            memory_pages: DEFAULT_MEMORY_PAGES,
+            kv_bytes: DEFAULT_KV_BYTES,
+            kv_keys: DEFAULT_KV_KEYS,
+            log_lines_per_minute: DEFAULT_LOG_LINES_PER_MINUTE,
+        }
+    }
+}
+
+impl AppLimits {
+    pub fn for_app(&self, manifest_limits: &ManifestLimits) -> Self {
+        fn capped<T: Ord + Copy>(requested: Option<T>, cap: T) -> T {
+            requested.map_or(cap, |requested| requested.min(cap))
+        }
+
+        Self {
+            call_timeout: capped(manifest_limits.call_timeout, self.call_timeout),
+            memory_pages: capped(manifest_limits.memory_pages, self.memory_pages),
+            kv_bytes: capped(manifest_limits.kv_bytes, self.kv_bytes),
+            kv_keys: capped(manifest_limits.kv_keys, self.kv_keys),
+            log_lines_per_minute: capped(
+                manifest_limits.log_lines_per_minute,
+                self.log_lines_per_minute,
+            ),
+        }
+    }
+}
+
+/// What an app is using of what it's allowed to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AppUsage {
+    pub kv_bytes: usize,
+    pub kv_keys: usize,
+    pub log_lines_this_minute: u32,
+    /// Lines dropped for going over the log limit since the app was loaded
+    pub log_lines_dropped: u64,
+}
+
+/// Each key counts towards the KV store's size along with its value.
+pub fn kv_store_bytes(kv_store: &KvStore) -> usize {
+    kv_store
+        .iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum()
+}
+
+/// Counts an app's log lines over a minute at a time.
+#[derive(Debug)]
+pub struct LogRate {
+    window_start: Instant,
+    lines: u32,
+    dropped_this_minute: u32,
+    dropped: u64,
+}
+
+impl Default for LogRate {
+    fn default() -> Self {
+        Self {
+            window_start: Instant::now(),
+            lines: 0,
+            dropped_this_minute: 0,
+            dropped: 0,
+        }
+    }
+}
+
+impl LogRate {
+    /// Counts a line, returning whether it's within `limit` and should be logged.
+    pub fn count_line(&mut self, limit: u32) -> bool {
+        if self.window_start.elapsed() >= LOG_WINDOW {
+            self.window_start = Instant::now();
+            self.lines = 0;
+            self.dropped_this_minute = 0;
+        }
+        if self.lines >= limit {
+            if self.dropped_this_minute == 0 {
+                tracing::warn!(
+                    "App went over its limit of {limit} log lines a minute, dropping its lines \
+                     until the minute's up"
+                );
+            }
+            self.dropped_this_minute += 1;
+            self.dropped += 1;
+            return false;
+        }
+        self.lines += 1;
+        true
+    }
+
+    pub fn lines_this_minute(&self) -> u32 {
+        if self.window_start.elapsed() >= LOG_WINDOW {
+            0
+        } else {
+            self.lines
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
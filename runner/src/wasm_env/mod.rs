@@ -1,20 +1,92 @@
 use self::host_functions::with_host_functions;
 use crate::{
-    apps::AppManifest,
+    apps::{manifest::Permissions, AppManifest},
     display::ScreenBufferHandle,
     streams::{api_server::ApiServerHandle, coproc_client::SyncConnection},
 };
-use std::{cell::RefCell, collections::BTreeMap, io, path::Path, rc::Rc, time::Duration};
+use megabit_serial_protocol::ButtonEventKind;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 mod host_functions;
+mod limits;
+mod storage;
+
+pub(crate) use self::host_functions::display::render;
+pub use limits::{
+    AppLimits, AppUsage, DEFAULT_CALL_TIMEOUT, DEFAULT_KV_BYTES, DEFAULT_KV_KEYS,
+    DEFAULT_LOG_LINES_PER_MINUTE, DEFAULT_MEMORY_PAGES,
+};
 
 pub type KvStore = BTreeMap<String, Vec<u8>>;
 
+/// A call into an app which failed, either by running out of time or with an error of its own.
+#[derive(Debug)]
+pub struct CallError {
+    pub function: &'static str,
+    pub kind: CallErrorKind,
+}
+
+#[derive(Debug)]
+pub enum CallErrorKind {
+    TimeLimitExceeded(Duration),
+    Failed(anyhow::Error),
+}
+
+impl CallErrorKind {
+    /// Extism stops a call which runs past its timeout by interrupting it, which it reports as an
+    /// error reading "timeout" or as wasmtime's interrupt trap. A call which failed without
+    /// trapping or saying why is only taken to have timed out if it ran for the whole limit.
+    fn classify(err: anyhow::Error, elapsed: Duration, call_timeout: Duration) -> Self {
+        let causes = err
+            .chain()
+            .map(|cause| cause.to_string())
+            .collect::<Vec<_>>();
+        let interrupted = causes
+            .iter()
+            .any(|cause| cause == "timeout" || cause.contains("interrupt"));
+        let trapped = causes.iter().any(|cause| cause.contains("wasm backtrace"));
+        if interrupted || (!trapped && elapsed >= call_timeout) {
+            CallErrorKind::TimeLimitExceeded(call_timeout)
+        } else {
+            CallErrorKind::Failed(err)
+        }
+    }
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            CallErrorKind::TimeLimitExceeded(limit) => write!(
+                f,
+                "call to {} ran past its limit of {} ms",
+                self.function,
+                limit.as_millis()
+            ),
+            CallErrorKind::Failed(err) => write!(f, "call to {} failed: {err}", self.function),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
 struct PersistentData {
     screen_buffer: ScreenBufferHandle,
     kv_store: Rc<RefCell<KvStore>>,
     conn: SyncConnection,
     api_server: ApiServerHandle,
+    limits: AppLimits,
+    log_rate: limits::LogRate,
+    /// Where the KV store is saved to, if it's kept between loads of the app
+    kv_store_path: Option<PathBuf>,
+    /// Whether the KV store has changed since it was last saved
+    kv_store_dirty: bool,
 }
 
 impl PersistentData {
@@ -22,15 +94,27 @@ impl PersistentData {
         conn: SyncConnection,
         screen_buffer: ScreenBufferHandle,
         api_server: ApiServerHandle,
-    ) -> Self {
-        let kv_store = Rc::new(RefCell::new(BTreeMap::new()));
+        limits: AppLimits,
+        kv_store_path: Option<PathBuf>,
+    ) -> io::Result<Self> {
+        let kv_store = match &kv_store_path {
+            Some(path) => storage::load(path).map_err(|err| {
+                tracing::error!("Failed to load KV store from {}: {err}", path.display());
+                err
+            })?,
+            None => BTreeMap::new(),
+        };
 
-        PersistentData {
+        Ok(PersistentData {
             screen_buffer,
-            kv_store,
+            kv_store: Rc::new(RefCell::new(kv_store)),
             conn,
             api_server,
-        }
+            limits,
+            log_rate: Default::default(),
+            kv_store_path,
+            kv_store_dirty: false,
+        })
     }
 }
 
@@ -63,46 +147,79 @@ pub struct WasmAppRunner {
     name: String,
     id: String,
     refresh_period: Option<Duration>,
+    limits: AppLimits,
+    permissions: Permissions,
+    user_data: extism::UserData<PersistentData>,
 }
 
 impl WasmAppRunner {
+    /// `limits` are used as they are, the manifest's own limits aren't applied on top of them. The
+    /// app's KV store is loaded from `kv_store_path` and saved back to it by
+    /// [`WasmAppRunner::flush_kv_store`], without one it starts out empty every time.
     pub fn new(
-        wasm_bin_path: impl AsRef<Path>,
-        refresh_period: Option<Duration>,
-        app_name: impl Into<String>,
-        checksum: String,
+        app_manifest: &AppManifest,
+        limits: AppLimits,
+        kv_store_path: Option<PathBuf>,
         serial_conn: SyncConnection,
         screen_buffer: ScreenBufferHandle,
         api_server: ApiServerHandle,
     ) -> anyhow::Result<Self> {
-        let wasm_app_bin = extism::Wasm::file(wasm_bin_path);
-        let user_data =
-            extism::UserData::new(PersistentData::new(serial_conn, screen_buffer, api_server));
-        let manifest = extism::Manifest::new([wasm_app_bin]);
-        let plugin = with_host_functions(extism::PluginBuilder::new(manifest), &user_data)
-            .with_wasi(true)
-            .build()?;
+        let wasm_app_bin = extism::Wasm::file(&app_manifest.app_bin_path);
+        let user_data = extism::UserData::new(PersistentData::new(
+            serial_conn,
+            screen_buffer,
+            api_server,
+            limits,
+            kv_store_path,
+        )?);
+        let permissions = &app_manifest.permissions;
+        let mut manifest = extism::Manifest::new([wasm_app_bin])
+            .with_timeout(limits.call_timeout)
+            .with_memory_max(limits.memory_pages);
+        // Requests to any other host are refused by extism
+        for host in &permissions.network_hosts {
+            manifest = manifest.with_allowed_host(host);
+        }
+        for dir in &permissions.filesystem_dirs {
+            manifest = manifest.with_allowed_path(dir.to_string_lossy().into_owned(), dir);
+        }
+        tracing::info!(
+            "Linking app {} with permissions: {permissions:?}",
+            app_manifest.app_name
+        );
+        let plugin = with_host_functions(
+            extism::PluginBuilder::new(manifest),
+            &user_data,
+            permissions,
+        )
+        .with_wasi(permissions.needs_wasi())
+        .build()?;
 
         Ok(WasmAppRunner {
             plugin,
-            id: checksum,
-            name: app_name.into(),
-            refresh_period,
+            id: app_manifest.md5sum.clone(),
+            name: app_manifest.app_name.clone(),
+            refresh_period: app_manifest.refresh_period,
+            limits,
+            permissions: permissions.clone(),
+            user_data,
         })
     }
 
+    /// `limits` are those of the runner, which the manifest can override. The app's KV store is kept
+    /// under the data dir its bundle is in.
     pub fn from_manifest(
         app_path: impl AsRef<Path>,
+        limits: AppLimits,
         serial_conn: SyncConnection,
         screen_buffer: ScreenBufferHandle,
         api_server: ApiServerHandle,
     ) -> anyhow::Result<Self> {
         let app_manifest = AppManifest::open(app_path)?;
         Self::new(
-            app_manifest.app_bin_path,
-            app_manifest.refresh_period,
-            app_manifest.app_name,
-            app_manifest.md5sum,
+            &app_manifest,
+            limits.for_app(&app_manifest.limits),
+            storage::kv_store_path(&app_manifest),
             serial_conn,
             screen_buffer,
             api_server,
@@ -121,11 +238,116 @@ impl WasmAppRunner {
         &self.id
     }
 
-    pub fn setup_app(&mut self) -> anyhow::Result<()> {
-        self.plugin.call::<_, ()>("setup", ())
+    pub fn limits(&self) -> &AppLimits {
+        &self.limits
+    }
+
+    pub fn permissions(&self) -> &Permissions {
+        &self.permissions
+    }
+
+    pub fn usage(&self) -> anyhow::Result<AppUsage> {
+        let data = self.user_data.get()?;
+        let data = data.lock().unwrap();
+        let kv_store = data.kv_store.borrow();
+        Ok(AppUsage {
+            kv_bytes: limits::kv_store_bytes(&kv_store),
+            kv_keys: kv_store.len(),
+            log_lines_this_minute: data.log_rate.lines_this_minute(),
+            log_lines_dropped: data.log_rate.dropped(),
+        })
+    }
+
+    /// Saves the app's KV store if it's changed since it was loaded or last saved.
+    pub fn flush_kv_store(&self) -> anyhow::Result<()> {
+        let data = self.user_data.get()?;
+        let mut data = data.lock().unwrap();
+        if !data.kv_store_dirty {
+            return Ok(());
+        }
+        if let Some(path) = &data.kv_store_path {
+            storage::save(path, &data.kv_store.borrow())?;
+            tracing::debug!("Saved KV store for app {} to {}", self.name, path.display());
+        }
+        data.kv_store_dirty = false;
+        Ok(())
+    }
+
+    pub fn setup_app(&mut self) -> Result<(), CallError> {
+        self.call("setup", ())
+    }
+
+    pub fn run_app_once(&mut self) -> Result<(), CallError> {
+        self.call("run", ())
+    }
+
+    /// Passes a button event to the app as the button ID followed by the kind of event. Apps built
+    /// before button events could be delivered don't have a handler, and don't get them, nor do
+    /// apps which haven't been granted input.
+    pub fn handle_button_event(
+        &mut self,
+        button_id: u8,
+        kind: ButtonEventKind,
+    ) -> Result<(), CallError> {
+        if !self.permissions.input || !self.plugin.function_exists("on_button_event") {
+            return Ok(());
+        }
+        self.call("on_button_event", [button_id, kind as u8].as_slice())
+    }
+
+    fn call<'a>(
+        &mut self,
+        function: &'static str,
+        input: impl extism::ToBytes<'a>,
+    ) -> Result<(), CallError> {
+        let start_time = Instant::now();
+        self.plugin
+            .call::<_, ()>(function, input)
+            .map_err(|err| CallError {
+                function,
+                kind: CallErrorKind::classify(err, start_time.elapsed(), self.limits.call_timeout),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Duration = Duration::from_millis(100);
+
+    fn trap(reason: &str) -> anyhow::Error {
+        anyhow::anyhow!("{reason}").context("error while executing at wasm backtrace:\n    0: run")
+    }
+
+    #[test]
+    fn interrupted_calls_ran_out_of_time() {
+        for err in [anyhow::anyhow!("timeout"), trap("interrupt")] {
+            assert!(matches!(
+                CallErrorKind::classify(err, LIMIT / 2, LIMIT),
+                CallErrorKind::TimeLimitExceeded(LIMIT)
+            ));
+        }
+    }
+
+    #[test]
+    fn traps_near_the_limit_are_failures() {
+        let err = trap("wasm `unreachable` instruction executed");
+        assert!(matches!(
+            CallErrorKind::classify(err, LIMIT, LIMIT),
+            CallErrorKind::Failed(_)
+        ));
     }
 
-    pub fn run_app_once(&mut self) -> anyhow::Result<()> {
-        self.plugin.call::<_, ()>("run", ())
+    #[test]
+    fn unexplained_failures_are_told_apart_by_how_long_they_took() {
+        assert!(matches!(
+            CallErrorKind::classify(anyhow::anyhow!("call failed"), LIMIT, LIMIT),
+            CallErrorKind::TimeLimitExceeded(LIMIT)
+        ));
+        assert!(matches!(
+            CallErrorKind::classify(anyhow::anyhow!("call failed"), LIMIT / 2, LIMIT),
+            CallErrorKind::Failed(_)
+        ));
     }
 }
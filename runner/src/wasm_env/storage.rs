@@ -0,0 +1,78 @@
+//! Apps' KV stores are kept between loads in a file each, in a hidden directory of the data dir so
+//! that writing to them isn't taken for a change to the app library.
+
+use super::KvStore;
+use crate::apps::AppManifest;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+pub const STORAGE_DIR: &str = ".storage";
+
+/// Where the app's KV store is kept, alongside its bundle and named after it so that it outlives
+/// rebuilds of the app. `None` for an app which isn't in a bundle directory.
+pub fn kv_store_path(app_manifest: &AppManifest) -> Option<PathBuf> {
+    let app_id = app_manifest.app_id()?;
+    let data_dir = app_manifest.path.parent()?.parent()?;
+    Some(data_dir.join(STORAGE_DIR).join(format!("{app_id}.json")))
+}
+
+/// An app which hasn't stored anything yet starts out with an empty store.
+pub fn load(path: &Path) -> io::Result<KvStore> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(KvStore::new()),
+        Err(err) => return Err(err),
+    };
+    let entries: BTreeMap<String, String> = serde_json::from_slice(&contents)?;
+    entries
+        .into_iter()
+        .map(|(key, value)| {
+            hex::decode(value)
+                .map(|value| (key, value))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        })
+        .collect()
+}
+
+/// The store is written out to a file next to `path` and then moved over it, so that it's never
+/// left half written if the runner goes down partway through.
+pub fn save(path: &Path, kv_store: &KvStore) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let entries = kv_store
+        .iter()
+        .map(|(key, value)| (key, hex::encode(value)))
+        .collect::<BTreeMap<_, _>>();
+    let temp_path = path.with_extension("json.tmp");
+    let mut file = File::create(&temp_path)?;
+    serde_json::to_writer(&mut file, &entries)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_stores_load_back_the_same() {
+        let dir = std::env::temp_dir().join(format!("megabit-storage-{}", std::process::id()));
+        let path = dir.join(STORAGE_DIR).join("app.json");
+        assert_eq!(load(&path).unwrap(), KvStore::new());
+
+        let kv_store = KvStore::from([
+            (String::from("x_offset"), vec![0xd0, 0xff, 0xff, 0xff]),
+            (String::from("empty"), vec![]),
+        ]);
+        save(&path, &kv_store).unwrap();
+        assert_eq!(load(&path).unwrap(), kv_store);
+        assert!(!path.with_extension("json.tmp").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use megabit_runner::streams::coproc_client::{
+    discover, Candidate, DeviceTransport, DiscoveryFilter, UsbInfo,
+};
+use megabit_sim_core::{loopback::LoopbackDevice, DisplayConfiguration};
+use std::{io, net::SocketAddr};
+use tokio::net::TcpListener;
+
+/// Serves a simulated device over TCP the way the simulator does, returning its address.
+async fn simulator() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let device = LoopbackDevice::new(DisplayConfiguration::RGB);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut device_stream = device.connect();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut device_stream).await;
+            });
+        }
+    });
+    addr
+}
+
+/// Accepts connections and never answers them, like a port with something else behind it.
+async fn silent_port() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+    addr
+}
+
+fn coproc(serial_number: &str) -> UsbInfo {
+    let filter = DiscoveryFilter::default();
+    UsbInfo {
+        vid: filter.vid,
+        pid: filter.pid,
+        product: filter.product,
+        serial_number: Some(String::from(serial_number)),
+    }
+}
+
+fn candidate(addr: SocketAddr, usb: UsbInfo) -> Candidate {
+    Candidate {
+        transport: DeviceTransport::Tcp(addr),
+        usb,
+    }
+}
+
+fn discovered_addr(transport: io::Result<DeviceTransport>) -> SocketAddr {
+    match transport {
+        Ok(DeviceTransport::Tcp(addr)) => addr,
+        other => panic!("Expected to discover a tcp device, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn skips_ports_which_are_not_the_coprocessor() {
+    let other_device = simulator().await;
+    let silent = silent_port().await;
+    let coproc_addr = simulator().await;
+    let candidates = vec![
+        candidate(
+            other_device,
+            UsbInfo {
+                vid: 0x2e8a,
+                pid: 0x000a,
+                product: Some(String::from("Pico")),
+                serial_number: None,
+            },
+        ),
+        candidate(silent, coproc("0000000000000001")),
+        candidate(coproc_addr, coproc("0000000000000002")),
+    ];
+
+    let found = discover(&DiscoveryFilter::default(), candidates).await;
+    assert_eq!(discovered_addr(found), coproc_addr);
+}
+
+#[tokio::test]
+async fn matches_by_product_string() {
+    let coproc_addr = simulator().await;
+    let candidates = vec![candidate(
+        coproc_addr,
+        UsbInfo {
+            vid: 0x1209,
+            pid: 0x0001,
+            ..coproc("0000000000000001")
+        },
+    )];
+
+    let found = discover(&DiscoveryFilter::default(), candidates).await;
+    assert_eq!(discovered_addr(found), coproc_addr);
+}
+
+#[tokio::test]
+async fn selects_a_board_by_serial_number() {
+    let first = simulator().await;
+    let second = simulator().await;
+    let candidates = vec![
+        candidate(first, coproc("0000000000000001")),
+        candidate(second, coproc("0000000000000002")),
+    ];
+
+    let filter = DiscoveryFilter {
+        serial_number: Some(String::from("0000000000000002")),
+        ..Default::default()
+    };
+    let found = discover(&filter, candidates.clone()).await;
+    assert_eq!(discovered_addr(found), second);
+
+    let filter = DiscoveryFilter {
+        serial_number: Some(String::from("0000000000000003")),
+        ..Default::default()
+    };
+    let err = discover(&filter, candidates).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
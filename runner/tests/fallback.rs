@@ -0,0 +1,93 @@
+use megabit_runner::{
+    display::{DisplayConfiguration, ScreenBuffer, ScreenBufferHandle},
+    fallback::{FallbackScreen, FallbackStatus},
+};
+use megabit_utils::rgb555::Rgb555;
+use std::collections::BTreeSet;
+
+/// The pixels a device would light, going through the same rows a render sends it.
+fn lit_pixels(screen_buffer: &ScreenBufferHandle) -> Vec<(usize, usize)> {
+    let config = screen_buffer.display_config();
+    (0..config.height)
+        .flat_map(|row| {
+            let lit_cols = if config.is_rgb {
+                let (row_data, _) = screen_buffer.get_row_rgb(row).unwrap();
+                row_data
+                    .into_iter()
+                    .map(|color| color != Rgb555::from_rgb(0x00, 0x00, 0x00))
+                    .collect()
+            } else {
+                screen_buffer.get_row(row).unwrap().0
+            };
+            lit_cols
+                .into_iter()
+                .enumerate()
+                .filter(|(_, lit)| *lit)
+                .map(move |(col, _)| (row, col))
+        })
+        .collect()
+}
+
+#[test]
+fn status_fits_on_the_display() {
+    for (width, height, is_rgb) in [(64, 32, true), (32, 16, true), (32, 16, false)] {
+        let screen_buffer = ScreenBuffer::create_for_display(&DisplayConfiguration {
+            width,
+            height,
+            is_rgb,
+        });
+        let mut fallback = FallbackScreen::new(FallbackStatus::NoAppsInstalled);
+        fallback.draw(&screen_buffer).unwrap();
+        let lit_pixels = lit_pixels(&screen_buffer);
+
+        // The address and "NO APPS", then "INSTALLED" too when there's room for a third line.
+        // Lines are 5 pixels tall with 2 blank rows between them, centered as a block.
+        let line_count = if height >= 19 { 3 } else { 2 };
+        let top = (height - (line_count * 7 - 2)) / 2;
+        let lines = (0..line_count)
+            .map(|idx| (top + idx * 7)..(top + idx * 7 + 5))
+            .collect::<Vec<_>>();
+        assert!(lit_pixels
+            .iter()
+            .all(|(row, _)| lines.iter().any(|line| line.contains(row))));
+        let lit_rows = lit_pixels
+            .iter()
+            .map(|(row, _)| *row)
+            .collect::<BTreeSet<_>>();
+        assert!(lines
+            .iter()
+            .all(|line| line.clone().any(|row| lit_rows.contains(&row))));
+
+        // "NO APPS" fits, so it's centered rather than cut off at either edge
+        let status_cols = lit_pixels
+            .iter()
+            .filter(|(row, _)| lines[1].contains(row))
+            .map(|(_, col)| *col);
+        let (left, right) = (
+            status_cols.clone().min().unwrap(),
+            status_cols.max().unwrap(),
+        );
+        assert!(left > 0 && right < width - 1);
+        assert!((left + right).abs_diff(width - 1) <= 2);
+    }
+}
+
+#[test]
+fn long_errors_scroll_and_short_status_does_not() {
+    let screen_buffer = ScreenBuffer::create(64, 32);
+    let mut fallback = FallbackScreen::new(FallbackStatus::NoAppsInstalled);
+    fallback.draw(&screen_buffer).unwrap();
+    let first_frame = lit_pixels(&screen_buffer);
+    fallback.draw(&screen_buffer).unwrap();
+    assert_eq!(first_frame, lit_pixels(&screen_buffer));
+
+    fallback.set_status(FallbackStatus::AppsFailed {
+        app_name: String::from("clock"),
+        error: String::from("failed to find function export `run`"),
+    });
+    fallback.draw(&screen_buffer).unwrap();
+    let first_frame = lit_pixels(&screen_buffer);
+    assert_ne!(first_frame, lit_pixels(&ScreenBuffer::create(64, 32)));
+    fallback.draw(&screen_buffer).unwrap();
+    assert_ne!(first_frame, lit_pixels(&screen_buffer));
+}
@@ -0,0 +1,230 @@
+use megabit_runner::{
+    apps::{
+        manifest::{self, Permissions},
+        AppManifest, Library,
+    },
+    streams::inotify::LibraryWatcher,
+    wasm_env::AppLimits,
+};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// An empty data dir of its own for each test, removed when the test finishes.
+struct DataDir(PathBuf);
+
+impl DataDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("megabit-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    fn add_bundle(&self, bundle: &str, app_name: &str, wasm: &[u8]) -> PathBuf {
+        let bundle_dir = self.0.join(bundle);
+        std::fs::create_dir_all(&bundle_dir).unwrap();
+        std::fs::write(
+            bundle_dir.join("manifest.json"),
+            format!(r#"{{"name": "{app_name}", "bin": "app.wasm"}}"#),
+        )
+        .unwrap();
+        std::fs::write(bundle_dir.join("app.wasm"), wasm).unwrap();
+        bundle_dir.join("manifest.json")
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for DataDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn app_names(library: &Library) -> Vec<String> {
+    let Some(first) = library.get_first() else {
+        return Vec::new();
+    };
+    let mut names = vec![first.app_name];
+    let mut current = first.md5sum;
+    while let Some(next) = library.get_next(&current) {
+        if next.md5sum == library.get_first().unwrap().md5sum {
+            break;
+        }
+        names.push(next.app_name);
+        current = next.md5sum;
+    }
+    names
+}
+
+#[test]
+fn reload_picks_up_added_and_removed_bundles() {
+    let data_dir = DataDir::new("reload-added-removed");
+    data_dir.add_bundle("b", "Bravo", b"bravo");
+    let library = Library::new(data_dir.path()).unwrap();
+    assert_eq!(app_names(&library), ["Bravo"]);
+
+    data_dir.add_bundle("a", "Alpha", b"alpha");
+    data_dir.add_bundle("c", "Charlie", b"charlie");
+    library.reload().unwrap();
+    assert_eq!(app_names(&library), ["Alpha", "Bravo", "Charlie"]);
+
+    std::fs::remove_dir_all(data_dir.path().join("b")).unwrap();
+    library.reload().unwrap();
+    assert_eq!(app_names(&library), ["Alpha", "Charlie"]);
+}
+
+#[test]
+fn rebuilt_app_keeps_its_path_but_not_its_checksum() {
+    let data_dir = DataDir::new("reload-rebuilt");
+    let manifest_path = data_dir.add_bundle("app", "App", b"first build");
+    let library = Library::new(data_dir.path()).unwrap();
+    let first_build = library.get_by_path(&manifest_path).unwrap();
+
+    data_dir.add_bundle("app", "App", b"second build");
+    library.reload().unwrap();
+    let second_build = library.get_by_path(&manifest_path).unwrap();
+    assert_ne!(first_build.md5sum, second_build.md5sum);
+    assert!(library.get_app(&first_build.md5sum).is_none());
+    assert!(library.get_app(&second_build.md5sum).is_some());
+    assert_eq!(first_build.app_id(), Some("app"));
+    assert_eq!(first_build.app_id(), second_build.app_id());
+}
+
+#[test]
+fn hidden_directories_are_not_bundles() {
+    let data_dir = DataDir::new("hidden-dirs");
+    data_dir.add_bundle("app", "App", b"app");
+    data_dir.add_bundle(".storage", "Storage", b"storage");
+    let library = Library::new(data_dir.path()).unwrap();
+    assert_eq!(app_names(&library), ["App"]);
+}
+
+#[test]
+fn manifest_only_lowers_limits() {
+    let data_dir = DataDir::new("manifest-limits");
+    let manifest_path = data_dir.add_bundle("app", "App", b"app");
+    let bundle_dir = manifest_path.parent().unwrap();
+    let limits = AppLimits::default();
+    let manifest = AppManifest::open(bundle_dir).unwrap();
+    assert_eq!(limits.for_app(&manifest.limits), limits);
+
+    std::fs::write(
+        &manifest_path,
+        format!(
+            r#"{{"name": "App", "bin": "app.wasm", "call_timeout_ms": {}, "max_kv_keys": 4, "max_kv_bytes": {}}}"#,
+            limits.call_timeout.as_millis() * 2,
+            limits.kv_bytes * 2,
+        ),
+    )
+    .unwrap();
+    let manifest = AppManifest::open(bundle_dir).unwrap();
+    let app_limits = limits.for_app(&manifest.limits);
+    assert_eq!(app_limits.call_timeout, limits.call_timeout);
+    assert_eq!(app_limits.kv_keys, 4);
+    assert_eq!(app_limits.kv_bytes, limits.kv_bytes);
+    assert_eq!(app_limits.memory_pages, limits.memory_pages);
+
+    std::fs::write(
+        &manifest_path,
+        r#"{"name": "App", "bin": "app.wasm", "call_timeout_ms": 250}"#,
+    )
+    .unwrap();
+    let manifest = AppManifest::open(bundle_dir).unwrap();
+    assert_eq!(
+        limits.for_app(&manifest.limits).call_timeout,
+        Duration::from_millis(250)
+    );
+}
+
+#[test]
+fn manifests_without_permissions_get_what_every_app_used_to() {
+    let data_dir = DataDir::new("manifest-permissions");
+    let manifest_path = data_dir.add_bundle("app", "App", b"app");
+    let bundle_dir = manifest_path.parent().unwrap();
+    let manifest = AppManifest::open(bundle_dir).unwrap();
+    assert_eq!(manifest.permissions, Permissions::implicit());
+    assert!(manifest.permissions.kv && manifest.permissions.time && manifest.permissions.input);
+    assert!(!manifest.permissions.notifications && !manifest.permissions.messaging);
+    assert!(manifest.permissions.network_hosts.is_empty());
+    assert!(manifest.permissions.filesystem_dirs.is_empty());
+
+    // Anything left out of a permissions section isn't granted
+    std::fs::write(
+        &manifest_path,
+        r#"{"name": "App", "bin": "app.wasm", "permissions": {"input": true}}"#,
+    )
+    .unwrap();
+    let manifest = AppManifest::open(bundle_dir).unwrap();
+    assert_eq!(
+        manifest.permissions,
+        Permissions {
+            input: true,
+            ..Default::default()
+        }
+    );
+    assert!(!manifest.permissions.needs_wasi());
+}
+
+#[test]
+fn written_permissions_keep_the_rest_of_the_manifest() {
+    let data_dir = DataDir::new("write-permissions");
+    let manifest_path = data_dir.add_bundle("app", "App", b"app");
+    let permissions = Permissions {
+        kv: true,
+        network_hosts: vec![String::from("api.example.com")],
+        filesystem_dirs: vec![data_dir.path().join("photos")],
+        ..Default::default()
+    };
+    manifest::write_permissions(&manifest_path, &permissions).unwrap();
+
+    let manifest = AppManifest::open(manifest_path.parent().unwrap()).unwrap();
+    assert_eq!(manifest.app_name, "App");
+    assert_eq!(manifest.permissions, permissions);
+    assert!(manifest.permissions.needs_wasi());
+    assert!(!manifest_path.with_file_name(".manifest.json.tmp").exists());
+}
+
+#[tokio::test]
+async fn watcher_reports_changed_bundles_once_they_settle() {
+    let data_dir = DataDir::new("watch-bundles");
+    data_dir.add_bundle("app", "App", b"first build");
+    let mut watcher = LibraryWatcher::new(data_dir.path()).unwrap();
+
+    data_dir.add_bundle("new", "New", b"new");
+    tokio::time::timeout(Duration::from_secs(5), watcher.changed())
+        .await
+        .expect("adding a bundle should be reported")
+        .unwrap();
+
+    // Bundles which were there from the start and ones which were just added are both watched
+    for bundle in ["app", "new"] {
+        std::fs::write(data_dir.path().join(bundle).join("app.wasm"), b"rebuilt").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .expect("rebuilding an app should be reported")
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn watcher_ignores_hidden_files() {
+    let data_dir = DataDir::new("watch-hidden");
+    data_dir.add_bundle("app", "App", b"app");
+    let mut watcher = LibraryWatcher::new(data_dir.path()).unwrap();
+
+    std::fs::write(data_dir.path().join(".state"), b"state").unwrap();
+    std::fs::write(data_dir.path().join("app").join(".app.wasm.swp"), b"swap").unwrap();
+    // Nor is anything in hidden directories, such as where apps' KV stores are kept
+    std::fs::create_dir(data_dir.path().join(".storage")).unwrap();
+    std::fs::write(data_dir.path().join(".storage").join("app.json"), b"{}").unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_secs(1), watcher.changed())
+            .await
+            .is_err()
+    );
+}
@@ -0,0 +1,171 @@
+use megabit_runner::streams::coproc_client::{self, Connection, DeviceTransport, LinkState};
+use megabit_serial_protocol::{DeviceFeatures, PixelRepresentation, Status, UpdateRegionRgb};
+use megabit_sim_core::loopback::LoopbackDevice;
+use megabit_utils::rgb555::Rgb555;
+use std::{future::Future, io, time::Duration};
+
+/// Long enough for anything over the loopback link, short enough that a hang fails the test.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn within_timeout<T>(fut: impl Future<Output = T>) -> T {
+    tokio::time::timeout(TIMEOUT, fut)
+        .await
+        .expect("Timed out waiting on the loopback device")
+}
+
+async fn connect(uri: &str) -> (Connection, LoopbackDevice) {
+    let transport = uri.parse::<DeviceTransport>().unwrap();
+    let DeviceTransport::Loopback(device) = transport.clone() else {
+        panic!("Expected a loopback transport from {uri}");
+    };
+    let (conn, task) = coproc_client::start_transport_task(transport);
+    tokio::spawn(Box::into_pin(task));
+    within_timeout(conn.wait_until_connected()).await.unwrap();
+    (conn, device)
+}
+
+/// Waits for the link to leave the connected state and come back to it. The link can pass
+/// through several states before this gets to look at it, so any change is taken as having left.
+async fn wait_for_resync(conn: &Connection, trigger: impl FnOnce()) {
+    let mut link_state = conn.watch_link_state();
+    link_state.mark_unchanged();
+    trigger();
+    within_timeout(link_state.changed()).await.unwrap();
+    within_timeout(conn.wait_until_connected()).await.unwrap();
+}
+
+#[tokio::test]
+async fn handshake_reports_the_simulated_device() {
+    let (conn, _device) = connect("loopback://").await;
+
+    let device_info = within_timeout(conn.handshake()).await.unwrap().unwrap();
+    assert!(device_info
+        .features
+        .contains(DeviceFeatures::SET_BRIGHTNESS));
+    assert!(conn
+        .device_features()
+        .contains(DeviceFeatures::UPDATE_ROW_RGB_DELTA));
+
+    let display_info = within_timeout(conn.get_display_info()).await.unwrap();
+    assert_eq!((display_info.width, display_info.height), (64, 32));
+    assert_eq!(
+        display_info.pixel_representation,
+        PixelRepresentation::RGB555
+    );
+    assert_eq!(display_info.queue_depth(), 8);
+}
+
+#[tokio::test]
+async fn rows_reach_the_device() {
+    let (conn, device) = connect("loopback://").await;
+    within_timeout(conn.handshake()).await.unwrap();
+
+    let frame = |offset: u16| {
+        (0..32u8)
+            .map(|row| (row, (0..64u16).map(|col| col + offset).collect::<Vec<_>>()))
+            .collect::<Vec<_>>()
+    };
+    // The second frame differs slightly from the first so it goes out as deltas
+    for offset in [0, 1] {
+        let responses = within_timeout(conn.update_rows_rgb(frame(offset)))
+            .await
+            .unwrap();
+        assert!(responses
+            .iter()
+            .all(|response| response.status == Status::Success));
+        within_timeout(conn.commit_render()).await.unwrap();
+
+        let display_buffer = device.core().display_buffer().lock().unwrap();
+        for (row, data) in frame(offset) {
+            assert_eq!(display_buffer.row_rgb(row), &data[..]);
+        }
+    }
+}
+
+#[tokio::test]
+async fn malformed_regions_are_refused_before_being_sent() {
+    let (conn, device) = connect("loopback://").await;
+    within_timeout(conn.handshake()).await.unwrap();
+
+    let region = UpdateRegionRgb {
+        x: 3,
+        y: 1,
+        width: 2,
+        height: 2,
+        data: vec![0x7fff; 4],
+    };
+    let response = within_timeout(conn.update_region_rgb(region.clone()))
+        .await
+        .unwrap();
+    assert_eq!(response.status, Status::Success);
+    within_timeout(conn.commit_render()).await.unwrap();
+
+    for bad_region in [
+        UpdateRegionRgb {
+            data: vec![0x001f; 3],
+            ..region.clone()
+        },
+        UpdateRegionRgb {
+            width: 64,
+            height: 5,
+            data: vec![0x001f; 320],
+            ..region
+        },
+    ] {
+        let err = within_timeout(conn.update_region_rgb(bad_region))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+    within_timeout(conn.commit_render()).await.unwrap();
+
+    let display_buffer = device.core().display_buffer().lock().unwrap();
+    assert_eq!(display_buffer.row_rgb(1)[3..5], [0x7fff, 0x7fff]);
+    assert_eq!(display_buffer.row_rgb(2)[3..5], [0x7fff, 0x7fff]);
+}
+
+#[tokio::test]
+async fn reboot_resyncs_the_device() {
+    let (conn, device) = connect("loopback://?monocolor=true").await;
+    within_timeout(conn.handshake()).await.unwrap();
+    let color = Rgb555::from_rgb(0x00, 0xff, 0x00);
+    within_timeout(conn.set_monocolor_palette(color))
+        .await
+        .unwrap();
+    within_timeout(conn.set_brightness(42)).await.unwrap();
+
+    wait_for_resync(&conn, || device.reboot()).await;
+
+    let display_buffer = device.core().display_buffer().lock().unwrap();
+    assert_eq!(display_buffer.get_monocolor(), color.0);
+    assert_eq!(display_buffer.get_brightness(), 42);
+}
+
+#[tokio::test]
+async fn resync_follows_the_devices_queue_depth_up_to_the_configured_max() {
+    let (conn, device) = connect("loopback://").await;
+    within_timeout(conn.handshake()).await.unwrap();
+    // As though the device had come up with a shallower queue before it rebooted
+    conn.set_pipeline_window(1);
+
+    wait_for_resync(&conn, || device.reboot()).await;
+    assert_eq!(conn.pipeline_window(), 8);
+
+    conn.set_max_pipeline_window(4);
+    assert_eq!(conn.pipeline_window(), 4);
+    conn.set_pipeline_window(1);
+
+    wait_for_resync(&conn, || device.reboot()).await;
+    assert_eq!(conn.pipeline_window(), 4);
+}
+
+#[tokio::test]
+async fn reconnects_after_the_link_drops() {
+    let (conn, device) = connect("loopback://").await;
+    within_timeout(conn.handshake()).await.unwrap();
+
+    wait_for_resync(&conn, || device.disconnect()).await;
+
+    within_timeout(conn.ping()).await.unwrap();
+    assert_eq!(conn.link_state(), LinkState::Connected);
+}
@@ -0,0 +1,471 @@
+//! Owned versions of the messages in the message table for hosts, which convert to and from the
+//! `wire` layer to get on and off the wire.
+
+use crate::wire::{self, PixelSlice, RunSlice};
+use std::{fmt, io};
+
+pub use crate::wire::{
+    ButtonEventKind, DeviceFeatures, ErrorCode, PixelRepresentation, ProtocolVersion, ResetReason,
+    RleRun, Status, CRC_FLAG, CRC_LEN, PROTOCOL_VERSION, UNSOLICITED_SEQ,
+};
+
+impl From<wire::Error> for io::Error {
+    fn from(err: wire::Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{err:?}"))
+    }
+}
+
+/// Converts a decoded field into the type a host keeps it as.
+pub trait ToOwnedField<O> {
+    fn to_owned_field(&self) -> O;
+}
+
+/// Borrows a host's field as the type it's encoded from.
+pub trait AsField<'a, V> {
+    fn as_field(&'a self) -> V;
+}
+
+impl<T: Copy> ToOwnedField<T> for T {
+    fn to_owned_field(&self) -> T {
+        *self
+    }
+}
+
+impl<T: Copy> AsField<'_, T> for T {
+    fn as_field(&self) -> T {
+        *self
+    }
+}
+
+impl ToOwnedField<Vec<u8>> for &[u8] {
+    fn to_owned_field(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+}
+
+impl<'a> AsField<'a, &'a [u8]> for Vec<u8> {
+    fn as_field(&'a self) -> &'a [u8] {
+        self
+    }
+}
+
+impl ToOwnedField<Vec<u16>> for PixelSlice<'_> {
+    fn to_owned_field(&self) -> Vec<u16> {
+        self.iter().collect()
+    }
+}
+
+impl<'a> AsField<'a, PixelSlice<'a>> for Vec<u16> {
+    fn as_field(&'a self) -> PixelSlice<'a> {
+        PixelSlice::Values(self)
+    }
+}
+
+impl ToOwnedField<Vec<RleRun>> for RunSlice<'_> {
+    fn to_owned_field(&self) -> Vec<RleRun> {
+        self.iter().collect()
+    }
+}
+
+impl<'a> AsField<'a, RunSlice<'a>> for Vec<RleRun> {
+    fn as_field(&'a self) -> RunSlice<'a> {
+        RunSlice::Values(self)
+    }
+}
+
+macro_rules! define_host_messages {
+    ($(
+        $(#[$meta:meta])*
+        $name:ident $(<$lt:lifetime>)? = ($major:literal, $minor:literal) {
+            $(
+                $(#[$field_meta:meta])*
+                $field:ident : $wire_ty:ty => $owned_ty:ty
+            ),* $(,)?
+        }
+    ),* $(,)?) => {
+        $(
+            $(#[$meta])*
+            #[derive(Clone, Debug)]
+            pub struct $name {
+                $(
+                    $(#[$field_meta])*
+                    pub $field: $owned_ty,
+                )*
+            }
+
+            impl From<$name> for SerialMessage {
+                fn from(msg: $name) -> Self {
+                    SerialMessage::$name(msg)
+                }
+            }
+        )*
+
+        #[derive(Clone, Debug)]
+        pub enum SerialMessage {
+            $($name($name),)*
+        }
+
+        impl AsRef<str> for SerialMessage {
+            fn as_ref(&self) -> &str {
+                match self {
+                    $(SerialMessage::$name(_) => stringify!($name),)*
+                }
+            }
+        }
+
+        impl SerialMessage {
+            pub fn as_wire(&self) -> wire::Message<'_> {
+                match self {
+                    $(SerialMessage::$name(_inner) => wire::Message::$name(wire::$name {
+                        $($field: AsField::as_field(&_inner.$field),)*
+                    }),)*
+                }
+            }
+
+            pub fn from_wire(msg: wire::Message<'_>) -> Self {
+                match msg {
+                    $(wire::Message::$name(_inner) => SerialMessage::$name($name {
+                        $($field: ToOwnedField::to_owned_field(&_inner.$field),)*
+                    }),)*
+                }
+            }
+        }
+    };
+}
+
+for_each_message!(define_host_messages);
+
+impl SerialMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let msg = self.as_wire();
+        let mut out = vec![0u8; msg.encoded_len()];
+        msg.encode(&mut out)
+            .expect("Buffer is sized to fit the message");
+        out
+    }
+
+    pub fn try_from_bytes(data: &[u8]) -> io::Result<Self> {
+        match wire::Message::decode(data) {
+            Ok(msg) => Ok(SerialMessage::from_wire(msg)),
+            Err(wire::Error::UnknownMessage { major, minor }) => {
+                tracing::error!("Unexpected serial message kind 0x{major:02x}{minor:02x}");
+                Err(io::ErrorKind::InvalidData.into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// A message along with the sequence number used to correlate a request with its response, see
+/// `wire::Frame` for the encoding.
+#[derive(Clone, Debug)]
+pub struct SerialFrame {
+    pub seq: u16,
+    pub msg: SerialMessage,
+    /// Whether the frame carries a CRC trailer
+    pub crc: bool,
+}
+
+impl SerialFrame {
+    pub fn new(seq: u16, msg: SerialMessage) -> Self {
+        Self {
+            seq,
+            msg,
+            crc: false,
+        }
+    }
+
+    pub fn unsolicited(msg: SerialMessage) -> Self {
+        Self::new(UNSOLICITED_SEQ, msg)
+    }
+
+    pub fn with_crc(self, crc: bool) -> Self {
+        Self { crc, ..self }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let frame = wire::Frame::new(self.seq, self.msg.as_wire()).with_crc(self.crc);
+        let mut out = vec![0u8; frame.encoded_len()];
+        frame
+            .encode(&mut out)
+            .expect("Buffer is sized to fit the frame");
+        out
+    }
+
+    pub fn try_from_bytes(data: &[u8]) -> io::Result<Self> {
+        let frame = wire::Frame::decode(data)?;
+        Ok(Self {
+            seq: frame.seq,
+            msg: SerialMessage::from_wire(frame.msg),
+            crc: frame.crc,
+        })
+    }
+}
+
+/// Adds a CRC trailer to a frame which has already been encoded.
+pub fn append_crc(frame: &mut Vec<u8>) {
+    let len = frame.len();
+    frame.resize(len + CRC_LEN, 0);
+    wire::append_crc(frame, len).expect("Buffer is sized to fit the trailer");
+}
+
+impl GetDisplayInfoResponse {
+    /// The depth of the device's command queue, devices which don't report it are assumed to
+    /// handle a single request at a time.
+    pub fn queue_depth(&self) -> u8 {
+        self.cmd_queue_depth.unwrap_or(1).max(1)
+    }
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "device rejected request 0x{:04x}: {:?}",
+            self.request_type, self.code
+        )
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
+
+/// Lets a request which the device answered with an `ErrorResponse` fail with it as the source
+/// of its `io::Error`, where it can be recovered with `io::Error::get_ref` and a downcast.
+impl From<ErrorResponse> for io::Error {
+    fn from(err: ErrorResponse) -> Self {
+        let kind = match err.code {
+            ErrorCode::UnknownMessage | ErrorCode::Unsupported => io::ErrorKind::Unsupported,
+            ErrorCode::BadLength => io::ErrorKind::InvalidData,
+            ErrorCode::OutOfRange => io::ErrorKind::InvalidInput,
+            ErrorCode::QueueFull => io::ErrorKind::WouldBlock,
+            ErrorCode::InvalidState => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+impl UpdateRegionRgb {
+    /// The most pixels a single region update may carry, larger regions need to be split up to
+    /// fit in the device's receive buffer.
+    pub const MAX_PIXELS: usize = 256;
+
+    /// Checks that the region carries exactly one pixel for each of its `width` by `height`
+    /// pixels, and no more than fit in a single update.
+    pub fn validate(&self) -> io::Result<()> {
+        let pixel_count = self.width as usize * self.height as usize;
+        if self.data.len() != pixel_count {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a {}x{} region needs {pixel_count} pixels, got {}",
+                    self.width,
+                    self.height,
+                    self.data.len()
+                ),
+            ))
+        } else if pixel_count > Self::MAX_PIXELS {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a {}x{} region is more than the {} pixels a region update can carry",
+                    self.width,
+                    self.height,
+                    Self::MAX_PIXELS
+                ),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl WriteChunk {
+    /// The most image bytes a single chunk may carry, the same as the largest region update so
+    /// that it fits in the device's receive buffer.
+    pub const MAX_LEN: usize = UpdateRegionRgb::MAX_PIXELS * 2;
+}
+
+pub fn pack_bools_to_bytes(bits: &[bool]) -> Vec<u8> {
+    bits.iter()
+        .enumerate()
+        .fold(Vec::new(), |mut acc, (idx, elem)| {
+            let byte_idx = idx / 8;
+            let bit_idx = idx % 8;
+            if acc.len() <= byte_idx {
+                acc.push(0x00);
+            }
+            if *elem {
+                acc[byte_idx] |= 1 << bit_idx;
+            }
+            acc
+        })
+}
+
+/// Collapses a row of pixel values into runs of identical values.
+pub fn rle_encode(values: &[u16]) -> Vec<RleRun> {
+    let mut runs: Vec<RleRun> = Vec::new();
+    for value in values {
+        match runs.last_mut() {
+            Some(run) if run.value == *value && run.length < u8::MAX => run.length += 1,
+            _ => runs.push(RleRun {
+                length: 1,
+                value: *value,
+            }),
+        }
+    }
+    runs
+}
+
+pub fn rle_decode(runs: &[RleRun]) -> Vec<u16> {
+    runs.iter()
+        .flat_map(|run| std::iter::repeat_n(run.value, run.length as usize))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        for crc in [false, true] {
+            let frame = SerialFrame::new(
+                7,
+                SerialMessage::SetRgbState(SetRgbState { r: 1, g: 2, b: 3 }),
+            )
+            .with_crc(crc);
+            let decoded = SerialFrame::try_from_bytes(&frame.to_bytes()).unwrap();
+            assert_eq!(decoded.seq, 7);
+            assert_eq!(decoded.crc, crc);
+            let SerialMessage::SetRgbState(SetRgbState { r, g, b }) = decoded.msg else {
+                panic!("Decoded {} instead", decoded.msg.as_ref());
+            };
+            assert_eq!((r, g, b), (1, 2, 3));
+        }
+    }
+
+    #[test]
+    fn crc_appended_to_an_encoded_frame_is_checked() {
+        let mut data = SerialFrame::new(9, SerialMessage::Ping(Ping {})).to_bytes();
+        append_crc(&mut data);
+        let decoded = SerialFrame::try_from_bytes(&data).unwrap();
+        assert_eq!(decoded.seq, 9);
+        assert!(decoded.crc);
+
+        data[2] ^= 0x01;
+        let err = SerialFrame::try_from_bytes(&data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn devices_which_dont_report_a_queue_depth_get_one() {
+        let info = GetDisplayInfoResponse {
+            width: 64,
+            height: 32,
+            pixel_representation: PixelRepresentation::RGB555,
+            cmd_queue_depth: None,
+        };
+        assert_eq!(info.queue_depth(), 1);
+        let info = GetDisplayInfoResponse {
+            cmd_queue_depth: Some(0),
+            ..info
+        };
+        assert_eq!(info.queue_depth(), 1);
+        let info = GetDisplayInfoResponse {
+            cmd_queue_depth: Some(4),
+            ..info
+        };
+        assert_eq!(info.queue_depth(), 4);
+    }
+
+    #[test]
+    fn regions_must_hold_their_pixels_and_fit_in_an_update() {
+        let region = |width: u8, height: u8, pixel_count: usize| UpdateRegionRgb {
+            x: 0,
+            y: 0,
+            width,
+            height,
+            data: vec![0x7fff; pixel_count],
+        };
+        assert!(region(4, 2, 8).validate().is_ok());
+        assert!(region(128, 2, 256).validate().is_ok());
+        assert!(region(0, 0, 0).validate().is_ok());
+
+        for bad_region in [region(4, 2, 7), region(4, 2, 9), region(255, 2, 510)] {
+            let err = bad_region.validate().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn rle_round_trips() {
+        let row = [0x7fff, 0x7fff, 0x7fff, 0x001f, 0x0000, 0x0000];
+        let runs = rle_encode(&row);
+        assert_eq!(
+            runs,
+            [
+                RleRun {
+                    length: 3,
+                    value: 0x7fff
+                },
+                RleRun {
+                    length: 1,
+                    value: 0x001f
+                },
+                RleRun {
+                    length: 2,
+                    value: 0x0000
+                },
+            ]
+        );
+        assert_eq!(rle_decode(&runs), row);
+        assert!(rle_encode(&[]).is_empty());
+    }
+
+    #[test]
+    fn rle_splits_runs_longer_than_a_run_can_hold() {
+        let row = vec![0x1234; 300];
+        let runs = rle_encode(&row);
+        assert_eq!(
+            runs,
+            [
+                RleRun {
+                    length: 255,
+                    value: 0x1234
+                },
+                RleRun {
+                    length: 45,
+                    value: 0x1234
+                },
+            ]
+        );
+        assert_eq!(rle_decode(&runs), row);
+    }
+
+    #[test]
+    fn rle_rows_round_trip_through_the_wire() {
+        let msg = SerialMessage::UpdateRowRgbRle(UpdateRowRgbRle {
+            row_number: 4,
+            runs: rle_encode(&[0x7fff, 0x7fff, 0x001f]),
+        });
+        let SerialMessage::UpdateRowRgbRle(decoded) =
+            SerialMessage::try_from_bytes(&msg.to_bytes()).unwrap()
+        else {
+            panic!("Decoded the wrong message");
+        };
+        assert_eq!(decoded.row_number, 4);
+        assert_eq!(rle_decode(&decoded.runs), [0x7fff, 0x7fff, 0x001f]);
+    }
+
+    #[test]
+    fn rle_rows_with_leftover_bytes_are_rejected() {
+        let mut data = SerialMessage::UpdateRowRgbRle(UpdateRowRgbRle {
+            row_number: 4,
+            runs: rle_encode(&[0x7fff]),
+        })
+        .to_bytes();
+        data.push(0x00);
+        let err = SerialMessage::try_from_bytes(&data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,181 @@
+/// The table of every message exchanged with the coprocessor, this is the only place a message's
+/// ID and payload layout are written down. Each entry lists the payload fields in wire order,
+/// giving both the borrowed type used by the allocation-free `wire` layer and the owned type used
+/// by the host's `SerialMessage`.
+///
+/// The table is handed to a generator macro so that the same definitions can be expanded into
+/// more than one set of types, `for_each_message!(generator)` invokes `generator!` with it.
+macro_rules! for_each_message {
+    ($generator:ident) => {
+        $generator! {
+            /// Replaces a row of a monocolor display, one bit per pixel.
+            UpdateRow<'a> = (0xa0, 0x00) {
+                row_number: u8 => u8,
+                /// The number of pixels packed into `row_data`
+                row_data_len: u8 => u8,
+                row_data: &'a [u8] => Vec<u8>,
+            },
+            UpdateRowResponse = (0xa0, 0x01) {
+                status: Status => Status,
+            },
+            UpdateRowRgb<'a> = (0xa0, 0x02) {
+                row_number: u8 => u8,
+                row_data_len: u8 => u8,
+                row_data: PixelSlice<'a> => Vec<u16>,
+            },
+            UpdateRowRgbResponse = (0xa0, 0x03) {
+                status: Status => Status,
+            },
+            GetDisplayInfo = (0xa0, 0x04) {},
+            GetDisplayInfoResponse = (0xa0, 0x05) {
+                width: u32 => u32,
+                height: u32 => u32,
+                pixel_representation: PixelRepresentation => PixelRepresentation,
+                /// How many display commands the device can queue up, which bounds how many
+                /// requests a host can have in flight without the device stalling. Devices which
+                /// don't report it can only be relied on to handle one request at a time.
+                cmd_queue_depth: Option<u8> => Option<u8>,
+            },
+            RequestCommitRender = (0xa0, 0x06) {},
+            CommitRenderResponse = (0xa0, 0x07) {
+                status: Status => Status,
+            },
+            SetMonocolorPalette = (0xa0, 0x08) {
+                color: u16 => u16,
+            },
+            SetMonocolorPaletteResponse = (0xa0, 0x09) {
+                status: Status => Status,
+            },
+            /// Updates a row with run-length encoded pixel data, the runs must cover the whole
+            /// row.
+            UpdateRowRgbRle<'a> = (0xa0, 0x0a) {
+                row_number: u8 => u8,
+                runs: RunSlice<'a> => Vec<RleRun>,
+            },
+            UpdateRowRgbRleResponse = (0xa0, 0x0b) {
+                status: Status => Status,
+            },
+            /// Updates a row by XORing run-length encoded values into the row's current contents
+            /// on the device, which are whatever was last written to that row. The runs must
+            /// cover the whole row.
+            UpdateRowRgbDelta<'a> = (0xa0, 0x0c) {
+                row_number: u8 => u8,
+                runs: RunSlice<'a> => Vec<RleRun>,
+            },
+            UpdateRowRgbDeltaResponse = (0xa0, 0x0d) {
+                status: Status => Status,
+            },
+            /// Writes a `width` by `height` block of pixels, given row by row, with its top left
+            /// corner at (`x`, `y`).
+            UpdateRegionRgb<'a> = (0xa0, 0x0e) {
+                x: u8 => u8,
+                y: u8 => u8,
+                width: u8 => u8,
+                height: u8 => u8,
+                data: PixelSlice<'a> => Vec<u16>,
+            },
+            UpdateRegionRgbResponse = (0xa0, 0x0f) {
+                status: Status => Status,
+            },
+            /// Scales how long each pixel is lit for, from fully off at 0 to the full on-time at
+            /// 255. The device keeps the last value across reboots.
+            SetBrightness = (0xa0, 0x10) {
+                brightness: u8 => u8,
+            },
+            SetBrightnessResponse = (0xa0, 0x11) {
+                status: Status => Status,
+            },
+            SetSingleCell = (0xa0, 0x50) {
+                row: u8 => u8,
+                col: u8 => u8,
+                value: bool => bool,
+            },
+            SetSingleCellResponse = (0xa0, 0x51) {
+                status: Status => Status,
+            },
+            SetLedState = (0xde, 0x00) {
+                new_state: bool => bool,
+            },
+            SetLedStateResponse = (0xde, 0x01) {
+                status: Status => Status,
+            },
+            SetRgbState = (0xde, 0x02) {
+                r: u8 => u8,
+                g: u8 => u8,
+                b: u8 => u8,
+            },
+            SetRgbStateResponse = (0xde, 0x03) {
+                status: Status => Status,
+            },
+            /// Sent by older firmware when the button is released, superseded by
+            /// `ReportButtonEvent`.
+            ReportButtonPress = (0xde, 0x04) {},
+            ReportButtonEvent = (0xde, 0x05) {
+                /// Which of the device's buttons the event is for, starting from zero
+                button_id: u8 => u8,
+                kind: ButtonEventKind => ButtonEventKind,
+            },
+            /// Sent unprompted once the device has come out of reset and the host has connected to
+            /// it, whatever the host had set up on the device before has been lost.
+            DeviceBooted = (0xde, 0x06) {
+                reset_reason: ResetReason => ResetReason,
+                /// Version from the header of the firmware image the device booted into
+                image_version: u32 => u32,
+            },
+            GetDeviceInfo = (0xde, 0x10) {},
+            GetDeviceInfoResponse = (0xde, 0x11) {
+                protocol_version: ProtocolVersion => ProtocolVersion,
+                /// Version from the header of the firmware image the device is running
+                image_version: u32 => u32,
+                /// The boot partition the running image was loaded from
+                active_partition: u8 => u8,
+                bootloader_version: u32 => u32,
+                features: DeviceFeatures => DeviceFeatures,
+            },
+            /// Starts writing a new firmware image into the boot partition the device isn't
+            /// running from, anything already there is erased.
+            BeginUpdate = (0xde, 0x20) {
+                /// Length of the app image, not counting the image header
+                image_size: u32 => u32,
+                image_version: u32 => u32,
+                /// CRC32 of the app image which the written image is checked against
+                crc32: u32 => u32,
+            },
+            BeginUpdateResponse = (0xde, 0x21) {
+                status: Status => Status,
+            },
+            /// Writes the next piece of the image begun with `BeginUpdate`, chunks have to be
+            /// written in order with each starting where the last left off.
+            WriteChunk<'a> = (0xde, 0x22) {
+                /// Where the chunk starts within the image
+                offset: u32 => u32,
+                data: &'a [u8] => Vec<u8>,
+            },
+            WriteChunkResponse = (0xde, 0x23) {
+                status: Status => Status,
+            },
+            /// Checks the written image against its CRC and marks it to be booted into. The
+            /// device resets itself once it has answered.
+            FinishUpdate = (0xde, 0x24) {},
+            FinishUpdateResponse = (0xde, 0x25) {
+                status: Status => Status,
+            },
+            /// Marks the running image as good, so the bootloader stops counting boots against it.
+            ConfirmBoot = (0xde, 0x26) {},
+            ConfirmBootResponse = (0xde, 0x27) {
+                status: Status => Status,
+            },
+            /// Sent in place of a response to a request the device couldn't act on.
+            ErrorResponse = (0xde, 0xfc) {
+                /// The major and minor message kind bytes of the request
+                request_type: u16 => u16,
+                code: ErrorCode => ErrorCode,
+            },
+            /// Sent in place of a response to a frame which failed its CRC check, the sender should
+            /// retransmit the request.
+            Nack = (0xde, 0xfd) {},
+            Ping = (0xde, 0xfe) {},
+            PingResponse = (0xde, 0xff) {},
+        }
+    };
+}
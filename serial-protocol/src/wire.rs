@@ -0,0 +1,964 @@
+//! Allocation-free encoding and decoding of the messages in the message table. Decoded messages
+//! borrow their variable length payloads from the buffer they were decoded from, so this layer
+//! is usable from the coprocessor firmware as well as the host.
+
+use core::fmt;
+
+/// Sequence number carried by frames which the coprocessor sends without being asked, such as
+/// button presses. Hosts never allocate it for a request.
+pub const UNSOLICITED_SEQ: u16 = 0;
+
+/// Set in the encoded sequence number of a frame which ends with a CRC32 trailer, so sequence
+/// numbers themselves only use the lower 15 bits.
+pub const CRC_FLAG: u16 = 0x8000;
+
+/// Length of the CRC32 trailer, which covers every byte of the frame before it.
+pub const CRC_LEN: usize = 4;
+
+/// Version of the message set described by this crate. Hosts and devices which disagree on the
+/// major version can't talk to each other, a minor version bump only adds messages which are
+/// advertised through `DeviceFeatures`.
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion { major: 1, minor: 0 };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The payload ended partway through a field
+    UnexpectedEnd,
+    /// There were bytes left over after the last field of the payload
+    TrailingBytes,
+    /// A field held a value it isn't allowed to
+    InvalidValue,
+    UnknownMessage {
+        major: u8,
+        minor: u8,
+    },
+    /// The buffer being encoded into can't fit the message
+    BufferTooSmall,
+    /// The frame's CRC trailer doesn't match its contents, the sequence number is only a best
+    /// guess since it may be what got corrupted
+    CrcMismatch {
+        seq: u16,
+    },
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len <= self.data.len() {
+            let (taken, rest) = self.data.split_at(len);
+            self.data = rest;
+            Ok(taken)
+        } else {
+            Err(Error::UnexpectedEnd)
+        }
+    }
+
+    pub fn take_rest(&mut self) -> &'a [u8] {
+        core::mem::take(&mut self.data)
+    }
+
+    pub fn take_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
+
+pub struct Writer<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Writer<'b> {
+    pub fn new(buf: &'b mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn put(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let dst = self
+            .buf
+            .get_mut(self.len..(self.len + bytes.len()))
+            .ok_or(Error::BufferTooSmall)?;
+        dst.copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+}
+
+/// A value which can appear as a field of a message payload.
+pub trait Field<'a>: Sized {
+    fn encoded_len(&self) -> usize;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error>;
+
+    fn decode(reader: &mut Reader<'a>) -> Result<Self, Error>;
+}
+
+impl Field<'_> for u8 {
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.put(&[*self])
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        Ok(reader.take_array::<1>()?[0])
+    }
+}
+
+impl Field<'_> for bool {
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.put(&[if *self { 0x01 } else { 0x00 }])
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        Ok(reader.take_array::<1>()?[0] != 0x00)
+    }
+}
+
+impl Field<'_> for u16 {
+    fn encoded_len(&self) -> usize {
+        2
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.put(&self.to_be_bytes())
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        Ok(u16::from_be_bytes(reader.take_array()?))
+    }
+}
+
+impl Field<'_> for u32 {
+    fn encoded_len(&self) -> usize {
+        4
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.put(&self.to_be_bytes())
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        Ok(u32::from_be_bytes(reader.take_array()?))
+    }
+}
+
+/// A trailing byte which older senders leave off, it must be the last field of a payload.
+impl Field<'_> for Option<u8> {
+    fn encoded_len(&self) -> usize {
+        self.map_or(0, |_| 1)
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        match self {
+            Some(value) => value.encode(writer),
+            None => Ok(()),
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        if reader.is_empty() {
+            Ok(None)
+        } else {
+            u8::decode(reader).map(Some)
+        }
+    }
+}
+
+/// Raw bytes filling up the rest of the payload.
+impl<'a> Field<'a> for &'a [u8] {
+    fn encoded_len(&self) -> usize {
+        self.len()
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.put(self)
+    }
+
+    fn decode(reader: &mut Reader<'a>) -> Result<Self, Error> {
+        Ok(reader.take_rest())
+    }
+}
+
+/// Big-endian RGB555 pixel values filling up the rest of the payload, either still encoded in a
+/// received buffer or as values which are about to be sent.
+#[derive(Clone, Copy, Debug)]
+pub enum PixelSlice<'a> {
+    Encoded(&'a [u8]),
+    Values(&'a [u16]),
+}
+
+impl<'a> PixelSlice<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            PixelSlice::Encoded(bytes) => bytes.len() / 2,
+            PixelSlice::Values(values) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, idx: usize) -> Option<u16> {
+        match self {
+            PixelSlice::Encoded(bytes) => bytes
+                .get((idx * 2)..(idx * 2 + 2))
+                .map(|pixel| u16::from_be_bytes([pixel[0], pixel[1]])),
+            PixelSlice::Values(values) => values.get(idx).copied(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + 'a {
+        let pixels = *self;
+        (0..pixels.len()).filter_map(move |idx| pixels.get(idx))
+    }
+}
+
+impl<'a> Field<'a> for PixelSlice<'a> {
+    fn encoded_len(&self) -> usize {
+        self.len() * 2
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        match self {
+            PixelSlice::Encoded(bytes) => writer.put(bytes),
+            PixelSlice::Values(values) => values
+                .iter()
+                .try_for_each(|value| writer.put(&value.to_be_bytes())),
+        }
+    }
+
+    fn decode(reader: &mut Reader<'a>) -> Result<Self, Error> {
+        let bytes = reader.take_rest();
+        if bytes.len().is_multiple_of(2) {
+            Ok(PixelSlice::Encoded(bytes))
+        } else {
+            Err(Error::InvalidValue)
+        }
+    }
+}
+
+/// A run of `length` consecutive pixels which all have the same value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RleRun {
+    pub length: u8,
+    pub value: u16,
+}
+
+impl RleRun {
+    pub const ENCODED_LEN: usize = 3;
+}
+
+/// A count-prefixed list of runs filling up the rest of the payload, either still encoded in a
+/// received buffer or as values which are about to be sent. Runs are never empty.
+#[derive(Clone, Copy, Debug)]
+pub enum RunSlice<'a> {
+    Encoded(&'a [u8]),
+    Values(&'a [RleRun]),
+}
+
+impl<'a> RunSlice<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            RunSlice::Encoded(bytes) => bytes.len() / RleRun::ENCODED_LEN,
+            RunSlice::Values(runs) => runs.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, idx: usize) -> Option<RleRun> {
+        match self {
+            RunSlice::Encoded(bytes) => bytes
+                .get((idx * RleRun::ENCODED_LEN)..((idx + 1) * RleRun::ENCODED_LEN))
+                .map(|run| RleRun {
+                    length: run[0],
+                    value: u16::from_be_bytes([run[1], run[2]]),
+                }),
+            RunSlice::Values(runs) => runs.get(idx).copied(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = RleRun> + 'a {
+        let runs = *self;
+        (0..runs.len()).filter_map(move |idx| runs.get(idx))
+    }
+}
+
+impl<'a> Field<'a> for RunSlice<'a> {
+    fn encoded_len(&self) -> usize {
+        1 + self.len() * RleRun::ENCODED_LEN
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        let run_count = u8::try_from(self.len()).map_err(|_| Error::InvalidValue)?;
+        run_count.encode(writer)?;
+        self.iter().try_for_each(|run| {
+            run.length.encode(writer)?;
+            run.value.encode(writer)
+        })
+    }
+
+    fn decode(reader: &mut Reader<'a>) -> Result<Self, Error> {
+        let run_count = u8::decode(reader)? as usize;
+        let bytes = reader.take_rest();
+        let runs = RunSlice::Encoded(bytes);
+        if bytes.len() != run_count * RleRun::ENCODED_LEN || runs.iter().any(|run| run.length == 0)
+        {
+            Err(Error::InvalidValue)
+        } else {
+            Ok(runs)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    Success = 0,
+    Failure = 1,
+    InProgress = 2,
+}
+
+impl TryFrom<u8> for Status {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Status::Success),
+            1 => Ok(Status::Failure),
+            2 => Ok(Status::InProgress),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+impl From<Status> for u8 {
+    fn from(value: Status) -> Self {
+        value as u8
+    }
+}
+
+impl Field<'_> for Status {
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        u8::from(*self).encode(writer)
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        Status::try_from(u8::decode(reader)?)
+    }
+}
+
+/// What happened to a button in a `ReportButtonEvent`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ButtonEventKind {
+    Press = 0,
+    Release = 1,
+    /// The button has been held down for a while, it's followed by `Repeat` events for as long as
+    /// it stays down
+    LongPress = 2,
+    /// The button was pressed again shortly after a short press, sent along with the `Press`
+    DoublePress = 3,
+    Repeat = 4,
+}
+
+impl Field<'_> for ButtonEventKind {
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        (*self as u8).encode(writer)
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        match u8::decode(reader)? {
+            0 => Ok(ButtonEventKind::Press),
+            1 => Ok(ButtonEventKind::Release),
+            2 => Ok(ButtonEventKind::LongPress),
+            3 => Ok(ButtonEventKind::DoublePress),
+            4 => Ok(ButtonEventKind::Repeat),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+/// What caused the device to last come out of reset, sent in a `DeviceBooted`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ResetReason {
+    /// The device couldn't tell, i.e. it was reset by a reset request from its own firmware
+    Unknown = 0,
+    PowerOn = 1,
+    /// The reset pin was pulled low
+    ResetPin = 2,
+    /// A debugger connected to the device reset it
+    Debugger = 3,
+    /// The watchdog timer ran out
+    Watchdog = 4,
+    /// The firmware deliberately rebooted the device through the watchdog
+    Software = 5,
+}
+
+impl Field<'_> for ResetReason {
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        (*self as u8).encode(writer)
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        match u8::decode(reader)? {
+            0 => Ok(ResetReason::Unknown),
+            1 => Ok(ResetReason::PowerOn),
+            2 => Ok(ResetReason::ResetPin),
+            3 => Ok(ResetReason::Debugger),
+            4 => Ok(ResetReason::Watchdog),
+            5 => Ok(ResetReason::Software),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+/// Why a device refused to act on a request, sent back in an `ErrorResponse`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// The device doesn't handle requests of this type
+    UnknownMessage = 1,
+    /// The request's payload was too short or too long for its type
+    BadLength = 2,
+    /// A field of the request, such as a row number, was outside of what the device accepts
+    OutOfRange = 3,
+    /// The device's command queue was full, the request can be sent again later
+    QueueFull = 4,
+    /// The device understood the request but can't carry it out, i.e. an RGB update sent to a
+    /// monocolor display
+    Unsupported = 5,
+    /// The request doesn't follow on from the ones before it, i.e. a chunk of a firmware update
+    /// which was never begun
+    InvalidState = 6,
+}
+
+impl ErrorCode {
+    /// The code to answer a request which failed to decode with, frames which fail their CRC
+    /// check get a `Nack` instead.
+    pub fn for_decode_error(err: Error) -> Option<ErrorCode> {
+        match err {
+            Error::UnknownMessage { .. } => Some(ErrorCode::UnknownMessage),
+            Error::UnexpectedEnd | Error::TrailingBytes => Some(ErrorCode::BadLength),
+            Error::InvalidValue => Some(ErrorCode::OutOfRange),
+            Error::BufferTooSmall | Error::CrcMismatch { .. } => None,
+        }
+    }
+}
+
+impl Field<'_> for ErrorCode {
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        (*self as u8).encode(writer)
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        match u8::decode(reader)? {
+            1 => Ok(ErrorCode::UnknownMessage),
+            2 => Ok(ErrorCode::BadLength),
+            3 => Ok(ErrorCode::OutOfRange),
+            4 => Ok(ErrorCode::QueueFull),
+            5 => Ok(ErrorCode::Unsupported),
+            6 => Ok(ErrorCode::InvalidState),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PixelRepresentation {
+    Monocolor = 0,
+    RGB555 = 1,
+}
+
+impl Field<'_> for PixelRepresentation {
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        (*self as u8).encode(writer)
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        match u8::decode(reader)? {
+            0 => Ok(PixelRepresentation::Monocolor),
+            1 => Ok(PixelRepresentation::RGB555),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl ProtocolVersion {
+    pub fn is_compatible_with(&self, other: &ProtocolVersion) -> bool {
+        self.major == other.major
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl Field<'_> for ProtocolVersion {
+    fn encoded_len(&self) -> usize {
+        2
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        writer.put(&[self.major, self.minor])
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        let [major, minor] = reader.take_array()?;
+        Ok(ProtocolVersion { major, minor })
+    }
+}
+
+/// Bitmap of the optional messages a device supports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeviceFeatures(pub u32);
+
+impl DeviceFeatures {
+    pub const NONE: DeviceFeatures = DeviceFeatures(0);
+    pub const UPDATE_ROW_RGB_RLE: DeviceFeatures = DeviceFeatures(1 << 0);
+    pub const UPDATE_ROW_RGB_DELTA: DeviceFeatures = DeviceFeatures(1 << 1);
+    pub const UPDATE_REGION_RGB: DeviceFeatures = DeviceFeatures(1 << 2);
+    /// The device checks CRC trailers on incoming frames and answers frames which fail the check
+    /// with a `Nack`
+    pub const FRAME_CRC32: DeviceFeatures = DeviceFeatures(1 << 3);
+    pub const SET_BRIGHTNESS: DeviceFeatures = DeviceFeatures(1 << 4);
+    /// The device can write a new firmware image to its other boot partition, see `BeginUpdate`
+    pub const FIRMWARE_UPDATE: DeviceFeatures = DeviceFeatures(1 << 5);
+
+    pub fn contains(&self, other: DeviceFeatures) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for DeviceFeatures {
+    type Output = DeviceFeatures;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        DeviceFeatures(self.0 | rhs.0)
+    }
+}
+
+impl Field<'_> for DeviceFeatures {
+    fn encoded_len(&self) -> usize {
+        4
+    }
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+        self.0.encode(writer)
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        u32::decode(reader).map(DeviceFeatures)
+    }
+}
+
+macro_rules! define_wire_messages {
+    ($(
+        $(#[$meta:meta])*
+        $name:ident $(<$lt:lifetime>)? = ($major:literal, $minor:literal) {
+            $(
+                $(#[$field_meta:meta])*
+                $field:ident : $wire_ty:ty => $owned_ty:ty
+            ),* $(,)?
+        }
+    ),* $(,)?) => {
+        $(
+            $(#[$meta])*
+            #[derive(Clone, Copy, Debug)]
+            pub struct $name $(<$lt>)? {
+                $(
+                    $(#[$field_meta])*
+                    pub $field: $wire_ty,
+                )*
+            }
+
+            impl $(<$lt>)? $name $(<$lt>)? {
+                pub const MAJOR: u8 = $major;
+                pub const MINOR: u8 = $minor;
+            }
+
+            impl<'a> Field<'a> for $name $(<$lt>)? {
+                fn encoded_len(&self) -> usize {
+                    0 $(+ self.$field.encoded_len())*
+                }
+
+                #[allow(unused_variables)]
+                fn encode(&self, writer: &mut Writer) -> Result<(), Error> {
+                    $(self.$field.encode(writer)?;)*
+                    Ok(())
+                }
+
+                #[allow(unused_variables)]
+                fn decode(reader: &mut Reader<'a>) -> Result<Self, Error> {
+                    Ok(Self {
+                        $($field: <$wire_ty as Field<'a>>::decode(reader)?,)*
+                    })
+                }
+            }
+
+            impl<'a> From<$name $(<$lt>)?> for Message<'a> {
+                fn from(msg: $name $(<$lt>)?) -> Self {
+                    Message::$name(msg)
+                }
+            }
+        )*
+
+        /// Any message from the message table, borrowing its payload from the buffer it was
+        /// decoded from.
+        #[derive(Clone, Copy, Debug)]
+        pub enum Message<'a> {
+            $($name($name $(<$lt>)?),)*
+        }
+
+        impl<'a> Message<'a> {
+            /// The major and minor message kind bytes which lead the message on the wire.
+            pub fn id(&self) -> (u8, u8) {
+                match self {
+                    $(Message::$name(_) => ($major, $minor),)*
+                }
+            }
+
+            /// The message kind as a single value, the way an `ErrorResponse` refers to a request.
+            pub fn request_type(&self) -> u16 {
+                let (major, minor) = self.id();
+                u16::from_be_bytes([major, minor])
+            }
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Message::$name(_) => stringify!($name),)*
+                }
+            }
+
+            pub fn encoded_len(&self) -> usize {
+                2 + match self {
+                    $(Message::$name(inner) => inner.encoded_len(),)*
+                }
+            }
+
+            /// Encodes the message kind followed by its payload into `buf`, returning the number
+            /// of bytes written.
+            pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+                let mut writer = Writer::new(buf);
+                let (major, minor) = self.id();
+                writer.put(&[major, minor])?;
+                match self {
+                    $(Message::$name(inner) => inner.encode(&mut writer)?,)*
+                }
+                Ok(writer.len())
+            }
+
+            pub fn decode(data: &'a [u8]) -> Result<Self, Error> {
+                let mut reader = Reader::new(data);
+                let [major, minor] = reader.take_array()?;
+                let msg = match (major, minor) {
+                    $(($major, $minor) => Message::$name(<$name as Field>::decode(&mut reader)?),)*
+                    _ => return Err(Error::UnknownMessage { major, minor }),
+                };
+                if reader.is_empty() {
+                    Ok(msg)
+                } else {
+                    Err(Error::TrailingBytes)
+                }
+            }
+        }
+    };
+}
+
+for_each_message!(define_wire_messages);
+
+/// A message along with the sequence number used to correlate a request with its response.
+/// Frames are encoded as the big-endian sequence number followed by the message bytes, and a
+/// device echoes the sequence number of a request in its response. A frame may also end with a
+/// CRC32 trailer, which is flagged with `CRC_FLAG` in the encoded sequence number.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame<'a> {
+    pub seq: u16,
+    pub msg: Message<'a>,
+    /// Whether the frame carries a CRC trailer
+    pub crc: bool,
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(seq: u16, msg: impl Into<Message<'a>>) -> Self {
+        Self {
+            seq,
+            msg: msg.into(),
+            crc: false,
+        }
+    }
+
+    pub fn unsolicited(msg: impl Into<Message<'a>>) -> Self {
+        Self::new(UNSOLICITED_SEQ, msg)
+    }
+
+    pub fn with_crc(self, crc: bool) -> Self {
+        Self { crc, ..self }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        2 + self.msg.encoded_len() + if self.crc { CRC_LEN } else { 0 }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let seq_bytes = buf.get_mut(..2).ok_or(Error::BufferTooSmall)?;
+        seq_bytes.copy_from_slice(&(self.seq & !CRC_FLAG).to_be_bytes());
+        let len = 2 + self.msg.encode(&mut buf[2..])?;
+        if self.crc {
+            append_crc(buf, len)
+        } else {
+            Ok(len)
+        }
+    }
+
+    pub fn decode(data: &'a [u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(data);
+        let seq = u16::decode(&mut reader)?;
+        let crc = seq & CRC_FLAG != 0;
+        let mut msg_data = reader.take_rest();
+        if crc {
+            let split_idx = msg_data
+                .len()
+                .checked_sub(CRC_LEN)
+                .ok_or(Error::UnexpectedEnd)?;
+            let (body, trailer) = msg_data.split_at(split_idx);
+            if crc32::crc32(&data[..(2 + body.len())]).to_be_bytes() != trailer {
+                return Err(Error::CrcMismatch {
+                    seq: seq & !CRC_FLAG,
+                });
+            }
+            msg_data = body;
+        }
+        Ok(Self {
+            seq: seq & !CRC_FLAG,
+            msg: Message::decode(msg_data)?,
+            crc,
+        })
+    }
+}
+
+/// Flags the `len` byte frame encoded at the start of `buf` as carrying a CRC trailer and writes
+/// the trailer after it, returning the new length of the frame.
+pub fn append_crc(buf: &mut [u8], len: usize) -> Result<usize, Error> {
+    if buf.len() < len + CRC_LEN || len < 2 {
+        return Err(Error::BufferTooSmall);
+    }
+    buf[0] |= (CRC_FLAG >> 8) as u8;
+    let crc = crc32::crc32(&buf[..len]);
+    buf[len..(len + CRC_LEN)].copy_from_slice(&crc.to_be_bytes());
+    Ok(len + CRC_LEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_runs(data: &[u8]) -> Result<RunSlice<'_>, Error> {
+        RunSlice::decode(&mut Reader::new(data))
+    }
+
+    #[test]
+    fn runs_round_trip() {
+        let runs = [
+            RleRun {
+                length: 3,
+                value: 0x7fff,
+            },
+            RleRun {
+                length: 255,
+                value: 0x001f,
+            },
+        ];
+        let mut buf = [0u8; 7];
+        let mut writer = Writer::new(&mut buf);
+        RunSlice::Values(&runs).encode(&mut writer).unwrap();
+        assert_eq!(writer.len(), RunSlice::Values(&runs).encoded_len());
+        assert_eq!(buf, [2, 3, 0x7f, 0xff, 255, 0x00, 0x1f]);
+
+        let decoded = decode_runs(&buf).unwrap();
+        assert!(decoded.iter().eq(runs.iter().copied()));
+    }
+
+    #[test]
+    fn runs_which_dont_fill_the_payload_exactly_are_rejected() {
+        // A byte or two left over after the last run
+        assert_eq!(
+            decode_runs(&[1, 3, 0x7f, 0xff, 0x00]).unwrap_err(),
+            Error::InvalidValue
+        );
+        assert_eq!(
+            decode_runs(&[1, 3, 0x7f, 0xff, 0x00, 0x00]).unwrap_err(),
+            Error::InvalidValue
+        );
+        // Fewer or more runs than the count says
+        assert_eq!(
+            decode_runs(&[2, 3, 0x7f, 0xff]).unwrap_err(),
+            Error::InvalidValue
+        );
+        assert_eq!(
+            decode_runs(&[0, 3, 0x7f, 0xff]).unwrap_err(),
+            Error::InvalidValue
+        );
+        assert_eq!(decode_runs(&[]).unwrap_err(), Error::UnexpectedEnd);
+        assert!(decode_runs(&[0]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        for crc in [false, true] {
+            let frame = Frame::new(0x1234, SetBrightness { brightness: 200 }).with_crc(crc);
+            let mut buf = [0u8; 16];
+            let len = frame.encode(&mut buf).unwrap();
+            assert_eq!(len, frame.encoded_len());
+            assert_eq!(buf[0] & 0x80 != 0, crc);
+
+            let decoded = Frame::decode(&buf[..len]).unwrap();
+            assert_eq!(decoded.seq, 0x1234);
+            assert_eq!(decoded.crc, crc);
+            let Message::SetBrightness(msg) = decoded.msg else {
+                panic!("Decoded {} instead", decoded.msg.name());
+            };
+            assert_eq!(msg.brightness, 200);
+        }
+    }
+
+    #[test]
+    fn crc_mismatches_carry_the_frames_seq() {
+        let frame = Frame::new(0x0042, Ping {}).with_crc(true);
+        let mut buf = [0u8; 8];
+        let len = frame.encode(&mut buf).unwrap();
+        buf[len - 1] ^= 0xff;
+        assert_eq!(
+            Frame::decode(&buf[..len]).unwrap_err(),
+            Error::CrcMismatch { seq: 0x0042 }
+        );
+
+        // Too short to hold a trailer at all
+        assert_eq!(
+            Frame::decode(&[0x80, 0x42, 0x00]).unwrap_err(),
+            Error::UnexpectedEnd
+        );
+    }
+
+    #[test]
+    fn decode_errors_map_to_error_codes() {
+        assert_eq!(
+            ErrorCode::for_decode_error(Error::UnknownMessage {
+                major: 0xde,
+                minor: 0x80
+            }),
+            Some(ErrorCode::UnknownMessage)
+        );
+        assert_eq!(
+            ErrorCode::for_decode_error(Error::UnexpectedEnd),
+            Some(ErrorCode::BadLength)
+        );
+        assert_eq!(
+            ErrorCode::for_decode_error(Error::TrailingBytes),
+            Some(ErrorCode::BadLength)
+        );
+        assert_eq!(
+            ErrorCode::for_decode_error(Error::InvalidValue),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(ErrorCode::for_decode_error(Error::BufferTooSmall), None);
+        assert_eq!(
+            ErrorCode::for_decode_error(Error::CrcMismatch { seq: 1 }),
+            None
+        );
+    }
+
+    #[test]
+    fn trailing_optional_fields_decode_from_older_payloads() {
+        let old = [0xa0, 0x05, 0, 0, 0, 64, 0, 0, 0, 32, 1];
+        let Message::GetDisplayInfoResponse(msg) = Message::decode(&old).unwrap() else {
+            panic!("Decoded the wrong message");
+        };
+        assert_eq!((msg.width, msg.height), (64, 32));
+        assert_eq!(msg.cmd_queue_depth, None);
+
+        let mut new = [0u8; 12];
+        new[..11].copy_from_slice(&old);
+        new[11] = 8;
+        let Message::GetDisplayInfoResponse(msg) = Message::decode(&new).unwrap() else {
+            panic!("Decoded the wrong message");
+        };
+        assert_eq!(msg.cmd_queue_depth, Some(8));
+
+        let mut buf = [0u8; 12];
+        assert_eq!(
+            Message::GetDisplayInfoResponse(GetDisplayInfoResponse {
+                cmd_queue_depth: None,
+                ..msg
+            })
+            .encode(&mut buf),
+            Ok(old.len())
+        );
+        assert_eq!(buf[..old.len()], old);
+    }
+
+    #[test]
+    fn empty_runs_are_rejected() {
+        assert_eq!(
+            decode_runs(&[2, 3, 0x7f, 0xff, 0, 0x00, 0x1f]).unwrap_err(),
+            Error::InvalidValue
+        );
+    }
+}
@@ -1,5 +1,6 @@
-use super::{display_buffer::DisplayBuffer, Color};
+use super::Color;
 use async_channel::{Receiver, Sender};
+use megabit_sim_core::DisplayBuffer;
 use std::{
     path::PathBuf,
     sync::{Arc, Mutex},
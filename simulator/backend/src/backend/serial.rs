@@ -1,5 +1,10 @@
 use async_channel::{Receiver, Sender};
-use std::{io, time::Duration};
+use megabit_serial_protocol::{append_crc, wire, Nack, SerialFrame, SerialMessage};
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{
@@ -34,12 +39,14 @@ impl SerialListener {
                     tracing::warn!("Failed to sett tcp nodelay: {err:?}");
                 }
                 let (reader, writer) = stream.split();
+                let (nack_tx, nack_rx) = async_channel::unbounded();
+                let peer_uses_crc = AtomicBool::new(false);
 
                 tokio::select! {
-                    _ = Self::handle_incoming_serial_bytes(reader, to_sim) => {
+                    _ = Self::handle_incoming_serial_bytes(reader, to_sim, nack_tx, &peer_uses_crc) => {
                         tracing::info!("Exiting transport reader");
                     },
-                    res = Self::handle_simulator_packet(writer, from_sim) => {
+                    res = Self::handle_simulator_packet(writer, from_sim, nack_rx, &peer_uses_crc) => {
                         match res {
                             Ok(()) => tracing::info!("Exiting transport writer"),
                             Err(err) => tracing::error!("Transport writer closed due to error: {err:?}"),
@@ -50,7 +57,14 @@ impl SerialListener {
         }
     }
 
-    async fn handle_incoming_serial_bytes(mut reader: ReadHalf<'_>, to_simulator: Sender<Vec<u8>>) {
+    /// Forwards frames from the host to the simulator, answering frames which fail their CRC
+    /// check with a NACK rather than forwarding them.
+    async fn handle_incoming_serial_bytes(
+        mut reader: ReadHalf<'_>,
+        to_simulator: Sender<Vec<u8>>,
+        nack_tx: Sender<Vec<u8>>,
+        peer_uses_crc: &AtomicBool,
+    ) {
         let mut incoming_buffer = Vec::with_capacity(4096);
         loop {
             if incoming_buffer.len() >= 3 {
@@ -63,9 +77,24 @@ impl SerialListener {
                         decoded_data.len(),
                         incoming_buffer.len()
                     );
-                    if let Err(err) = to_simulator.send(decoded_data).await {
-                        tracing::error!("Failed to send serial payload: {err}");
-                        break;
+                    match wire::Frame::decode(&decoded_data[..]) {
+                        Err(wire::Error::CrcMismatch { seq }) => {
+                            tracing::warn!("Frame for request {seq} failed its CRC check");
+                            peer_uses_crc.store(true, Ordering::Relaxed);
+                            let nack = SerialFrame::new(seq, SerialMessage::Nack(Nack {}));
+                            if nack_tx.send(nack.to_bytes()).await.is_err() {
+                                break;
+                            }
+                        }
+                        result => {
+                            if let Ok(frame) = result {
+                                peer_uses_crc.store(frame.crc, Ordering::Relaxed);
+                            }
+                            if let Err(err) = to_simulator.send(decoded_data).await {
+                                tracing::error!("Failed to send serial payload: {err}");
+                                break;
+                            }
+                        }
                     }
                     let (encoded_len, _) = incoming_buffer
                         .iter()
@@ -86,11 +115,25 @@ impl SerialListener {
         tracing::info!("Exiting handler for incoming serial data");
     }
 
+    /// Sends frames from the simulator to the host, adding CRC trailers once the host has
+    /// started using them.
     async fn handle_simulator_packet(
         mut writer: WriteHalf<'_>,
         from_simulator: Receiver<Vec<u8>>,
+        nack_rx: Receiver<Vec<u8>>,
+        peer_uses_crc: &AtomicBool,
     ) -> io::Result<()> {
-        while let Ok(msg) = from_simulator.recv().await {
+        loop {
+            let msg = tokio::select! {
+                msg = from_simulator.recv() => msg,
+                nack = nack_rx.recv() => nack,
+            };
+            let Ok(mut msg) = msg else {
+                break;
+            };
+            if peer_uses_crc.load(Ordering::Relaxed) {
+                append_crc(&mut msg);
+            }
             let mut encoded_data = cobs::encode_vec(&msg[..]);
             encoded_data.push(0x00);
             tracing::debug!("Sending serial data: {encoded_data:02x?}");
@@ -1,15 +1,12 @@
 use crate::backend::recorder::start_recorder;
 
-use super::DisplayConfiguration;
-use super::{display_buffer::DisplayBuffer, recorder::RecorderClient};
+use super::recorder::RecorderClient;
 use async_channel::{Receiver, Sender};
 use megabit_serial_protocol::*;
+use megabit_sim_core::{DisplayConfiguration, Effect, SimulatorCore};
 use megabit_sim_msgs::{SetDebugLed, SetMatrixRow, SetMatrixRowRgb, SetRgbLed, SimMessage};
+use std::sync::Arc;
 use std::time::Duration;
-use std::{
-    convert::AsRef,
-    sync::{Arc, Mutex},
-};
 
 pub async fn run(
     from_ws: Receiver<Vec<u8>>,
@@ -18,17 +15,14 @@ pub async fn run(
     to_serial: Sender<Vec<u8>>,
     display_cfg: DisplayConfiguration,
 ) {
-    let display_buffer = Arc::new(Mutex::new(DisplayBuffer::new(
-        display_cfg.width as usize,
-        display_cfg.height as usize,
-    )));
-    let recorder = start_recorder(display_buffer.clone());
+    let core = Arc::new(SimulatorCore::new(display_cfg));
+    let recorder = start_recorder(core.display_buffer().clone());
 
     tokio::select! {
-        _ = handle_serial_packets(from_serial, to_ws.clone(), to_serial.clone(), display_cfg, display_buffer.clone(), recorder.clone()) => {
+        _ = handle_serial_packets(from_serial, to_ws.clone(), to_serial.clone(), core.clone(), recorder.clone()) => {
             tracing::info!("Serial handler exited");
         },
-        _ = handle_ws_message(from_ws, to_serial, to_ws, &display_cfg, recorder) => {
+        _ = handle_ws_message(from_ws, to_serial, to_ws, core, recorder) => {
             tracing::info!("Websocket message handler exited");
         }
     }
@@ -38,218 +32,96 @@ async fn handle_serial_packets(
     from_serial: Receiver<Vec<u8>>,
     to_ws: Sender<Vec<u8>>,
     to_serial: Sender<Vec<u8>>,
-    display_cfg: DisplayConfiguration,
-    display_buffer: Arc<Mutex<DisplayBuffer>>,
+    core: Arc<SimulatorCore>,
     recorder: RecorderClient,
 ) {
+    let mut effects = Vec::new();
     while let Ok(msg) = from_serial.recv().await {
-        if let Ok(msg) = SerialMessage::try_from_bytes(&msg[..]) {
-            if let Err(err) = handle_serial_message(
-                &to_serial,
-                &to_ws,
-                &display_cfg,
-                &display_buffer,
-                &recorder,
-                msg,
-            )
-            .await
-            {
+        let response = match SerialFrame::try_from_bytes(&msg[..]) {
+            Ok(frame) => Some(core.handle_frame(frame, &mut effects)),
+            Err(_) => core.handle_undecodable(&msg[..]),
+        };
+        if let Err(err) = show_effects(&to_ws, &core, &recorder, effects.drain(..)).await {
+            tracing::error!("Error on updating the frontend: {err}");
+        }
+        if let Some(response) = response {
+            if let Err(err) = to_serial.send(response.to_bytes()).await {
                 tracing::error!("Error on handling serial message: {err}");
             }
-        } else {
-            if msg.len() >= 2 {
-                tracing::warn!(
-                    "Failed to parse serial message with message type: 0x{:02x}{:02x}",
-                    msg[0],
-                    msg[1]
-                )
-            } else {
-                tracing::error!(
-                    "Got message with length {}, not even long enough to have a message type",
-                    msg.len()
-                );
-            }
         }
     }
 }
 
-async fn handle_serial_message(
-    to_serial: &Sender<Vec<u8>>,
+/// Reflects changes to the simulated device in the frontend.
+async fn show_effects(
     to_ws: &Sender<Vec<u8>>,
-    display_cfg: &DisplayConfiguration,
-    display_buffer: &Arc<Mutex<DisplayBuffer>>,
+    core: &SimulatorCore,
     recorder: &RecorderClient,
-    msg: SerialMessage,
+    effects: impl Iterator<Item = Effect>,
 ) -> anyhow::Result<()> {
-    tracing::debug!("Handling serial message: {}", msg.as_ref());
-    match msg {
-        SerialMessage::Ping => {
-            to_serial
-                .send(SerialMessage::PingResponse.to_bytes())
-                .await?;
-        }
-        SerialMessage::UpdateRow(UpdateRow {
-            row_number,
-            row_data_len,
-            row_data,
-        }) => {
-            let status = handle_update_row(
-                to_ws,
-                display_cfg,
-                display_buffer,
-                row_number,
-                row_data_len,
-                row_data,
-            )
-            .await;
-            to_serial
-                .send(SerialMessage::UpdateRowResponse(UpdateRowResponse { status }).to_bytes())
-                .await?;
-        }
-        SerialMessage::UpdateRowRgb(UpdateRowRgb {
-            row_number,
-            row_data_len: _,
-            row_data,
-        }) => {
-            to_ws
-                .send(
-                    rmp_serde::to_vec(&SimMessage::SetMatrixRowRgb(SetMatrixRowRgb {
-                        row: row_number as usize,
-                        data: row_data.clone(),
-                    }))
-                    .unwrap(),
-                )
-                .await?;
-            {
-                let mut display_buffer = display_buffer.lock().unwrap();
-                display_buffer.update_row_rgb(row_number, row_data);
-            }
-            to_serial
-                .send(
-                    SerialMessage::UpdateRowRgbResponse(UpdateRowRgbResponse {
-                        status: Status::Success,
+    let display_cfg = core.display_config();
+    for effect in effects {
+        match effect {
+            Effect::RowUpdated(row_number) => {
+                let row_data = core
+                    .display_buffer()
+                    .lock()
+                    .unwrap()
+                    .row_rgb(row_number)
+                    .to_vec();
+                let row = usize::from(row_number);
+                let msg = if display_cfg.is_rgb {
+                    SimMessage::SetMatrixRowRgb(SetMatrixRowRgb {
+                        row,
+                        data: row_data,
                     })
-                    .to_bytes(),
-                )
-                .await?;
-        }
-        SerialMessage::GetDisplayInfo(GetDisplayInfo) => {
-            to_serial
-                .send(SerialMessage::GetDisplayInfoResponse((*display_cfg).into()).to_bytes())
-                .await?
-        }
-        SerialMessage::SetLedState(SetLedState { new_state }) => {
-            if let Ok(msg) = rmp_serde::to_vec(&SimMessage::SetDebugLed(SetDebugLed { new_state }))
-            {
-                let _ = to_ws.send(msg).await;
-            }
-            to_serial
-                .send(
-                    SerialMessage::SetLedStateResponse(SetLedStateResponse {
-                        status: Status::Success,
+                } else {
+                    SimMessage::SetMatrixRow(SetMatrixRow {
+                        row,
+                        data: row_data.into_iter().map(|pixel| pixel != 0).collect(),
                     })
-                    .to_bytes(),
-                )
-                .await?;
-        }
-        SerialMessage::SetRgbState(SetRgbState { r, g, b }) => {
-            if let Ok(msg) = rmp_serde::to_vec(&SimMessage::SetRgbLed(SetRgbLed { r, g, b })) {
-                let _ = to_ws.send(msg).await;
-            }
-            to_serial
-                .send(
-                    SerialMessage::SetRgbStateResponse(SetRgbStateResponse {
-                        status: Status::Success,
-                    })
-                    .to_bytes(),
-                )
-                .await?;
-        }
-        SerialMessage::RequestCommitRender(RequestCommitRender {}) => {
-            if display_cfg.is_rgb {
-                let _ = to_ws
-                    .send(rmp_serde::to_vec(&SimMessage::RequestRgb).unwrap())
-                    .await;
+                };
+                to_ws.send(rmp_serde::to_vec(&msg)?).await?;
             }
-            if let Ok(msg) = rmp_serde::to_vec(&SimMessage::CommitRender) {
-                let _ = to_ws.send(msg).await;
+            Effect::CommitRender => {
+                if display_cfg.is_rgb {
+                    to_ws
+                        .send(rmp_serde::to_vec(&SimMessage::RequestRgb)?)
+                        .await?;
+                }
+                to_ws
+                    .send(rmp_serde::to_vec(&SimMessage::CommitRender)?)
+                    .await?;
+                if let Err(err) = recorder.capture_frame().await {
+                    tracing::error!("Tried to capture frame on render: {err}");
+                }
             }
-            if let Err(err) = recorder.capture_frame().await {
-                tracing::error!("Tried to capture frame on render: {err}");
+            Effect::SetDebugLed(new_state) => {
+                to_ws
+                    .send(rmp_serde::to_vec(&SimMessage::SetDebugLed(SetDebugLed {
+                        new_state,
+                    }))?)
+                    .await?;
             }
-            to_serial
-                .send(
-                    SerialMessage::CommitRenderResponse(CommitRenderResponse {
-                        status: Status::Success,
-                    })
-                    .to_bytes(),
-                )
-                .await?;
-        }
-        SerialMessage::SetMonocolorPalette(SetMonocolorPalette { color }) => {
-            {
-                let mut display_buf = display_buffer.lock().unwrap();
-                display_buf.set_monocolor_palette(color);
+            Effect::SetRgbLed(r, g, b) => {
+                to_ws
+                    .send(rmp_serde::to_vec(&SimMessage::SetRgbLed(SetRgbLed {
+                        r,
+                        g,
+                        b,
+                    }))?)
+                    .await?;
             }
-            to_serial
-                .send(
-                    SerialMessage::SetMonocolorPaletteResponse(SetMonocolorPaletteResponse {
-                        status: Status::Success,
-                    })
-                    .to_bytes(),
-                )
-                .await?;
+            Effect::SetBrightness(brightness) => send_brightness(to_ws, brightness).await,
         }
-        m => tracing::debug!("Unhandled message received: {}", m.as_ref()),
     }
-
     Ok(())
 }
 
-async fn handle_update_row(
-    to_ws: &Sender<Vec<u8>>,
-    display_cfg: &DisplayConfiguration,
-    display_buffer: &Arc<Mutex<DisplayBuffer>>,
-    row_number: u8,
-    row_data_len: u8,
-    row_data: Vec<u8>,
-) -> Status {
-    if usize::from((row_data_len / 8) + if row_data_len % 8 == 0 { 0 } else { 1 }) == row_data.len()
-    {
-        let pixel_states = row_data
-            .into_iter()
-            .flat_map(|byte| (0..8).map(move |bit| (byte & (1 << bit)) != 0x00))
-            .collect::<Vec<bool>>();
-        let monocolor = {
-            let mut display_buffer = display_buffer.lock().unwrap();
-            display_buffer.update_row(row_number, pixel_states.clone());
-            display_buffer.get_monocolor()
-        };
-        if display_cfg.is_rgb {
-            if let Ok(msg) = rmp_serde::to_vec(&SimMessage::SetMatrixRowRgb(SetMatrixRowRgb {
-                row: usize::from(row_number),
-                data: pixel_states
-                    .into_iter()
-                    .map(|state| if state { monocolor } else { 0 })
-                    .collect(),
-            })) {
-                let _ = to_ws.send(msg).await;
-                Status::Success
-            } else {
-                Status::Failure
-            }
-        } else if let Ok(msg) = rmp_serde::to_vec(&SimMessage::SetMatrixRow(SetMatrixRow {
-            row: usize::from(row_number),
-            data: pixel_states,
-        })) {
-            let _ = to_ws.send(msg).await;
-            Status::Success
-        } else {
-            Status::Failure
-        }
-    } else {
-        tracing::warn!("Got a request to write a matrix row of invalid length");
-        Status::Failure
+async fn send_brightness(to_ws: &Sender<Vec<u8>>, brightness: u8) {
+    let msg = SimMessage::SetBrightness(megabit_sim_msgs::SetBrightness { brightness });
+    if let Ok(msg) = rmp_serde::to_vec(&msg) {
+        let _ = to_ws.send(msg).await;
     }
 }
 
@@ -257,19 +129,55 @@ async fn handle_ws_message(
     from_ws: Receiver<Vec<u8>>,
     to_serial: Sender<Vec<u8>>,
     to_ws: Sender<Vec<u8>>,
-    display_cfg: &DisplayConfiguration,
+    core: Arc<SimulatorCore>,
     recorder: RecorderClient,
 ) {
+    let display_cfg = core.display_config();
     while let Ok(msg) = from_ws.recv().await {
         if let Ok(msg) = rmp_serde::from_slice::<SimMessage>(&msg) {
             match msg {
-                SimMessage::ReportButtonPress => {
-                    tracing::info!("Sending button press notification");
+                SimMessage::ReportButtonEvent(event) => {
+                    tracing::info!(
+                        "Sending {:?} notification for button {}",
+                        event.kind,
+                        event.button_id
+                    );
+                    let kind = match event.kind {
+                        megabit_sim_msgs::ButtonEventKind::Press => ButtonEventKind::Press,
+                        megabit_sim_msgs::ButtonEventKind::Release => ButtonEventKind::Release,
+                        megabit_sim_msgs::ButtonEventKind::LongPress => ButtonEventKind::LongPress,
+                        megabit_sim_msgs::ButtonEventKind::DoublePress => {
+                            ButtonEventKind::DoublePress
+                        }
+                        megabit_sim_msgs::ButtonEventKind::Repeat => ButtonEventKind::Repeat,
+                    };
                     to_serial
-                        .send(SerialMessage::ReportButtonPress.to_bytes())
+                        .send(
+                            SerialFrame::unsolicited(
+                                ReportButtonEvent {
+                                    button_id: event.button_id,
+                                    kind,
+                                }
+                                .into(),
+                            )
+                            .to_bytes(),
+                        )
                         .await
                         .unwrap();
                 }
+                SimMessage::RebootDevice => {
+                    tracing::info!("Rebooting the simulated device");
+                    let mut effects = Vec::new();
+                    let booted = core.reboot(&mut effects);
+                    if let Err(err) =
+                        show_effects(&to_ws, &core, &recorder, effects.into_iter()).await
+                    {
+                        tracing::error!("Failed to show the rebooted device: {err}");
+                    }
+                    if let Err(err) = to_serial.send(booted.to_bytes()).await {
+                        tracing::error!("Failed to announce the rebooted device: {err}");
+                    }
+                }
                 SimMessage::FrontendStarted => {
                     tracing::debug!("Got message indicating that the frontend is started");
                     if display_cfg.is_rgb {
@@ -278,6 +186,9 @@ async fn handle_ws_message(
                             .await
                             .unwrap();
                     }
+                    // A reloaded page starts out at full brightness
+                    let brightness = core.display_buffer().lock().unwrap().get_brightness();
+                    send_brightness(&to_ws, brightness).await;
                 }
                 SimMessage::StartRecording => {
                     if let Err(err) = recorder.start(Duration::from_secs(30)).await {
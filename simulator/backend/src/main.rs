@@ -24,17 +24,9 @@ async fn main() {
         .init();
 
     let display_cfg = if !args.is_monocolor.unwrap_or(false) {
-        DisplayConfiguration {
-            is_rgb: true,
-            width: 64,
-            height: 32,
-        }
+        DisplayConfiguration::RGB
     } else {
-        DisplayConfiguration {
-            is_rgb: false,
-            width: 32,
-            height: 16,
-        }
+        DisplayConfiguration::MONOCOLOR
     };
     tracing::info!("Simulating display with config: {:?}", display_cfg);
 
@@ -5,6 +5,7 @@ pub struct DisplayBuffer {
     height: usize,
     buffer: Vec<u16>,
     monocolor: u16,
+    brightness: u8,
 }
 
 impl DisplayBuffer {
@@ -14,9 +15,17 @@ impl DisplayBuffer {
             height,
             buffer: vec![0; width * height],
             monocolor: DEFAULT_MONOCOLOR,
+            brightness: u8::MAX,
         }
     }
 
+    /// Clears everything a reboot would lose, the brightness is kept like the device keeps it in
+    /// flash.
+    pub fn reset(&mut self) {
+        self.buffer.fill(0);
+        self.monocolor = DEFAULT_MONOCOLOR;
+    }
+
     pub fn dims(&self) -> (usize, usize) {
         (self.width, self.height)
     }
@@ -33,6 +42,14 @@ impl DisplayBuffer {
         self.monocolor
     }
 
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
+
+    pub fn get_brightness(&self) -> u8 {
+        self.brightness
+    }
+
     pub fn update_row(&mut self, row_number: u8, data: Vec<bool>) {
         let row_number = row_number as usize;
         let start_idx = row_number * self.width;
@@ -41,6 +58,11 @@ impl DisplayBuffer {
         }
     }
 
+    pub fn row_rgb(&self, row_number: u8) -> &[u16] {
+        let start_idx = row_number as usize * self.width;
+        &self.buffer[start_idx..(start_idx + self.width)]
+    }
+
     pub fn update_row_rgb(&mut self, row_number: u8, data: Vec<u16>) {
         let row_number = row_number as usize;
         let start_idx = row_number * self.width;
@@ -48,4 +70,25 @@ impl DisplayBuffer {
             self.buffer[idx] = *new_value;
         }
     }
+
+    /// Writes a rectangle of pixels, returning false without writing anything if the region
+    /// doesn't fit on the display or the data doesn't match its dimensions.
+    pub fn update_region_rgb(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        data: &[u16],
+    ) -> bool {
+        if x + width > self.width || y + height > self.height || data.len() != width * height {
+            return false;
+        }
+        for row_offset in 0..height {
+            let start_idx = (y + row_offset) * self.width + x;
+            self.buffer[start_idx..(start_idx + width)]
+                .copy_from_slice(&data[(row_offset * width)..((row_offset + 1) * width)]);
+        }
+        true
+    }
 }
@@ -0,0 +1,342 @@
+//! The simulated coprocessor on its own, answering the serial protocol the same way the firmware
+//! does. It backs both the simulator, which shows the device in a web frontend, and the runner's
+//! in-process loopback transport.
+
+use megabit_serial_protocol::*;
+use std::sync::{Arc, Mutex};
+
+mod display_buffer;
+pub mod loopback;
+
+pub use display_buffer::DisplayBuffer;
+
+/// Depth of the display command queue reported to hosts, matching the coprocessor firmware.
+const CMD_QUEUE_DEPTH: u8 = 8;
+
+/// Identifies the simulator the same way the firmware identifies itself from its image header,
+/// the simulator has no bootloader and always runs out of the first partition.
+pub fn device_info() -> GetDeviceInfoResponse {
+    let version_part = |part: &str| part.parse::<u32>().unwrap_or(0);
+    GetDeviceInfoResponse {
+        protocol_version: PROTOCOL_VERSION,
+        image_version: version_part(env!("CARGO_PKG_VERSION_MAJOR")) << 16
+            | version_part(env!("CARGO_PKG_VERSION_MINOR")) << 8
+            | version_part(env!("CARGO_PKG_VERSION_PATCH")),
+        active_partition: 0,
+        bootloader_version: 0,
+        features: DeviceFeatures::UPDATE_ROW_RGB_RLE
+            | DeviceFeatures::UPDATE_ROW_RGB_DELTA
+            | DeviceFeatures::UPDATE_REGION_RGB
+            | DeviceFeatures::FRAME_CRC32
+            | DeviceFeatures::SET_BRIGHTNESS,
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayConfiguration {
+    pub is_rgb: bool,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DisplayConfiguration {
+    /// The 64x32 RGB panel the coprocessor drives.
+    pub const RGB: DisplayConfiguration = DisplayConfiguration {
+        is_rgb: true,
+        width: 64,
+        height: 32,
+    };
+
+    /// The original 32x16 monocolor display.
+    pub const MONOCOLOR: DisplayConfiguration = DisplayConfiguration {
+        is_rgb: false,
+        width: 32,
+        height: 16,
+    };
+}
+
+impl From<DisplayConfiguration> for GetDisplayInfoResponse {
+    fn from(value: DisplayConfiguration) -> Self {
+        GetDisplayInfoResponse {
+            width: value.width,
+            height: value.height,
+            pixel_representation: if value.is_rgb {
+                PixelRepresentation::RGB555
+            } else {
+                PixelRepresentation::Monocolor
+            },
+            cmd_queue_depth: Some(CMD_QUEUE_DEPTH),
+        }
+    }
+}
+
+/// A change to the simulated device which a frontend showing it may want to reflect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effect {
+    /// A row of the display was written to, its new contents are in the display buffer
+    RowUpdated(u8),
+    CommitRender,
+    SetDebugLed(bool),
+    SetRgbLed(u8, u8, u8),
+    SetBrightness(u8),
+}
+
+/// The response to a request, or why the request was refused.
+type Response = Result<SerialMessage, ErrorCode>;
+
+pub struct SimulatorCore {
+    display_cfg: DisplayConfiguration,
+    display_buffer: Arc<Mutex<DisplayBuffer>>,
+}
+
+impl SimulatorCore {
+    pub fn new(display_cfg: DisplayConfiguration) -> Self {
+        Self {
+            display_cfg,
+            display_buffer: Arc::new(Mutex::new(DisplayBuffer::new(
+                display_cfg.width as usize,
+                display_cfg.height as usize,
+            ))),
+        }
+    }
+
+    pub fn display_config(&self) -> DisplayConfiguration {
+        self.display_cfg
+    }
+
+    pub fn display_buffer(&self) -> &Arc<Mutex<DisplayBuffer>> {
+        &self.display_buffer
+    }
+
+    /// Acts on a request from the host, returning the frame to answer it with. Anything the
+    /// request changed which a frontend would show is added to `effects`.
+    pub fn handle_frame(
+        &self,
+        SerialFrame { seq, msg, .. }: SerialFrame,
+        effects: &mut Vec<Effect>,
+    ) -> SerialFrame {
+        tracing::debug!("Handling serial message: {} ({seq})", msg.as_ref());
+        let request_type = msg.as_wire().request_type();
+        let display_cfg = &self.display_cfg;
+        let response: Response = match msg {
+            SerialMessage::Ping(Ping {}) => Ok(PingResponse {}.into()),
+            SerialMessage::UpdateRow(UpdateRow {
+                row_number,
+                row_data_len,
+                row_data,
+            }) => self
+                .update_row(row_number, row_data_len, row_data, effects)
+                .map(|status| UpdateRowResponse { status }.into()),
+            SerialMessage::UpdateRowRgb(UpdateRowRgb {
+                row_number,
+                row_data_len: _,
+                row_data,
+            }) => self
+                .update_row_rgb(row_number, row_data, effects)
+                .map(|status| UpdateRowRgbResponse { status }.into()),
+            SerialMessage::UpdateRowRgbRle(UpdateRowRgbRle { row_number, runs }) => {
+                tracing::debug!(
+                    "Row {row_number} arrived as {} runs ({} bytes, {} raw)",
+                    runs.len(),
+                    runs.len() * 3,
+                    display_cfg.width * 2
+                );
+                let row_data = rle_decode(&runs);
+                self.update_row_rgb(row_number, row_data, effects)
+                    .map(|status| UpdateRowRgbRleResponse { status }.into())
+            }
+            SerialMessage::UpdateRowRgbDelta(UpdateRowRgbDelta { row_number, runs }) => {
+                tracing::debug!(
+                    "Row {row_number} arrived as {} delta runs ({} bytes, {} raw)",
+                    runs.len(),
+                    runs.len() * 3,
+                    display_cfg.width * 2
+                );
+                let deltas = rle_decode(&runs);
+                let row_data = if u32::from(row_number) < display_cfg.height {
+                    let display_buffer = self.display_buffer.lock().unwrap();
+                    display_buffer
+                        .row_rgb(row_number)
+                        .iter()
+                        .zip(deltas.iter())
+                        .map(|(prev, delta)| prev ^ delta)
+                        .collect()
+                } else {
+                    deltas
+                };
+                self.update_row_rgb(row_number, row_data, effects)
+                    .map(|status| UpdateRowRgbDeltaResponse { status }.into())
+            }
+            SerialMessage::UpdateRegionRgb(UpdateRegionRgb {
+                x,
+                y,
+                width,
+                height,
+                data,
+            }) => {
+                let mut display_buffer = self.display_buffer.lock().unwrap();
+                if data.len() != width as usize * height as usize {
+                    Err(ErrorCode::BadLength)
+                } else if display_buffer.update_region_rgb(
+                    x as usize,
+                    y as usize,
+                    width as usize,
+                    height as usize,
+                    &data,
+                ) {
+                    effects.extend((y..(y + height)).map(Effect::RowUpdated));
+                    Ok(UpdateRegionRgbResponse {
+                        status: Status::Success,
+                    }
+                    .into())
+                } else {
+                    tracing::warn!(
+                        "Got a request to write an RGB region which doesn't fit the display"
+                    );
+                    Err(ErrorCode::OutOfRange)
+                }
+            }
+            SerialMessage::GetDisplayInfo(GetDisplayInfo {}) => {
+                Ok(SerialMessage::GetDisplayInfoResponse((*display_cfg).into()))
+            }
+            SerialMessage::GetDeviceInfo(GetDeviceInfo {}) => Ok(device_info().into()),
+            SerialMessage::SetLedState(SetLedState { new_state }) => {
+                effects.push(Effect::SetDebugLed(new_state));
+                Ok(SetLedStateResponse {
+                    status: Status::Success,
+                }
+                .into())
+            }
+            SerialMessage::SetRgbState(SetRgbState { r, g, b }) => {
+                effects.push(Effect::SetRgbLed(r, g, b));
+                Ok(SetRgbStateResponse {
+                    status: Status::Success,
+                }
+                .into())
+            }
+            SerialMessage::RequestCommitRender(RequestCommitRender {}) => {
+                effects.push(Effect::CommitRender);
+                Ok(CommitRenderResponse {
+                    status: Status::Success,
+                }
+                .into())
+            }
+            SerialMessage::SetBrightness(SetBrightness { brightness }) => {
+                self.display_buffer
+                    .lock()
+                    .unwrap()
+                    .set_brightness(brightness);
+                effects.push(Effect::SetBrightness(brightness));
+                Ok(SetBrightnessResponse {
+                    status: Status::Success,
+                }
+                .into())
+            }
+            SerialMessage::SetMonocolorPalette(SetMonocolorPalette { color }) => {
+                self.display_buffer
+                    .lock()
+                    .unwrap()
+                    .set_monocolor_palette(color);
+                Ok(SetMonocolorPaletteResponse {
+                    status: Status::Success,
+                }
+                .into())
+            }
+            m => {
+                tracing::debug!("Unhandled message received: {}", m.as_ref());
+                Err(ErrorCode::UnknownMessage)
+            }
+        };
+
+        let response = response.unwrap_or_else(|code| ErrorResponse { request_type, code }.into());
+        SerialFrame::new(seq, response)
+    }
+
+    /// Answers a frame which couldn't be decoded with an `ErrorResponse` so that the host isn't
+    /// left waiting on it.
+    pub fn handle_undecodable(&self, msg: &[u8]) -> Option<SerialFrame> {
+        if msg.len() < 4 {
+            tracing::error!(
+                "Got message with length {}, not even long enough to have a message type",
+                msg.len()
+            );
+            return None;
+        }
+
+        let request_type = u16::from_be_bytes([msg[2], msg[3]]);
+        tracing::warn!("Failed to parse serial message with message type: 0x{request_type:04x}");
+        let code = wire::Frame::decode(msg)
+            .err()
+            .and_then(ErrorCode::for_decode_error)?;
+        let seq = u16::from_be_bytes([msg[0], msg[1]]) & !CRC_FLAG;
+        Some(SerialFrame::new(
+            seq,
+            ErrorResponse { request_type, code }.into(),
+        ))
+    }
+
+    /// Blanks the display like a reboot would, returning the announcement the device sends the
+    /// host once it's back up.
+    pub fn reboot(&self, effects: &mut Vec<Effect>) -> SerialFrame {
+        self.display_buffer.lock().unwrap().reset();
+        effects.extend((0..self.display_cfg.height as u8).map(Effect::RowUpdated));
+        effects.push(Effect::CommitRender);
+
+        SerialFrame::unsolicited(
+            DeviceBooted {
+                reset_reason: ResetReason::Software,
+                image_version: device_info().image_version,
+            }
+            .into(),
+        )
+    }
+
+    fn update_row(
+        &self,
+        row_number: u8,
+        row_data_len: u8,
+        row_data: Vec<u8>,
+        effects: &mut Vec<Effect>,
+    ) -> Result<Status, ErrorCode> {
+        if u32::from(row_number) >= self.display_cfg.height {
+            tracing::warn!("Got a request to write a matrix row which is off the display");
+            return Err(ErrorCode::OutOfRange);
+        } else if usize::from(row_data_len.div_ceil(8)) != row_data.len() {
+            tracing::warn!("Got a request to write a matrix row of invalid length");
+            return Err(ErrorCode::BadLength);
+        }
+
+        let pixel_states = row_data
+            .into_iter()
+            .flat_map(|byte| (0..8).map(move |bit| (byte & (1 << bit)) != 0x00))
+            .collect::<Vec<bool>>();
+        self.display_buffer
+            .lock()
+            .unwrap()
+            .update_row(row_number, pixel_states);
+        effects.push(Effect::RowUpdated(row_number));
+        Ok(Status::Success)
+    }
+
+    fn update_row_rgb(
+        &self,
+        row_number: u8,
+        row_data: Vec<u16>,
+        effects: &mut Vec<Effect>,
+    ) -> Result<Status, ErrorCode> {
+        if u32::from(row_number) >= self.display_cfg.height {
+            tracing::warn!("Got a request to write an RGB matrix row which is off the display");
+            return Err(ErrorCode::OutOfRange);
+        } else if row_data.len() != self.display_cfg.width as usize {
+            tracing::warn!("Got a request to write an RGB matrix row of invalid length");
+            return Err(ErrorCode::BadLength);
+        }
+
+        self.display_buffer
+            .lock()
+            .unwrap()
+            .update_row_rgb(row_number, row_data);
+        effects.push(Effect::RowUpdated(row_number));
+        Ok(Status::Success)
+    }
+}
@@ -0,0 +1,149 @@
+//! An in-process device for hosts which want to talk to the simulator without sockets or a
+//! separate process, like the runner's integration tests.
+
+use crate::{DisplayConfiguration, SimulatorCore};
+use megabit_serial_protocol::{wire, Nack, SerialFrame, SerialMessage};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    sync::mpsc,
+};
+
+/// Size of the in-memory pipe between the host and the device, comfortably more than a full
+/// pipeline of the largest frames.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// A simulated device reachable over an in-memory pipe. Clones refer to the same device, so a
+/// test can keep one to poke at the device while the host holds another.
+#[derive(Clone)]
+pub struct LoopbackDevice {
+    core: Arc<SimulatorCore>,
+    /// Frames the device sends on its own to the currently connected host, if there is one
+    unsolicited_tx: Arc<Mutex<Option<mpsc::UnboundedSender<SerialFrame>>>>,
+}
+
+impl LoopbackDevice {
+    pub fn new(display_cfg: DisplayConfiguration) -> Self {
+        Self {
+            core: Arc::new(SimulatorCore::new(display_cfg)),
+            unsolicited_tx: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Opens a new connection to the device, any previous connection is dropped like a port
+    /// which has been opened again.
+    pub fn connect(&self) -> DuplexStream {
+        let (host_end, device_end) = tokio::io::duplex(PIPE_CAPACITY);
+        let (unsolicited_tx, unsolicited_rx) = mpsc::unbounded_channel();
+        *self.unsolicited_tx.lock().unwrap() = Some(unsolicited_tx);
+        tokio::spawn(serve(self.core.clone(), device_end, unsolicited_rx));
+        host_end
+    }
+
+    pub fn core(&self) -> &SimulatorCore {
+        &self.core
+    }
+
+    /// Reboots the device, blanking its display and announcing the boot to the connected host.
+    pub fn reboot(&self) {
+        let booted = self.core.reboot(&mut Vec::new());
+        self.send_frame(booted);
+    }
+
+    /// Sends a message to the connected host as if the device had raised it on its own,
+    /// returning false if there's no host connected to receive it.
+    pub fn send_unsolicited(&self, msg: SerialMessage) -> bool {
+        self.send_frame(SerialFrame::unsolicited(msg))
+    }
+
+    /// Drops the connection to the host like an unplugged cable, the host sees the end of its
+    /// stream.
+    pub fn disconnect(&self) {
+        self.unsolicited_tx.lock().unwrap().take();
+    }
+
+    fn send_frame(&self, frame: SerialFrame) -> bool {
+        self.unsolicited_tx
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|tx| tx.send(frame).is_ok())
+    }
+}
+
+impl fmt::Debug for LoopbackDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoopbackDevice")
+            .field("display_cfg", &self.core.display_config())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Answers the host's requests on one connection until either end hangs up.
+async fn serve(
+    core: Arc<SimulatorCore>,
+    mut stream: DuplexStream,
+    mut unsolicited_rx: mpsc::UnboundedReceiver<SerialFrame>,
+) {
+    let mut incoming_buffer = Vec::with_capacity(4096);
+    let mut peer_uses_crc = false;
+    loop {
+        let outgoing = tokio::select! {
+            read = stream.read_buf(&mut incoming_buffer) => {
+                match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => handle_incoming(&core, &mut incoming_buffer, &mut peer_uses_crc),
+                }
+            }
+            frame = unsolicited_rx.recv() => {
+                let Some(frame) = frame else {
+                    break;
+                };
+                vec![frame.with_crc(peer_uses_crc)]
+            }
+        };
+
+        for frame in outgoing {
+            let mut encoded_data = cobs::encode_vec(&frame.to_bytes());
+            encoded_data.push(0x00);
+            if stream.write_all(&encoded_data).await.is_err() {
+                return;
+            }
+        }
+    }
+    tracing::debug!("Loopback connection closed");
+}
+
+/// Handles every complete frame in the buffer, returning the frames to answer them with.
+fn handle_incoming(
+    core: &SimulatorCore,
+    incoming_buffer: &mut Vec<u8>,
+    peer_uses_crc: &mut bool,
+) -> Vec<SerialFrame> {
+    let mut responses = Vec::new();
+    while let Some(terminator) = incoming_buffer.iter().position(|byte| *byte == 0x00) {
+        let packet = incoming_buffer.drain(..=terminator).collect::<Vec<_>>();
+        let Ok(decoded_data) = cobs::decode_vec(&packet[..terminator]) else {
+            tracing::warn!("Dropping a packet which isn't valid COBS");
+            continue;
+        };
+        let response = match wire::Frame::decode(&decoded_data) {
+            Err(wire::Error::CrcMismatch { seq }) => {
+                *peer_uses_crc = true;
+                Some(SerialFrame::new(seq, SerialMessage::Nack(Nack {})))
+            }
+            Ok(frame) => {
+                *peer_uses_crc = frame.crc;
+                SerialFrame::try_from_bytes(&decoded_data)
+                    .ok()
+                    .map(|frame| core.handle_frame(frame, &mut Vec::new()))
+            }
+            Err(_) => core.handle_undecodable(&decoded_data),
+        };
+        responses.extend(response.map(|frame| frame.with_crc(*peer_uses_crc)));
+    }
+    responses
+}
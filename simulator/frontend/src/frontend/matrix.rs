@@ -40,7 +40,10 @@ pub fn canvas(props: &CanvasProperties) -> Html {
             width={display_size.clone().deref().0.to_string()}
             height={display_size.deref().1.to_string()}
             ref={node_ref}
-            style="background-color: #000000; padding: 10px;"
+            style={format!(
+                "background-color: #000000; padding: 10px; filter: brightness({}%);",
+                props.brightness as u32 * 100 / u8::MAX as u32
+            )}
         >
         </canvas>
     }
@@ -50,6 +53,8 @@ pub fn canvas(props: &CanvasProperties) -> Html {
 pub struct CanvasProperties {
     pub renderer: Callback<HtmlCanvasElement>,
     pub last_render_time: UseStateHandle<JsString>,
+    /// Dims the display, from off at 0 to fully lit at 255
+    pub brightness: u8,
 }
 
 pub struct MatrixBuffer {
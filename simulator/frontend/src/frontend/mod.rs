@@ -5,6 +5,8 @@ mod debug_led;
 use debug_led::DebugLed;
 mod matrix;
 use matrix::{Canvas, MatrixBuffer};
+mod reboot_button;
+use reboot_button::RebootButton;
 mod recording_buttons;
 use recording_buttons::{StartRecording, StopRecording};
 mod rgb_led;
@@ -27,6 +29,9 @@ pub fn app() -> Html {
     let rgb_state = use_state(|| (0, 0, 0));
     let rgb_state_setter = rgb_state.setter();
 
+    let brightness = use_state(|| u8::MAX);
+    let brightness_setter = brightness.setter();
+
     let is_rgb_display = use_state(|| false);
     let is_rgb_display_setter = is_rgb_display.setter();
 
@@ -90,18 +95,20 @@ pub fn app() -> Html {
     };
 
     html! {
-        <WebsocketProvider set_led_state={led_state_setter} set_rgb_state={rgb_state_setter} {update_row_cb} {update_row_rgb_cb} {is_rgb_display_setter} {commit_render_cb}>
+        <WebsocketProvider set_led_state={led_state_setter} set_rgb_state={rgb_state_setter} set_brightness={brightness_setter} {update_row_cb} {update_row_rgb_cb} {is_rgb_display_setter} {commit_render_cb}>
             <h1>{ "Megabit Coproc Simulator" }</h1>
             <div style="display:flex;">
-                <UserButton/>
+                <UserButton button_id={0} />
+                <UserButton button_id={1} />
                 <StartRecording/>
                 <StopRecording/>
+                <RebootButton/>
             </div>
             <div style="display:flex">
                 <DebugLed {led_state} />
                 <RgbLed {rgb_state} />
             </div>
-            <Canvas renderer={renderer_cb} {last_render_time} />
+            <Canvas renderer={renderer_cb} {last_render_time} brightness={*brightness} />
         </WebsocketProvider>
     }
 }
@@ -0,0 +1,30 @@
+use crate::frontend::websocket_provider::use_websocket;
+use megabit_sim_msgs::SimMessage;
+use yew::prelude::*;
+
+#[function_component(RebootButton)]
+pub fn reboot_button(_props: &RebootButtonProperties) -> Html {
+    let ws = use_websocket();
+    let node_ref = NodeRef::default();
+
+    let on_press = {
+        let ws = ws.clone();
+        Callback::from(move |_| {
+            ws.send_message(rmp_serde::to_vec(&SimMessage::RebootDevice).unwrap())
+        })
+    };
+
+    html! {
+        <div style="margin: 10px">
+            <button
+                ref={node_ref}
+                onclick={on_press}
+            >
+                <p>{"Reboot Device"}</p>
+            </button>
+        </div>
+    }
+}
+
+#[derive(Properties, PartialEq)]
+pub struct RebootButtonProperties {}
@@ -1,16 +1,90 @@
-use crate::frontend::websocket_provider::use_websocket;
-use megabit_sim_msgs::SimMessage;
+use crate::frontend::websocket_provider::{use_websocket, WebsocketHandle};
+use gloo::timers::callback::{Interval, Timeout};
+use megabit_sim_msgs::{ButtonEventKind, ReportButtonEvent, SimMessage};
+use std::{cell::RefCell, rc::Rc};
 use yew::prelude::*;
 
+/// Same timings the coprocessor firmware uses to pick out gestures.
+const LONG_PRESS_MS: u32 = 800;
+const REPEAT_INTERVAL_MS: u32 = 200;
+const DOUBLE_PRESS_WINDOW_MS: f64 = 300.0;
+
+/// Tracks a press of the button, dropping the timers cancels the long press and repeats.
+#[derive(Default)]
+struct PressState {
+    held: bool,
+    repeat_timer: Rc<RefCell<Option<Interval>>>,
+    long_press_timer: Option<Timeout>,
+    double_press: bool,
+    last_short_release: Option<f64>,
+}
+
+fn send_button_event(ws: &WebsocketHandle, button_id: u8, kind: ButtonEventKind) {
+    ws.send_message(
+        rmp_serde::to_vec(&SimMessage::ReportButtonEvent(ReportButtonEvent {
+            button_id,
+            kind,
+        }))
+        .unwrap(),
+    )
+}
+
 #[function_component(UserButton)]
-pub fn user_button(_props: &UserButtonProperties) -> Html {
+pub fn user_button(props: &UserButtonProperties) -> Html {
     let ws = use_websocket();
     let node_ref = NodeRef::default();
+    let press_state = use_mut_ref(PressState::default);
+    let button_id = props.button_id;
 
     let on_press = {
         let ws = ws.clone();
+        let press_state = press_state.clone();
         Callback::from(move |_| {
-            ws.send_message(rmp_serde::to_vec(&SimMessage::ReportButtonPress).unwrap())
+            let mut state = press_state.borrow_mut();
+            if state.held {
+                return;
+            }
+            state.held = true;
+            send_button_event(&ws, button_id, ButtonEventKind::Press);
+
+            let now = js_sys::Date::now();
+            state.double_press = state
+                .last_short_release
+                .take()
+                .is_some_and(|released_at| now - released_at <= DOUBLE_PRESS_WINDOW_MS);
+            if state.double_press {
+                send_button_event(&ws, button_id, ButtonEventKind::DoublePress);
+            }
+
+            let ws = ws.clone();
+            let repeat_timer = state.repeat_timer.clone();
+            state.long_press_timer = Some(Timeout::new(LONG_PRESS_MS, move || {
+                send_button_event(&ws, button_id, ButtonEventKind::LongPress);
+                let ws = ws.clone();
+                repeat_timer
+                    .borrow_mut()
+                    .replace(Interval::new(REPEAT_INTERVAL_MS, move || {
+                        send_button_event(&ws, button_id, ButtonEventKind::Repeat)
+                    }));
+            }));
+        })
+    };
+
+    let on_release = {
+        let press_state = press_state.clone();
+        Callback::from(move |_| {
+            let mut state = press_state.borrow_mut();
+            if !state.held {
+                return;
+            }
+            state.held = false;
+            state.long_press_timer = None;
+            let was_long_press = state.repeat_timer.borrow_mut().take().is_some();
+            send_button_event(&ws, button_id, ButtonEventKind::Release);
+
+            if !was_long_press && !state.double_press {
+                state.last_short_release = Some(js_sys::Date::now());
+            }
         })
     };
 
@@ -18,13 +92,18 @@ pub fn user_button(_props: &UserButtonProperties) -> Html {
         <div style="margin: 10px">
             <button
                 ref={node_ref}
-                onclick={on_press}
+                onmousedown={on_press}
+                onmouseup={on_release.clone()}
+                onmouseleave={on_release}
             >
-                <p>{"User Button"}</p>
+                <p>{format!("User Button {button_id}")}</p>
             </button>
         </div>
     }
 }
 
 #[derive(Properties, PartialEq)]
-pub struct UserButtonProperties {}
+pub struct UserButtonProperties {
+    /// Which of the device's buttons this is, starting from zero
+    pub button_id: u8,
+}
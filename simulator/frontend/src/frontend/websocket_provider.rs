@@ -3,7 +3,9 @@ use gloo::{
     net::websocket::{futures::WebSocket, Message},
     utils::window,
 };
-use megabit_sim_msgs::{SetDebugLed, SetMatrixRow, SetMatrixRowRgb, SetRgbLed, SimMessage};
+use megabit_sim_msgs::{
+    SetBrightness, SetDebugLed, SetMatrixRow, SetMatrixRowRgb, SetRgbLed, SimMessage,
+};
 use std::{cell::RefCell, rc::Rc};
 use wasm_bindgen_futures::spawn_local;
 use yew::prelude::*;
@@ -39,6 +41,7 @@ pub fn WebsocketProvider(props: &WebsocketProviderProps) -> Html {
     use_effect_with((), {
         let led_state_setter = props.set_led_state.clone();
         let rgb_state_setter = props.set_rgb_state.clone();
+        let brightness_setter = props.set_brightness.clone();
         let is_rgb_display_setter = props.is_rgb_display_setter.clone();
         let update_cb = props.update_row_cb.clone();
         let update_rgb_cb = props.update_row_rgb_cb.clone();
@@ -67,6 +70,7 @@ pub fn WebsocketProvider(props: &WebsocketProviderProps) -> Html {
                                     &msg[..],
                                     &led_state_setter,
                                     &rgb_state_setter,
+                                    &brightness_setter,
                                     &is_rgb_display_setter,
                                     &update_cb,
                                     &update_rgb_cb,
@@ -110,6 +114,7 @@ pub fn WebsocketProvider(props: &WebsocketProviderProps) -> Html {
 pub struct WebsocketProviderProps {
     pub set_led_state: UseStateSetter<bool>,
     pub set_rgb_state: UseStateSetter<(u8, u8, u8)>,
+    pub set_brightness: UseStateSetter<u8>,
     pub is_rgb_display_setter: UseStateSetter<bool>,
     pub update_row_cb: Callback<(u8, Vec<bool>)>,
     pub update_row_rgb_cb: Callback<(u8, Vec<u16>)>,
@@ -126,6 +131,7 @@ fn handle_simulator_message(
     msg: &[u8],
     led_state_setter: &UseStateSetter<bool>,
     rgb_state_setter: &UseStateSetter<(u8, u8, u8)>,
+    brightness_setter: &UseStateSetter<u8>,
     is_rgb_display_setter: &UseStateSetter<bool>,
     update_cb: &Callback<(u8, Vec<bool>)>,
     update_row_rgb_cb: &Callback<(u8, Vec<u16>)>,
@@ -136,6 +142,9 @@ fn handle_simulator_message(
             SimMessage::CommitRender => commit_cb.emit(()),
             SimMessage::SetDebugLed(SetDebugLed { new_state }) => led_state_setter.set(new_state),
             SimMessage::SetRgbLed(SetRgbLed { r, g, b }) => rgb_state_setter.set((r, g, b)),
+            SimMessage::SetBrightness(SetBrightness { brightness }) => {
+                brightness_setter.set(brightness)
+            }
             SimMessage::SetMatrixRow(SetMatrixRow { row, data }) => {
                 update_cb.emit((row as u8, data));
             }
@@ -6,7 +6,10 @@ pub enum SimMessage {
     FrontendStarted,
     SetDebugLed(SetDebugLed),
     SetRgbLed(SetRgbLed),
-    ReportButtonPress,
+    SetBrightness(SetBrightness),
+    ReportButtonEvent(ReportButtonEvent),
+    /// Resets the simulated device as if it had rebooted, it announces itself to the host again
+    RebootDevice,
     SetMatrixRow(SetMatrixRow),
     SetMatrixRowRgb(SetMatrixRowRgb),
     RequestRgb,
@@ -26,6 +29,26 @@ pub struct SetRgbLed {
     pub b: u8,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetBrightness {
+    pub brightness: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ButtonEventKind {
+    Press,
+    Release,
+    LongPress,
+    DoublePress,
+    Repeat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReportButtonEvent {
+    pub button_id: u8,
+    pub kind: ButtonEventKind,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SetMatrixRow {
     pub row: usize,